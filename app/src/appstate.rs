@@ -1,5 +1,6 @@
 use std::{collections::{BTreeMap, HashMap}, sync::Arc};
 use my_web_app::CountFileMetaColumnData;
+use my_web_app::MarkersResponse;
 
 use std::fmt;
 
@@ -142,6 +143,65 @@ impl MetadataData {
         newself
     }
 
+    ////////////////////////////////////////////////////////////
+    /// Forget a metadata column, return new datas structure
+    pub fn remove_column(&self, column_name: &String) -> MetadataData {
+        let mut newself =  MetadataData {
+            metadatas: self.metadatas.clone()
+        };
+        newself.metadatas.remove(&PerCellDataSource::Metadata(column_name.clone()));
+        newself
+    }
+
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Marker features loaded so far, by (count table name, metadata column)
+pub struct MarkerData {
+    pub markers: HashMap<(String, String), AsyncData<MarkersResponse>>,
+}
+impl MarkerData {
+    ////////////////////////////////////////////////////////////
+    /// Contructor
+    pub fn new() -> MarkerData {
+        MarkerData {
+            markers: HashMap::new(),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get markers for a given count table and metadata column
+    pub fn get(&self, k: &(String, String)) -> AsyncData<MarkersResponse> {
+        let v = self.markers.get(k);
+        if let Some(v) = v {
+            v.clone()
+        } else {
+            AsyncData::NotLoaded
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Insert new entry, return new datas structure
+    pub fn insert(&self, k: &(String, String), value: AsyncData<MarkersResponse>) -> MarkerData {
+        let mut newself =  MarkerData {
+            markers: self.markers.clone()
+        };
+        newself.markers.insert(k.clone(), value);
+        newself
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Forget markers of a metadata column, for all count tables. Returns new datas structure
+    pub fn remove_column(&self, column_name: &String) -> MarkerData {
+        let mut newself =  MarkerData {
+            markers: self.markers.clone()
+        };
+        newself.markers.retain(|(_counts_name, k_column_name), _| k_column_name != column_name);
+        newself
+    }
+
 }
 
 
@@ -1,45 +1,125 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use my_web_app::BarcodesRequest;
+use my_web_app::BarcodesResponse;
+use my_web_app::CellProfileRequest;
+use my_web_app::CellProfileResponse;
+use my_web_app::CountNormalization;
+use my_web_app::DiffExpRequest;
+use my_web_app::FeatureSearchRequest;
+use my_web_app::FeatureSearchResponse;
+use my_web_app::FeatureLookupRequest;
+use my_web_app::FeatureLookupResponse;
+use my_web_app::DiffExpResponse;
+use my_web_app::FeatureCountsBatchRequest;
+use my_web_app::MarkersRequest;
+use my_web_app::MarkersResponse;
+use my_web_app::FeatureCountsBatchResponse;
 use my_web_app::FeatureCountsRequest;
 use my_web_app::DatasetDescRequest;
 use my_web_app::DatasetDescResponse;
+use my_web_app::DatasetListRequest;
+use my_web_app::DatasetListResponse;
 use my_web_app::MetadataColumnRequest;
 use my_web_app::MetadataColumnResponse;
 use my_web_app::ReductionRequest;
 use my_web_app::ReductionResponse;
+use my_web_app::AnnotationEdit;
+use my_web_app::AnnotationEditRequest;
+use my_web_app::SubsetExportRequest;
+use my_web_app::SubsetFormat;
+use my_web_app::SubsetJobRequest;
+use my_web_app::SubsetJobResponse;
+use my_web_app::SubsetJobState;
+use my_web_app::SessionContent;
+use my_web_app::SessionListRequest;
+use my_web_app::SessionListResponse;
+use my_web_app::SessionLoadResponse;
+use my_web_app::SessionRequest;
+use my_web_app::SessionSaveRequest;
 
 use my_web_app::gbrowser_struct::GBrowserGFFchunkID;
 use my_web_app::gbrowser_struct::GBrowserGFFchunkRequest;
 use my_web_app::gbrowser_struct::GBrowserGFFchunkResponse;
 use my_web_app::gbrowser_struct::GBrowserGFFdescription;
 use my_web_app::gbrowser_struct::GBrowserGFFdescriptionRequest;
+use my_web_app::gbrowser_struct::GBrowserFeatureLinkRequest;
+use my_web_app::gbrowser_struct::GBrowserFeatureLinkResponse;
+use my_web_app::gbrowser_struct::GBrowserLocus;
+use my_web_app::gbrowser_struct::GBrowserLocusRequest;
+use my_web_app::gbrowser_struct::GBrowserLocusResponse;
+use wasm_bindgen::prelude::Closure;
+use wasm_bindgen::JsCast;
+use wasm_bindgen::JsValue;
 use web_sys::window;
+use web_sys::EventTarget;
+use web_sys::HtmlSelectElement;
 use yew::prelude::*;
 
 use bytes::Buf;
 
 use crate::appstate::AsyncData;
 use crate::appstate::BiscviCache;
+use crate::appstate::MarkerData;
 use crate::appstate::MetadataData;
 use crate::appstate::PerCellDataSource;
 use crate::appstate::ReductionData;
 use crate::gbrowser::ClientGBrowseData;
+use crate::redview::redview_camera::ViewCamera;
 use crate::redview::redview_main::convert_from_response_to_reduction_data;
 use crate::resize::ComponentSize;
 use crate::resize::ComponentSizeObserver;
+use crate::urlstate::ViewState;
+
+
+/// Number of marker features to show per category
+pub const NUM_MARKERS_SHOWN: usize = 10;
+
+/// Number of hits to show when searching features
+pub const NUM_FEATURE_SEARCH_HITS: usize = 30;
+
+/// Number of features to show per count matrix in the cell inspector
+pub const NUM_CELL_PROFILE_FEATURES: usize = 20;
 
 
 ////////////////////////////////////////////////////////////
 /// Which page is currently being shown?
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone,Copy)]
 pub enum CurrentPage {
     Home,
     Files,
     GenomeBrowser,
     About,
 }
+impl CurrentPage {
+
+    ////////////////////////////////////////////////////////////
+    /// Name of the page in the URL
+    pub fn url_name(&self) -> &'static str {
+        match self {
+            CurrentPage::Home => "reduction",
+            CurrentPage::Files => "files",
+            CurrentPage::GenomeBrowser => "genome",
+            CurrentPage::About => "about",
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get a page given its name in the URL
+    pub fn from_url_name(name: &str) -> Option<CurrentPage> {
+        match name {
+            "reduction" => Some(CurrentPage::Home),
+            "files" => Some(CurrentPage::Files),
+            "genome" => Some(CurrentPage::GenomeBrowser),
+            "about" => Some(CurrentPage::About),
+            _ => None,
+        }
+    }
+}
 
 
 ////////////////////////////////////////////////////////////
@@ -48,18 +128,47 @@ pub enum CurrentPage {
 pub enum MsgCore {
 
     OpenPage(CurrentPage),
+    RestoreViewState(ViewState),
+
+    GetDatasetList(),
+    SetDatasetList(DatasetListResponse),
+    SelectDataset(String),
 
     GetDatasetDesc(),
-    SetDatasetDesc(DatasetDescResponse),
+    SetDatasetDesc(String, DatasetDescResponse),
+
+    GetBarcodes(),
+    SetBarcodes(String, BarcodesResponse),
 
     GetGffDesc(),
-    SetGffDesc(GBrowserGFFdescription),
+    SetGffDesc(String, GBrowserGFFdescription),
 
     GetReduction(String),
-    SetReduction(String, ReductionResponse),
+    SetReduction(String, String, ReductionResponse),
+    SetReductionCamera(ViewCamera),
 
     RequestSetColorByMeta(PerCellDataSource),
-    SetColorByMeta(PerCellDataSource, Option<MetadataColumnResponse>),
+    SetColorByMeta(String, PerCellDataSource, CountNormalization, Option<MetadataColumnResponse>),
+
+    RequestFeaturePanel(String, Vec<String>),
+    SetFeaturePanel(String, String, Vec<String>, CountNormalization, FeatureCountsBatchResponse),
+    SetNormalization(CountNormalization),
+
+    RequestFeatureSearch(String),
+    SetFeatureSearch(String, FeatureSearchResponse),
+    RequestFeatureLookup(String, Vec<String>),
+    SetFeatureLookup(String, FeatureLookupResponse),
+    OpenFeature(PerCellDataSource),
+
+    SetCellSelection(Vec<usize>),
+    RequestCellProfile(usize),
+    SetCellProfile(String, CellProfileResponse),
+    CloseCellProfile,
+    RequestDiffExp(DiffExpRequest),
+    SetDiffExp(String, DiffExpResponse),
+
+    RequestMarkers(String, String),
+    SetMarkers(String, String, String, MarkersResponse),
 
     DataChanged, //Just update using "true"
 
@@ -67,6 +176,27 @@ pub enum MsgCore {
 
     RequestGFFchunks(GBrowserGFFchunkRequest),
     SetGFFchunks(GBrowserGFFchunkResponse),
+    RequestGFFfeatureLink(Vec<(String, String)>),
+    SetGFFfeatureLink(String, GBrowserFeatureLinkResponse),
+    RequestFeatureLocus(String, String),
+    SetFeatureLocus(String, GBrowserLocusResponse),
+    SetGBrowserRange(GBrowserLocus),
+
+    RequestSubsetExport(SubsetFormat),
+    RequestSubsetStatus(String, u64),
+    SetSubsetJob(String, SubsetJobResponse),
+
+    RequestEditAnnotation(AnnotationEdit),
+    SetAnnotationEdited(String, AnnotationEdit, DatasetDescResponse),
+
+    ToggleMetaExpanded(String),
+
+    GetSessionList(),
+    SetSessionList(String, SessionListResponse),
+    RequestSaveSession(String),
+    RequestLoadSession(String),
+    SetSessionLoaded(String, SessionLoadResponse),
+    RequestDeleteSession(String),
 
 }
 
@@ -77,18 +207,55 @@ pub enum MsgCore {
 /// State of the page
 pub struct Model {
     pub current_page: CurrentPage,
+    pub current_dataset: Option<String>,
+    pub datasets: AsyncData<DatasetListResponse>,
     pub current_reduction: Option<String>,              //should be state of a page; move later
     pub current_datadesc: AsyncData<DatasetDescResponse>,  //For now, makes sense to keep this here, as it is static. but risks becoming really large
 
     pub current_gff: AsyncData<Mutex<ClientGBrowseData>>,
+    pub gbrowser_goto: Option<GBrowserLocus>,     // Where the genome browser should move to, when asked from elsewhere
+    pub gbrowser_range: Option<GBrowserLocus>,    // Range last shown by the genome browser
+
+    // Names of cells, shown when hovering
+    pub barcodes: AsyncData<BarcodesResponse>,
 
     // For count tables
     pub reductions: BiscviCache<ReductionData>,        
     pub metadatas: BiscviCache<MetadataData>,          // call something else? countdatas?
 
     pub current_colorby: PerCellDataSource,
+    pub normalization: CountNormalization,     // Applied to all feature counts
+    pub last_feature_search: String,
+    pub feature_search: AsyncData<FeatureSearchResponse>,
+    pub open_features: Vec<PerCellDataSource>,    // Features in the panel, in the order they were opened
+    pub expanded_meta: HashSet<String>,           // Metadata columns expanded in the panel
     pub last_component_size: ComponentSize,
 
+    // Cells selected in the reduction, and differential expression between selections
+    pub cell_selection: BiscviCache<Vec<usize>>,
+    pub diffexp: AsyncData<DiffExpResponse>,
+    pub markers: BiscviCache<MarkerData>,
+
+    // Cell shown in the inspector, and everything about it
+    pub inspected_cell: Option<usize>,
+    pub cell_profile: AsyncData<CellProfileResponse>,
+
+    // Last subset of cells written by the server as a new dataset
+    pub subset_job: AsyncData<SubsetJobResponse>,
+
+    // Workspaces saved on the server for this dataset, and the one last saved or loaded
+    pub sessions: AsyncData<SessionListResponse>,
+    pub current_session: Option<String>,
+
+    // Camera of the reduction as last moved, and where it should move to, such as when going back in history
+    pub reduction_camera: Option<ViewCamera>,
+    pub camera_goto: Option<(String, ViewCamera)>,
+
+    // View kept in the URL. Restoring a view waits until the description of its dataset is known
+    pub last_view: ViewState,
+    pub pending_view: Option<ViewState>,
+    pub popstate_listener: Closure<dyn Fn()>,
+
 }
 impl Component for Model {
 
@@ -99,20 +266,50 @@ impl Component for Model {
     /// Create a new component
     fn create(ctx: &Context<Self>) -> Self {
 
-        //Get initial data to show
-        ctx.link().send_message(MsgCore::GetDatasetDesc());  //reduction desc?
-        ctx.link().send_message(MsgCore::GetGffDesc());
+        //Get initial data to show. The dataset description is requested once a dataset has been picked
+        ctx.link().send_message(MsgCore::RestoreViewState(ViewState::from_current_url()));
+        ctx.link().send_message(MsgCore::GetDatasetList());
+
+        //Going back and forth in the browser history shows the view kept in the URL
+        let link = ctx.link().clone();
+        let popstate_listener = Closure::wrap(Box::new(move || {
+            link.send_message(MsgCore::RestoreViewState(ViewState::from_current_url()));
+        }) as Box<dyn Fn()>);
+        window().expect("no window").set_onpopstate(Some(popstate_listener.as_ref().unchecked_ref()));
 
         Self {
             current_page: CurrentPage::Home,
+            current_dataset: None,
+            datasets: AsyncData::NotLoaded,
             current_reduction: None,
             current_datadesc: AsyncData::NotLoaded,
             current_gff: AsyncData::NotLoaded,
+            gbrowser_goto: None,
+            gbrowser_range: None,
+            barcodes: AsyncData::NotLoaded,
 
             reductions: BiscviCache::new(ReductionData::new()),
             metadatas: BiscviCache::new(MetadataData::new()),
             last_component_size: ComponentSize { width: 100.0, height: 100.0 },
             current_colorby: PerCellDataSource::Metadata("".into()),
+            normalization: CountNormalization::Raw,
+            last_feature_search: String::new(),
+            feature_search: AsyncData::NotLoaded,
+            open_features: Vec::new(),
+            expanded_meta: HashSet::new(),
+            cell_selection: BiscviCache::new(Vec::new()),
+            diffexp: AsyncData::NotLoaded,
+            markers: BiscviCache::new(MarkerData::new()),
+            inspected_cell: None,
+            cell_profile: AsyncData::NotLoaded,
+            subset_job: AsyncData::NotLoaded,
+            sessions: AsyncData::NotLoaded,
+            current_session: None,
+            reduction_camera: None,
+            camera_goto: None,
+            last_view: ViewState::default(),
+            pending_view: None,
+            popstate_listener,
         }
     }
 
@@ -120,9 +317,9 @@ impl Component for Model {
 
 
     ////////////////////////////////////////////////////////////
-    /// Handle an update message
+    /// Handle an update message. The URL follows whatever changed
     fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
-        match msg {
+        let do_update = match msg {
 
             ////////////////////////////////////////////////////////////
             // Message: Data changed, redraw
@@ -137,10 +334,121 @@ impl Component for Model {
                 true
             },
 
+            ////////////////////////////////////////////////////////////
+            // Message: Show a view kept in the URL, on load or when going back and forth in history.
+            // If the view is of another dataset, it is shown once that dataset is loaded
+            MsgCore::RestoreViewState(state) => {
+                self.last_view = state.clone();
+                if let Some(page) = state.page {
+                    self.current_page = page;
+                }
+                match &state.dataset {
+                    Some(dataset_name) if !self.is_current_dataset(dataset_name) => {
+                        if let AsyncData::Loaded(datasets) = &self.datasets {
+                            if datasets.datasets.contains(dataset_name) {
+                                ctx.link().send_message(MsgCore::SelectDataset(dataset_name.clone()));
+                                self.pending_view = Some(state);
+                            } else {
+                                alert(&format!("There is no dataset {}", dataset_name));
+                            }
+                        } else {
+                            //Picked once the list of datasets is known
+                            self.pending_view = Some(state);
+                        }
+                    },
+                    _ => {
+                        if let AsyncData::Loaded(_) = &self.current_datadesc {
+                            self.apply_view_state(ctx, state);
+                        } else {
+                            self.pending_view = Some(state);
+                        }
+                    }
+                }
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Get general dataset description
+            MsgCore::GetDatasetList() => {
+                let query = DatasetListRequest {
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+                
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_dataset_list",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetDatasetList(res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Set list of datasets, sent from server
+            MsgCore::SetDatasetList(res) => {
+                //Pick the dataset in the URL, or else the first one, if none is shown yet
+                if self.current_dataset.is_none() {
+                    let url_dataset = self.pending_view.as_ref()
+                        .and_then(|state| state.dataset.clone())
+                        .filter(|dataset_name| res.datasets.contains(dataset_name));
+                    if let Some(dataset_name) = url_dataset.or_else(|| res.datasets.first().cloned()) {
+                        ctx.link().send_message(MsgCore::SelectDataset(dataset_name));
+                    }
+                }
+                self.datasets = AsyncData::new(res);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Switch to another dataset. All cached data belongs to the old dataset and is dropped
+            MsgCore::SelectDataset(dataset_name) => {
+                self.current_dataset = Some(dataset_name);
+                self.current_reduction = None;
+                self.current_datadesc = AsyncData::NotLoaded;
+                self.current_gff = AsyncData::NotLoaded;
+                self.gbrowser_goto = None;
+                self.gbrowser_range = None;
+                self.barcodes = AsyncData::NotLoaded;
+                self.reductions = BiscviCache::new(ReductionData::new());
+                self.metadatas = BiscviCache::new(MetadataData::new());
+                self.current_colorby = PerCellDataSource::Metadata("".into());
+                self.cell_selection = BiscviCache::new(Vec::new());
+                self.diffexp = AsyncData::NotLoaded;
+                self.markers = BiscviCache::new(MarkerData::new());
+                self.inspected_cell = None;
+                self.cell_profile = AsyncData::NotLoaded;
+                self.last_feature_search = String::new();
+                self.feature_search = AsyncData::NotLoaded;
+                self.open_features = Vec::new();
+                self.expanded_meta = HashSet::new();
+                self.subset_job = AsyncData::NotLoaded;
+                self.sessions = AsyncData::NotLoaded;
+                self.current_session = None;
+                self.reduction_camera = None;
+                self.camera_goto = None;
+
+                ctx.link().send_message(MsgCore::GetDatasetDesc());
+                ctx.link().send_message(MsgCore::GetBarcodes());
+                ctx.link().send_message(MsgCore::GetGffDesc());
+                ctx.link().send_message(MsgCore::GetSessionList());
+                true
+            },
+
             ////////////////////////////////////////////////////////////
             // Message: Get general dataset description
             MsgCore::GetDatasetDesc() => {
+                let dataset_name = self.get_current_dataset();
                 let query = DatasetDescRequest {
+                    dataset_name: dataset_name.clone(),
                 };
                 let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
                 
@@ -157,7 +465,7 @@ impl Component for Model {
                         .await
                         .expect("Could not get binary data");
                     let res = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
-                    MsgCore::SetDatasetDesc(res)
+                    MsgCore::SetDatasetDesc(dataset_name, res)
                 };
                 ctx.link().send_future(get_data);
                 false
@@ -165,16 +473,65 @@ impl Component for Model {
 
             ////////////////////////////////////////////////////////////
             // Message: Set reduction data, sent from server
-            MsgCore::SetDatasetDesc(res) => {
+            MsgCore::SetDatasetDesc(dataset_name, res) => {
                 //log::debug!("got desc {:?}",res);
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
                 self.current_datadesc = AsyncData::new(res);
+
+                //Show the view kept in the URL, now that it can be checked against the dataset
+                if let Some(state) = self.pending_view.take() {
+                    if state.dataset.is_none() || state.dataset == self.current_dataset {
+                        self.apply_view_state(ctx, state);
+                    }
+                }
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Get names of all cells
+            MsgCore::GetBarcodes() => {
+                let dataset_name = self.get_current_dataset();
+                let query = BarcodesRequest {
+                    dataset_name: dataset_name.clone(),
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_barcodes",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetBarcodes(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Set names of cells, sent from server
+            MsgCore::SetBarcodes(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                self.barcodes = AsyncData::new(res);
                 true
             },
 
             ////////////////////////////////////////////////////////////
             // Message: Get x description
             MsgCore::GetGffDesc() => {
+                let dataset_name = self.get_current_dataset();
                 let query = GBrowserGFFdescriptionRequest {
+                    dataset_name: dataset_name.clone(),
                 };
                 let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
                 
@@ -191,7 +548,7 @@ impl Component for Model {
                         .await
                         .expect("Could not get binary data");
                     let res = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
-                    MsgCore::SetGffDesc(res)
+                    MsgCore::SetGffDesc(dataset_name, res)
                 };
                 ctx.link().send_future(get_data);
                 false
@@ -199,8 +556,11 @@ impl Component for Model {
 
             ////////////////////////////////////////////////////////////
             // Message: Set xx, sent from server
-            MsgCore::SetGffDesc(res) => {
+            MsgCore::SetGffDesc(dataset_name, res) => {
                 //log::debug!("got desc {:?}",res);
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
                 self.current_gff = AsyncData::new(Mutex::new(ClientGBrowseData { 
                     desc: res,
                     chunks: HashMap::new()
@@ -212,8 +572,12 @@ impl Component for Model {
             // Message: Get a given reduction
             MsgCore::GetReduction(reduction_name) => {
 
-                //Show new reduction
+                //Show new reduction. Where the camera was only applies to the previous one
                 //log::debug!("GetReduction ask for reduction {:?}",reduction_name);
+                if self.current_reduction.as_ref() != Some(&reduction_name) {
+                    self.reduction_camera = None;
+                    self.camera_goto = None;
+                }
                 self.current_reduction = Some(reduction_name.clone());
 
                 //Insert a loading place holder until data received
@@ -221,7 +585,9 @@ impl Component for Model {
                 //log::debug!("for now added Loading reduction {:?}",reduction_name);
 
                 //Request data
+                let dataset_name = self.get_current_dataset();
                 let query = ReductionRequest {
+                    dataset_name: dataset_name.clone(),
                     reduction_name: reduction_name.clone()
                 };
                 let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
@@ -239,7 +605,7 @@ impl Component for Model {
                         .expect("Could not get binary data");
                     //log::debug!("sent reduction request {:?}",res);
                     let res = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
-                    MsgCore::SetReduction(reduction_name, res)
+                    MsgCore::SetReduction(dataset_name, reduction_name, res)
                 };
                 ctx.link().send_future(get_data);
 
@@ -248,7 +614,10 @@ impl Component for Model {
 
             ////////////////////////////////////////////////////////////
             // Message: Set reduction data, sent from server
-            MsgCore::SetReduction(reduction_name, res) => {
+            MsgCore::SetReduction(dataset_name, reduction_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
                 //log::debug!("set reduction from server {} :: {:?}; this should trigger a refresh??",reduction_name, res);
                 //log::debug!("set reduction from server {} ",reduction_name);
 
@@ -258,6 +627,14 @@ impl Component for Model {
                 true
             },
 
+            ////////////////////////////////////////////////////////////
+            // Message: The camera of the reduction has moved. Only the URL needs to follow.
+            // Once moved by the user, the camera no longer needs to go anywhere
+            MsgCore::SetReductionCamera(cam) => {
+                self.reduction_camera = Some(cam);
+                self.camera_goto.take().is_some()
+            },
+
             ////////////////////////////////////////////////////////////
             // Message: Set reduction data, sent from server
             MsgCore::RequestSetColorByMeta(name) => {   //name??
@@ -268,7 +645,9 @@ impl Component for Model {
 
                 //For now, point to show new data. But we might not yet have it
                 self.current_colorby = name.clone();
-                ctx.link().send_message(MsgCore::SetColorByMeta(name.clone(), None));
+                let dataset_name = self.get_current_dataset();
+                let normalization = self.normalization;
+                ctx.link().send_message(MsgCore::SetColorByMeta(dataset_name.clone(), name.clone(), normalization, None));
 
                 //If needed, request data
                 if !has_data {
@@ -277,6 +656,7 @@ impl Component for Model {
                         PerCellDataSource::Metadata(column_name) => {
 
                             let query: MetadataColumnRequest = MetadataColumnRequest {
+                                dataset_name: dataset_name.clone(),
                                 column_name: column_name.clone(),
                             };
                             let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
@@ -297,7 +677,7 @@ impl Component for Model {
 
                                 //log::debug!("got MetadataColumnRequest response {:?}",res);
 
-                                MsgCore::SetColorByMeta(name, Some(res))
+                                MsgCore::SetColorByMeta(dataset_name, name, normalization, Some(res))
                             };
                             ctx.link().send_future(get_data);                            
 
@@ -305,8 +685,10 @@ impl Component for Model {
                         PerCellDataSource::Counts(counts_name, feature_name) => {
 
                             let query = FeatureCountsRequest {
+                                dataset_name: dataset_name.clone(),
                                 counts_name: counts_name.clone(),
                                 feature_name: feature_name.clone(), // 0, // column_name.clone(),   feature_name
+                                normalization: normalization,
                             };
                             let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
 
@@ -326,7 +708,7 @@ impl Component for Model {
 
                                 //log::debug!("got FeatureCountsRequest response {:?}",res);
 
-                                MsgCore::SetColorByMeta(name, Some(res))
+                                MsgCore::SetColorByMeta(dataset_name, name, normalization, Some(res))
                             };
                             ctx.link().send_future(get_data);
 
@@ -339,8 +721,17 @@ impl Component for Model {
 
             ////////////////////////////////////////////////////////////
             // Message: Set reduction data, sent from server
-            MsgCore::SetColorByMeta(name, res) => {  
+            MsgCore::SetColorByMeta(dataset_name, name, normalization, res) => {  
                 //log::debug!("SetColorByMeta {} {:?}",name, res);
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                //Counts normalized in a mode no longer used are dropped
+                if let PerCellDataSource::Counts(_, _) = &name {
+                    if res.is_some() && normalization != self.normalization {
+                        return false;
+                    }
+                }
                 //Update data if needed
                 if let Some(res) = res {
                     self.metadatas = BiscviCache::new(self.metadatas.data.insert(&name, AsyncData::new(res.data)));
@@ -349,30 +740,38 @@ impl Component for Model {
             },
 
             ////////////////////////////////////////////////////////////
-            // Message: Window is resized
-            MsgCore::WindowResize(size) => {  
-                self.last_component_size = size;
-                true
-            },
-
-            ////////////////////////////////////////////////////////////
-            // Message: Get GFF data for genome browser
-            MsgCore::RequestGFFchunks(query) => {
+            // Message: Load counts for a list of features, in one request
+            MsgCore::RequestFeaturePanel(counts_name, feature_names) => {
+
+                //Only ask for what is not already loaded or loading
+                let feature_names: Vec<String> = feature_names.into_iter()
+                    .filter(|f| !self.metadatas.data.metadatas.contains_key(&PerCellDataSource::Counts(counts_name.clone(), f.clone())))
+                    .collect();
+                if feature_names.is_empty() {
+                    return false;
+                }
 
                 //Insert loading place holders until data received
-                if let AsyncData::Loaded(current_gff) = &self.current_gff.clone() {  //can we avoid clone? TODO
-                    let mut current_gff_content = current_gff.lock().unwrap();
-                    current_gff_content.set_loading(&query);
-                    //Make a note that the content changed
-                    self.current_gff = AsyncData::Loaded(Arc::clone(current_gff))
+                let mut new_metadatas = MetadataData { metadatas: self.metadatas.data.metadatas.clone() };
+                for f in &feature_names {
+                    new_metadatas.metadatas.insert(PerCellDataSource::Counts(counts_name.clone(), f.clone()), AsyncData::Loading);
                 }
+                self.metadatas = BiscviCache::new(new_metadatas);
 
                 //Request data
+                let dataset_name = self.get_current_dataset();
+                let query = FeatureCountsBatchRequest {
+                    dataset_name: dataset_name.clone(),
+                    counts_name: counts_name.clone(),
+                    feature_names: feature_names.clone(),
+                    normalization: self.normalization,
+                };
                 let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
 
+                let normalization = self.normalization;
                 let get_data = async move {
                     let client = reqwest::Client::new();
-                    let res = client.post(format!("{}/get_gff_chunks",get_host_url()))
+                    let res = client.post(format!("{}/get_featurecounts_batch",get_host_url()))
                         .header("Content-Type", "application/json")
                         .body(query_json) 
                         .send()
@@ -381,83 +780,999 @@ impl Component for Model {
                         .bytes()
                         .await
                         .expect("Could not get binary data");
-                    let res = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
-                    MsgCore::SetGFFchunks(res)
+                    let res: FeatureCountsBatchResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetFeaturePanel(dataset_name, counts_name, feature_names, normalization, res)
                 };
                 ctx.link().send_future(get_data);
-                true //can already show loading status, so true
+                true
             },
 
             ////////////////////////////////////////////////////////////
-            // Message: Set GFF data, sent from server
-            MsgCore::SetGFFchunks(res) => {  //may need to know what is empty. or store in message back
-                log::debug!("SetGFFchunks");
-                if let AsyncData::Loaded(current_gff) = &self.current_gff.clone() {
-                    let mut current_gff_content = current_gff.lock().unwrap();
-                    current_gff_content.set_chunks(res);
-                    //Make a note that the content changed
-                    self.current_gff = AsyncData::Loaded(Arc::clone(current_gff))
+            // Message: Set counts for a list of features, sent from server
+            MsgCore::SetFeaturePanel(dataset_name, counts_name, feature_names, normalization, res) => {
+                if !self.is_current_dataset(&dataset_name) || normalization != self.normalization {
+                    return false;
                 }
+                let mut new_metadatas = MetadataData { metadatas: self.metadatas.data.metadatas.clone() };
+                for (f, data) in feature_names.into_iter().zip(res.data.into_iter()) {
+                    new_metadatas.metadatas.insert(PerCellDataSource::Counts(counts_name.clone(), f), AsyncData::new(data));
+                }
+                self.metadatas = BiscviCache::new(new_metadatas);
                 true
             },
 
+            ////////////////////////////////////////////////////////////
+            // Message: Change how feature counts are normalized. Counts loaded so far are
+            // dropped, and loaded again in the new mode
+            MsgCore::SetNormalization(normalization) => {
+                if self.normalization == normalization {
+                    return false;
+                }
+                self.normalization = normalization;
 
-        }
-    }
-
-
-    ////////////////////////////////////////////////////////////
-    /// Top renderer of the page
-    fn view(&self, ctx: &Context<Self>) -> Html {
+                let mut to_reload: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                let mut new_metadatas = MetadataData::new();
+                for (k, v) in &self.metadatas.data.metadatas {
+                    match k {
+                        PerCellDataSource::Counts(counts_name, feature_name) => {
+                            to_reload.entry(counts_name.clone()).or_default().push(feature_name.clone());
+                        },
+                        PerCellDataSource::Metadata(_) => {
+                            new_metadatas.metadatas.insert(k.clone(), v.clone());
+                        },
+                    }
+                }
+                self.metadatas = BiscviCache::new(new_metadatas);
+                for (counts_name, feature_names) in to_reload {
+                    ctx.link().send_message(MsgCore::RequestFeaturePanel(counts_name, feature_names));
+                }
+                true
+            },
 
+            ////////////////////////////////////////////////////////////
+            // Message: Search features by name. Sent for each keystroke
+            MsgCore::RequestFeatureSearch(query) => {
+                if query == self.last_feature_search {
+                    return false;
+                }
+                self.last_feature_search = query.clone();
+                if query.trim().is_empty() {
+                    self.feature_search = AsyncData::NotLoaded;
+                    return true;
+                }
 
-        let current_page = match self.current_page { 
-            CurrentPage::Home => self.view_dimred_page(&ctx),
-            CurrentPage::GenomeBrowser => self.view_gbrowser_page(&ctx),
-            CurrentPage::Files => self.view_files_page(&ctx),
-            CurrentPage::About => self.view_about_page(&ctx),
-        };
+                let dataset_name = self.get_current_dataset();
+                let query = FeatureSearchRequest {
+                    dataset_name: dataset_name.clone(),
+                    query: query,
+                    limit: NUM_FEATURE_SEARCH_HITS,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
 
-        fn active_if(cond: bool) -> String {
-            if cond {
-                "btn_top_active".to_string()
-            } else {
-                "btn_top_inactive".to_string()
-            }
-        }
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/search_features",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: FeatureSearchResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetFeatureSearch(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
 
-        //let window = window().expect("no window");
-        // window.addEventListener('resize', resizeCanvas, false);
-        // https://yew.rs/docs/next/concepts/html/events
+            ////////////////////////////////////////////////////////////
+            // Message: Set search hits, sent from server. Hits for anything but the last query are dropped
+            MsgCore::SetFeatureSearch(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) || res.query != self.last_feature_search {
+                    return false;
+                }
+                self.feature_search = AsyncData::new(res);
+                true
+            },
 
-        let onsize = ctx.link().callback(|size: ComponentSize| {
-            MsgCore::WindowResize(size)
-        });
+            ////////////////////////////////////////////////////////////
+            // Message: Check which names in a pasted list are features. Only the server knows all names
+            MsgCore::RequestFeatureLookup(counts_name, feature_names) => {
+                let dataset_name = self.get_current_dataset();
+                let query = FeatureLookupRequest {
+                    dataset_name: dataset_name.clone(),
+                    counts_name: counts_name,
+                    feature_names: feature_names,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
 
-        html! {
-            <div style="position: relative;"> // added style
-                <ComponentSizeObserver onsize={onsize} />
-                <div class="biscvi-topdiv">
-                    <div style="float: left; padding: 10px; font-size: 30px; font-family: 'Roboto', sans-serif; font-weight: 900;">
-                        {"Biscvi"}
-                    </div>
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/lookup_features",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: FeatureLookupResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetFeatureLookup(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
 
-                    <a class={active_if(self.current_page==CurrentPage::About)}          onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::About))}>{"About"}</a> 
-                    <a class={active_if(self.current_page==CurrentPage::GenomeBrowser)}  onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::GenomeBrowser))}>{"Genome Browser"}</a> 
-                    <a class={active_if(self.current_page==CurrentPage::Files)}          onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::Files))}>{"Files"}</a> 
-                    <a class={active_if(self.current_page==CurrentPage::Home)}           onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::Home))}>{"Dimensional Reduction"}</a> 
+            ////////////////////////////////////////////////////////////
+            // Message: Set result of checking a list of features, sent from server. Counts of known features are loaded right away
+            MsgCore::SetFeatureLookup(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                for name in &res.unknown {
+                    log::debug!("Skipping unknown feature {}", name);
+                }
 
-                </div>
-                { current_page }
+                //Open the known features. Unknown ones may have come from the URL, and are closed
+                let counts_name = &res.counts_name;
+                self.open_features.retain(|f| match f {
+                    PerCellDataSource::Counts(c, name) => !(c == counts_name && res.unknown.contains(name)),
+                    PerCellDataSource::Metadata(_) => true,
+                });
+                for name in &res.known {
+                    let feature_name = PerCellDataSource::Counts(counts_name.clone(), name.clone());
+                    if !self.open_features.contains(&feature_name) {
+                        self.open_features.push(feature_name);
+                    }
+                }
+                if let PerCellDataSource::Counts(c, name) = &self.current_colorby {
+                    if c == counts_name && res.unknown.contains(name) {
+                        self.current_colorby = PerCellDataSource::Metadata("".into());
+                    }
+                }
 
+                if !res.known.is_empty() {
+                    ctx.link().send_message(MsgCore::RequestFeaturePanel(res.counts_name.clone(), res.known.clone()));
+                }
+                true
+            },
 
-            </div>
-        }
-    }
+            ////////////////////////////////////////////////////////////
+            // Message: Add a feature to the panel
+            MsgCore::OpenFeature(feature_name) => {
+                if self.open_features.contains(&feature_name) {
+                    return false;
+                }
+                self.open_features.push(feature_name);
+                true
+            },
 
-}
+            ////////////////////////////////////////////////////////////
+            // Message: Cells have been selected in the reduction
+            MsgCore::SetCellSelection(cells) => {
+                self.cell_selection = BiscviCache::new(cells);
+                true
+            },
 
+            ////////////////////////////////////////////////////////////
+            // Message: Get everything about one cell, for the inspector
+            MsgCore::RequestCellProfile(cell) => {
+                if self.inspected_cell == Some(cell) {
+                    return false;
+                }
+                self.inspected_cell = Some(cell);
+                self.cell_profile = AsyncData::Loading;
+
+                let dataset_name = self.get_current_dataset();
+                let query = CellProfileRequest {
+                    dataset_name: dataset_name.clone(),
+                    cell: cell,
+                    top_n: NUM_CELL_PROFILE_FEATURES,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
 
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_cell_profile",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: CellProfileResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetCellProfile(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Set cell profile, sent from server. Ignored if another cell has been clicked since
+            MsgCore::SetCellProfile(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) || self.inspected_cell != Some(res.cell) {
+                    return false;
+                }
+                self.cell_profile = AsyncData::new(res);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Hide the cell inspector
+            MsgCore::CloseCellProfile => {
+                self.inspected_cell = None;
+                self.cell_profile = AsyncData::NotLoaded;
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Compute differential expression between two groups of cells
+            MsgCore::RequestDiffExp(query) => {
+                self.diffexp = AsyncData::Loading;
+
+                let dataset_name = self.get_current_dataset();
+                let mut query = query;
+                query.dataset_name = dataset_name.clone();
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_diffexp",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: DiffExpResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetDiffExp(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Set differential expression, sent from server
+            MsgCore::SetDiffExp(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                self.diffexp = AsyncData::new(res);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Get marker features for each category of a metadata column
+            MsgCore::RequestMarkers(counts_name, column_name) => {
+                let key = (counts_name.clone(), column_name.clone());
+                if self.markers.data.markers.contains_key(&key) {
+                    return false;
+                }
+
+                //Insert a loading place holder until data received
+                self.markers = BiscviCache::new(self.markers.data.insert(&key, AsyncData::Loading));
+
+                let dataset_name = self.get_current_dataset();
+                let query = MarkersRequest {
+                    dataset_name: dataset_name.clone(),
+                    counts_name: counts_name.clone(),
+                    column_name: column_name.clone(),
+                    top_n: NUM_MARKERS_SHOWN,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_markers",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: MarkersResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetMarkers(dataset_name, counts_name, column_name, res)
+                };
+                ctx.link().send_future(get_data);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Set marker features, sent from server
+            MsgCore::SetMarkers(dataset_name, counts_name, column_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                self.markers = BiscviCache::new(self.markers.data.insert(&(counts_name, column_name), AsyncData::new(res)));
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Window is resized
+            MsgCore::WindowResize(size) => {  
+                self.last_component_size = size;
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Get GFF data for genome browser
+            MsgCore::RequestGFFchunks(query) => {
+
+                //Insert loading place holders until data received
+                if let AsyncData::Loaded(current_gff) = &self.current_gff.clone() {  //can we avoid clone? TODO
+                    let mut current_gff_content = current_gff.lock().unwrap();
+                    current_gff_content.set_loading(&query);
+                    //Make a note that the content changed
+                    self.current_gff = AsyncData::Loaded(Arc::clone(current_gff))
+                }
+
+                //Request data
+                let mut query = query;
+                query.dataset_name = self.get_current_dataset();
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_gff_chunks",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetGFFchunks(res)
+                };
+                ctx.link().send_future(get_data);
+                true //can already show loading status, so true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Set GFF data, sent from server
+            MsgCore::SetGFFchunks(res) => {  //may need to know what is empty. or store in message back
+                log::debug!("SetGFFchunks");
+                if let AsyncData::Loaded(current_gff) = &self.current_gff.clone() {
+                    let mut current_gff_content = current_gff.lock().unwrap();
+                    current_gff_content.set_chunks(res);
+                    //Make a note that the content changed
+                    self.current_gff = AsyncData::Loaded(Arc::clone(current_gff))
+                }
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: A GFF record was clicked. Find out which feature it is, by its attributes
+            MsgCore::RequestGFFfeatureLink(attributes) => {
+                let dataset_name = self.get_current_dataset();
+                let query = GBrowserFeatureLinkRequest {
+                    dataset_name: dataset_name.clone(),
+                    attributes: attributes,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_gff_feature_link",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: GBrowserFeatureLinkResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetGFFfeatureLink(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Feature of a clicked GFF record, sent from server. Color the reduction by it
+            MsgCore::SetGFFfeatureLink(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                if let Some((counts_name, feature_name)) = res.feature {
+                    ctx.link().send_message(MsgCore::RequestSetColorByMeta(PerCellDataSource::Counts(counts_name, feature_name)));
+                    ctx.link().send_message(MsgCore::OpenPage(CurrentPage::Home));
+                } else {
+                    alert("This GFF record does not match any feature in the count matrices");
+                }
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Show a feature in the genome browser. Its locus is known by the server
+            MsgCore::RequestFeatureLocus(counts_name, feature_name) => {
+                let dataset_name = self.get_current_dataset();
+                let query = GBrowserLocusRequest {
+                    dataset_name: dataset_name.clone(),
+                    counts_name: counts_name,
+                    feature_name: feature_name,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/get_feature_locus",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: GBrowserLocusResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetFeatureLocus(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Locus of a feature, sent from server. Open the genome browser there
+            MsgCore::SetFeatureLocus(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                if let Some(locus) = res.locus {
+                    self.gbrowser_goto = Some(locus);
+                    self.gbrowser_range = None;
+                    self.current_page = CurrentPage::GenomeBrowser;
+                    true
+                } else {
+                    alert(&format!("{} is not in the GFF file", res.feature_name));
+                    false
+                }
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: The genome browser has moved. Only the URL needs to follow
+            MsgCore::SetGBrowserRange(locus) => {
+                self.gbrowser_range = Some(locus);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Write the selected cells as a new dataset, for download
+            MsgCore::RequestSubsetExport(format) => {
+                self.subset_job = AsyncData::Loading;
+
+                let dataset_name = self.get_current_dataset();
+                let query = SubsetExportRequest {
+                    dataset_name: dataset_name.clone(),
+                    cells: self.cell_selection.data.iter().map(|x| *x as u32).collect(),
+                    format: format,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/subset_start",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: SubsetJobResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetSubsetJob(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Ask about a subset job. The server answers once the job has progressed
+            MsgCore::RequestSubsetStatus(dataset_name, job_id) => {
+                let query = SubsetJobRequest {
+                    job_id: job_id,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/subset_status",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: SubsetJobResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetSubsetJob(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: State of a subset job, sent from server. Keep asking until it is finished
+            MsgCore::SetSubsetJob(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                //Ignore answers about jobs replaced by a newer one. Job IDs only increase
+                if let AsyncData::Loaded(current) = &self.subset_job {
+                    if res.job_id < current.job_id {
+                        return false;
+                    }
+                }
+                if let SubsetJobState::Running(_) = &res.state {
+                    ctx.link().send_message(MsgCore::RequestSubsetStatus(dataset_name, res.job_id));
+                }
+                self.subset_job = AsyncData::new(res);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Change a column made by users, such as labelling the selected cells
+            MsgCore::RequestEditAnnotation(edit) => {
+                let dataset_name = self.get_current_dataset();
+                let query = AnnotationEditRequest {
+                    dataset_name: dataset_name.clone(),
+                    edit: edit.clone(),
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/edit_annotation",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request");
+                    if !res.status().is_success() {
+                        let msg = res.text().await.unwrap_or_default();
+                        alert(&format!("Could not change annotation: {}", msg));
+                        return MsgCore::DataChanged;
+                    }
+                    let res = res
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: DatasetDescResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetAnnotationEdited(dataset_name, edit, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: A column made by users has changed, sent from server. Values loaded
+            // before are dropped, and the coloring follows the column
+            MsgCore::SetAnnotationEdited(dataset_name, edit, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                let column_name = edit.get_column_name();
+                let mut metadatas = self.metadatas.data.remove_column(column_name);
+                let mut markers = self.markers.data.remove_column(column_name);
+                if let AnnotationEdit::RenameColumn(_, new_name) = &edit {
+                    metadatas = metadatas.remove_column(new_name);
+                    markers = markers.remove_column(new_name);
+                }
+                self.metadatas = BiscviCache::new(metadatas);
+                self.markers = BiscviCache::new(markers);
+                self.current_datadesc = AsyncData::new(res);
+
+                if self.current_colorby == PerCellDataSource::Metadata(column_name.clone()) {
+                    match &edit {
+                        AnnotationEdit::SetLabel(_, _, _) => ctx.link().send_message(MsgCore::RequestSetColorByMeta(self.current_colorby.clone())),
+                        AnnotationEdit::RenameColumn(_, new_name) => ctx.link().send_message(MsgCore::RequestSetColorByMeta(PerCellDataSource::Metadata(new_name.clone()))),
+                        AnnotationEdit::DeleteColumn(_) => self.current_colorby = PerCellDataSource::Metadata("".into()),
+                    }
+                }
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Expand or collapse a metadata column in the panel
+            MsgCore::ToggleMetaExpanded(column_name) => {
+                if !self.expanded_meta.remove(&column_name) {
+                    self.expanded_meta.insert(column_name);
+                }
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Get the sessions saved for this dataset
+            MsgCore::GetSessionList() => {
+                let dataset_name = self.get_current_dataset();
+                let query = SessionListRequest {
+                    dataset_name: dataset_name.clone(),
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/session_list",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request")
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: SessionListResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetSessionList(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Set list of saved sessions, sent from server
+            MsgCore::SetSessionList(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                self.sessions = AsyncData::new(res);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Save the workspace under a name. The full selection is kept, even if too large for the URL
+            MsgCore::RequestSaveSession(session_name) => {
+                let dataset_name = self.get_current_dataset();
+                let mut expanded_meta: Vec<String> = self.expanded_meta.iter().cloned().collect();
+                expanded_meta.sort();
+                let query = SessionSaveRequest {
+                    dataset_name: dataset_name.clone(),
+                    session_name: session_name.clone(),
+                    content: SessionContent {
+                        view: self.get_view_state().to_url_hash(),
+                        expanded_meta: expanded_meta,
+                        cell_selection: self.cell_selection.data.iter().map(|x| *x as u32).collect(),
+                    },
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+                self.current_session = Some(session_name);
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/session_save",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request");
+                    if !res.status().is_success() {
+                        let msg = res.text().await.unwrap_or_default();
+                        alert(&format!("Could not save session: {}", msg));
+                        return MsgCore::DataChanged;
+                    }
+                    let res = res
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: SessionListResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetSessionList(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Load a saved session
+            MsgCore::RequestLoadSession(session_name) => {
+                let dataset_name = self.get_current_dataset();
+                let query = SessionRequest {
+                    dataset_name: dataset_name.clone(),
+                    session_name: session_name,
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/session_load",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request");
+                    if !res.status().is_success() {
+                        let msg = res.text().await.unwrap_or_default();
+                        alert(&format!("Could not load session: {}", msg));
+                        return MsgCore::DataChanged;
+                    }
+                    let res = res
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: SessionLoadResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetSessionLoaded(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: A saved session, sent from server. It is shown as a view from the URL would be,
+            // within the current dataset
+            MsgCore::SetSessionLoaded(dataset_name, res) => {
+                if !self.is_current_dataset(&dataset_name) {
+                    return false;
+                }
+                let mut state = ViewState::from_url_hash(&res.content.view);
+                state.dataset = self.current_dataset.clone();
+                state.cells = Some(res.content.cell_selection.iter().map(|x| *x as usize).collect());
+                if let Some(page) = state.page {
+                    self.current_page = page;
+                }
+                self.expanded_meta = res.content.expanded_meta.into_iter().collect();
+                self.current_session = Some(res.session_name);
+                self.apply_view_state(ctx, state);
+                true
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Delete a saved session
+            MsgCore::RequestDeleteSession(session_name) => {
+                let dataset_name = self.get_current_dataset();
+                let query = SessionRequest {
+                    dataset_name: dataset_name.clone(),
+                    session_name: session_name.clone(),
+                };
+                let query_json = serde_json::to_vec(&query).expect("Could not convert to json");
+                if self.current_session.as_ref() == Some(&session_name) {
+                    self.current_session = None;
+                }
+
+                let get_data = async move {
+                    let client = reqwest::Client::new();
+                    let res = client.post(format!("{}/session_delete",get_host_url()))
+                        .header("Content-Type", "application/json")
+                        .body(query_json) 
+                        .send()
+                        .await
+                        .expect("Failed to send request");
+                    if !res.status().is_success() {
+                        let msg = res.text().await.unwrap_or_default();
+                        alert(&format!("Could not delete session: {}", msg));
+                        return MsgCore::DataChanged;
+                    }
+                    let res = res
+                        .bytes()
+                        .await
+                        .expect("Could not get binary data");
+                    let res: SessionListResponse = serde_cbor::from_reader(res.reader()).expect("Failed to deserialize");
+                    MsgCore::SetSessionList(dataset_name, res)
+                };
+                ctx.link().send_future(get_data);
+                true
+            },
+
+
+        };
+        self.sync_url();
+        do_update
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Top renderer of the page
+    fn view(&self, ctx: &Context<Self>) -> Html {
+
+
+        let current_page = match self.current_page { 
+            CurrentPage::Home => self.view_dimred_page(&ctx),
+            CurrentPage::GenomeBrowser => self.view_gbrowser_page(&ctx),
+            CurrentPage::Files => self.view_files_page(&ctx),
+            CurrentPage::About => self.view_about_page(&ctx),
+        };
+
+        fn active_if(cond: bool) -> String {
+            if cond {
+                "btn_top_active".to_string()
+            } else {
+                "btn_top_inactive".to_string()
+            }
+        }
+
+        //let window = window().expect("no window");
+        // window.addEventListener('resize', resizeCanvas, false);
+        // https://yew.rs/docs/next/concepts/html/events
+
+        let onsize = ctx.link().callback(|size: ComponentSize| {
+            MsgCore::WindowResize(size)
+        });
+
+        //Callback: pick another dataset
+        let cb_select_dataset = ctx.link().callback(move |e: Event | { 
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlSelectElement = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok()).expect("wrong type");
+            e.prevent_default();
+            MsgCore::SelectDataset(input.value())
+        });
+
+        //List of datasets to pick from
+        let mut list_datasets_html = Vec::new();
+        if let AsyncData::Loaded(datasets) = &self.datasets {
+            for dataset_name in &datasets.datasets {
+                let is_selected = self.is_current_dataset(dataset_name);
+                list_datasets_html.push(html! {
+                    <option value={dataset_name.clone()} selected={is_selected}>
+                        {dataset_name.clone()}
+                    </option>
+                });
+            }
+        }
+
+        html! {
+            <div style="position: relative;"> // added style
+                <ComponentSizeObserver onsize={onsize} />
+                <div class="biscvi-topdiv">
+                    <div style="float: left; padding: 10px; font-size: 30px; font-family: 'Roboto', sans-serif; font-weight: 900;">
+                        {"Biscvi"}
+                    </div>
+                    <div style="float: left; padding: 18px 10px;">
+                        {"Dataset: "}
+                        <select onchange={cb_select_dataset}>
+                            {list_datasets_html}
+                        </select>
+                    </div>
+                    { self.view_session_menu(&ctx) }
+
+                    <a class={active_if(self.current_page==CurrentPage::About)}          onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::About))}>{"About"}</a> 
+                    <a class={active_if(self.current_page==CurrentPage::GenomeBrowser)}  onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::GenomeBrowser))}>{"Genome Browser"}</a> 
+                    <a class={active_if(self.current_page==CurrentPage::Files)}          onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::Files))}>{"Files"}</a> 
+                    <a class={active_if(self.current_page==CurrentPage::Home)}           onclick={ctx.link().callback(|_| MsgCore::OpenPage(CurrentPage::Home))}>{"Dimensional Reduction"}</a> 
+
+                </div>
+                <div key={self.get_current_dataset()}> // Recreate all components when the dataset changes
+                    { current_page }
+                </div>
+
+
+            </div>
+        }
+    }
+
+}
+
+
+
+impl Model {
+
+    ////////////////////////////////////////////////////////////
+    /// Get name of the dataset currently shown
+    pub fn get_current_dataset(&self) -> String {
+        self.current_dataset.clone().unwrap_or_default()
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check if data received belongs to the dataset currently shown. Responses can arrive after the user switched dataset
+    pub fn is_current_dataset(&self, dataset_name: &String) -> bool {
+        self.current_dataset.as_ref() == Some(dataset_name)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the view currently shown, as kept in the URL
+    pub fn get_view_state(&self) -> ViewState {
+        let colorby = match &self.current_colorby {
+            PerCellDataSource::Metadata(column_name) if column_name.is_empty() => None,
+            colorby => Some(colorby.clone()),
+        };
+        ViewState {
+            page: Some(self.current_page),
+            dataset: self.current_dataset.clone(),
+            reduction: self.current_reduction.clone(),
+            colorby: colorby,
+            camera: self.reduction_camera.clone(),
+            features: self.open_features.clone(),
+            locus: self.gbrowser_range.clone(),
+            cells: if self.cell_selection.data.is_empty() { None } else { Some(self.cell_selection.data.as_ref().clone()) },
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Show a view kept in the URL. The dataset description must be loaded, as names are checked against it.
+    /// Features are checked by the server before their counts are loaded
+    fn apply_view_state(&mut self, ctx: &Context<Self>, state: ViewState) {
+        let datadesc = if let AsyncData::Loaded(datadesc) = &self.current_datadesc {
+            datadesc.clone()
+        } else {
+            return;
+        };
+
+        //Reduction, and where its camera was
+        if let Some(reduction_name) = &state.reduction {
+            if datadesc.reductions.contains_key(reduction_name) && self.current_reduction.as_ref() != Some(reduction_name) {
+                self.current_reduction = Some(reduction_name.clone());
+                ctx.link().send_message(MsgCore::GetReduction(reduction_name.clone()));
+            }
+        }
+        if let (Some(reduction_name), Some(cam)) = (&state.reduction, &state.camera) {
+            if self.current_reduction.as_ref() == Some(reduction_name) {
+                self.reduction_camera = Some(cam.clone());
+                self.camera_goto = Some((reduction_name.clone(), cam.clone()));
+            }
+        }
+
+        //Coloring, and features in the panel
+        let mut to_lookup: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        match &state.colorby {
+            Some(PerCellDataSource::Metadata(column_name)) if datadesc.meta.contains_key(column_name) => {
+                ctx.link().send_message(MsgCore::RequestSetColorByMeta(PerCellDataSource::Metadata(column_name.clone())));
+                self.current_colorby = PerCellDataSource::Metadata(column_name.clone());
+            },
+            Some(PerCellDataSource::Counts(counts_name, feature_name)) if datadesc.matrices.contains_key(counts_name) => {
+                to_lookup.entry(counts_name.clone()).or_default().push(feature_name.clone());
+                self.current_colorby = PerCellDataSource::Counts(counts_name.clone(), feature_name.clone());
+            },
+            _ => {}
+        }
+        self.open_features = state.features.into_iter()
+            .filter(|f| matches!(f, PerCellDataSource::Counts(counts_name, _) if datadesc.matrices.contains_key(counts_name)))
+            .collect();
+        for f in &self.open_features {
+            if let PerCellDataSource::Counts(counts_name, feature_name) = f {
+                to_lookup.entry(counts_name.clone()).or_default().push(feature_name.clone());
+            }
+        }
+        for (counts_name, feature_names) in to_lookup {
+            ctx.link().send_message(MsgCore::RequestFeatureLookup(counts_name, feature_names));
+        }
+
+        //Range of the genome browser
+        if state.locus.is_some() {
+            self.gbrowser_range = state.locus;
+        }
+
+        //Selection. Cells are numbered as in the reductions
+        let num_cells = datadesc.reductions.values().map(|r| r.num_sample).max().unwrap_or(0);
+        let cells: Vec<usize> = state.cells.unwrap_or_default().into_iter().filter(|c| *c < num_cells).collect();
+        if *self.cell_selection.data != cells {
+            self.cell_selection = BiscviCache::new(cells);
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Keep the URL in sync with the view. Navigation adds an entry to the browser history, while other
+    /// changes replace the current entry. Nothing is done while a view from the URL is still being restored
+    fn sync_url(&mut self) {
+        if self.pending_view.is_some() {
+            return;
+        }
+        let state = self.get_view_state();
+        if state == self.last_view {
+            return;
+        }
+        let url = state.to_url_hash();
+        let history = window().expect("no window").history().expect("no history");
+        let res = if state.is_navigation_from(&self.last_view) {
+            history.push_state_with_url(&JsValue::NULL, "", Some(&url))
+        } else {
+            history.replace_state_with_url(&JsValue::NULL, "", Some(&url))
+        };
+        if let Err(e) = res {
+            log::debug!("Could not update URL {:?}", e);
+        }
+        self.last_view = state;
+    }
+
+}
 
 
 ////////////////////////////////////////////////////////////
@@ -1,4 +1,8 @@
 use bstr::BString;
+use my_web_app::gbrowser_struct::GBrowserLocus;
+
+/// Fraction of a locus added on each side when moving to it
+const LOCUS_MARGIN: f32 = 0.1;
 
 
 
@@ -28,6 +32,38 @@ impl GBrowserCamera {
     }
 
 
+    ////////////////////////////////////////////////////////////
+    /// Move to show a locus, with some margin around it
+    pub fn show_locus(&mut self, locus: &GBrowserLocus) {
+        let span = (locus.end as i64 - locus.start as i64).max(1);
+        let margin = ((span as f32)*LOCUS_MARGIN) as i64 + 1;
+        self.chr = locus.chr.clone();
+        self.from = locus.start as i64 - margin;
+        self.to = locus.end as i64 + margin;
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Move to show exactly a range, as given by get_range
+    pub fn show_range(&mut self, locus: &GBrowserLocus) {
+        self.chr = locus.chr.clone();
+        self.from = locus.start as i64;
+        self.to = (locus.end as i64).max(self.from + 1);
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Get the range currently shown. The view can extend before the start of the chromosome, which is cut off
+    pub fn get_range(&self) -> GBrowserLocus {
+        let from = self.from.max(0);
+        GBrowserLocus {
+            chr: self.chr.clone(),
+            start: from as u64,
+            end: self.to.max(from + 1) as u64,
+        }
+    }
+
+
     ////////////////////////////////////////////////////////////
     /// Zoom around middle position
     pub fn zoom(&mut self, scale: f32) {
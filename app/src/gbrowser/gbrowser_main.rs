@@ -2,7 +2,7 @@
 use std::sync::Mutex;
 
 use my_web_app::DatasetDescResponse;
-use my_web_app::gbrowser_struct::{GBrowserGFFchunkID, GBrowserGFFchunkRequest};
+use my_web_app::gbrowser_struct::{GBrowserAttributeValue, GBrowserGFFchunkID, GBrowserGFFchunkRequest, GBrowserLocus};
 use wasm_bindgen::JsCast;
 use web_sys::{DomRect, EventTarget, HtmlInputElement, HtmlSelectElement, SvgElement};
 use yew::{Callback, Component, Context, Event, Html, KeyboardEvent, MouseEvent, NodeRef, WheelEvent, html};
@@ -30,6 +30,9 @@ pub enum MsgGBrowse {
 
     MouseMove(f32,f32, bool),
     MouseWheel(f32),
+    MouseDown,
+    MouseUp,
+    ClickRecord(Vec<(String, String)>),
 
     SetChromosome(BString),
 }
@@ -41,6 +44,9 @@ pub enum MsgGBrowse {
 pub struct Props {
     pub current_datadesc: AsyncData<DatasetDescResponse>,
     pub current_gff: AsyncData<Mutex<ClientGBrowseData>>,
+    pub goto_locus: Option<GBrowserLocus>,
+    pub current_locus: Option<GBrowserLocus>,     // Range shown before, such as kept in the URL
+    pub on_locus_changed: Callback<GBrowserLocus>,
 
     pub last_component_size: ComponentSize,
     
@@ -56,6 +62,7 @@ pub struct GBrowseView {
 
     pub last_pos: (f32,f32),
     pub enable_verlines: bool,
+    pub has_dragged: bool,    // If the view was panned since the mouse button was pressed; then it is not a click
 }
 
 impl Component for GBrowseView {
@@ -64,19 +71,25 @@ impl Component for GBrowseView {
 
     ////////////////////////////////////////////////////////////
     /// Create this component
-    fn create(_ctx: &Context<Self>) -> Self {    
+    fn create(ctx: &Context<Self>) -> Self {    
 
-        let camera = GBrowserCamera {
+        let mut camera = GBrowserCamera {
             from: 0,
             to: 1000000,
             chr: "1".into()
         };
+        if let Some(locus) = &ctx.props().current_locus {
+            camera.show_range(locus);
+        } else if let Some(locus) = &ctx.props().goto_locus {
+            camera.show_locus(locus);
+        }
 
         Self {
             node_ref: NodeRef::default(),
             camera,
             last_pos: (0.0,0.0),
             enable_verlines: true,
+            has_dragged: false,
         }
     }
 
@@ -96,6 +109,7 @@ impl Component for GBrowseView {
             // Message: Zoom image around middle point
             MsgGBrowse::Zoom(scale) => {
                 self.camera.zoom(scale);
+                self.emit_locus(ctx);
                 true
             },
 
@@ -104,6 +118,7 @@ impl Component for GBrowseView {
             // Message: Set chromosome to show
             MsgGBrowse::SetChromosome(chr)  => {
                 self.camera.chr=chr;
+                self.emit_locus(ctx);
                 true
             }
 
@@ -115,6 +130,7 @@ impl Component for GBrowseView {
                     if let Ok(value) = value {
                         if value > self.camera.from {
                             self.camera.to = value;
+                            self.emit_locus(ctx);
                         }
                     }
                     true
@@ -131,6 +147,7 @@ impl Component for GBrowseView {
                     if let Ok(value) = value {
                         if value < self.camera.to {
                             self.camera.from = value;
+                            self.emit_locus(ctx);
                         }
                     }
                     true
@@ -163,6 +180,7 @@ impl Component for GBrowseView {
 
                     self.camera.to += wdx as i64;
                     self.camera.from += wdx as i64;
+                    self.has_dragged = true;
                     return true;
                 }
                 false
@@ -179,9 +197,35 @@ impl Component for GBrowseView {
                 let scale = (10.0f32).powf(dy / 1000.0);
                 //log::debug!("zoom scale {}",scale);
                 self.camera.zoom_around(scale, wx as i64);
+                self.emit_locus(ctx);
                 true
             },
 
+            ////////////////////////////////////////////////////////////
+            // Message: Mouse button pressed. Could be a click or the start of panning
+            MsgGBrowse::MouseDown => {
+                self.has_dragged = false;
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: Mouse button released. Panning has ended, if there was any
+            MsgGBrowse::MouseUp => {
+                if self.has_dragged {
+                    self.emit_locus(ctx);
+                }
+                false
+            },
+
+            ////////////////////////////////////////////////////////////
+            // Message: A GFF record was clicked. Color the reduction by the feature it links to
+            MsgGBrowse::ClickRecord(attributes) => {
+                if !self.has_dragged {
+                    ctx.props().on_propagate.emit(MsgCore::RequestGFFfeatureLink(attributes));
+                }
+                false
+            },
+
 
 
         }
@@ -214,6 +258,14 @@ impl Component for GBrowseView {
             e.prevent_default();
             MsgGBrowse::MouseWheel(e.delta_y() as f32)
         });
+
+        let cb_mousedown = ctx.link().callback(move |_e: MouseEvent | { 
+            MsgGBrowse::MouseDown
+        });
+
+        let cb_mouseup = ctx.link().callback(move |_e: MouseEvent | { 
+            MsgGBrowse::MouseUp
+        });
         /*
 
         let cb_mouseclicked = ctx.link().callback(move |_e: MouseEvent | { 
@@ -258,7 +310,7 @@ impl Component for GBrowseView {
             //Get list of chromosomes
             for chr in current_gff.desc.chrom_sizes.keys() {
                 list_chr_html.push(html! {
-                    <option>{chr.to_string()}</option>
+                    <option selected={*chr==self.camera.chr}>{chr.to_string()}</option>
                 });
 
             }                
@@ -356,17 +408,23 @@ impl Component for GBrowseView {
 
                         //Perform additional clipping to reduce content to render, if possible
                         if pos_end > 0.0 || pos_start < gbrowse_width {
+
+                            //Clicking a record colors by the feature it links to
+                            let attributes = rec.attributes.clone();
+                            let cb_click_record = ctx.link().callback(move |_e: MouseEvent | { 
+                                let list_attr = attributes.iter().map(|(k, GBrowserAttributeValue::String(v))| (k.to_string(), v.to_string())).collect();
+                                MsgGBrowse::ClickRecord(list_attr)
+                            });
+
                             if rec.ty=="transcript" {
                                 list_transcripts.push(html!{
-                                    <line x1={pos_start.to_string()} y1={mid_y.to_string()} x2={pos_end.to_string()} y2={mid_y.to_string()} stroke="black"/>
+                                    <line x1={pos_start.to_string()} y1={mid_y.to_string()} x2={pos_end.to_string()} y2={mid_y.to_string()} stroke="black" stroke-width="3" style="cursor: pointer;" onclick={cb_click_record}/>
                                 });
-                            }
-
-                            if rec.ty=="exon" {
+                            } else if rec.ty=="exon" {
                                 let height = 20;
                                 let y_upper = mid_y - height/2;
                                 list_comp.push(html!{
-                                    <rect x={pos_start.to_string()} y={y_upper.to_string()} width={width.to_string()} height={height.to_string()}/>
+                                    <rect x={pos_start.to_string()} y={y_upper.to_string()} width={width.to_string()} height={height.to_string()} style="cursor: pointer;" onclick={cb_click_record}/>
                                 });
                             }
                         }
@@ -401,12 +459,14 @@ impl Component for GBrowseView {
                 //Grab some data at a time for faster update and to not kill the browser  ----- how to do this best?
                 if list_request.len()>100 {
                     let query = GBrowserGFFchunkRequest {
+                        dataset_name: String::new(), //Filled in by Model
                         to_get: vec![list_request.last().unwrap().clone()]  //list_request[0..1].to_vec()  // start from end to get highest level features first
                     };
                     ctx.props().on_propagate.emit(MsgCore::RequestGFFchunks(query));
                     //NOTE: odd behavior with showing "loading" boxes when using this feature
                 } else {
                     let query = GBrowserGFFchunkRequest {
+                        dataset_name: String::new(), //Filled in by Model
                         to_get: list_request
                     };
                     ctx.props().on_propagate.emit(MsgCore::RequestGFFchunks(query));
@@ -420,6 +480,8 @@ impl Component for GBrowseView {
                         viewBox={format!("0 0 {} {}", gbrowse_width, gbrowse_height)}
 
                         onmousemove={cb_mousemoved} 
+                        onmousedown={cb_mousedown} 
+                        onmouseup={cb_mouseup} 
                         onwheel={cb_mousewheel} 
                         /*
                         onclick={cb_mouseclicked} 
@@ -490,8 +552,41 @@ impl Component for GBrowseView {
 
 
     ////////////////////////////////////////////////////////////
-    /// Called after DOM has been generated
-    fn rendered(&mut self, _ctx: &Context<Self>, _first_render: bool) {
+    /// Called when properties change. Move to a locus if asked to show a new one, or back to a range shown before
+    fn changed(&mut self, ctx: &Context<Self>, old_props: &Self::Properties) -> bool {
+        if ctx.props().goto_locus != old_props.goto_locus {
+            if let Some(locus) = &ctx.props().goto_locus {
+                self.camera.show_locus(locus);
+                self.emit_locus(ctx);
+            }
+        }
+        if ctx.props().current_locus != old_props.current_locus {
+            if let Some(locus) = &ctx.props().current_locus {
+                if *locus != self.camera.get_range() {
+                    self.camera.show_range(locus);
+                }
+            }
+        }
+        true
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Called after DOM has been generated. Tell where the browser starts out
+    fn rendered(&mut self, ctx: &Context<Self>, first_render: bool) {
+        if first_render {
+            self.emit_locus(ctx);
+        }
+    }
+}
+
+
+impl GBrowseView {
+
+    ////////////////////////////////////////////////////////////
+    /// Tell the component above which range is shown, after it changed
+    fn emit_locus(&self, ctx: &Context<Self>) {
+        ctx.props().on_locus_changed.emit(self.camera.get_range());
     }
 }
 
@@ -1,3 +1,5 @@
+use my_web_app::gbrowser_struct::GBrowserLocus;
+
 use crate::{core_model::*, gbrowser::GBrowseView};
 
 use yew::prelude::*;
@@ -15,6 +17,11 @@ impl Model {
             sig
         });
 
+        //Callback: the range shown has changed
+        let on_locus_changed = ctx.link().callback(move |locus: GBrowserLocus| {
+            MsgCore::SetGBrowserRange(locus)
+        });
+
 
 
         html! {
@@ -29,6 +36,9 @@ impl Model {
     //                metadatas={self.metadatas.clone()}
                     current_datadesc={self.current_datadesc.clone()}
                     current_gff={self.current_gff.clone()}
+                    goto_locus={self.gbrowser_goto.clone()}
+                    current_locus={self.gbrowser_range.clone()}
+                    on_locus_changed={on_locus_changed}
       //              current_reduction_name={self.current_reduction.clone()}
                 />
 
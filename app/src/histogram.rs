@@ -1,4 +1,10 @@
+use std::collections::HashMap;
+
 use my_web_app::CountFileMetaColumnData;
+use yew::{html, Html};
+
+/// Text columns show at most this many distinct values in a histogram; the rest are grouped
+const MAX_TEXT_CATEGORIES: usize = 20;
 
 
 ////////////////////////////////////////////////////////////
@@ -45,9 +51,11 @@ impl FeatureHistogram {
                 let mut count: Vec<u64> = Vec::new();
                 count.resize(list_cats.len(), 0);
 
-                //Count all entries
+                //Count all entries. Codes past the last category are missing values
                 for v in list_data.iter() {
-                    count[*v as usize] += 1;
+                    if let Some(c) = count.get_mut(*v as usize) {
+                        *c += 1;
+                    }
                 }
                 
                 FeatureHistogram::CategoricalFeatureHistogram(CategoricalFeatureHistogram {
@@ -57,18 +65,35 @@ impl FeatureHistogram {
                 })
             },
 
-            ///// Dense numeric array of data
+            ///// Dense numeric array of data. Missing values are NaN
             CountFileMetaColumnData::Numeric(list_data) => {
-                make_histo_continuous_data(list_data)
+                let list_present = list_data.iter().cloned().filter(|x| !x.is_nan()).collect::<Vec<_>>();
+                make_histo_continuous_data(&list_present)
             },
 
             ///// Sparse numeric array or data
             CountFileMetaColumnData::SparseNumeric(_list_indices, list_data) => {
                 make_histo_continuous_data(list_data)
             },
+
+            ///// Integers, ignoring missing values
+            CountFileMetaColumnData::Integer(list_data) => {
+                let list_present = list_data.iter().filter_map(|x| x.map(|x| x as f32)).collect::<Vec<_>>();
+                make_histo_continuous_data(&list_present)
+            },
+
+            ///// Booleans, shown as categories
+            CountFileMetaColumnData::Boolean(list_data) => {
+                let labels = list_data.iter().map(|x| x.map(|x| x.to_string())).collect::<Vec<_>>();
+                make_histo_labels(&labels, MAX_TEXT_CATEGORIES)
+            },
+
+            ///// Free text; only the most common values are shown
+            CountFileMetaColumnData::Text(list_data) => {
+                make_histo_labels(list_data, MAX_TEXT_CATEGORIES)
+            },
         }
     }
-
 }
 
 
@@ -129,14 +154,105 @@ fn make_histo_continuous_data(list_data: &Vec<f32>) -> FeatureHistogram { //////
 
 
 
+////////////////////////////////////////////////////////////
+/// Make a histogram of text values, most common first. Missing values are counted as "NA"
+fn make_histo_labels(list_data: &Vec<Option<String>>, max_categories: usize) -> FeatureHistogram {
+    let mut map_count: HashMap<&str, u64> = HashMap::new();
+    for v in list_data.iter() {
+        let v = v.as_ref().map(|x| x.as_str()).unwrap_or("NA");
+        *map_count.entry(v).or_insert(0) += 1;
+    }
+    let mut list_count = map_count.into_iter().collect::<Vec<_>>();
+    list_count.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    let mut category = Vec::new();
+    let mut count = Vec::new();
+    for (i, (v, cnt)) in list_count.iter().enumerate() {
+        if i < max_categories {
+            category.push(v.to_string());
+            count.push(*cnt);
+        } else if i == max_categories {
+            category.push("(other)".to_string());
+            count.push(*cnt);
+        } else {
+            *count.last_mut().unwrap() += *cnt;
+        }
+    }
+
+    FeatureHistogram::CategoricalFeatureHistogram(CategoricalFeatureHistogram {
+        category: category,
+        count: count,
+        total: list_data.len() as u64,
+    })
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Render a histogram as SVG. Categorical histograms are drawn as one bar per category
+pub fn make_histogram_svg(h: &FeatureHistogram, hist_width: f32, hist_height: f32) -> Html {
+    let mut list_bins_html = Vec::new();
+    match h {
+        FeatureHistogram::ContinuousFeatureHistogram(h) => {
+            let scale_y = hist_height/(h.max_count.max(1) as f32);
+            let span = h.max - h.min;
+            let scale_x = if span > 0.0 { hist_width/span } else { 0.0 };
+            let bin_width = hist_width/(h.bin.len() as f32);
+
+            for (bin,cnt) in h.bin.iter().zip(h.count.iter()) {
+                let bar_height = (*cnt as f32) * scale_y;
+                let x = (*bin as f32)*scale_x;
+                list_bins_html.push(html! {
+                    <rect 
+                        x={x.to_string()} 
+                        y={(hist_height-bar_height).to_string()}
+                        width={bin_width.to_string()}
+                        height={bar_height.to_string()} 
+                        style="fill: rgb(0, 0, 0);"  
+                    />
+                });
+            }
+        },
+        FeatureHistogram::CategoricalFeatureHistogram(h) => {
+            let max_count = h.count.iter().cloned().max().unwrap_or(1).max(1);
+            let scale_y = hist_height/(max_count as f32);
+            let bin_width = hist_width/(h.count.len().max(1) as f32);
+
+            for (i, (cat, cnt)) in h.category.iter().zip(h.count.iter()).enumerate() {
+                let bar_height = (*cnt as f32) * scale_y;
+                list_bins_html.push(html! {
+                    <rect 
+                        x={(bin_width*(i as f32)).to_string()} 
+                        y={(hist_height-bar_height).to_string()}
+                        width={(bin_width*0.9).to_string()}
+                        height={bar_height.to_string()} 
+                        style="fill: rgb(0, 0, 0);"  
+                    >
+                        <title>{format!("{}: {}", cat, cnt)}</title>
+                    </rect>
+                });
+            }
+        },
+    }
+
+    html! {
+        <svg width={hist_width.to_string()} height={hist_height.to_string()} style="display: block;">  
+            {list_bins_html}
+        </svg>
+    }
+}
+
+
+
 ////////////////////////////////////////////////////////////
 /// Find min and max values of a list of floats, even if list is empty
 pub fn make_safe_minmax(list_data: &Vec<f32>) -> (f32,f32) {
     if list_data.is_empty() {
         (0.0,0.0)
     } else {
-        let mut it = list_data.iter();
-        let firstval = *it.next().unwrap();
+        //Missing values (NaN) are ignored
+        let mut it = list_data.iter().filter(|x| !x.is_nan());
+        let firstval = if let Some(v) = it.next() { *v } else { return (0.0, 0.0) };
         let mut minval=firstval;
         let mut maxval=firstval;
         for v in it {
@@ -8,6 +8,8 @@ pub mod about;
 pub mod appstate;
 pub mod resize;
 pub mod histogram;
+pub mod urlstate;
+pub mod session_model;
 
 use crate::core_model::*;
 
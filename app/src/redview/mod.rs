@@ -5,14 +5,23 @@ pub mod redview_main;
 pub mod redview_left;
 pub mod redview_right;
 pub mod redview_closestpoint;
+pub mod redview_diffexp;
+pub mod redview_inspector;
+pub mod redview_export;
+pub mod redview_annotate;
 
 
 //Re-exports
 pub use redview_main::ReductionViewData;
 pub use redview_closestpoint::ClosestPointIndex2D;
 pub use redview_camera::Camera2D;
+pub use redview_camera::Camera3D;
 pub use redview_camera::Rectangle2D;
 
 pub use redview_main::ReductionView;
 pub use redview_left::MetadataView;
 pub use redview_right::FeatureView;
+pub use redview_diffexp::DiffExpView;
+pub use redview_inspector::CellInspectorView;
+pub use redview_export::ExportView;
+pub use redview_annotate::AnnotateView;
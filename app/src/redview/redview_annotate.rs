@@ -0,0 +1,197 @@
+use my_web_app::AnnotationEdit;
+use my_web_app::DatasetDescResponse;
+use my_web_app::ExportCellsRequest;
+use my_web_app::ExportFormat;
+use web_sys::window;
+use web_sys::HtmlInputElement;
+use yew::{html, Callback, Component, Context, Html, MouseEvent, NodeRef};
+use yew::Properties;
+
+use crate::appstate::{AsyncData, BiscviCache, PerCellDataSource};
+use crate::core_model::{alert, get_host_url};
+
+
+////////////////////////////////////////////////////////////
+/// Message sent to the event system for updating the page
+#[derive(Debug)]
+pub enum MsgAnnotate {
+    ToggleExpand,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Properties for AnnotateView
+#[derive(Properties, PartialEq)]
+pub struct Props {
+    pub dataset_name: String,
+    pub current_datadesc: AsyncData<DatasetDescResponse>,
+    pub cell_selection: BiscviCache<Vec<usize>>,
+    pub on_editannotation: Callback<AnnotationEdit>,
+    pub on_colorbymeta: Callback<PerCellDataSource>,
+}
+
+
+////////////////////////////////////////////////////////////
+/// This component lets users give the selected cells a label, in categorical columns of their own.
+/// The columns are kept by the server, and can be renamed, deleted and exported
+pub struct AnnotateView {
+    pub expanded: bool,
+    pub column_ref: NodeRef,
+    pub label_ref: NodeRef,
+}
+
+impl Component for AnnotateView {
+    type Message = MsgAnnotate;
+    type Properties = Props;
+
+    ////////////////////////////////////////////////////////////
+    /// Create this component
+    fn create(_ctx: &Context<Self>) -> Self {
+        Self {
+            expanded: false,
+            column_ref: NodeRef::default(),
+            label_ref: NodeRef::default(),
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Handle an update message
+    fn update(&mut self, _ctx: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+
+            //////// Show or hide the options
+            MsgAnnotate::ToggleExpand => {
+                self.expanded = !self.expanded;
+                true
+            },
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Render the annotation panel
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let num_selected = ctx.props().cell_selection.data.len();
+
+        if !self.expanded {
+            return html! {
+                <div class="biscvi-annotate">
+                    <button onclick={ctx.link().callback(|_| MsgAnnotate::ToggleExpand)}>{"Annotate cells..."}</button>
+                </div>
+            };
+        }
+
+        let user_columns = if let AsyncData::Loaded(current_datadesc) = &ctx.props().current_datadesc {
+            current_datadesc.user_meta.clone()
+        } else {
+            Vec::new()
+        };
+
+        //Callback: set or remove the label of the selected cells. An unknown column is created
+        let make_cb_label = |set_label: bool| {
+            let column_ref = self.column_ref.clone();
+            let label_ref = self.label_ref.clone();
+            let cell_selection = ctx.props().cell_selection.clone();
+            let on_editannotation = ctx.props().on_editannotation.clone();
+            Callback::from(move |_e: MouseEvent| {
+                let column_name = column_ref.cast::<HtmlInputElement>().expect("Missing column input").value().trim().to_string();
+                let label = label_ref.cast::<HtmlInputElement>().expect("Missing label input").value().trim().to_string();
+                if column_name.is_empty() {
+                    alert("Give the name of a column");
+                    return;
+                }
+                if set_label && label.is_empty() {
+                    alert("Give a label for the cells");
+                    return;
+                }
+                let cells = cell_selection.data.iter().map(|x| *x as u32).collect();
+                on_editannotation.emit(AnnotationEdit::SetLabel(column_name, if set_label { Some(label) } else { None }, cells));
+            })
+        };
+
+        //Each user column, with what can be done to it
+        let list_columns_html = user_columns.iter().map(|column_name| {
+
+            //Callback: color by this column
+            let on_colorbymeta = ctx.props().on_colorbymeta.clone();
+            let name = column_name.clone();
+            let cb_color = Callback::from(move |_e: MouseEvent| {
+                on_colorbymeta.emit(PerCellDataSource::Metadata(name.clone()));
+            });
+
+            //Callback: rename the column
+            let on_editannotation = ctx.props().on_editannotation.clone();
+            let name = column_name.clone();
+            let cb_rename = Callback::from(move |_e: MouseEvent| {
+                let window = window().expect("no window");
+                if let Ok(Some(new_name)) = window.prompt_with_message_and_default("New name of column", &name) {
+                    let new_name = new_name.trim().to_string();
+                    if !new_name.is_empty() && new_name != name {
+                        on_editannotation.emit(AnnotationEdit::RenameColumn(name.clone(), new_name));
+                    }
+                }
+            });
+
+            //Callback: delete the column
+            let on_editannotation = ctx.props().on_editannotation.clone();
+            let name = column_name.clone();
+            let cb_delete = Callback::from(move |_e: MouseEvent| {
+                let window = window().expect("no window");
+                if window.confirm_with_message(&format!("Delete column {}?", name)).unwrap_or(false) {
+                    on_editannotation.emit(AnnotationEdit::DeleteColumn(name.clone()));
+                }
+            });
+
+            //The export is a table of all cells with this column only
+            let query = ExportCellsRequest {
+                dataset_name: ctx.props().dataset_name.clone(),
+                cells: None,
+                meta_columns: vec![column_name.clone()],
+                reductions: Vec::new(),
+                features: Vec::new(),
+                normalization: Default::default(),
+                format: ExportFormat::CSV,
+            };
+            let query_json = serde_json::to_string(&query).expect("Could not convert to json");
+
+            html! {
+                <div>
+                    <form method="post" action={format!("{}/export_cells", get_host_url())}>
+                        {column_name}
+                        {" "}
+                        <button type="button" onclick={cb_color}>{"Color"}</button>
+                        <button type="button" onclick={cb_rename}>{"Rename"}</button>
+                        <button type="button" onclick={cb_delete}>{"Delete"}</button>
+                        <input type="hidden" name="query" value={query_json}/>
+                        <button type="submit">{"Export"}</button>
+                    </form>
+                </div>
+            }
+        }).collect::<Vec<_>>();
+
+        let list_column_options_html = user_columns.iter().map(|column_name| html! {
+            <option value={column_name.clone()}/>
+        }).collect::<Vec<_>>();
+
+        html! {
+            <div class="biscvi-annotate">
+                <div>
+                    <button onclick={ctx.link().callback(|_| MsgAnnotate::ToggleExpand)}>{"Hide annotation"}</button>
+                </div>
+                <div>
+                    <input type="text" placeholder="Column" list="biscvi-annotate-columns" ref={self.column_ref.clone()}/>
+                    <datalist id="biscvi-annotate-columns">
+                        {list_column_options_html}
+                    </datalist>
+                    <input type="text" placeholder="Label" ref={self.label_ref.clone()}/>
+                </div>
+                <div>
+                    <button onclick={make_cb_label(true)} disabled={num_selected == 0}>{format!("Label selected cells ({})", num_selected)}</button>
+                    <button onclick={make_cb_label(false)} disabled={num_selected == 0}>{"Remove label"}</button>
+                </div>
+                { list_columns_html }
+            </div>
+        }
+    }
+}
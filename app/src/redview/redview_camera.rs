@@ -44,6 +44,18 @@ impl Camera2D {
     }
 
 
+    ////////////////////////////////////////////////////////////
+    /// Matrix from world to GL clip coordinates, column-major. Clip Y is inverted
+    /// relative to camera Y, as camera Y points down the screen
+    pub fn view_matrix(&self) -> ViewMatrix {
+        [
+            self.zoom_x, 0.0, 0.0, 0.0,
+            0.0, -self.zoom_y, 0.0, 0.0,
+            0.0, 0.0, 0.0, 0.0,
+            -self.x*self.zoom_x, self.y*self.zoom_y, 0.0, 1.0,
+        ]
+    }
+
     ////////////////////////////////////////////////////////////
     /// Adjust camera to fit all points 
     pub fn fit_reduction(&mut self, umap: &ReductionViewData) {
@@ -87,6 +99,207 @@ impl Camera2D {
 
 
 
+////////////////////////////////////////////////////////////
+/// Matrix from world to GL clip coordinates, 4x4 column-major
+pub type ViewMatrix = [f32; 16];
+
+
+////////////////////////////////////////////////////////////
+/// Project a world point into camera coordinates, [-1,1] with Y pointing down. Also returns depth
+pub fn project_point(m: &ViewMatrix, wx: f32, wy: f32, wz: f32) -> (f32,f32,f32) {
+    let clip_x = m[0]*wx + m[4]*wy + m[8]*wz + m[12];
+    let clip_y = m[1]*wx + m[5]*wy + m[9]*wz + m[13];
+    let clip_z = m[2]*wx + m[6]*wy + m[10]*wz + m[14];
+    (clip_x, -clip_y, clip_z)
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// An orbit camera for 3D scenes. The camera rotates around a center point, 
+/// using an orthographic projection
+#[derive(Debug, PartialEq)]
+pub struct Camera3D {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub yaw: f32,       // Rotation around Y axis, radians
+    pub pitch: f32,     // Rotation around X axis, radians
+    pub zoom: f32,
+    pub depth_scale: f32,
+    pub aspect: f32,    // Width/height of canvas
+}
+impl Camera3D {
+
+    ////////////////////////////////////////////////////////////
+    /// Construct a neutral camera
+    pub fn new() -> Camera3D {
+        Camera3D {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            yaw: 0.0,
+            pitch: 0.0,
+            zoom: 1.0,
+            depth_scale: 1.0,
+            aspect: 1.0,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Matrix from world to GL clip coordinates, column-major.
+    /// Rotation is first around Y (yaw), then around X (pitch)
+    pub fn view_matrix(&self) -> ViewMatrix {
+        let (sy, cy) = self.yaw.sin_cos();
+        let (sp, cp) = self.pitch.sin_cos();
+
+        //Rows of the rotation matrix
+        let r0 = [cy, 0.0, sy];
+        let r1 = [sp*sy, cp, -sp*cy];
+        let r2 = [-cp*sy, sp, cp*cy];
+
+        //Scale of each output axis. Y is inverted as for the 2D camera
+        let scale = [self.zoom/self.aspect, -self.zoom, self.depth_scale];
+        let rows = [r0, r1, r2];
+
+        let mut m = [0.0; 16];
+        for (row_i, (r, s)) in rows.iter().zip(scale.iter()).enumerate() {
+            for col_i in 0..3 {
+                m[col_i*4 + row_i] = r[col_i]*s;
+            }
+            //Translation by -center
+            m[12 + row_i] = -(r[0]*self.x + r[1]*self.y + r[2]*self.z)*s;
+        }
+        m[15] = 1.0;
+        m
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Adjust camera to fit all points, looking at them from the front
+    pub fn fit_reduction(&mut self, umap: &ReductionViewData) {
+        self.x = (umap.min_x + umap.max_x)/2.0;
+        self.y = (umap.min_y + umap.max_y)/2.0;
+        self.z = (umap.min_z + umap.max_z)/2.0;
+        self.yaw = 0.0;
+        self.pitch = 0.0;
+
+        //Fit the bounding sphere, so that points stay in view while rotating
+        let dx = umap.max_x - umap.min_x;
+        let dy = umap.max_y - umap.min_y;
+        let dz = umap.max_z - umap.min_z;
+        let radius = ((dx*dx + dy*dy + dz*dz).sqrt()/2.0).max(f32::MIN_POSITIVE);
+
+        let margin = 0.9;
+        self.zoom = margin/radius;
+        self.depth_scale = margin/radius;
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Rotate the camera around the center. Pitch is limited to avoid flipping over
+    pub fn orbit(&mut self, d_yaw: f32, d_pitch: f32) {
+        let max_pitch = std::f32::consts::FRAC_PI_2;
+        self.yaw += d_yaw;
+        self.pitch = (self.pitch + d_pitch).clamp(-max_pitch, max_pitch);
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Move the center, given a movement in camera coordinates
+    pub fn pan(&mut self, dcx: f32, dcy: f32) {
+        let (sy, cy) = self.yaw.sin_cos();
+        let (sp, cp) = self.pitch.sin_cos();
+
+        //Camera right and down vectors, in world coordinates. These are rows 0 and 1 of the rotation
+        let right = [cy, 0.0, sy];
+        let down = [sp*sy, cp, -sp*cy];
+
+        let dx = dcx*self.aspect/self.zoom;
+        let dy = dcy/self.zoom;
+        self.x -= right[0]*dx + down[0]*dy;
+        self.y -= right[1]*dx + down[1]*dy;
+        self.z -= right[2]*dx + down[2]*dy;
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Zoom in and out around the center
+    pub fn zoom_by(&mut self, scale: f32) {
+        self.zoom *= scale;
+    }
+}
+
+
+
+
+////////////////////////////////////////////////////////////
+/// Where a camera looks, so that the same view can be shown again later.
+/// The aspect ratio of the 3D camera follows the canvas, and is not kept
+#[derive(Debug, Clone, PartialEq)]
+pub enum ViewCamera {
+    Flat(f32, f32, f32, f32),                   // x, y, zoom_x, zoom_y
+    Orbit(f32, f32, f32, f32, f32, f32, f32),   // x, y, z, yaw, pitch, zoom, depth_scale
+}
+impl ViewCamera {
+
+    ////////////////////////////////////////////////////////////
+    /// Get where a 2D camera looks
+    pub fn from_camera2d(cam: &Camera2D) -> ViewCamera {
+        ViewCamera::Flat(cam.x, cam.y, cam.zoom_x, cam.zoom_y)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get where a 3D camera looks
+    pub fn from_camera3d(cam: &Camera3D) -> ViewCamera {
+        ViewCamera::Orbit(cam.x, cam.y, cam.z, cam.yaw, cam.pitch, cam.zoom, cam.depth_scale)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Move the camera of the same kind to look here
+    pub fn apply(&self, camera: &mut Camera2D, camera3d: &mut Camera3D) {
+        match *self {
+            ViewCamera::Flat(x, y, zoom_x, zoom_y) => {
+                camera.x = x;
+                camera.y = y;
+                camera.zoom_x = zoom_x;
+                camera.zoom_y = zoom_y;
+            },
+            ViewCamera::Orbit(x, y, z, yaw, pitch, zoom, depth_scale) => {
+                camera3d.x = x;
+                camera3d.y = y;
+                camera3d.z = z;
+                camera3d.yaw = yaw;
+                camera3d.pitch = pitch;
+                camera3d.zoom = zoom;
+                camera3d.depth_scale = depth_scale;
+            },
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Format as a list of numbers, for the URL. The first tells the kind of camera
+    pub fn to_url_value(&self) -> String {
+        let values = match *self {
+            ViewCamera::Flat(x, y, zoom_x, zoom_y) => vec![2.0, x, y, zoom_x, zoom_y],
+            ViewCamera::Orbit(x, y, z, yaw, pitch, zoom, depth_scale) => vec![3.0, x, y, z, yaw, pitch, zoom, depth_scale],
+        };
+        values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Parse from the URL. Anything malformed is ignored
+    pub fn from_url_value(value: &str) -> Option<ViewCamera> {
+        let values = value.split(',')
+            .map(|v| v.parse::<f32>().ok().filter(|v| v.is_finite()))
+            .collect::<Option<Vec<f32>>>()?;
+        match values.as_slice() {
+            [kind, x, y, zoom_x, zoom_y] if *kind == 2.0 => Some(ViewCamera::Flat(*x, *y, *zoom_x, *zoom_y)),
+            [kind, x, y, z, yaw, pitch, zoom, depth_scale] if *kind == 3.0 => Some(ViewCamera::Orbit(*x, *y, *z, *yaw, *pitch, *zoom, *depth_scale)),
+            _ => None,
+        }
+    }
+}
+
+
+
+
 ////////////////////////////////////////////////////////////
 /// A 2D rectangle
 #[derive(Debug, PartialEq)]
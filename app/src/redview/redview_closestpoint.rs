@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::redview::ReductionViewData;
+use crate::redview::redview_camera::{project_point, ViewMatrix};
 
 
 ////////////////////////////////////////////////////////////
@@ -17,6 +18,9 @@ type IndexedPoint = (f32,f32,usize);
 /// The system is simple: the world is divided up into square buckets, reducing
 /// the number of points to be checked. This assumes that points beyond a certain
 /// distance are not relevant.
+///
+/// Points are indexed by where they are projected on screen, so the index
+/// must be rebuilt whenever the camera moves
 pub struct ClosestPointIndex2D {
     sectors: HashMap<SectorID, Vec<IndexedPoint>>,
     max_dist: f32
@@ -43,20 +47,24 @@ impl ClosestPointIndex2D {
     /// Get sector ID (bucket) for a given point
     pub fn get_sector_id(&self, x: f32, y: f32) -> SectorID {
         (
-            ((x as f32)/self.max_dist) as i32,
-            ((y as f32)/self.max_dist) as i32,
+            ((x as f32)/self.max_dist).floor() as i32,
+            ((y as f32)/self.max_dist).floor() as i32,
         )
     }
 
     ////////////////////////////////////////////////////////////
-    /// From a reduction, place all points into their buckets
-    pub fn build_point_index(&mut self, umap: &ReductionViewData, max_dist: f32) {
+    /// From a reduction, place all points into their buckets, in camera coordinates
+    pub fn build_point_index(&mut self, umap: &ReductionViewData, view: &ViewMatrix, max_dist: f32) {
         self.clear();
         self.max_dist = max_dist;
 
         for i in 0..umap.num_point {
-            let x = umap.data[i*2+0];
-            let y: f32 = umap.data[i*2+1];
+            let (x, y, _depth) = project_point(
+                view,
+                umap.data[i*3+0],
+                umap.data[i*3+1],
+                umap.data[i*3+2],
+            );
 
             let sector_id = self.get_sector_id(x,y);
 
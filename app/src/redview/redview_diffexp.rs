@@ -0,0 +1,289 @@
+use std::cmp::Ordering;
+
+use my_web_app::DatasetDescResponse;
+use my_web_app::DiffExpFeature;
+use my_web_app::DiffExpRequest;
+use my_web_app::DiffExpResponse;
+use wasm_bindgen::JsCast;
+use web_sys::EventTarget;
+use web_sys::HtmlSelectElement;
+use yew::{html, Callback, Component, Context, Event, Html, MouseEvent};
+use yew::Properties;
+
+use crate::appstate::{AsyncData, BiscviCache, PerCellDataSource};
+
+/// Maximum number of features to show in the table
+const MAX_SHOWN_FEATURES: usize = 200;
+
+
+////////////////////////////////////////////////////////////
+/// Column to sort differential expression results by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffExpSortColumn {
+    Feature,
+    FoldChange,
+    FracA,
+    FracB,
+    PValue,
+    QValue,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Message sent to the event system for updating the page
+#[derive(Debug)]
+pub enum MsgDiffExp {
+    SetGroupA,
+    SetGroupB,
+    ClearGroupB,
+    SetCountName(String),
+    Compute,
+    SortBy(DiffExpSortColumn),
+    SetColorBy(String),
+}
+
+
+////////////////////////////////////////////////////////////
+/// Properties for DiffExpView
+#[derive(Properties, PartialEq)]
+pub struct Props {
+    pub current_datadesc: AsyncData<DatasetDescResponse>,
+    pub cell_selection: BiscviCache<Vec<usize>>,
+    pub diffexp: AsyncData<DiffExpResponse>,
+    pub on_diffexp: Callback<DiffExpRequest>,
+    pub on_colorbyfeature: Callback<PerCellDataSource>,
+}
+
+
+////////////////////////////////////////////////////////////
+/// This component compares two groups of selected cells, and lists differentially expressed features
+pub struct DiffExpView {
+    pub group_a: Vec<usize>,
+    pub group_b: Option<Vec<usize>>,    // None means the rest of the cells
+    pub counts_name: String,
+    pub computed_counts_name: String,
+    pub sort_column: DiffExpSortColumn,
+    pub sort_ascending: bool,
+}
+
+impl Component for DiffExpView {
+    type Message = MsgDiffExp;
+    type Properties = Props;
+
+    ////////////////////////////////////////////////////////////
+    /// Create this component
+    fn create(_ctx: &Context<Self>) -> Self {
+        Self {
+            group_a: Vec::new(),
+            group_b: None,
+            counts_name: String::new(),
+            computed_counts_name: String::new(),
+            sort_column: DiffExpSortColumn::PValue,
+            sort_ascending: true,
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Handle an update message
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+
+            //////// Use current selection as the first group
+            MsgDiffExp::SetGroupA => {
+                self.group_a = ctx.props().cell_selection.data.as_ref().clone();
+                true
+            },
+
+            //////// Use current selection as the second group
+            MsgDiffExp::SetGroupB => {
+                self.group_b = Some(ctx.props().cell_selection.data.as_ref().clone());
+                true
+            },
+
+            //////// Compare to all other cells
+            MsgDiffExp::ClearGroupB => {
+                self.group_b = None;
+                true
+            },
+
+            //////// Pick count matrix to compare
+            MsgDiffExp::SetCountName(counts_name) => {
+                self.counts_name = counts_name;
+                true
+            },
+
+            //////// Ask server to compute differential expression
+            MsgDiffExp::Compute => {
+                if self.group_a.is_empty() {
+                    return false;
+                }
+                self.computed_counts_name = self.counts_name.clone();
+                ctx.props().on_diffexp.emit(DiffExpRequest {
+                    dataset_name: String::new(), //Filled in by Model
+                    counts_name: self.counts_name.clone(),
+                    group_a: self.group_a.iter().map(|x| *x as u32).collect(),
+                    group_b: self.group_b.as_ref().map(|g| g.iter().map(|x| *x as u32).collect()),
+                });
+                true
+            },
+
+            //////// Sort table by column. Clicking the same column again reverses the order
+            MsgDiffExp::SortBy(col) => {
+                if self.sort_column == col {
+                    self.sort_ascending = !self.sort_ascending;
+                } else {
+                    self.sort_column = col;
+                    self.sort_ascending = true;
+                }
+                true
+            },
+
+            //////// Color reduction by a feature
+            MsgDiffExp::SetColorBy(feature_name) => {
+                ctx.props().on_colorbyfeature.emit(PerCellDataSource::Counts(self.computed_counts_name.clone(), feature_name));
+                false
+            },
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Render the differential expression panel
+    fn view(&self, ctx: &Context<Self>) -> Html {
+
+        //Generate SELECT for all count tables
+        let mut list_count_names = Vec::new();
+        if let AsyncData::Loaded(current_datadesc) = &ctx.props().current_datadesc {
+            for mat_name in current_datadesc.matrices.keys() {
+                list_count_names.push(mat_name.clone());
+            }
+        }
+        list_count_names.sort();
+        if self.counts_name.is_empty() {
+            if let Some(first_entry) = list_count_names.first() {
+                ctx.link().send_message(MsgDiffExp::SetCountName(first_entry.clone()));
+            }
+        }
+        let list_count_names_html = list_count_names.iter().map(|t| html! {
+            <option value={t.clone()} selected={&self.counts_name==t}>
+                {t}
+            </option>
+        }).collect::<Vec<_>>();
+
+        //Callback: change of count matrix
+        let cb_change_mat = ctx.link().callback(move |e: Event | {
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlSelectElement = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok()).expect("wrong type");
+            e.prevent_default();
+            MsgDiffExp::SetCountName(input.value())
+        });
+
+        let num_selected = ctx.props().cell_selection.data.len();
+        let text_group_b = if let Some(group_b) = &self.group_b {
+            format!("{} cells", group_b.len())
+        } else {
+            "all other cells".to_string()
+        };
+
+        html! {
+            <div class="biscvi-diffexp">
+                <div>
+                    {format!("Selected: {} cells. ", num_selected)}
+                    <button onclick={ctx.link().callback(|_| MsgDiffExp::SetGroupA)}>{"Set as group A"}</button>
+                    <button onclick={ctx.link().callback(|_| MsgDiffExp::SetGroupB)}>{"Set as group B"}</button>
+                    <button onclick={ctx.link().callback(|_| MsgDiffExp::ClearGroupB)}>{"B = rest"}</button>
+                </div>
+                <div>
+                    {format!("Group A: {} cells. Group B: {}. ", self.group_a.len(), text_group_b)}
+                    <select onchange={cb_change_mat}>
+                        {list_count_names_html}
+                    </select>
+                    <button onclick={ctx.link().callback(|_| MsgDiffExp::Compute)} disabled={self.group_a.is_empty()}>{"Compare"}</button>
+                </div>
+                { self.make_table(ctx) }
+            </div>
+        }
+    }
+}
+
+
+impl DiffExpView {
+
+    ////////////////////////////////////////////////////////////
+    /// Render the table of results
+    fn make_table(&self, ctx: &Context<Self>) -> Html {
+        let res = match &ctx.props().diffexp {
+            AsyncData::NotLoaded => return html! {""},
+            AsyncData::Loading => return html! { <div>{"Computing..."}</div> },
+            AsyncData::Loaded(res) => res,
+        };
+
+        //Sort results
+        let mut list_features = res.features.iter().collect::<Vec<_>>();
+        let col = self.sort_column;
+        list_features.sort_by(|a, b| {
+            let o = compare_diffexp(a, b, col);
+            if self.sort_ascending { o } else { o.reverse() }
+        });
+
+        //Header with clickable columns
+        let make_header = |name: &str, col: DiffExpSortColumn| {
+            let arrow = if self.sort_column == col {
+                if self.sort_ascending { " ▲" } else { " ▼" }
+            } else {
+                ""
+            };
+            let cb = ctx.link().callback(move |_e: MouseEvent| MsgDiffExp::SortBy(col));
+            html! {
+                <th onclick={cb} style="cursor: pointer;">{format!("{}{}", name, arrow)}</th>
+            }
+        };
+
+        let list_rows = list_features.iter().take(MAX_SHOWN_FEATURES).map(|f| {
+            let feature_name = f.feature_name.clone();
+            let cb = ctx.link().callback(move |_e: MouseEvent| MsgDiffExp::SetColorBy(feature_name.clone()));
+            html! {
+                <tr>
+                    <td onclick={cb} style="cursor: pointer; text-decoration: underline;">{f.feature_name.clone()}</td>
+                    <td>{format!("{:.2}", f.log2_fold_change)}</td>
+                    <td>{format!("{:.2}", f.frac_a)}</td>
+                    <td>{format!("{:.2}", f.frac_b)}</td>
+                    <td>{format!("{:.2e}", f.pvalue)}</td>
+                    <td>{format!("{:.2e}", f.qvalue)}</td>
+                </tr>
+            }
+        }).collect::<Vec<_>>();
+
+        html! {
+            <div>
+                {format!("Showing {} of {} features", list_rows.len(), list_features.len())}
+                <table>
+                    <tr>
+                        { make_header("Feature", DiffExpSortColumn::Feature) }
+                        { make_header("log2FC", DiffExpSortColumn::FoldChange) }
+                        { make_header("Frac A", DiffExpSortColumn::FracA) }
+                        { make_header("Frac B", DiffExpSortColumn::FracB) }
+                        { make_header("p", DiffExpSortColumn::PValue) }
+                        { make_header("q", DiffExpSortColumn::QValue) }
+                    </tr>
+                    { list_rows }
+                </table>
+            </div>
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Compare two features by given column
+fn compare_diffexp(a: &DiffExpFeature, b: &DiffExpFeature, col: DiffExpSortColumn) -> Ordering {
+    match col {
+        DiffExpSortColumn::Feature => a.feature_name.cmp(&b.feature_name),
+        DiffExpSortColumn::FoldChange => a.log2_fold_change.partial_cmp(&b.log2_fold_change).unwrap_or(Ordering::Equal),
+        DiffExpSortColumn::FracA => a.frac_a.partial_cmp(&b.frac_a).unwrap_or(Ordering::Equal),
+        DiffExpSortColumn::FracB => a.frac_b.partial_cmp(&b.frac_b).unwrap_or(Ordering::Equal),
+        DiffExpSortColumn::PValue => a.pvalue.partial_cmp(&b.pvalue).unwrap_or(Ordering::Equal),
+        DiffExpSortColumn::QValue => a.qvalue.partial_cmp(&b.qvalue).unwrap_or(Ordering::Equal),
+    }
+}
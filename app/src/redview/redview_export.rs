@@ -0,0 +1,301 @@
+use std::collections::BTreeSet;
+
+use my_web_app::CountNormalization;
+use my_web_app::DatasetDescResponse;
+use my_web_app::ExportCellsRequest;
+use my_web_app::ExportFormat;
+use my_web_app::SubsetFormat;
+use my_web_app::SubsetJobResponse;
+use my_web_app::SubsetJobState;
+use wasm_bindgen::JsCast;
+use web_sys::EventTarget;
+use web_sys::HtmlInputElement;
+use web_sys::HtmlSelectElement;
+use yew::{html, Callback, Component, Context, Event, Html, MouseEvent, NodeRef, SubmitEvent};
+use yew::Properties;
+
+use crate::appstate::{AsyncData, BiscviCache, MetadataData, PerCellDataSource};
+use crate::core_model::get_host_url;
+
+
+////////////////////////////////////////////////////////////
+/// Something that can be included in an export
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExportItem {
+    Meta(String),
+    Reduction(String),
+    Feature(String, String),
+}
+
+
+////////////////////////////////////////////////////////////
+/// Message sent to the event system for updating the page
+#[derive(Debug)]
+pub enum MsgExport {
+    ToggleExpand,
+    Toggle(ExportItem),
+    SetAllCells(bool),
+    SetFormat(ExportFormat),
+    SetSubsetFormat(SubsetFormat),
+}
+
+
+////////////////////////////////////////////////////////////
+/// Properties for ExportView
+#[derive(Properties, PartialEq)]
+pub struct Props {
+    pub dataset_name: String,
+    pub current_datadesc: AsyncData<DatasetDescResponse>,
+    pub cell_selection: BiscviCache<Vec<usize>>,
+    pub metadatas: BiscviCache<MetadataData>,
+    pub normalization: CountNormalization,
+    pub subset_job: AsyncData<SubsetJobResponse>,
+    pub on_subsetexport: Callback<SubsetFormat>,
+}
+
+
+////////////////////////////////////////////////////////////
+/// This component downloads values of the selected cells, or all cells, as a table.
+/// The download is a plain form post, so the browser saves the table as the server writes it.
+/// The selected cells can also be written by the server as a new dataset, and downloaded when done
+pub struct ExportView {
+    pub expanded: bool,
+    pub chosen: BTreeSet<ExportItem>,
+    pub all_cells: bool,
+    pub format: ExportFormat,
+    pub subset_format: SubsetFormat,
+    pub query_ref: NodeRef,
+}
+
+impl Component for ExportView {
+    type Message = MsgExport;
+    type Properties = Props;
+
+    ////////////////////////////////////////////////////////////
+    /// Create this component
+    fn create(_ctx: &Context<Self>) -> Self {
+        Self {
+            expanded: false,
+            chosen: BTreeSet::new(),
+            all_cells: false,
+            format: ExportFormat::CSV,
+            subset_format: SubsetFormat::Biscvi5,
+            query_ref: NodeRef::default(),
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Handle an update message
+    fn update(&mut self, _ctx: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+
+            //////// Show or hide the options
+            MsgExport::ToggleExpand => {
+                self.expanded = !self.expanded;
+                true
+            },
+
+            //////// Include or exclude a column
+            MsgExport::Toggle(item) => {
+                if !self.chosen.remove(&item) {
+                    self.chosen.insert(item);
+                }
+                true
+            },
+
+            //////// Export all cells rather than the selection
+            MsgExport::SetAllCells(all_cells) => {
+                self.all_cells = all_cells;
+                true
+            },
+
+            //////// Pick format of table
+            MsgExport::SetFormat(format) => {
+                self.format = format;
+                true
+            },
+
+            //////// Pick format of subset
+            MsgExport::SetSubsetFormat(format) => {
+                self.subset_format = format;
+                true
+            },
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Render the export panel
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let num_selected = ctx.props().cell_selection.data.len();
+
+        if !self.expanded {
+            return html! {
+                <div class="biscvi-export">
+                    <button onclick={ctx.link().callback(|_| MsgExport::ToggleExpand)}>{"Export cells..."}</button>
+                </div>
+            };
+        }
+
+        //Everything that can be exported. Features are those loaded so far
+        let mut list_items = Vec::new();
+        if let AsyncData::Loaded(current_datadesc) = &ctx.props().current_datadesc {
+            let mut meta_names = current_datadesc.meta.keys().cloned().collect::<Vec<_>>();
+            meta_names.sort();
+            list_items.extend(meta_names.into_iter().map(ExportItem::Meta));
+
+            let mut reduction_names = current_datadesc.reductions.keys().cloned().collect::<Vec<_>>();
+            reduction_names.sort();
+            list_items.extend(reduction_names.into_iter().map(ExportItem::Reduction));
+        }
+        let mut list_features = ctx.props().metadatas.data.metadatas.keys().filter_map(|k| match k {
+            PerCellDataSource::Counts(counts_name, feature_name) => Some(ExportItem::Feature(counts_name.clone(), feature_name.clone())),
+            PerCellDataSource::Metadata(_) => None,
+        }).collect::<Vec<_>>();
+        list_features.sort();
+        list_items.extend(list_features);
+
+        let list_items_html = list_items.into_iter().map(|item| {
+            let label = match &item {
+                ExportItem::Meta(name) => name.clone(),
+                ExportItem::Reduction(name) => format!("{} (coordinates)", name),
+                ExportItem::Feature(counts_name, feature_name) => format!("{}: {}", counts_name, feature_name),
+            };
+            let checked = self.chosen.contains(&item);
+            let cb = ctx.link().callback(move |_e: MouseEvent| MsgExport::Toggle(item.clone()));
+            html! {
+                <div>
+                    <label>
+                        <input type="checkbox" checked={checked} onclick={cb}/>
+                        {label}
+                    </label>
+                </div>
+            }
+        }).collect::<Vec<_>>();
+
+        //Callback: change of format
+        let list_format_html = ExportFormat::all().into_iter().map(|t| html! {
+            <option value={t.name()} selected={self.format==t}>
+                {t.name()}
+            </option>
+        }).collect::<Vec<_>>();
+        let cb_change_format = ctx.link().batch_callback(move |e: Event | {
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlSelectElement = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok()).expect("wrong type");
+            e.prevent_default();
+            let t = input.value();
+            ExportFormat::all().into_iter().find(|x| x.name() == t).map(MsgExport::SetFormat)
+        });
+
+        //Callback: the request is only made when submitting, as the list of cells can be long
+        let query_ref = self.query_ref.clone();
+        let cell_selection = ctx.props().cell_selection.clone();
+        let chosen = self.chosen.clone();
+        let all_cells = self.all_cells;
+        let format = self.format;
+        let normalization = ctx.props().normalization;
+        let dataset_name = ctx.props().dataset_name.clone();
+        let cb_submit = Callback::from(move |_e: SubmitEvent| {
+            let mut query = ExportCellsRequest {
+                dataset_name: dataset_name.clone(),
+                cells: if all_cells { None } else { Some(cell_selection.data.iter().map(|x| *x as u32).collect()) },
+                meta_columns: Vec::new(),
+                reductions: Vec::new(),
+                features: Vec::new(),
+                normalization,
+                format,
+            };
+            for item in &chosen {
+                match item {
+                    ExportItem::Meta(name) => query.meta_columns.push(name.clone()),
+                    ExportItem::Reduction(name) => query.reductions.push(name.clone()),
+                    ExportItem::Feature(counts_name, feature_name) => query.features.push((counts_name.clone(), feature_name.clone())),
+                }
+            }
+            let query_json = serde_json::to_string(&query).expect("Could not convert to json");
+            let input = query_ref.cast::<HtmlInputElement>().expect("Missing query input");
+            input.set_value(&query_json);
+        });
+
+        let can_export = self.all_cells || num_selected > 0;
+
+        //Callback: change of subset format
+        let list_subset_format_html = SubsetFormat::all().into_iter().map(|t| html! {
+            <option value={t.name()} selected={self.subset_format==t}>
+                {t.name()}
+            </option>
+        }).collect::<Vec<_>>();
+        let cb_change_subset_format = ctx.link().batch_callback(move |e: Event | {
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlSelectElement = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok()).expect("wrong type");
+            e.prevent_default();
+            let t = input.value();
+            SubsetFormat::all().into_iter().find(|x| x.name() == t).map(MsgExport::SetSubsetFormat)
+        });
+
+        //Callback: write the selected cells as a new dataset
+        let subset_format = self.subset_format;
+        let on_subsetexport = ctx.props().on_subsetexport.clone();
+        let cb_subset = Callback::from(move |_e: MouseEvent| {
+            on_subsetexport.emit(subset_format);
+        });
+
+        //State of the last subset, with links to the files once written
+        let subset_state_html = match &ctx.props().subset_job {
+            AsyncData::NotLoaded => html! {},
+            AsyncData::Loading => html! { <div>{"Starting..."}</div> },
+            AsyncData::Loaded(job) => match &job.state {
+                SubsetJobState::Running(msg) => html! { <div>{format!("{}...", msg)}</div> },
+                SubsetJobState::Failed(msg) => html! { <div>{format!("Failed: {}", msg)}</div> },
+                SubsetJobState::Done(files) => {
+                    let list_files_html = files.iter().map(|f| html! {
+                        <div>
+                            <a href={format!("{}/subset_download/{}/{}", get_host_url(), job.job_id, f)} download={f.clone()}>{f}</a>
+                        </div>
+                    }).collect::<Vec<_>>();
+                    html! { <div>{list_files_html}</div> }
+                },
+            },
+        };
+        let subset_running = match &ctx.props().subset_job {
+            AsyncData::Loading => true,
+            AsyncData::Loaded(job) => matches!(job.state, SubsetJobState::Running(_)),
+            AsyncData::NotLoaded => false,
+        };
+
+        html! {
+            <div class="biscvi-export">
+                <div>
+                    <button onclick={ctx.link().callback(|_| MsgExport::ToggleExpand)}>{"Hide export"}</button>
+                </div>
+                <div>
+                    <label>
+                        <input type="radio" name="export-cells" checked={!self.all_cells} onclick={ctx.link().callback(|_| MsgExport::SetAllCells(false))}/>
+                        {format!("Selected cells ({})", num_selected)}
+                    </label>
+                    <label>
+                        <input type="radio" name="export-cells" checked={self.all_cells} onclick={ctx.link().callback(|_| MsgExport::SetAllCells(true))}/>
+                        {"All cells"}
+                    </label>
+                </div>
+                { list_items_html }
+                <form method="post" action={format!("{}/export_cells", get_host_url())} onsubmit={cb_submit}>
+                    <input type="hidden" name="query" ref={self.query_ref.clone()}/>
+                    <select onchange={cb_change_format}>
+                        {list_format_html}
+                    </select>
+                    <button type="submit" disabled={!can_export}>{"Download"}</button>
+                </form>
+                <div>
+                    {"Selected cells as a new dataset: "}
+                    <select onchange={cb_change_subset_format}>
+                        {list_subset_format_html}
+                    </select>
+                    <button onclick={cb_subset} disabled={num_selected == 0 || subset_running}>{"Write subset"}</button>
+                </div>
+                { subset_state_html }
+            </div>
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use my_web_app::CellProfileResponse;
+use yew::{html, Callback, Component, Context, Html, MouseEvent};
+use yew::Properties;
+
+use crate::appstate::{AsyncData, PerCellDataSource};
+use crate::core_model::get_host_url;
+
+
+////////////////////////////////////////////////////////////
+/// Message sent to the event system for updating the page
+#[derive(Debug)]
+pub enum MsgInspector {
+    Close,
+    SetColorBy(String, String),
+}
+
+
+////////////////////////////////////////////////////////////
+/// Properties for CellInspectorView
+#[derive(Properties, PartialEq)]
+pub struct Props {
+    pub current_dataset: Option<String>,
+    pub cell_profile: AsyncData<CellProfileResponse>,
+    pub on_close: Callback<()>,
+    pub on_colorbyfeature: Callback<PerCellDataSource>,
+}
+
+
+////////////////////////////////////////////////////////////
+/// This component shows everything about the last clicked cell: metadata, the features with most counts,
+/// and its reads for download
+pub struct CellInspectorView {
+}
+
+impl Component for CellInspectorView {
+    type Message = MsgInspector;
+    type Properties = Props;
+
+    ////////////////////////////////////////////////////////////
+    /// Create this component
+    fn create(_ctx: &Context<Self>) -> Self {
+        Self {
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Handle an update message
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+
+            //////// Hide the panel
+            MsgInspector::Close => {
+                ctx.props().on_close.emit(());
+                false
+            },
+
+            //////// Color reduction by a feature
+            MsgInspector::SetColorBy(counts_name, feature_name) => {
+                ctx.props().on_colorbyfeature.emit(PerCellDataSource::Counts(counts_name, feature_name));
+                false
+            },
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Render the inspector panel. Nothing is shown until a cell has been clicked
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let profile = match &ctx.props().cell_profile {
+            AsyncData::NotLoaded => return html! {""},
+            AsyncData::Loading => return html! { <div class="biscvi-inspector">{"Loading cell..."}</div> },
+            AsyncData::Loaded(profile) => profile,
+        };
+
+        //Metadata values
+        let list_meta = profile.meta.iter().map(|(name, value)| {
+            let value = value.clone().unwrap_or("(missing)".to_string());
+            html! {
+                <tr>
+                    <td>{name.clone()}</td>
+                    <td>{value}</td>
+                </tr>
+            }
+        }).collect::<Html>();
+
+        //Top features of each count matrix
+        let list_matrices = profile.matrices.iter().map(|(counts_name, mat)| {
+            let list_features = mat.top_features.iter().map(|(feature_name, value)| {
+                let counts_name = counts_name.clone();
+                let feature_name_cb = feature_name.clone();
+                let cb = ctx.link().callback(move |_e: MouseEvent| MsgInspector::SetColorBy(counts_name.clone(), feature_name_cb.clone()));
+                html! {
+                    <tr>
+                        <td onclick={cb} style="cursor: pointer; text-decoration: underline;">{feature_name.clone()}</td>
+                        <td>{format!("{}", value)}</td>
+                    </tr>
+                }
+            }).collect::<Html>();
+
+            html! {
+                <div>
+                    <div>
+                        <b>{counts_name.clone()}</b>
+                        {format!(": total {}, {} features detected", mat.total, mat.num_detected)}
+                    </div>
+                    <table>
+                        { list_features }
+                    </table>
+                </div>
+            }
+        }).collect::<Html>();
+
+        //Files of reads, if the dataset has them
+        let list_reads = if let Some(dataset_name) = &ctx.props().current_dataset {
+            profile.read_files.iter().map(|f| {
+                let url = format!("{}/cell_reads/{}/{}/{}/{}",
+                    get_host_url(),
+                    encode(dataset_name),
+                    encode(&f.shard_set),
+                    encode(&profile.barcode),
+                    encode(&f.file_name)
+                );
+                html! {
+                    <div>
+                        <a href={url} download={format!("{}_{}", profile.barcode, f.file_name.replace('/', "_"))}>{format!("{}/{}", f.shard_set, f.file_name)}</a>
+                        {format!(" ({} bytes)", f.size)}
+                    </div>
+                }
+            }).collect::<Html>()
+        } else {
+            html! {""}
+        };
+
+        html! {
+            <div class="biscvi-inspector">
+                <div>
+                    <b>{format!("Cell {}", profile.barcode)}</b>
+                    {format!(" (#{}) ", profile.cell)}
+                    <button onclick={ctx.link().callback(|_| MsgInspector::Close)}>{"Close"}</button>
+                </div>
+                <table>
+                    { list_meta }
+                </table>
+                { list_matrices }
+                { list_reads }
+            </div>
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Percent-encode a part of a URL path
+fn encode(s: &str) -> String {
+    js_sys::encode_uri_component(s).into()
+}
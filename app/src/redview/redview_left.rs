@@ -3,10 +3,14 @@ use std::collections::HashSet;
 
 use my_web_app::countfile_struct::CountFileMetaColumnDesc;
 use my_web_app::DatasetDescResponse;
-use yew::{html, Callback, Component, Context, Html, MouseEvent, NodeRef};
+use my_web_app::MetaColumnFilter;
+use wasm_bindgen::JsCast;
+use web_sys::{EventTarget, HtmlInputElement, HtmlSelectElement};
+use yew::{html, Callback, Component, Context, Event, Html, MouseEvent, NodeRef, TargetCast};
 use yew::Properties;
 
-use crate::appstate::{AsyncData, PerCellDataSource};
+use crate::appstate::{AsyncData, BiscviCache, MarkerData, MetadataData, PerCellDataSource};
+use crate::histogram::{make_histogram_svg, FeatureHistogram};
 use crate::redview::redview_main::get_palette_for_categories;
 
 
@@ -15,7 +19,14 @@ use crate::redview::redview_main::get_palette_for_categories;
 #[derive(Debug)]
 pub enum MsgMetadata {
     SetColorBy(String),
-    ToggleExpand(String)
+    ToggleExpand(String),
+    ShowMarkers(String, usize),
+    SetMarkerCountName(String),
+    SetColorByFeature(String),
+    SetFilterMin(String),
+    SetFilterMax(String),
+    SetFilterText(String),
+    SelectCells(String, MetaColumnFilter),
 }
 
 
@@ -26,6 +37,12 @@ pub struct Props {
     pub current_datadesc: AsyncData<DatasetDescResponse>,
     pub on_colorbymeta: Callback<PerCellDataSource>,
     pub current_colorby: PerCellDataSource,
+    pub markers: BiscviCache<MarkerData>,
+    pub on_requestmarkers: Callback<(String, String)>,
+    pub metadatas: BiscviCache<MetadataData>,
+    pub on_selectcells: Callback<Vec<usize>>,
+    pub expanded_meta: HashSet<String>,
+    pub on_toggleexpand: Callback<String>,
 }
 
 
@@ -34,8 +51,15 @@ pub struct Props {
 pub struct MetadataView {
     pub node_ref: NodeRef,
 
-    pub expanded_meta: HashSet<String>,
     pub selected_meta: HashSet<String>,
+
+    pub marker_counts_name: String,
+    pub shown_markers: Option<(String, usize)>, // metadata column, category
+
+    //Filter state, for columns that are not categorical
+    pub filter_min: String,
+    pub filter_max: String,
+    pub filter_text: String,
 }
 
 impl Component for MetadataView {
@@ -47,8 +71,12 @@ impl Component for MetadataView {
     fn create(_ctx: &Context<Self>) -> Self {    
         Self {
             node_ref: NodeRef::default(),
-            expanded_meta: HashSet::new(),
             selected_meta: HashSet::new(),
+            marker_counts_name: String::new(),
+            shown_markers: None,
+            filter_min: String::new(),
+            filter_max: String::new(),
+            filter_text: String::new(),
             //last_colorby: PerCellDataSource::Metadata("".into()),  //terrible!
         }
     }
@@ -66,16 +94,63 @@ impl Component for MetadataView {
                 true
             },
 
-            ///// Expand this metadata column to show categories etc
+            ///// Expand this metadata column to show categories etc. Which are expanded is kept above, as part of saved sessions
             MsgMetadata::ToggleExpand(metadata_name) => {
-                if self.expanded_meta.contains(&metadata_name) {
-                    self.expanded_meta.remove(&metadata_name);
+                ctx.props().on_toggleexpand.emit(metadata_name);
+                false
+            },
+
+            ///// Show marker features of a category. Clicking it again hides them
+            MsgMetadata::ShowMarkers(metadata_name, level_i) => {
+                let key = Some((metadata_name.clone(), level_i));
+                if self.shown_markers == key {
+                    self.shown_markers = None;
                 } else {
-                    self.expanded_meta.insert(metadata_name);
+                    self.shown_markers = key;
+                    ctx.props().on_requestmarkers.emit((self.marker_counts_name.clone(), metadata_name));
+                }
+                true
+            },
+
+            ///// Pick count table to find markers in
+            MsgMetadata::SetMarkerCountName(counts_name) => {
+                self.marker_counts_name = counts_name;
+                if let Some((metadata_name, _)) = &self.shown_markers {
+                    ctx.props().on_requestmarkers.emit((self.marker_counts_name.clone(), metadata_name.clone()));
                 }
                 true
             },
 
+            ///// Color by a marker feature
+            MsgMetadata::SetColorByFeature(feature_name) => {
+                ctx.props().on_colorbymeta.emit(PerCellDataSource::Counts(self.marker_counts_name.clone(), feature_name));
+                true
+            },
+
+            ///// Update filter settings
+            MsgMetadata::SetFilterMin(v) => {
+                self.filter_min = v;
+                false
+            },
+            MsgMetadata::SetFilterMax(v) => {
+                self.filter_max = v;
+                false
+            },
+            MsgMetadata::SetFilterText(v) => {
+                self.filter_text = v;
+                false
+            },
+
+            ///// Select cells matching a filter on a metadata column. Needs the column to be loaded
+            MsgMetadata::SelectCells(metadata_name, filter) => {
+                let id = PerCellDataSource::Metadata(metadata_name);
+                if let AsyncData::Loaded(data) = ctx.props().metadatas.data.get(&id) {
+                    let cells = data.select_cells(0, &filter);
+                    ctx.props().on_selectcells.emit(cells);
+                }
+                false
+            },
+
         }
     }
 
@@ -116,13 +191,18 @@ impl Component for MetadataView {
             for (meta_name,meta_data) in current_datadesc.meta.iter() {
                 let meta_name_id = PerCellDataSource::Metadata(meta_name.clone());
 
-                //////////// Discrete category
-                if let CountFileMetaColumnDesc::Categorical(categories ) = meta_data {
+                //////////// Discrete category. Booleans are shown as two categories
+                let categories = match meta_data {
+                    CountFileMetaColumnDesc::Categorical(categories) => Some(categories.clone()),
+                    CountFileMetaColumnDesc::Boolean() => Some(vec!["false".to_string(), "true".to_string()]),
+                    _ => None
+                };
+                if let Some(categories) = &categories {
                     let palette = get_palette_for_categories(categories.len());
 
                     //// Produce a list of all categories
                     let mut list_levels = Vec::new();
-                    if self.expanded_meta.contains(meta_name) {
+                    if ctx.props().expanded_meta.contains(meta_name) {
                         for (level_i, level_name) in categories.iter().enumerate() {
 
                             //Show a palette if this category is selected
@@ -131,10 +211,24 @@ impl Component for MetadataView {
                             let col = palette.get(level_i % palette.len()).unwrap();
 
                             let num_cells = "";
+
+                            //Callback to select all cells of this category
+                            let meta_name_copy = meta_name.clone();
+                            let level_name_copy = level_name.clone();
+                            let cb_select = ctx.link().callback(move |e: MouseEvent | { 
+                                e.stop_propagation();
+                                MsgMetadata::SelectCells(meta_name_copy.clone(), MetaColumnFilter::Label(level_name_copy.clone()))
+                            });
+
+                            //Callback to show markers of this category
+                            let meta_name_copy = meta_name.clone();
+                            let cb_show_markers = ctx.link().callback(move |_e: MouseEvent | { 
+                                MsgMetadata::ShowMarkers(meta_name_copy.clone(), level_i)
+                            });
                             
                             list_levels.push(                                
                                 html! {
-                                    <div style="padding: 4px 10px 4px 7px; display: flex; align-items: baseline; justify-content: space-between; margin-bottom: 2px; border-radius: 2px;">
+                                    <div onclick={cb_show_markers} style="cursor: pointer; padding: 4px 10px 4px 7px; display: flex; align-items: baseline; justify-content: space-between; margin-bottom: 2px; border-radius: 2px;">
                                         <div style="margin: 0px; padding: 0px; user-select: none; width: 245px; display: flex; justify-content: space-between;">
                                             <div style="display: flex; align-items: baseline;">
                                                 <span class="ignore-capture" style="margin: 0px; height: 18px;">
@@ -164,6 +258,9 @@ impl Component for MetadataView {
                                                 <span style="color: black; top: 10px;" class="bisci-label-left">
                                                     {format!("{}",num_cells)} 
                                                 </span>
+                                                <span onclick={cb_select} style="cursor: pointer; text-decoration: underline; font-size: 10px;" title="Select these cells">
+                                                    {"select"}
+                                                </span>
                                             <span style="vertical-align: baseline;">
                                                 <svg display="auto" style={format!{"top: 3px; width: 15px; height: 15px; margin-left: 5px; position: relative; background-color: rgb({}, {}, {});", col.0*255.0, col.1*255.0, col.2*255.0}}></svg>
                                                 </span>
@@ -174,6 +271,10 @@ impl Component for MetadataView {
                                 }
                             );                                
 
+                            //List markers below the category, if shown
+                            if self.shown_markers == Some((meta_name.clone(), level_i)) {
+                                list_levels.push(self.make_markers(ctx, meta_name, level_i));
+                            }
                         }
                     }
 
@@ -221,8 +322,8 @@ impl Component for MetadataView {
 
                 }
 
-                //////////// Continuous categories
-                if let CountFileMetaColumnDesc::Numeric() = meta_data {
+                //////////// Continuous categories, and other values that cannot be listed as categories
+                if categories.is_none() {
 
                     let style_colorbutton = if ctx.props().current_colorby == meta_name_id {
                         "background-color:  #FF0000; "
@@ -236,6 +337,18 @@ impl Component for MetadataView {
                         MsgMetadata::SetColorBy(meta_name_copy.clone())
                     });
 
+                    let meta_name_copy = meta_name.clone();
+                    let toggle_expand = ctx.link().callback(move |_e: MouseEvent | { 
+                        MsgMetadata::ToggleExpand(meta_name_copy.clone())
+                    });
+
+                    //Distribution and filters, if expanded
+                    let details = if ctx.props().expanded_meta.contains(meta_name) {
+                        self.make_value_details(ctx, meta_name, meta_data)
+                    } else {
+                        html! {""}
+                    };
+
                     //// Option to color by continuous metadata
                     list_meta_cont.push(
                         html! { 
@@ -244,9 +357,10 @@ impl Component for MetadataView {
                                     <div style="display:table-cell;">
                                         <input type="checkbox" checked=true />
                                         { meta_name.clone() }
-                                        //<span onclick={toggle_expand}>
-                                        //    { arrow_down_svg.clone()}
-                                        //</span>
+                                        <span style="color: gray; font-size: 10px;">{format!(" ({})", meta_data.type_name())}</span>
+                                        <span onclick={toggle_expand}>
+                                            { arrow_down_svg.clone()}
+                                        </span>
                                     </div>
                                     <div style="text-align: right;">
                                         <button type="button" style={style_colorbutton} onclick={cb_color_by}>
@@ -254,6 +368,7 @@ impl Component for MetadataView {
                                         </button>
                                     </div>
                                 </div> 
+                                { details }
                             </div>
                         }
                     );
@@ -261,12 +376,45 @@ impl Component for MetadataView {
             }
         }
 
+        //Generate SELECT for all count tables, to find markers in
+        let mut list_count_names = Vec::new();
+        if let AsyncData::Loaded(current_datadesc) = &current_datadesc {
+            for mat_name in current_datadesc.matrices.keys() {
+                list_count_names.push(mat_name.clone());
+            }
+        }
+        list_count_names.sort();
+        if self.marker_counts_name.is_empty() {
+            if let Some(first_entry) = list_count_names.first() {
+                ctx.link().send_message(MsgMetadata::SetMarkerCountName(first_entry.clone()));
+            }
+        }
+        let list_count_names_html = list_count_names.iter().map(|t| html! {
+            <option value={t.clone()} selected={&self.marker_counts_name==t}>
+                {t}
+            </option>
+        }).collect::<Vec<_>>();
+
+        //Callback: change of count table for markers
+        let cb_change_marker_mat = ctx.link().callback(move |e: Event | { 
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlSelectElement = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok()).expect("wrong type");
+            e.prevent_default();
+            MsgMetadata::SetMarkerCountName(input.value())
+        });
+
         html! {
             <div class="biscvi-dimred-leftdiv">
                 <div>
                     <span style="color:blue;font-weight:bold;">
                         {"Discrete categories:"}
                     </span>
+                    <div>
+                        {"Markers from: "}
+                        <select onchange={cb_change_marker_mat}>
+                            {list_count_names_html}
+                        </select>
+                    </div>
                     { list_meta_cat }
                     <span style="color:blue;font-weight:bold;">
                         {"Continuous categories:"}
@@ -287,5 +435,119 @@ impl Component for MetadataView {
 }
 
 
+impl MetadataView {
+
+    ////////////////////////////////////////////////////////////
+    /// Render the histogram and filters of a column that is not categorical
+    fn make_value_details(&self, ctx: &Context<Self>, meta_name: &String, meta_data: &CountFileMetaColumnDesc) -> Html {
+        let id = PerCellDataSource::Metadata(meta_name.clone());
+        let data = if let AsyncData::Loaded(data) = ctx.props().metadatas.data.get(&id) {
+            data
+        } else {
+            return html! { <div style="margin-left: 30px; font-size: 12px;">{"Color by this column to see its values"}</div> };
+        };
+
+        let histogram = make_histogram_svg(&FeatureHistogram::build(data.as_ref()), 150.0, 15.0);
+        let num_cells = data.len().unwrap_or(0);
+        let num_missing = (0..num_cells).filter(|i| data.is_missing(*i)).count();
+
+        //Callback: select cells with missing values
+        let meta_name_copy = meta_name.clone();
+        let cb_select_missing = ctx.link().callback(move |_e: MouseEvent | { 
+            MsgMetadata::SelectCells(meta_name_copy.clone(), MetaColumnFilter::Missing)
+        });
+
+        let filter = if let CountFileMetaColumnDesc::Text() = meta_data {
+            //Text is filtered by substring
+            let cb_text = ctx.link().callback(move |e: Event | { 
+                let input: HtmlInputElement = e.target_unchecked_into();
+                MsgMetadata::SetFilterText(input.value())
+            });
+            let meta_name_copy = meta_name.clone();
+            let filter_text = self.filter_text.clone();
+            let cb_select = ctx.link().callback(move |_e: MouseEvent | { 
+                MsgMetadata::SelectCells(meta_name_copy.clone(), MetaColumnFilter::Contains(filter_text.clone()))
+            });
+            html! {
+                <div>
+                    {"Contains: "}
+                    <input type="text" size="10" value={self.filter_text.clone()} onchange={cb_text}/>
+                    <button onclick={cb_select}>{"Select"}</button>
+                </div>
+            }
+        } else {
+            //Numbers are filtered by range
+            let cb_min = ctx.link().callback(move |e: Event | { 
+                let input: HtmlInputElement = e.target_unchecked_into();
+                MsgMetadata::SetFilterMin(input.value())
+            });
+            let cb_max = ctx.link().callback(move |e: Event | { 
+                let input: HtmlInputElement = e.target_unchecked_into();
+                MsgMetadata::SetFilterMax(input.value())
+            });
+            let meta_name_copy = meta_name.clone();
+            let min = self.filter_min.parse::<f32>().unwrap_or(f32::NEG_INFINITY);
+            let max = self.filter_max.parse::<f32>().unwrap_or(f32::INFINITY);
+            let cb_select = ctx.link().callback(move |_e: MouseEvent | { 
+                MsgMetadata::SelectCells(meta_name_copy.clone(), MetaColumnFilter::Range(min, max))
+            });
+            html! {
+                <div>
+                    <input type="number" style="width: 60px;" placeholder="min" value={self.filter_min.clone()} onchange={cb_min}/>
+                    {" to "}
+                    <input type="number" style="width: 60px;" placeholder="max" value={self.filter_max.clone()} onchange={cb_max}/>
+                    <button onclick={cb_select}>{"Select"}</button>
+                </div>
+            }
+        };
+
+        html! {
+            <div style="margin-left: 30px; font-size: 12px;">
+                { histogram }
+                { filter }
+                <div>
+                    {format!("Missing: {} ", num_missing)}
+                    <button onclick={cb_select_missing} disabled={num_missing==0}>{"Select"}</button>
+                </div>
+            </div>
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Render the list of marker features for one category
+    fn make_markers(&self, ctx: &Context<Self>, meta_name: &String, level_i: usize) -> Html {
+        let key = (self.marker_counts_name.clone(), meta_name.clone());
+        match ctx.props().markers.data.get(&key) {
+            AsyncData::Loaded(markers) => {
+                let list_markers = markers.markers.get(level_i).cloned().unwrap_or_default();
+                if list_markers.is_empty() {
+                    return html! { <div style="margin-left: 30px;">{"No markers found"}</div> };
+                }
+                let list_html = list_markers.iter().map(|f| {
+                    let feature_name = f.feature_name.clone();
+                    let cb = ctx.link().callback(move |e: MouseEvent| {
+                        e.stop_propagation();
+                        MsgMetadata::SetColorByFeature(feature_name.clone())
+                    });
+                    html! {
+                        <div onclick={cb} style="cursor: pointer;" title={format!("q={:.2e}, in {:.0}% vs {:.0}% of cells", f.qvalue, f.frac_a*100.0, f.frac_b*100.0)}>
+                            {format!("{} (log2FC {:.2})", f.feature_name, f.log2_fold_change)}
+                        </div>
+                    }
+                }).collect::<Vec<_>>();
+                html! {
+                    <div style="margin-left: 30px; font-size: 12px;">
+                        {list_html}
+                    </div>
+                }
+            },
+            _ => {
+                html! { <div style="margin-left: 30px;">{"Finding markers..."}</div> }
+            }
+        }
+    }
+}
+
+
 
 
@@ -2,6 +2,7 @@ use std::io::BufRead;
 use std::io::Cursor;
 use std::io::BufReader;
 
+use my_web_app::BarcodesResponse;
 use my_web_app::CountFileMetaColumnData;
 use my_web_app::DatasetDescResponse;
 use my_web_app::ReductionResponse;
@@ -23,13 +24,27 @@ use crate::appstate::ReductionData;
 use crate::core_model::MsgCore;
 use crate::histogram::make_safe_minmax;
 use crate::redview::Camera2D;
+use crate::redview::Camera3D;
 use crate::redview::ClosestPointIndex2D;
+use crate::redview::redview_camera::{project_point, ViewCamera, ViewMatrix};
 use crate::redview::Rectangle2D;
 use crate::resize::ComponentSize;
 
 
 // see https://github.com/yewstack/yew/blob/master/examples/webgl/src/main.rs
 
+/// Distance within which a point is picked when hovering, in camera coordinates
+const POINT_PICK_DIST: f32 = 0.02;
+
+/// Color of cells where the value is missing
+const MISSING_COLOR: (f32, f32, f32) = (0.8, 0.8, 0.8);
+
+/// Distance from the mouse pointer to the hover tooltip, in pixels
+const TOOLTIP_OFFSET: f32 = 15.0;
+
+/// Most metadata values listed in the hover tooltip
+const TOOLTIP_MAX_VALUES: usize = 8;
+
 
 ////////////////////////////////////////////////////////////
 /// RGB color, 0...1
@@ -60,13 +75,25 @@ pub enum ReductionColoringWithData {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ReductionViewData {
     pub num_point: usize,
-    pub data: Vec<f32>,
+    pub num_dim: usize,     // 2 or 3
+    pub data: Vec<f32>,     // x,y,z for each point. z is 0 for 2D reductions
     //pub ids: Vec<String>, //cluster_id
 
     pub max_x: f32,
     pub max_y: f32,
+    pub max_z: f32,
     pub min_x: f32,
     pub min_y: f32,
+    pub min_z: f32,
+}
+impl ReductionViewData {
+
+    ////////////////////////////////////////////////////////////
+    /// Check if this reduction should be shown in 3D. Only reductions of exactly 3 dimensions are;
+    /// the server leaves out the third axis of larger ones, such as PCA
+    pub fn is_3d(&self) -> bool {
+        self.num_dim == 3
+    }
 }
     //    keep this in a cache? x,y and xy together??
 
@@ -77,12 +104,15 @@ pub struct ReductionViewData {
 pub fn convert_from_response_to_reduction_data(resp: ReductionResponse) -> ReductionViewData {
 
     let num_point= resp.x.len();
+    let num_dim = if resp.z.is_some() { 3 } else { 2 };
 
     //Figure out reduction point range
     let mut max_x = f32::MIN;
     let mut max_y = f32::MIN;
+    let mut max_z = 0.0f32;
     let mut min_x = f32::MAX;
     let mut min_y = f32::MAX;
+    let mut min_z = 0.0f32;
 
     resp.x.iter().for_each(|v|{
         max_x = max_x.max(*v);
@@ -94,20 +124,32 @@ pub fn convert_from_response_to_reduction_data(resp: ReductionResponse) -> Reduc
         min_y = min_y.min(*v);
     });
 
-    //Convert coordinates to flat list. better to send in this format already?  --- code is likely fairly slow in current design
-    let mut data:Vec<f32> = Vec::with_capacity(num_point*2);
-    unsafe {
-        data.set_len(num_point*2);
+    if let Some(z) = &resp.z {
+        max_z = f32::MIN;
+        min_z = f32::MAX;
+        z.iter().for_each(|v|{
+            max_z = max_z.max(*v);
+            min_z = min_z.min(*v);
+        });
     }
 
+    //Convert coordinates to flat list. better to send in this format already?  --- code is likely fairly slow in current design
+    let mut data:Vec<f32> = vec![0.0; num_point*3];
+
     resp.x.iter().enumerate().for_each(|(i,v)| {
-        data[i*2] = *v;
+        data[i*3] = *v;
     });
 
     resp.y.iter().enumerate().for_each(|(i,v)| {
-        data[i*2+1] = *v;
+        data[i*3+1] = *v;
     });
 
+    if let Some(z) = &resp.z {
+        z.iter().enumerate().for_each(|(i,v)| {
+            data[i*3+2] = *v;
+        });
+    }
+
     /*
     is above faster? it should eliminate a bound check at minimum. but would be great if we could instead do below unsafely
     for i in 0..num_point {
@@ -118,11 +160,14 @@ pub fn convert_from_response_to_reduction_data(resp: ReductionResponse) -> Reduc
 
     ReductionViewData {
         num_point: num_point,
+        num_dim: num_dim,
         data: data,
         max_x: max_x,
         max_y: max_y,
+        max_z: max_z,
         min_x: min_x,
-        min_y: min_y
+        min_y: min_y,
+        min_z: min_z,
     }
 }
 
@@ -137,7 +182,8 @@ pub fn convert_from_response_to_reduction_data(resp: ReductionResponse) -> Reduc
 pub enum CurrentTool {
     Zoom,
     ZoomAll,
-    Select
+    Select,
+    Orbit,  // Only for 3D reductions
 }
 
 
@@ -146,6 +192,7 @@ pub enum CurrentTool {
 #[derive(Debug)]
 pub enum MsgReduction {
     MouseMove(f32,f32, bool),
+    MouseLeave,
     MouseClick,
     MouseWheel(f32),
     MouseStartSelect(f32,f32),
@@ -172,6 +219,11 @@ pub struct Props {
     pub metadatas: BiscviCache<MetadataData>,          // call something else? countdatas?
 
     pub current_reduction_name: Option<String>,
+    pub barcodes: AsyncData<BarcodesResponse>,
+
+    // Where the camera should look, for the given reduction, rather than fitting all points
+    pub camera_goto: Option<(String, ViewCamera)>,
+    pub on_camera_changed: Callback<ViewCamera>,
 
 }
 
@@ -185,8 +237,10 @@ pub struct ReductionView {
     closest_point_index: ClosestPointIndex2D,
     current_tool: CurrentTool,
     camera: Camera2D,
-    current_selection: Option<Rectangle2D>,
+    camera3d: Camera3D,
+    current_selection: Option<Rectangle2D>,   // In camera coordinates
     last_reduction_data: AsyncData<ReductionViewData>,
+    point_index_dirty: bool,
 
 
 
@@ -209,8 +263,10 @@ impl Component for ReductionView {
             closest_point_index: ClosestPointIndex2D::new(), //tricky... adapt to umap size??
             current_tool: CurrentTool::Select,
             camera: Camera2D::new(),
+            camera3d: Camera3D::new(),
             current_selection: None,
             last_reduction_data: AsyncData::NotLoaded,
+            point_index_dirty: true,
             //current_reduction_name: "".to_string(),
         }
     }
@@ -229,6 +285,18 @@ impl Component for ReductionView {
                 false
             },
 
+            ////////////////////////////////////////////////////////////
+            // Message: Mouse has left the canvas. Hide the tooltip
+            MsgReduction::MouseLeave => {
+                if self.last_cell.is_some() {
+                    self.last_cell = None;
+                    ctx.props().on_cell_hovered.emit(None);
+                    true
+                } else {
+                    false
+                }
+            },
+
             ////////////////////////////////////////////////////////////
             // Message: Mouse has moved
             MsgReduction::MouseMove(x,y, press_left) => {
@@ -237,11 +305,17 @@ impl Component for ReductionView {
                 self.last_pos = (x,y);
                 //  log::debug!(".. {:?}", last_pos);
 
-                //Handle pointer in world coordinates
-                let (wx,wy) = self.camera.cam2world(x as f32, y as f32);
+                //Points are picked where they are shown on screen. Update the index if the camera moved, but not while dragging
+                if self.point_index_dirty && !press_left {
+                    if let AsyncData::Loaded(reduction_data) = get_current_reduction_data(ctx) {
+                        let view = self.get_view_matrix(&reduction_data);
+                        self.closest_point_index.build_point_index(&reduction_data, &view, POINT_PICK_DIST);
+                        self.point_index_dirty = false;
+                    }
+                }
 
                 //Handle hovering
-                let cp = self.closest_point_index.get_closest_point(wx, wy);  // sometimes a crash overflow here?? 666
+                let cp = self.closest_point_index.get_closest_point(x, y);
                 //log::debug!("p: {:?}",cp);
                 //log::debug!("{} {}",x,y);
 
@@ -263,8 +337,8 @@ impl Component for ReductionView {
                 }
 
                 if let Some(sel) = &mut self.current_selection {
-                    sel.x2=wx;
-                    sel.y2=wy;
+                    sel.x2=x;
+                    sel.y2=y;
                     //log::debug!("sel-move {:?}",sel);
                 }
 
@@ -273,8 +347,22 @@ impl Component for ReductionView {
                     let dx = x - last_pos.0;
                     let dy = y - last_pos.1;
                     //log::debug!("dd {:?}", (dx,dy));
-                    self.camera.x -= (dx as f32) / self.camera.zoom_x;
-                    self.camera.y -= (dy as f32) / self.camera.zoom_y;
+                    if self.is_current_3d(ctx) {
+                        self.camera3d.pan(dx, dy);
+                    } else {
+                        self.camera.x -= (dx as f32) / self.camera.zoom_x;
+                        self.camera.y -= (dy as f32) / self.camera.zoom_y;
+                    }
+                    self.point_index_dirty = true;
+                    return true;
+                }
+
+                //Handle rotation
+                if self.current_tool == CurrentTool::Orbit && press_left {
+                    let dx = x - last_pos.0;
+                    let dy = y - last_pos.1;
+                    self.camera3d.orbit(dx*std::f32::consts::PI, dy*std::f32::consts::PI);
+                    self.point_index_dirty = true;
                     return true;
                 }
 
@@ -289,9 +377,15 @@ impl Component for ReductionView {
             // Message: Mouse wheel rotated
             MsgReduction::MouseWheel(dy) => {
                 let (cx,cy) = self.last_pos;
-                let (wx, wy) = self.camera.cam2world(cx, cy);
                 let scale = (10.0f32).powf(dy / 100.0);
-                self.camera.zoom_around(wx,wy, scale);
+                if self.is_current_3d(ctx) {
+                    self.camera3d.zoom_by(scale);
+                } else {
+                    let (wx, wy) = self.camera.cam2world(cx, cy);
+                    self.camera.zoom_around(wx,wy, scale);
+                }
+                self.point_index_dirty = true;
+                self.emit_camera(ctx);
                 true
             },
 
@@ -311,6 +405,9 @@ impl Component for ReductionView {
                 if t==CurrentTool::ZoomAll {
                     if let AsyncData::Loaded(reduction_data) = reduction_data {
                         self.camera.fit_reduction(reduction_data.as_ref());
+                        self.camera3d.fit_reduction(reduction_data.as_ref());
+                        self.point_index_dirty = true;
+                        self.emit_camera(ctx);
                     }
                 } else {
                     self.current_tool=t;
@@ -322,12 +419,11 @@ impl Component for ReductionView {
             // Message: A selection of a region has started using mouse
             MsgReduction::MouseStartSelect(cx,cy) => {
                 if self.current_tool==CurrentTool::Select {
-                    let (wx,wy) = self.camera.cam2world(cx as f32, cy as f32);
                     self.current_selection = Some(Rectangle2D {
-                        x1: wx,
-                        x2: wx,
-                        y1: wy,
-                        y2: wy
+                        x1: cx,
+                        x2: cx,
+                        y1: cy,
+                        y2: cy
                     });
                     //log::debug!("sel-start {:?}",self.current_selection);
                     true
@@ -339,10 +435,13 @@ impl Component for ReductionView {
             ////////////////////////////////////////////////////////////
             // Message: A selection of a region has ended using mouse
             MsgReduction::MouseEndSelect(cx,cy) => {
+                //Panning or rotation has ended
+                if self.current_tool==CurrentTool::Zoom || self.current_tool==CurrentTool::Orbit {
+                    self.emit_camera(ctx);
+                }
                 if let Some(rect) = &mut self.current_selection {
-                    let (wx,wy) = self.camera.cam2world(cx as f32, cy as f32);
-                    rect.x2=wx;
-                    rect.y2=wy;
+                    rect.x2=cx;
+                    rect.y2=cy;
 
                     let reduction_data = get_current_reduction_data(ctx);// = ctx.props().reductions.data.get(&ctx.props().current_reduction_name);
 
@@ -362,13 +461,18 @@ impl Component for ReductionView {
                             log::debug!("this is a rect select");
                             //log::debug!("wrect {} -- {}     {} -- {}", x1,x2,    y1,y2);
 
-                            //Scan all points to see if they are within the selection 
+                            //Scan all points to see if they are within the selection, as shown on screen
+                            let view = self.get_view_matrix(&reduction_data);
                             let mut selected_vert = Vec::new();
                             let num_points = reduction_data.num_point;
                             let vertices = &reduction_data.data;    
                             for i in 0..num_points {
-                                let px = *vertices.get(i*2+0).unwrap();
-                                let py = *vertices.get(i*2+1).unwrap();
+                                let (px, py, _depth) = project_point(
+                                    &view,
+                                    vertices[i*3+0],
+                                    vertices[i*3+1],
+                                    vertices[i*3+2],
+                                );
                                 //log::debug!("{} {}", px, py);
                                 if px>x1 && px<x2 && py>y1 && py<y2 { /////////////////////// TODO - invert y axis??   ////////////////// points halfway down are at y=500
                                     let point_name = i;
@@ -408,6 +512,10 @@ impl Component for ReductionView {
             //there is mouse movement! https://developer.mozilla.org/en-US/docs/Web/API/MouseEvent/movementX 
         });
         
+        let cb_mouseleave = ctx.link().callback(move |_e: MouseEvent | { 
+            MsgReduction::MouseLeave
+        });
+
         let cb_mousewheel = ctx.link().callback(move |e: WheelEvent | { 
             e.prevent_default();
             MsgReduction::MouseWheel(e.delta_y() as f32)
@@ -429,6 +537,10 @@ impl Component for ReductionView {
             MsgReduction::SelectCurrentTool(CurrentTool::ZoomAll)
         });
 
+        let cb_click_orbit = ctx.link().callback(move |_e: MouseEvent | { 
+            MsgReduction::SelectCurrentTool(CurrentTool::Orbit)
+        });
+
         let cb_onmousedown = ctx.link().callback(move |e: MouseEvent | { 
             e.prevent_default();
             let (x_cam, y_cam) = mouseevent_get_cx(&e);
@@ -444,12 +556,10 @@ impl Component for ReductionView {
         // Render box representing current selection
         let html_select = if let Some(rect) = &self.current_selection {
 
+            //Selection is already in camera coordinates, in range [-1,1]
             let (x1,x2) = rect.range_x();
             let (y1,y2) = rect.range_y();
 
-            let (x1,y1) = self.camera.world2cam(x1, y1); //camera is in range [-1,1]
-            let (x2,y2) = self.camera.world2cam(x2, y2);
-
             let canvas = self.node_ref.cast::<HtmlCanvasElement>().unwrap();
             let w = canvas.width() as f32;
             let h = canvas.height() as f32;
@@ -538,6 +648,7 @@ impl Component for ReductionView {
                         ref={self.node_ref.clone()} 
                         style="border:1px solid #000000;"
                         onmousemove={cb_mousemoved} 
+                        onmouseleave={cb_mouseleave}
                         onclick={cb_mouseclicked} 
                         onwheel={cb_mousewheel} 
                         onmousedown={cb_onmousedown} 
@@ -554,6 +665,9 @@ impl Component for ReductionView {
                     </svg>
                 </div>
 
+                //Details of the hovered cell
+                { self.view_hover_tooltip(ctx, canvas_w as f32, canvas_h as f32) }
+
                 // Button: Select
                 <div style={get_tool_style(canvas_w-40, self.current_tool==CurrentTool::Select)} onclick={cb_click_select}>
                     <svg data-icon="polygon-filter" height="16" role="img" viewBox="0 0 16 16" width="16"><path d="M14 5c-.24 0-.47.05-.68.13L9.97 2.34c.01-.11.03-.22.03-.34 0-1.1-.9-2-2-2S6 .9 6 2c0 .04.01.08.01.12L2.88 4.21C2.61 4.08 2.32 4 2 4 .9 4 0 4.9 0 6c0 .74.4 1.38 1 1.72v4.55c-.6.35-1 .99-1 1.73 0 1.1.9 2 2 2 .74 0 1.38-.4 1.72-1h4.55c.35.6.98 1 1.72 1 1.1 0 2-.9 2-2 0-.37-.11-.7-.28-1L14 9c1.11-.01 2-.9 2-2s-.9-2-2-2zm-4.01 7c-.73 0-1.37.41-1.71 1H3.73c-.18-.3-.43-.55-.73-.72V7.72c.6-.34 1-.98 1-1.72 0-.04-.01-.08-.01-.12l3.13-2.09c.27.13.56.21.88.21.24 0 .47-.05.68-.13l3.35 2.79c-.01.11-.03.22-.03.34 0 .37.11.7.28 1l-2.29 4z" fill-rule="evenodd"></path></svg>
@@ -569,6 +683,13 @@ impl Component for ReductionView {
                     <svg data-icon="zoom-in" height="16" width="16" xmlns="http://www.w3.org/2000/svg"><path style="fill:none;stroke:#000;stroke-width:2.01074px;stroke-linecap:butt;stroke-linejoin:miter;stroke-opacity:1" d="M14.733 8.764v5.973H9.586m-8.29-5.973v5.973h5.146m8.29-7.5V1.264H9.587m-8.29 5.973V1.264h5.146"/></svg>
                 </div>
 
+                // Button: Orbit, for 3D reductions
+                if self.is_current_3d(ctx) {
+                    <div style={get_tool_style(canvas_w-40-30-30-30, self.current_tool==CurrentTool::Orbit)} onclick={cb_click_orbit}>
+                        <svg data-icon="refresh" height="16" role="img" viewBox="0 0 16 16" width="16"><path d="M14.99 6.99c-.55 0-1 .45-1 1 0 3.31-2.69 6-6 6-1.77 0-3.36-.78-4.46-2h1.46c.55 0 1-.45 1-1s-.45-1-1-1h-4c-.55 0-1 .45-1 1v4c0 .55.45 1 1 1s1-.45 1-1v-1.74A7.95 7.95 0 007.99 15.99c4.42 0 8-3.58 8-8 0-.55-.45-1-1-1zm0-6c-.55 0-1 .45-1 1v1.74A7.95 7.95 0 007.99-.01c-4.42 0-8 3.58-8 8 0 .55.45 1 1 1s1-.45 1-1c0-3.31 2.69-6 6-6 1.77 0 3.36.78 4.46 2h-1.46c-.55 0-1 .45-1 1s.45 1 1 1h4c.55 0 1-.45 1-1v-4c0-.55-.45-1-1-1z" fill-rule="evenodd"></path></svg>
+                    </div>
+                }
+
                 // Select: Current reduction
                 <div style="position: absolute; left:20px; top:10px; display: flex; border-radius: 3px; padding: 5px;"> //border: 2px solid gray; 
                     {"Reduction: "}
@@ -586,6 +707,22 @@ impl Component for ReductionView {
 
 
 
+    ////////////////////////////////////////////////////////////
+    /// Called when properties change. Move the camera if asked to
+    fn changed(&mut self, ctx: &Context<Self>, old_props: &Self::Properties) -> bool {
+        if ctx.props().camera_goto != old_props.camera_goto {
+            self.apply_camera_goto(ctx);
+            self.point_index_dirty = true;
+        }
+
+        //Points move on screen if the canvas changes shape
+        if ctx.props().last_component_size != old_props.last_component_size {
+            self.point_index_dirty = true;
+        }
+        true
+    }
+
+
     ////////////////////////////////////////////////////////////
     /// Called after DOM has been created
     fn rendered(&mut self, ctx: &Context<Self>, _first_render: bool) {
@@ -594,9 +731,12 @@ impl Component for ReductionView {
 
         if let AsyncData::Loaded(datapoints) = &reduction_data {
 
-            //Fit camera whenever we get a new umap to show
+            //Fit camera whenever we get a new umap to show, unless asked to look elsewhere
             if self.last_reduction_data != reduction_data {
                 self.camera.fit_reduction(datapoints.as_ref());
+                self.camera3d.fit_reduction(datapoints.as_ref());
+                self.apply_camera_goto(ctx);
+                self.point_index_dirty = true;
             }
             self.last_reduction_data = reduction_data.clone();
 
@@ -638,10 +778,10 @@ impl Component for ReductionView {
             let vec_vertex_size = 6; //Size of vec3+vec3
             vec_vertex.reserve(num_points * vec_vertex_size);  
             for i in 0..num_points {
-                let input_base = i*2;
+                let input_base = i*3;
                 vec_vertex.push(*vertices.get(input_base+0).unwrap());
                 vec_vertex.push(*vertices.get(input_base+1).unwrap());
-                vec_vertex.push(0.0); // only used for 3d reductions
+                vec_vertex.push(*vertices.get(input_base+2).unwrap()); // only used for 3d reductions
 
                 vec_vertex.push(0.0); ///////////////////////////////////////////////// color index. remove, put in separate buffer
                 vec_vertex.push(0.0); ///////////////////////////////////////////////// color index. remove, put in separate buffer    filler for now
@@ -677,14 +817,72 @@ impl Component for ReductionView {
                     ///////// Color by numerical data - plain array
                     CountFileMetaColumnData::Numeric(vec_data) => {
 
-                        //Normalize color range. TODO should only need to do this once during loading
-                        let (_min_val, max_val) = make_safe_minmax(&vec_data);
+                        //Normalize color range. TODO should only need to do this once during loading.
+                        //The scale starts at 0 unless there are negative values, as for z-scores
+                        let (min_val, max_val) = make_safe_minmax(&vec_data);
+                        let min_val = min_val.min(0.0);
+                        let range = if max_val > min_val { max_val - min_val } else { 1.0 };
 
                         for (i,p) in vec_data.into_iter().enumerate() {
                             let base = vec_vertex_size*i;
-                            vec_vertex[base + 3] = p/max_val;
-                            vec_vertex[base + 4] = 0.0;
-                            vec_vertex[base + 5] = 0.0;
+                            if p.is_nan() {
+                                set_missing_color(&mut vec_vertex[base+3..base+6]);
+                            } else {
+                                vec_vertex[base + 3] = (p - min_val)/range;
+                                vec_vertex[base + 4] = 0.0;
+                                vec_vertex[base + 5] = 0.0;
+                            }
+                        }
+                    },
+
+                    ///////// Color by integers, as for numerical data
+                    CountFileMetaColumnData::Integer(vec_data) => {
+                        let max_val = vec_data.iter().filter_map(|x| *x).max().unwrap_or(0).max(1) as f32;
+                        for (i,p) in vec_data.iter().enumerate() {
+                            let base = vec_vertex_size*i;
+                            if let Some(p) = p {
+                                vec_vertex[base + 3] = (*p as f32)/max_val;
+                                vec_vertex[base + 4] = 0.0;
+                                vec_vertex[base + 5] = 0.0;
+                            } else {
+                                set_missing_color(&mut vec_vertex[base+3..base+6]);
+                            }
+                        }
+                    },
+
+                    ///////// Color by booleans, as two categories
+                    CountFileMetaColumnData::Boolean(vec_data) => {
+                        let palette = get_palette_for_categories(2);
+                        for (i,p) in vec_data.iter().enumerate() {
+                            let base = vec_vertex_size*i;
+                            if let Some(p) = p {
+                                let col = palette.get((*p as usize) % palette.len()).unwrap();
+                                vec_vertex[base + 3] = col.0;
+                                vec_vertex[base + 4] = col.1;
+                                vec_vertex[base + 5] = col.2;
+                            } else {
+                                set_missing_color(&mut vec_vertex[base+3..base+6]);
+                            }
+                        }
+                    },
+
+                    ///////// Color by text. Each distinct value is treated as a category, in sorted order
+                    CountFileMetaColumnData::Text(vec_data) => {
+                        let mut list_values = vec_data.iter().filter_map(|x| x.as_ref()).collect::<Vec<_>>();
+                        list_values.sort();
+                        list_values.dedup();
+                        let palette = get_palette_for_categories(list_values.len());
+                        for (i,p) in vec_data.iter().enumerate() {
+                            let base = vec_vertex_size*i;
+                            if let Some(p) = p {
+                                let level_i = list_values.binary_search(&p).unwrap_or(0);
+                                let col = palette.get(level_i % palette.len()).unwrap();
+                                vec_vertex[base + 3] = col.0;
+                                vec_vertex[base + 4] = col.1;
+                                vec_vertex[base + 5] = col.2;
+                            } else {
+                                set_missing_color(&mut vec_vertex[base+3..base+6]);
+                            }
                         }
                     },
 
@@ -774,15 +972,15 @@ impl Component for ReductionView {
                 sizeof_float * 3
             );
 
-            //Attach camera attributes
-            let u_camera_x = gl.get_uniform_location(&shader_program, "u_camera_x");
-            let u_camera_y = gl.get_uniform_location(&shader_program, "u_camera_y");
-            let u_camera_zoom_x = gl.get_uniform_location(&shader_program, "u_camera_zoom_x");
-            let u_camera_zoom_y = gl.get_uniform_location(&shader_program, "u_camera_zoom_y");
-            gl.uniform1f(u_camera_x.as_ref(), self.camera.x as f32);
-            gl.uniform1f(u_camera_y.as_ref(), self.camera.y as f32);
-            gl.uniform1f(u_camera_zoom_x.as_ref(), self.camera.zoom_x as f32);
-            gl.uniform1f(u_camera_zoom_y.as_ref(), self.camera.zoom_y as f32);
+            //Attach camera. The aspect ratio is needed for 3D, to rotate without distortion
+            let aspect = (canvas.width() as f32)/(canvas.height() as f32);
+            if aspect != self.camera3d.aspect {
+                self.camera3d.aspect = aspect;
+                self.point_index_dirty = true;
+            }
+            let view = self.get_view_matrix(datapoints);
+            let u_view = gl.get_uniform_location(&shader_program, "u_view");
+            gl.uniform_matrix4fv_with_f32_array(u_view.as_ref(), false, &view);
 
             //log::debug!("canvas {} {}   {:?}", canvas.width(), canvas.height(), self.camera);
 
@@ -791,9 +989,15 @@ impl Component for ReductionView {
             gl.uniform1f(u_display_w.as_ref(), canvas.width() as f32);
             gl.uniform1f(u_display_h.as_ref(), canvas.height() as f32);
 
-            // clear canvas
+            // clear canvas. Points in front hide those behind in 3D
             gl.clear_color(1.0, 1.0, 1.0, 1.0);
-            gl.clear(GL::COLOR_BUFFER_BIT);
+            if datapoints.is_3d() {
+                gl.enable(GL::DEPTH_TEST);
+                gl.clear(GL::COLOR_BUFFER_BIT | GL::DEPTH_BUFFER_BIT);
+            } else {
+                gl.disable(GL::DEPTH_TEST);
+                gl.clear(GL::COLOR_BUFFER_BIT);
+            }
             
             // to make round points, need to draw square https://stackoverflow.com/questions/7237086/opengl-es-2-0-equivalent-for-es-1-0-circles-using-gl-point-smooth
             gl.draw_arrays(GL::POINTS, 0, num_points as i32);
@@ -805,6 +1009,120 @@ impl Component for ReductionView {
 
 
 
+impl ReductionView {
+
+    ////////////////////////////////////////////////////////////
+    /// Tooltip with the name of the hovered cell and its values for the loaded metadata.
+    /// Placed next to the mouse, on the side with the most space
+    fn view_hover_tooltip(&self, ctx: &Context<Self>, canvas_w: f32, canvas_h: f32) -> Html {
+        let cell = if let Some(cell) = self.last_cell {
+            cell
+        } else {
+            return html! {""};
+        };
+
+        let barcode = if let AsyncData::Loaded(barcodes) = &ctx.props().barcodes {
+            barcodes.barcodes.get(cell).cloned()
+        } else {
+            None
+        };
+        let barcode = barcode.unwrap_or_else(|| format!("Cell #{}", cell));
+
+        //Value used for coloring goes first, then other loaded metadata columns
+        let current_colorby = &ctx.props().current_colorby;
+        let mut list_values = Vec::new();
+        if let AsyncData::Loaded(data) = get_umap_coloring(ctx) {
+            list_values.push((current_colorby.to_string(), data.get_label(cell)));
+        }
+        let mut list_other = ctx.props().metadatas.data.metadatas.iter()
+            .filter(|(k, _v)| *k != current_colorby && matches!(k, PerCellDataSource::Metadata(_)))
+            .filter_map(|(k, v)| {
+                if let AsyncData::Loaded(data) = v {
+                    Some((k.to_string(), data.get_label(cell)))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        list_other.sort_by(|a, b| a.0.cmp(&b.0));
+        list_values.extend(list_other);
+        list_values.truncate(TOOLTIP_MAX_VALUES);
+
+        let list_values_html = list_values.iter().map(|(name, value)| {
+            let value = value.clone().unwrap_or("(missing)".to_string());
+            html! {
+                <div>
+                    <b>{name.clone()}{": "}</b>{value}
+                </div>
+            }
+        }).collect::<Html>();
+
+        //Mouse position, from camera coordinates
+        let x = (self.last_pos.0 + 1.0) / 2.0 * canvas_w;
+        let y = (self.last_pos.1 + 1.0) / 2.0 * canvas_h;
+        let pos_x = if x < canvas_w / 2.0 {
+            format!("left: {}px;", x + TOOLTIP_OFFSET)
+        } else {
+            format!("right: {}px;", canvas_w - x + TOOLTIP_OFFSET)
+        };
+        let pos_y = if y < canvas_h / 2.0 {
+            format!("top: {}px;", y + TOOLTIP_OFFSET)
+        } else {
+            format!("bottom: {}px;", canvas_h - y + TOOLTIP_OFFSET)
+        };
+
+        html! {
+            <div class="biscvi-hover-tooltip" style={format!("{} {}", pos_x, pos_y)}>
+                <div><b>{barcode}</b></div>
+                { list_values_html }
+            </div>
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the transform from world to screen, for the given reduction
+    fn get_view_matrix(&self, reduction_data: &ReductionViewData) -> ViewMatrix {
+        if reduction_data.is_3d() {
+            self.camera3d.view_matrix()
+        } else {
+            self.camera.view_matrix()
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check if the current reduction is shown in 3D
+    fn is_current_3d(&self, ctx: &Context<Self>) -> bool {
+        if let AsyncData::Loaded(reduction_data) = get_current_reduction_data(ctx) {
+            reduction_data.is_3d()
+        } else {
+            false
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Tell the component above where the camera looks, after it moved
+    fn emit_camera(&self, ctx: &Context<Self>) {
+        let cam = if self.is_current_3d(ctx) {
+            ViewCamera::from_camera3d(&self.camera3d)
+        } else {
+            ViewCamera::from_camera2d(&self.camera)
+        };
+        ctx.props().on_camera_changed.emit(cam);
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Move the camera as asked by the component above, if meant for the reduction shown
+    fn apply_camera_goto(&mut self, ctx: &Context<Self>) {
+        if let Some((reduction_name, cam)) = &ctx.props().camera_goto {
+            if ctx.props().current_reduction_name.as_ref() == Some(reduction_name) {
+                cam.apply(&mut self.camera, &mut self.camera3d);
+            }
+        }
+    }
+}
+
+
+
 ////////////////////////////////////////////////////////////
 /// Convert from vector to HTML color code
 pub fn rgbvec2string(c: Vec3) -> String {
@@ -912,6 +1230,14 @@ fn get_umap_coloring(ctx: &Context<ReductionView>) -> AsyncData<CountFileMetaCol
     ctx.props().metadatas.data.get(&current_colorby)
 }
 
+////////////////////////////////////////////////////////////
+/// Color of cells with missing values
+fn set_missing_color(col: &mut [f32]) {
+    col[0] = MISSING_COLOR.0;
+    col[1] = MISSING_COLOR.1;
+    col[2] = MISSING_COLOR.2;
+}
+
 ////////////////////////////////////////////////////////////
 /// Get the current reduction data
 fn get_current_reduction_data(ctx: &Context<ReductionView>) -> AsyncData<ReductionViewData> {
@@ -1,10 +1,16 @@
 use crate::{appstate::{PerCellDataSource}, core_model::*};
 
+use my_web_app::DiffExpRequest;
 use yew::{prelude::*};
 
 use super::ReductionView;
 use super::MetadataView;
 use super::FeatureView;
+use super::DiffExpView;
+use super::CellInspectorView;
+use super::ExportView;
+use super::AnnotateView;
+use super::redview_camera::ViewCamera;
 
 
 impl Model {
@@ -18,8 +24,33 @@ impl Model {
         let on_cell_hovered = Callback::from(move |_name: Option<usize>| {
         });
 
-        //Callback: Clicked on a cell
-        let on_cell_clicked = Callback::from(move |_name: Vec<usize>| {
+        //Callback: Clicked on a cell, or selected a region. A single cell is also shown in the inspector
+        let on_cell_clicked = ctx.link().batch_callback(move |cells: Vec<usize>| {
+            if cells.len() == 1 {
+                vec![MsgCore::RequestCellProfile(cells[0]), MsgCore::SetCellSelection(cells)]
+            } else {
+                vec![MsgCore::SetCellSelection(cells)]
+            }
+        });
+
+        //Callback: close the cell inspector
+        let on_closeinspector = ctx.link().callback(move |_: ()| {
+            MsgCore::CloseCellProfile
+        });
+
+        //Callback: expand or collapse a metadata column
+        let on_toggleexpand = ctx.link().callback(move |name: String| {
+            MsgCore::ToggleMetaExpanded(name)
+        });
+
+        //Callback: select cells by metadata value
+        let on_selectcells = ctx.link().callback(move |cells: Vec<usize>| {
+            MsgCore::SetCellSelection(cells)
+        });
+
+        //Callback: compute differential expression
+        let on_diffexp = ctx.link().callback(move |query: DiffExpRequest| {
+            MsgCore::RequestDiffExp(query)
         });
 
         //Callback: coloring by something
@@ -27,6 +58,51 @@ impl Model {
             MsgCore::RequestSetColorByMeta(name)  // UmapColoring instead?
         });
 
+        //Callback: add a feature to the panel
+        let on_openfeature = ctx.link().callback(move |name: PerCellDataSource| {
+            MsgCore::OpenFeature(name)
+        });
+
+        //Callback: the camera of the reduction has moved
+        let on_camera_changed = ctx.link().callback(move |cam: ViewCamera| {
+            MsgCore::SetReductionCamera(cam)
+        });
+
+        //Callback: check a pasted list of features, and load the known ones in one go
+        let on_lookupfeatures= ctx.link().callback(move |(counts_name, feature_names): (String, Vec<String>)| {
+            MsgCore::RequestFeatureLookup(counts_name, feature_names)
+        });
+
+        //Callback: show a feature in the genome browser
+        let on_showlocus = ctx.link().callback(move |(counts_name, feature_name): (String, String)| {
+            MsgCore::RequestFeatureLocus(counts_name, feature_name)
+        });
+
+        //Callback: change normalization of feature counts
+        let on_setnormalization = ctx.link().callback(move |normalization| {
+            MsgCore::SetNormalization(normalization)
+        });
+
+        //Callback: search features by name
+        let on_searchfeatures = ctx.link().callback(move |query: String| {
+            MsgCore::RequestFeatureSearch(query)
+        });
+
+        //Callback: get marker features of a metadata column
+        let on_requestmarkers= ctx.link().callback(move |(counts_name, column_name): (String, String)| {
+            MsgCore::RequestMarkers(counts_name, column_name)
+        });
+
+        //Callback: write the selected cells as a new dataset
+        let on_subsetexport = ctx.link().callback(move |format| {
+            MsgCore::RequestSubsetExport(format)
+        });
+
+        //Callback: change a column made by users
+        let on_editannotation = ctx.link().callback(move |edit| {
+            MsgCore::RequestEditAnnotation(edit)
+        });
+
         //Callback: send message to component above
         let on_propagate= ctx.link().callback(move |sig: MsgCore| {
             log::debug!("propagate {:?}", sig);
@@ -46,17 +122,63 @@ impl Model {
                         metadatas={self.metadatas.clone()}
                         current_datadesc={self.current_datadesc.clone()}
                         current_reduction_name={self.current_reduction.clone()}
+                        barcodes={self.barcodes.clone()}
+                        camera_goto={self.camera_goto.clone()}
+                        on_camera_changed={on_camera_changed}
+                    />
+                    <DiffExpView
+                        current_datadesc={self.current_datadesc.clone()}
+                        cell_selection={self.cell_selection.clone()}
+                        diffexp={self.diffexp.clone()}
+                        on_diffexp={on_diffexp}
+                        on_colorbyfeature={on_colorbymeta.clone()}
+                    />
+                    <ExportView
+                        dataset_name={self.get_current_dataset()}
+                        current_datadesc={self.current_datadesc.clone()}
+                        cell_selection={self.cell_selection.clone()}
+                        metadatas={self.metadatas.clone()}
+                        normalization={self.normalization}
+                        subset_job={self.subset_job.clone()}
+                        on_subsetexport={on_subsetexport}
+                    />
+                    <AnnotateView
+                        dataset_name={self.get_current_dataset()}
+                        current_datadesc={self.current_datadesc.clone()}
+                        cell_selection={self.cell_selection.clone()}
+                        on_editannotation={on_editannotation}
+                        on_colorbymeta={on_colorbymeta.clone()}
+                    />
+                    <CellInspectorView
+                        current_dataset={self.current_dataset.clone()}
+                        cell_profile={self.cell_profile.clone()}
+                        on_close={on_closeinspector}
+                        on_colorbyfeature={on_colorbymeta.clone()}
                     />
                 </div>
                 <MetadataView 
                     current_datadesc={self.current_datadesc.clone()} 
                     on_colorbymeta={on_colorbymeta.clone()}
                     current_colorby={self.current_colorby.clone()}
+                    markers={self.markers.clone()}
+                    on_requestmarkers={on_requestmarkers}
+                    metadatas={self.metadatas.clone()}
+                    on_selectcells={on_selectcells}
+                    expanded_meta={self.expanded_meta.clone()}
+                    on_toggleexpand={on_toggleexpand}
                 />
                 <FeatureView
                     metadatas={self.metadatas.clone()}
                     current_datadesc={self.current_datadesc.clone()}
                     on_colorbyfeature={on_colorbymeta}  //expand, not just meta?
+                    on_lookupfeatures={on_lookupfeatures}
+                    on_showlocus={on_showlocus}
+                    open_features={self.open_features.clone()}
+                    on_openfeature={on_openfeature}
+                    on_setnormalization={on_setnormalization}
+                    normalization={self.normalization}
+                    on_searchfeatures={on_searchfeatures}
+                    feature_search={self.feature_search.clone()}
                     current_colorby={self.current_colorby.clone()}
                     //current_data={self.current_data.clone()}
                 />
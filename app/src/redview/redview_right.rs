@@ -1,7 +1,9 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
 
+use my_web_app::CountNormalization;
 use my_web_app::DatasetDescResponse;
+use my_web_app::FeatureSearchResponse;
 use wasm_bindgen::JsCast;
 use web_sys::HtmlSelectElement;
 use web_sys::{EventTarget, HtmlInputElement};
@@ -11,16 +13,20 @@ use yew::{Callback, Component, Context, Html, KeyboardEvent, MouseEvent, NodeRef
 use yew::Properties;
 
 use crate::appstate::{AsyncData, BiscviCache, MetadataData, PerCellDataSource};
-use crate::histogram::FeatureHistogram;
+use crate::histogram::{make_histogram_svg, FeatureHistogram};
 
 ////////////////////////////////////////////////////////////
 /// Message sent to the event system for updating the page
 #[derive(Debug)]
 pub enum MsgFeature {
     SetColorBy(PerCellDataSource),
+    ShowInGenomeBrowser(String, String),
 //    ToggleExpand(String)
     FeatureSearchChange(String, bool),
     SetLastCountName(String),
+    AddFeatureList(String),
+    SetNormalization(CountNormalization),
+    OpenFeature(PerCellDataSource),
     //FeatureSearchMatChange(String),
 }
 
@@ -31,6 +37,14 @@ pub enum MsgFeature {
 pub struct Props {
     pub current_datadesc: AsyncData<DatasetDescResponse>,
     pub on_colorbyfeature: Callback<PerCellDataSource>,
+    pub on_lookupfeatures: Callback<(String, Vec<String>)>,
+    pub on_showlocus: Callback<(String, String)>,
+    pub open_features: Vec<PerCellDataSource>,
+    pub on_openfeature: Callback<PerCellDataSource>,
+    pub on_setnormalization: Callback<CountNormalization>,
+    pub normalization: CountNormalization,
+    pub on_searchfeatures: Callback<String>,
+    pub feature_search: AsyncData<FeatureSearchResponse>,
 
     pub current_colorby: PerCellDataSource,
     //pub current_data: Arc<Mutex<BiscviData>>,
@@ -47,8 +61,6 @@ pub struct FeatureView {
     pub expanded_meta: HashSet<String>,
     pub selected_meta: HashSet<String>,
 
-    pub open_features: Vec<PerCellDataSource>,
-
     //Search feature state
     pub last_search_feature_input: String,
     pub last_search_feature_mat: String,
@@ -67,7 +79,6 @@ impl Component for FeatureView {
             node_ref: NodeRef::default(),
             expanded_meta: HashSet::new(),
             selected_meta: HashSet::new(),
-            open_features: Vec::new(),
             last_search_feature_input: String::new(),
             last_search_feature_mat: String::new(),
             histograms: Mutex::new(HashMap::new()),
@@ -87,6 +98,12 @@ impl Component for FeatureView {
                 true
             },
 
+            //////// Show where a feature is in the genome browser
+            MsgFeature::ShowInGenomeBrowser(count_name, feature_name) => {
+                ctx.props().on_showlocus.emit((count_name, feature_name));
+                false
+            },
+
             //////// Key pressed in search feature input
             MsgFeature::FeatureSearchChange(value, is_enter) => {
                 self.last_search_feature_input = value.clone();
@@ -94,14 +111,48 @@ impl Component for FeatureView {
                 if is_enter {
                     self.last_search_feature_input = String::new();
                     //Check that the feature is not already there, or empty
-                    if !self.open_features.contains(&feature_name) && value != "" {
-                        self.open_features.push(feature_name.clone());
+                    if !ctx.props().open_features.contains(&feature_name) && value != "" {
+                        ctx.props().on_openfeature.emit(feature_name.clone());
                         ctx.props().on_colorbyfeature.emit(feature_name); // Color by this feature right away
                     }
                 }
+                ctx.props().on_searchfeatures.emit(self.last_search_feature_input.clone());
+                true
+            },
+
+            //////// A search hit has been picked. It may be in any count matrix
+            MsgFeature::OpenFeature(feature_name) => {
+                self.last_search_feature_input = String::new();
+                ctx.props().on_searchfeatures.emit(String::new());
+                if !ctx.props().open_features.contains(&feature_name) {
+                    ctx.props().on_openfeature.emit(feature_name.clone());
+                }
+                ctx.props().on_colorbyfeature.emit(feature_name);
                 true
             },
 
+            //////// A list of features pasted. The server checks which are known, and their counts are then loaded in one request
+            MsgFeature::AddFeatureList(value) => {
+                let mut list_new = Vec::new();
+                for name in value.split(|c: char| c.is_whitespace() || c==',' || c==';') {
+                    let name = name.trim().to_string();
+                    let feature_name = PerCellDataSource::Counts(self.last_search_feature_mat.clone(), name.clone());
+                    if !name.is_empty() && !ctx.props().open_features.contains(&feature_name) && !list_new.contains(&name) {
+                        list_new.push(name);
+                    }
+                }
+                if !list_new.is_empty() {
+                    ctx.props().on_lookupfeatures.emit((self.last_search_feature_mat.clone(), list_new));
+                }
+                false
+            },
+
+            //////// Change how counts are normalized
+            MsgFeature::SetNormalization(normalization) => {
+                ctx.props().on_setnormalization.emit(normalization);
+                false
+            },
+
             //////// Component updated, and a list of count tables is now present. UI has already been updated
             MsgFeature::SetLastCountName(countname) => {
                 self.last_search_feature_mat = countname;
@@ -136,11 +187,11 @@ impl Component for FeatureView {
 
 
         //log::debug!("open features");
-        //log::debug!("{:?}", self.open_features);
+        //log::debug!("{:?}", ctx.props().open_features);
 
         //Create controls for all open features
         let mut list_features:Vec<Html> = Vec::new();
-        for f in &self.open_features {
+        for f in &ctx.props().open_features {
             match f {
                 PerCellDataSource::Counts(count_name, feature_name) => {
                     let one_gene = self.make_one_feature(ctx, count_name, feature_name);
@@ -205,48 +256,79 @@ impl Component for FeatureView {
             MsgFeature::FeatureSearchChange(cur_value, is_enter)
         });
 
-        //Create autocomplete list for search -- get relevant features
-        let mut list_autocomplete_red = Vec::new();
-        //log::debug!("self.last_search_feature_input {}", self.last_search_feature_input);
-        if !self.last_search_feature_input.is_empty() {
-            if let AsyncData::Loaded(current_datadesc) = &ctx.props().current_datadesc {
-                let mat = current_datadesc.matrices.get(&self.last_search_feature_mat);
-                if let Some(mat) = mat {
-                    let last_search_feature_input = self.last_search_feature_input.to_lowercase();  //// TODO: need to store feature name too
-                    for item in &mat.list_feature_names {
-                        let item_lower = item.to_lowercase();
-                        if item_lower.starts_with(&last_search_feature_input) {  // TODO: for speed, it would make sense to have features sorted in alphabetic order and do some type of binary search for the start position in list
-                            list_autocomplete_red.push(item.clone());
-                        }
-                    }
-                } else {
-                    log::debug!("No count table found for {}", self.last_search_feature_mat);
-                }
+        //Callback for pasting a list of features
+        let input_list_onkeyup = ctx.link().batch_callback(move |e: KeyboardEvent | { 
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlInputElement = target.and_then(|t| t.dyn_into::<HtmlInputElement>().ok()).expect("wrong type");
+            let is_enter = e.key() == "Enter" || e.key_code() == 13;
+            if is_enter {
+                let cur_value = input.value();
+                input.set_value(""); 
+                e.prevent_default();
+                Some(MsgFeature::AddFeatureList(cur_value))
+            } else {
+                None
             }
-        }
-        //Create autocomplete list for search -- make html
-        let mut list_autocomplete_html = Vec::new();
-        for t in &list_autocomplete_red {
-
-            let t_copy = t.clone();
-            let cb_onclick = ctx.link().callback(move |e: MouseEvent | { 
-                let window = web_sys::window().unwrap();
-                let document = window.document().unwrap();
+        });
 
-                //Empty the search input
-                let target = document.get_element_by_id("search-feature-input");
-                let input: HtmlInputElement = target.and_then(|t| t.dyn_into::<HtmlInputElement>().ok()).expect("wrong type");
-                input.set_value("");
+        //Generate SELECT for normalization modes
+        let list_normalization_html = CountNormalization::all().into_iter().map(|t| html! {
+            <option value={t.name()} selected={ctx.props().normalization==t}>
+                {t.name()}
+            </option>
+        }).collect::<Vec<_>>();
 
-                e.prevent_default();
-                MsgFeature::FeatureSearchChange(t_copy.clone(), true)
-            });
+        //Callback: change of normalization
+        let cb_change_normalization = ctx.link().batch_callback(move |e: Event | { 
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlSelectElement = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok()).expect("wrong type");
+            e.prevent_default();
+            let t = input.value();
+            CountNormalization::all().into_iter().find(|x| x.name() == t).map(MsgFeature::SetNormalization)
+        });
 
-            list_autocomplete_html.push(html! {
-                <div onclick={cb_onclick}>
-                    {t}
-                </div>
-            });
+        //Create autocomplete list for search. Hits come from the server, for the last query sent
+        let mut list_autocomplete_html = Vec::new();
+        if !self.last_search_feature_input.is_empty() {
+            if let AsyncData::Loaded(feature_search) = &ctx.props().feature_search {
+                for hit in &feature_search.hits {
+
+                    //Where the name was found
+                    let text_source = if let Some(gff) = &hit.gff {
+                        format!("{} {}:{}-{}", gff.attribute, gff.seqid, gff.start, gff.end)
+                    } else {
+                        hit.counts_name.clone().unwrap_or_default()
+                    };
+
+                    //Only features in a count matrix can be opened
+                    if let Some(counts_name) = &hit.counts_name {
+                        let id = PerCellDataSource::Counts(counts_name.clone(), hit.feature_name.clone());
+                        let cb_onclick = ctx.link().callback(move |e: MouseEvent | { 
+                            let window = web_sys::window().unwrap();
+                            let document = window.document().unwrap();
+
+                            //Empty the search input
+                            let target = document.get_element_by_id("search-feature-input");
+                            let input: HtmlInputElement = target.and_then(|t| t.dyn_into::<HtmlInputElement>().ok()).expect("wrong type");
+                            input.set_value("");
+
+                            e.prevent_default();
+                            MsgFeature::OpenFeature(id.clone())
+                        });
+                        list_autocomplete_html.push(html! {
+                            <div onclick={cb_onclick}>
+                                {hit.feature_name.clone()} <span style="color: gray;">{format!(" {}", text_source)}</span>
+                            </div>
+                        });
+                    } else {
+                        list_autocomplete_html.push(html! {
+                            <div style="color: gray;">
+                                {hit.feature_name.clone()} {format!(" {}", text_source)}
+                            </div>
+                        });
+                    }
+                }
+            }
         }
 
         //Compose the view
@@ -267,7 +349,15 @@ impl Component for FeatureView {
                             {svg_search}
                         </span>
                     </div>
-
+                    <div>
+                        <input type="text" autocomplete="off" placeholder="Paste list of features" onkeyup={input_list_onkeyup}/>
+                    </div>
+                    <div>
+                        {"Normalization: "}
+                        <select onchange={cb_change_normalization}>
+                            {list_normalization_html}
+                        </select>
+                    </div>
                 </div>
                 <div>
                     {list_features}                
@@ -278,6 +368,15 @@ impl Component for FeatureView {
 
 
 
+    ////////////////////////////////////////////////////////////
+    /// Called when properties change. Histograms are of normalized counts, so are redone if the mode changes
+    fn changed(&mut self, ctx: &Context<Self>, old_props: &Self::Properties) -> bool {
+        if ctx.props().normalization != old_props.normalization {
+            self.histograms.lock().unwrap().clear();
+        }
+        true
+    }
+
     ////////////////////////////////////////////////////////////
     /// Called after component has been rendered
     fn rendered(&mut self, _ctx: &Context<Self>, _first_render: bool) {
@@ -320,8 +419,22 @@ impl FeatureView {
             </svg>
         };
 
+        //SVG: icon to show the locus in the genome browser
+        let svg_locus = html! {
+            <svg data-icon="map-marker" height="10" role="img" viewBox="0 0 16 16" width="10">
+                <path d="M8 0C4.69 0 2 2.69 2 6c0 5 6 10 6 10s6-5 6-10c0-3.31-2.69-6-6-6zm0 8.5c-1.38 0-2.5-1.12-2.5-2.5S6.62 3.5 8 3.5s2.5 1.12 2.5 2.5S9.38 8.5 8 8.5z" fill-rule="evenodd"></path>
+            </svg>
+        };
+
         let histo_svg = self.make_histogram(ctx, count_name, feature_name);
 
+        //Callback to show this feature in the genome browser
+        let count_name_copy = count_name.clone();
+        let feature_name_copy = feature_name.clone();
+        let cb_show_locus = ctx.link().callback(move |_e: MouseEvent | { 
+            MsgFeature::ShowInGenomeBrowser(count_name_copy.clone(), feature_name_copy.clone())
+        });
+
 
         //Callback to color by this column
         let combo_feature_copy= combo_feature.clone();
@@ -380,6 +493,11 @@ impl FeatureView {
                                 </span>
                             </button>
                         </div>
+                        <button type="button" onclick={cb_show_locus} style="margin-right: 2px;" title="Show in genome browser">
+                            <span aria-hidden="true">
+                                {svg_locus}
+                            </span>
+                        </button>
                         <button type="button" onclick={cb_color_by} style={style_colorby_button}> // class="bp5-button bp5-active bp5-minimal bp5-small bp5-intent-primary"
                             <span aria-hidden="true"> //  class="bp5-icon bp5-icon-tint"
                                 {svg_colorby}
@@ -397,38 +515,10 @@ impl FeatureView {
 
 
 
-    fn make_histogram_bins(&self, _ctx: &Context<Self>, h: &FeatureHistogram, list_bins_html: &mut Vec<VNode>, hist_height: f32, hist_width: f32) {
-        //log::debug!("made hist {:?}",h);
-        if let FeatureHistogram::ContinuousFeatureHistogram(h) = h {
-            //let max_count = h.max_count as f64;
-            let scale_y = (hist_height)/(h.max_count as f32);
-            let scale_x = hist_width/(h.max as f32);
-            let bin_width = hist_width/(h.bin.len() as f32);
-
-            for (bin,cnt) in h.bin.iter().zip(h.count.iter()) {
-                let h = (*cnt as f32) * scale_y;
-                let x = (*bin as f32)*scale_x;
-                list_bins_html.push(
-                    html! {
-                        <rect 
-                            x={x.to_string()} 
-                            y={(hist_height-h).to_string()}
-                            width={bin_width.to_string()}
-                            height={h.to_string()} 
-                            style="fill: rgb(0, 0, 0);"  
-                        />
-                    }
-                );
-            }
-        }
-    }
-
     ////////////////////////////////////////////////////////////
     /// Render the histogram for one feature
     fn make_histogram(&self, ctx: &Context<Self>, count_name: &String, feature_name: &String) -> VNode {
 
-        let mut list_bins_html = Vec::new();
-
         let hist_height=15.0f32;
         let hist_width=150.0f32; //fit resizing component to get this?
 
@@ -437,25 +527,13 @@ impl FeatureView {
 
             //Render and cache histogram
             let mut histo_cache = self.histograms.lock().unwrap();
-
-            let h = histo_cache.get(&id);
-            if let Some(h) = h {
-                self.make_histogram_bins(ctx, h, &mut list_bins_html, hist_height, hist_width);
-            } else {
-                let h = FeatureHistogram::build(x.as_ref());
-                self.make_histogram_bins(ctx, &h, &mut list_bins_html, hist_height, hist_width);
-                histo_cache.insert(id.clone(), h);
+            let h = histo_cache.entry(id.clone()).or_insert_with(|| FeatureHistogram::build(x.as_ref()));
+            make_histogram_svg(h, hist_width, hist_height)
+        } else {
+            html! {
+                <svg width={hist_width.to_string()} height={hist_height.to_string()} style="display: block;">  
+                </svg>
             }
-            
-        }
-        // "fill: rgb(175, 240, 91);"
-        // log::debug!("hist html {:?}",list_bins_html);
-
-
-        html! {
-            <svg width={hist_width.to_string()} height={hist_height.to_string()} style="display: block;">  
-                {list_bins_html}
-            </svg>
         }
     }
 }
@@ -1,7 +1,7 @@
 
 precision mediump float;
 
-attribute vec2 a_position;
+attribute vec3 a_position;
 attribute vec3 a_color;
 
 
@@ -9,11 +9,8 @@ varying highp vec3 color;
 ///// attribute vec3 color;
 
 
-uniform float u_camera_x;
-uniform float u_camera_y;
-
-uniform float u_camera_zoom_x;
-uniform float u_camera_zoom_y;
+// Transform from world to clip coordinates. Handles both 2D and 3D cameras
+uniform mat4 u_view;
 
 uniform float u_display_w;
 uniform float u_display_h;
@@ -21,15 +18,8 @@ uniform float u_display_h;
 
 void main() {
 
-    //Transform from world coordinates to [-1,1] camera coordinates
-    vec2 a_cam_pos = vec2(u_camera_x, u_camera_y);
-    vec2 a_position2 = vec2(a_position.x, a_position.y);
-    vec2 a_position3 = a_position2 - a_cam_pos;
-
-    vec2 u_camera_zoom = vec2(u_camera_zoom_x, u_camera_zoom_y);
-    vec2 scaled = a_position3 * u_camera_zoom;
-
-    gl_Position = vec4(scaled.x, -scaled.y, 0.0, 1.0);   // Invert camera y to match 
+    //Transform from world coordinates to [-1,1] camera coordinates. Camera y is inverted by the matrix
+    gl_Position = u_view * vec4(a_position, 1.0);
 
     //Set size of points
     gl_PointSize = 2.0;
@@ -0,0 +1,78 @@
+use wasm_bindgen::JsCast;
+use wasm_bindgen::JsValue;
+use web_sys::window;
+use web_sys::{EventTarget, HtmlSelectElement};
+use yew::prelude::*;
+
+use crate::appstate::AsyncData;
+use crate::core_model::*;
+
+impl Model {
+
+    ////////////////////////////////////////////////////////////
+    /// Render the menu of sessions saved for this dataset, for the top bar
+    pub fn view_session_menu(&self, ctx: &Context<Self>) -> Html {
+
+        //Callback: load the picked session
+        let cb_load_session = ctx.link().callback(move |e: Event | {
+            let target: Option<EventTarget> = e.target();
+            let input: HtmlSelectElement = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok()).expect("wrong type");
+            e.prevent_default();
+            MsgCore::RequestLoadSession(input.value())
+        });
+
+        //Callback: save under a name. The session last saved or loaded is suggested
+        let current_session = self.current_session.clone().unwrap_or_default();
+        let cb_save_session = ctx.link().batch_callback(move |_e: MouseEvent | {
+            let window = window().expect("no window");
+            if let Ok(Some(session_name)) = window.prompt_with_message_and_default("Save session as", &current_session) {
+                let session_name = session_name.trim().to_string();
+                if !session_name.is_empty() {
+                    return Some(MsgCore::RequestSaveSession(session_name));
+                }
+            }
+            None
+        });
+
+        //Callback: delete the current session
+        let current_session = self.current_session.clone();
+        let cb_delete_session = ctx.link().batch_callback(move |_e: MouseEvent | {
+            let session_name = current_session.clone()?;
+            let window = window().expect("no window");
+            if window.confirm_with_message(&format!("Delete session {}?", session_name)).unwrap_or(false) {
+                Some(MsgCore::RequestDeleteSession(session_name))
+            } else {
+                None
+            }
+        });
+
+        //List of sessions to pick from, with when they were saved
+        let mut list_sessions_html = Vec::new();
+        if let AsyncData::Loaded(sessions) = &self.sessions {
+            for session in &sessions.sessions {
+                let is_selected = self.current_session.as_ref() == Some(&session.session_name);
+                let saved_at = js_sys::Date::new(&JsValue::from_f64(session.saved_at as f64 * 1000.0));
+                let saved_at: String = saved_at.to_locale_string("default", &JsValue::UNDEFINED).into();
+                list_sessions_html.push(html! {
+                    <option value={session.session_name.clone()} selected={is_selected} title={format!("Saved {}", saved_at)}>
+                        {session.session_name.clone()}
+                    </option>
+                });
+            }
+        }
+
+        html! {
+            <div style="float: left; padding: 18px 10px;">
+                {"Session: "}
+                <select onchange={cb_load_session}>
+                    <option value="" disabled=true selected={self.current_session.is_none()}>{"Load..."}</option>
+                    {list_sessions_html}
+                </select>
+                {" "}
+                <button onclick={cb_save_session}>{"Save..."}</button>
+                <button onclick={cb_delete_session} disabled={self.current_session.is_none()}>{"Delete"}</button>
+            </div>
+        }
+    }
+
+}
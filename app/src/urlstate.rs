@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+
+use my_web_app::gbrowser_struct::GBrowserLocus;
+use web_sys::window;
+
+use crate::appstate::PerCellDataSource;
+use crate::core_model::CurrentPage;
+use crate::redview::redview_camera::ViewCamera;
+
+
+/// Longest list of selected cells kept in the URL, in characters. Larger selections are left out
+const MAX_URL_SELECTION_LEN: usize = 4000;
+
+/// Most cells that can be selected by a URL. Guards against absurd ranges
+const MAX_URL_CELLS: usize = 10_000_000;
+
+
+////////////////////////////////////////////////////////////
+/// Everything needed to show a view again. This is kept in the fragment of the URL, so that
+/// the view can be shared, and so that the browser can go back and forth between views
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ViewState {
+    pub page: Option<CurrentPage>,
+    pub dataset: Option<String>,
+    pub reduction: Option<String>,
+    pub colorby: Option<PerCellDataSource>,
+    pub camera: Option<ViewCamera>,
+    pub features: Vec<PerCellDataSource>,
+    pub locus: Option<GBrowserLocus>,
+    pub cells: Option<Vec<usize>>,
+}
+impl ViewState {
+
+    ////////////////////////////////////////////////////////////
+    /// Check if going from another view to this one is navigation, which adds an entry to the browser history.
+    /// Other changes, such as moving the camera or filling in what was not yet picked, replace the current entry
+    pub fn is_navigation_from(&self, other: &ViewState) -> bool {
+        fn changed<T: PartialEq>(from: &Option<T>, to: &Option<T>) -> bool {
+            from.is_some() && from != to
+        }
+        changed(&other.page, &self.page) ||
+            changed(&other.dataset, &self.dataset) ||
+            changed(&other.reduction, &self.reduction) ||
+            changed(&other.colorby, &self.colorby)
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Format as the fragment of a URL, starting with #. Names are percent-encoded, so , ; & and = can separate values
+    pub fn to_url_hash(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(page) = &self.page {
+            parts.push(format!("page={}", page.url_name()));
+        }
+        if let Some(dataset) = &self.dataset {
+            parts.push(format!("dataset={}", encode(dataset)));
+        }
+        if let Some(reduction) = &self.reduction {
+            parts.push(format!("reduction={}", encode(reduction)));
+        }
+        if let Some(colorby) = &self.colorby {
+            parts.push(format!("color={}", encode_source(colorby)));
+        }
+        if let Some(camera) = &self.camera {
+            parts.push(format!("camera={}", camera.to_url_value()));
+        }
+        if !self.features.is_empty() {
+            let features = self.features.iter().map(encode_source).collect::<Vec<_>>();
+            parts.push(format!("features={}", features.join(";")));
+        }
+        if let Some(locus) = &self.locus {
+            parts.push(format!("locus={},{},{}", encode(&locus.chr.to_string()), locus.start, locus.end));
+        }
+        if let Some(cells) = &self.cells {
+            let cells = encode_ranges(cells);
+            if cells.len() <= MAX_URL_SELECTION_LEN {
+                parts.push(format!("cells={}", cells));
+            }
+        }
+        format!("#{}", parts.join("&"))
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Parse from the fragment of a URL. Anything malformed is left out
+    pub fn from_url_hash(hash: &str) -> ViewState {
+        let mut values = BTreeMap::new();
+        for part in hash.trim_start_matches('#').split('&') {
+            if let Some((key, value)) = part.split_once('=') {
+                values.insert(key, value);
+            }
+        }
+
+        ViewState {
+            page: values.get("page").and_then(|v| CurrentPage::from_url_name(v)),
+            dataset: values.get("dataset").and_then(|v| decode(v)),
+            reduction: values.get("reduction").and_then(|v| decode(v)),
+            colorby: values.get("color").and_then(|v| decode_source(v)),
+            camera: values.get("camera").and_then(|v| ViewCamera::from_url_value(v)),
+            features: values.get("features").map(|v| v.split(';').filter_map(decode_source).collect()).unwrap_or_default(),
+            locus: values.get("locus").and_then(|v| decode_locus(v)),
+            cells: values.get("cells").and_then(|v| decode_ranges(v)),
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Get the view kept in the URL of the page
+    pub fn from_current_url() -> ViewState {
+        let location = window().expect("no window").location();
+        let hash = location.hash().unwrap_or_default();
+        ViewState::from_url_hash(&hash)
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Percent-encode a name
+fn encode(s: &str) -> String {
+    js_sys::encode_uri_component(s).into()
+}
+
+
+////////////////////////////////////////////////////////////
+/// Decode a percent-encoded name
+fn decode(s: &str) -> Option<String> {
+    js_sys::decode_uri_component(s).ok().map(|s| s.into())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Format a source of per-cell data as m,column or c,counts,feature
+fn encode_source(source: &PerCellDataSource) -> String {
+    match source {
+        PerCellDataSource::Metadata(column_name) => format!("m,{}", encode(column_name)),
+        PerCellDataSource::Counts(counts_name, feature_name) => format!("c,{},{}", encode(counts_name), encode(feature_name)),
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Parse a source of per-cell data, as given by encode_source
+fn decode_source(value: &str) -> Option<PerCellDataSource> {
+    let parts = value.split(',').collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["m", column_name] => Some(PerCellDataSource::Metadata(decode(column_name)?)),
+        ["c", counts_name, feature_name] => Some(PerCellDataSource::Counts(decode(counts_name)?, decode(feature_name)?)),
+        _ => None,
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Parse a range of the genome browser, as chr,start,end
+fn decode_locus(value: &str) -> Option<GBrowserLocus> {
+    let parts = value.split(',').collect::<Vec<_>>();
+    match parts.as_slice() {
+        [chr, start, end] => Some(GBrowserLocus {
+            chr: decode(chr)?.into(),
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Format a list of cells as sorted ranges, such as 0-5,8,10-12. Selections tend to be runs of cells
+fn encode_ranges(cells: &Vec<usize>) -> String {
+    let mut cells = cells.clone();
+    cells.sort();
+    cells.dedup();
+
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < cells.len() {
+        let from = cells[i];
+        while i + 1 < cells.len() && cells[i + 1] == cells[i] + 1 {
+            i += 1;
+        }
+        let to = cells[i];
+        if from == to {
+            parts.push(from.to_string());
+        } else {
+            parts.push(format!("{}-{}", from, to));
+        }
+        i += 1;
+    }
+    parts.join(",")
+}
+
+
+////////////////////////////////////////////////////////////
+/// Parse a list of cells, as given by encode_ranges
+fn decode_ranges(value: &str) -> Option<Vec<usize>> {
+    let mut cells = Vec::new();
+    for part in value.split(',').filter(|p| !p.is_empty()) {
+        let (from, to) = if let Some((from, to)) = part.split_once('-') {
+            (from.parse::<usize>().ok()?, to.parse::<usize>().ok()?)
+        } else {
+            let cell = part.parse::<usize>().ok()?;
+            (cell, cell)
+        };
+        if to < from || cells.len() + (to - from) >= MAX_URL_CELLS {
+            return None;
+        }
+        cells.extend(from..=to);
+    }
+    Some(cells)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_ranges_merges_runs() {
+        assert_eq!(encode_ranges(&vec![]), "");
+        assert_eq!(encode_ranges(&vec![7]), "7");
+        assert_eq!(encode_ranges(&vec![0, 1, 2, 3, 4, 5, 8, 10, 11, 12]), "0-5,8,10-12");
+        assert_eq!(encode_ranges(&vec![3, 4]), "3-4");
+    }
+
+    #[test]
+    fn encode_ranges_sorts_and_dedups() {
+        assert_eq!(encode_ranges(&vec![12, 10, 11, 11, 8, 8]), "8,10-12");
+    }
+
+    #[test]
+    fn decode_ranges_round_trip() {
+        for cells in [vec![], vec![0], vec![0, 1, 2, 5, 7, 8, 100], (0..1000).filter(|x| x % 3 != 0).collect::<Vec<_>>()] {
+            assert_eq!(decode_ranges(&encode_ranges(&cells)), Some(cells));
+        }
+    }
+
+    #[test]
+    fn decode_ranges_rejects_malformed() {
+        assert_eq!(decode_ranges(""), Some(vec![]));
+        assert_eq!(decode_ranges("1,,3"), Some(vec![1, 3]));
+        assert_eq!(decode_ranges("5-3"), None);
+        assert_eq!(decode_ranges("a"), None);
+        assert_eq!(decode_ranges("1-"), None);
+        assert_eq!(decode_ranges("-1"), None);
+        assert_eq!(decode_ranges("1-2-3"), None);
+    }
+
+    #[test]
+    fn decode_ranges_limits_number_of_cells() {
+        assert_eq!(decode_ranges(&format!("0-{}", MAX_URL_CELLS - 1)).map(|v| v.len()), Some(MAX_URL_CELLS));
+        assert_eq!(decode_ranges(&format!("0-{}", MAX_URL_CELLS)), None);
+        assert_eq!(decode_ranges(&format!("0-{},{}", MAX_URL_CELLS - 1, MAX_URL_CELLS)), None);
+    }
+}
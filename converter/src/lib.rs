@@ -0,0 +1,7 @@
+pub mod mtx;
+pub mod table;
+pub mod writer;
+
+pub use mtx::FeatureMajorMatrix;
+pub use table::{CellTable, MetaColumn};
+pub use writer::Biscvi5Writer;
@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+
+use biscvi_convert::mtx::{make_names_unique, read_mtx, read_tsv_column};
+use biscvi_convert::{Biscvi5Writer, CellTable};
+
+
+////////////////////////////////////////////////////////////
+/// Build a counts.biscvi5 file from 10x Matrix Market output and CSV/TSV tables
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Matrix Market file, features x cells, optionally gzipped
+    #[arg(long)]
+    mtx: PathBuf,
+
+    /// features.tsv(.gz), one line per matrix row
+    #[arg(long)]
+    features: PathBuf,
+
+    /// barcodes.tsv(.gz), one line per matrix column
+    #[arg(long)]
+    barcodes: PathBuf,
+
+    /// Column of features.tsv to use as feature name, 1-based. 10x files have ID, name, type
+    #[arg(long, default_value_t = 2)]
+    feature_column: usize,
+
+    /// Name of the count matrix in the output
+    #[arg(long, default_value = "counts")]
+    matrix_name: String,
+
+    /// Reduction as NAME=FILE. The file has a header, then barcode and coordinates per line. Can be repeated
+    #[arg(long = "reduction")]
+    reductions: Vec<String>,
+
+    /// Table of cell metadata with a header. The first column is the barcode
+    #[arg(long)]
+    obs: Option<PathBuf>,
+
+    /// Output file
+    #[arg(short, long, default_value = "counts.biscvi5")]
+    out: PathBuf,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Converter entry point
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    if args.feature_column == 0 {
+        anyhow::bail!("Feature column is 1-based");
+    }
+
+    //Read the matrix and names of rows and columns
+    let mat = read_mtx(&args.mtx)?;
+    let feature_names = make_names_unique(read_tsv_column(&args.features, args.feature_column - 1)?);
+    let barcodes = read_tsv_column(&args.barcodes, 0)?;
+    if barcodes.len() != mat.num_cells {
+        anyhow::bail!("Matrix has {} cells but {} barcodes were given", mat.num_cells, barcodes.len());
+    }
+
+    let mut writer = Biscvi5Writer::create(&args.out)?;
+    writer.write_barcodes(&barcodes)?;
+    writer.write_counts(&args.matrix_name, &mat, &feature_names)?;
+
+    //Reductions, in the order of the barcodes
+    for red in &args.reductions {
+        let (name, path) = red.split_once('=').context(format!("Reduction must be given as NAME=FILE, got {}", red))?;
+        let table = CellTable::read(&PathBuf::from(path))?;
+        let coords = table.get_reduction(&barcodes).context(format!("In reduction {}", name))?;
+        writer.write_reduction(name, &coords)?;
+    }
+
+    //Metadata columns
+    if let Some(p) = &args.obs {
+        let table = CellTable::read(p)?;
+        for (i, col_name) in table.column_names.iter().enumerate() {
+            let col = table.get_column(i, &barcodes).context(format!("In metadata column {}", col_name))?;
+            writer.write_meta(col_name, &col)?;
+        }
+    }
+
+    println!("Wrote {}", args.out.display());
+    Ok(())
+}
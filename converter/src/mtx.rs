@@ -0,0 +1,331 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::Context;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+
+////////////////////////////////////////////////////////////
+/// Sparse matrix in compressed row format. Rows are features and
+/// indices are cells, which is how count matrices are stored in biscvi5 files
+pub struct FeatureMajorMatrix {
+    pub num_features: usize,
+    pub num_cells: usize,
+    pub indptr: Vec<u32>,
+    pub indices: Vec<u32>,
+    pub data: Vec<f32>,
+}
+impl FeatureMajorMatrix {
+
+    ////////////////////////////////////////////////////////////
+    /// Build from (feature, cell, value) triplets, in any order
+    pub fn from_triplets(num_features: usize, num_cells: usize, triplets: Vec<(u32, u32, f32)>) -> anyhow::Result<FeatureMajorMatrix> {
+        if triplets.len() > u32::MAX as usize {
+            anyhow::bail!("Too many non-zero values ({}) for 32-bit indptr", triplets.len());
+        }
+
+        //Count entries per feature
+        let mut indptr = vec![0u32; num_features + 1];
+        for (feature, _cell, _value) in &triplets {
+            indptr[*feature as usize + 1] += 1;
+        }
+        for i in 0..num_features {
+            indptr[i + 1] += indptr[i];
+        }
+
+        //Place each entry in its row
+        let mut next_pos = indptr.clone();
+        let mut indices = vec![0u32; triplets.len()];
+        let mut data = vec![0f32; triplets.len()];
+        for (feature, cell, value) in triplets {
+            let pos = next_pos[feature as usize] as usize;
+            indices[pos] = cell;
+            data[pos] = value;
+            next_pos[feature as usize] += 1;
+        }
+
+        //Sort cells within each feature, as expected by readers
+        for i in 0..num_features {
+            let from = indptr[i] as usize;
+            let to = indptr[i + 1] as usize;
+            let row_indices = &indices[from..to];
+            if !row_indices.windows(2).all(|w| w[0] <= w[1]) {
+                let mut row = row_indices.iter().cloned().zip(data[from..to].iter().cloned()).collect::<Vec<_>>();
+                row.sort_by_key(|(cell, _)| *cell);
+                for (j, (cell, value)) in row.into_iter().enumerate() {
+                    indices[from + j] = cell;
+                    data[from + j] = value;
+                }
+            }
+        }
+
+        Ok(FeatureMajorMatrix {
+            num_features,
+            num_cells,
+            indptr,
+            indices,
+            data,
+        })
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Open a file for reading, decompressing it if the name ends with .gz
+pub fn open_maybe_gz(p: &Path) -> anyhow::Result<Box<dyn BufRead>> {
+    let f = File::open(p).context(format!("Could not open {}", p.display()))?;
+    let is_gz = p.extension().map(|e| e == "gz").unwrap_or(false);
+    let reader: Box<dyn Read> = if is_gz {
+        Box::new(MultiGzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+
+////////////////////////////////////////////////////////////
+/// Create a file for writing, compressing it if the name ends with .gz
+pub fn create_maybe_gz(p: &Path) -> anyhow::Result<Box<dyn Write>> {
+    let f = File::create(p).context(format!("Could not create {}", p.display()))?;
+    let is_gz = p.extension().map(|e| e == "gz").unwrap_or(false);
+    let writer: Box<dyn Write> = if is_gz {
+        Box::new(GzEncoder::new(f, Compression::default()))
+    } else {
+        Box::new(f)
+    };
+    Ok(Box::new(BufWriter::new(writer)))
+}
+
+
+////////////////////////////////////////////////////////////
+/// Read a Matrix Market coordinate file, as written by 10x Cell Ranger.
+/// Rows are features and columns are cells
+pub fn read_mtx(p: &Path) -> anyhow::Result<FeatureMajorMatrix> {
+    println!("Reading matrix {}", p.display());
+    let reader = open_maybe_gz(p)?;
+    let mut lines = reader.lines();
+
+    //Header: %%MatrixMarket matrix coordinate <field> <symmetry>
+    let header = lines.next().context("Empty matrix file")??;
+    let header_parts = header.split_whitespace().map(|s| s.to_lowercase()).collect::<Vec<_>>();
+    if header_parts.len() < 5 || header_parts[0] != "%%matrixmarket" || header_parts[1] != "matrix" {
+        anyhow::bail!("Not a Matrix Market file: {}", header);
+    }
+    if header_parts[2] != "coordinate" {
+        anyhow::bail!("Only coordinate Matrix Market files are supported, got {}", header_parts[2]);
+    }
+    let is_pattern = match header_parts[3].as_str() {
+        "integer" | "real" => false,
+        "pattern" => true,
+        other => anyhow::bail!("Unsupported Matrix Market field type {}", other),
+    };
+    if header_parts[4] != "general" {
+        anyhow::bail!("Only general Matrix Market files are supported, got {}", header_parts[4]);
+    }
+
+    //Size line comes after any comments
+    let mut size_line = None;
+    for line in &mut lines {
+        let line = line?;
+        if !line.starts_with('%') && !line.trim().is_empty() {
+            size_line = Some(line);
+            break;
+        }
+    }
+    let size_line = size_line.context("Matrix Market file lacks size line")?;
+    let size_parts = size_line.split_whitespace().map(|s| s.parse::<usize>()).collect::<Result<Vec<_>, _>>()
+        .context("Could not parse Matrix Market size line")?;
+    if size_parts.len() != 3 {
+        anyhow::bail!("Malformed Matrix Market size line: {}", size_line);
+    }
+    let (num_features, num_cells, num_entries) = (size_parts[0], size_parts[1], size_parts[2]);
+    println!("Matrix has {} features, {} cells, {} entries", num_features, num_cells, num_entries);
+
+    //Entries, 1-based
+    let mut triplets = Vec::with_capacity(num_entries);
+    for (line_num, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let mut next_index = |max: usize| -> anyhow::Result<u32> {
+            let i = parts.next().context("Missing index")?.parse::<usize>()?;
+            if i == 0 || i > max {
+                anyhow::bail!("Index {} out of range 1..{}", i, max);
+            }
+            Ok((i - 1) as u32)
+        };
+        let feature = next_index(num_features).context(format!("On entry line {}", line_num + 1))?;
+        let cell = next_index(num_cells).context(format!("On entry line {}", line_num + 1))?;
+        let value = if is_pattern {
+            1.0
+        } else {
+            parts.next().context("Missing value")?.parse::<f32>().context(format!("On entry line {}", line_num + 1))?
+        };
+        if value != 0.0 {
+            triplets.push((feature, cell, value));
+        }
+    }
+    if triplets.len() > num_entries {
+        anyhow::bail!("Matrix Market file has more entries than the declared {}", num_entries);
+    }
+
+    FeatureMajorMatrix::from_triplets(num_features, num_cells, triplets)
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write a Matrix Market coordinate file, as read by read_mtx.
+/// Rows are features and columns are cells
+pub fn write_mtx(p: &Path, mat: &FeatureMajorMatrix) -> anyhow::Result<()> {
+    println!("Writing matrix {}", p.display());
+    let mut writer = create_maybe_gz(p)?;
+    writeln!(writer, "%%MatrixMarket matrix coordinate real general")?;
+    writeln!(writer, "{} {} {}", mat.num_features, mat.num_cells, mat.data.len())?;
+    for feature in 0..mat.num_features {
+        let from = mat.indptr[feature] as usize;
+        let to = mat.indptr[feature + 1] as usize;
+        for (cell, value) in mat.indices[from..to].iter().zip(mat.data[from..to].iter()) {
+            writeln!(writer, "{} {} {}", feature + 1, cell + 1, value)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Read one column of a TSV file without header, such as features.tsv or barcodes.tsv.
+/// If the file has fewer columns, the last one is used
+pub fn read_tsv_column(p: &Path, column: usize) -> anyhow::Result<Vec<String>> {
+    let reader = open_maybe_gz(p)?;
+    let mut out = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let parts = line.split('\t').collect::<Vec<_>>();
+        let value = parts.get(column).or(parts.last()).context("Empty line")?;
+        out.push(value.to_string());
+    }
+    Ok(out)
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write a TSV file without header with one value per line, such as barcodes.tsv
+pub fn write_tsv_column(p: &Path, values: &Vec<String>) -> anyhow::Result<()> {
+    let mut writer = create_maybe_gz(p)?;
+    for v in values {
+        writeln!(writer, "{}", v)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Make names unique by appending -1, -2 etc to duplicates, as scanpy does.
+/// The server looks up features by name, so duplicates would otherwise be unreachable
+pub fn make_names_unique(names: Vec<String>) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut num_dup = std::collections::HashMap::new();
+    let mut out = Vec::with_capacity(names.len());
+    for name in &names {
+        seen.insert(name.clone());
+    }
+    let mut used = std::collections::HashSet::new();
+    for name in names {
+        if used.insert(name.clone()) {
+            out.push(name);
+        } else {
+            let n: &mut usize = num_dup.entry(name.clone()).or_insert(0);
+            loop {
+                *n += 1;
+                let candidate = format!("{}-{}", name, n);
+                if !seen.contains(&candidate) && used.insert(candidate.clone()) {
+                    out.push(candidate);
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ////////////////////////////////////////////////////////////
+    /// Path of a file for a test, in the temporary directory
+    fn test_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("biscvi_mtx_test_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn triplets_are_sorted_by_feature_then_cell() {
+        let mat = FeatureMajorMatrix::from_triplets(2, 3, vec![(1, 2, 5.0), (0, 1, 1.0), (1, 0, 4.0)]).unwrap();
+        assert_eq!(mat.indptr, vec![0, 1, 3]);
+        assert_eq!(mat.indices, vec![1, 0, 2]);
+        assert_eq!(mat.data, vec![1.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn mtx_round_trip() {
+        let mat = FeatureMajorMatrix::from_triplets(3, 4, vec![(0, 3, 2.0), (2, 0, 0.5), (2, 1, 7.0)]).unwrap();
+        for name in ["m.mtx", "m.mtx.gz"] {
+            let p = test_path(name);
+            write_mtx(&p, &mat).unwrap();
+            let read = read_mtx(&p).unwrap();
+            assert_eq!((read.num_features, read.num_cells), (3, 4));
+            assert_eq!(read.indptr, mat.indptr);
+            assert_eq!(read.indices, mat.indices);
+            assert_eq!(read.data, mat.data);
+            std::fs::remove_file(&p).unwrap();
+        }
+    }
+
+    #[test]
+    fn mtx_pattern_and_comments() {
+        let p = test_path("pattern.mtx");
+        std::fs::write(&p, "%%MatrixMarket matrix coordinate pattern general\n% comment\n2 2 2\n1 2\n2 1\n").unwrap();
+        let mat = read_mtx(&p).unwrap();
+        assert_eq!(mat.indices, vec![1, 0]);
+        assert_eq!(mat.data, vec![1.0, 1.0]);
+        std::fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn mtx_rejects_bad_input() {
+        let p = test_path("bad.mtx");
+        std::fs::write(&p, "%%MatrixMarket matrix coordinate real general\n2 2 1\n3 1 1.0\n").unwrap();
+        assert!(read_mtx(&p).is_err());
+        std::fs::write(&p, "%%MatrixMarket matrix array real general\n2 2\n").unwrap();
+        assert!(read_mtx(&p).is_err());
+        std::fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn tsv_round_trip() {
+        let p = test_path("features.tsv.gz");
+        let names = vec!["GENE1".to_string(), "GENE2".to_string()];
+        write_tsv_column(&p, &names).unwrap();
+        assert_eq!(read_tsv_column(&p, 1).unwrap(), names);
+        std::fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn names_made_unique() {
+        let names = vec!["a", "b", "a", "a-1", "a"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(make_names_unique(names), vec!["a", "b", "a-2", "a-1", "a-3"]);
+    }
+}
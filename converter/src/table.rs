@@ -0,0 +1,243 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::mtx::open_maybe_gz;
+
+
+////////////////////////////////////////////////////////////
+/// A table with a header, where the first column is the cell barcode
+pub struct CellTable {
+    pub column_names: Vec<String>,
+    pub barcodes: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+impl CellTable {
+
+    ////////////////////////////////////////////////////////////
+    /// Read a CSV or TSV file. The separator is decided by the file name
+    pub fn read(p: &Path) -> anyhow::Result<CellTable> {
+        println!("Reading table {}", p.display());
+        let name = p.to_string_lossy().to_lowercase();
+        let delimiter = if name.ends_with(".tsv") || name.ends_with(".tsv.gz") || name.ends_with(".txt") || name.ends_with(".txt.gz") {
+            b'\t'
+        } else {
+            b','
+        };
+
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(true)
+            .flexible(false)
+            .from_reader(open_maybe_gz(p)?);
+
+        let header = reader.headers()?.clone();
+        if header.len() < 2 {
+            anyhow::bail!("Table {} needs a barcode column and at least one more column", p.display());
+        }
+        let column_names = header.iter().skip(1).map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let mut barcodes = Vec::new();
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let record = record.context(format!("Could not parse {}", p.display()))?;
+            barcodes.push(record.get(0).context("Missing barcode")?.to_string());
+            rows.push(record.iter().skip(1).map(|s| s.to_string()).collect::<Vec<_>>());
+        }
+
+        Ok(CellTable {
+            column_names,
+            barcodes,
+            rows,
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// For each cell in the matrix, find the row of this table. Every cell must be present
+    pub fn rows_for_barcodes(&self, barcodes: &Vec<String>) -> anyhow::Result<Vec<usize>> {
+        let map_row = self.barcodes.iter().enumerate().map(|(i, bc)| (bc.as_str(), i)).collect::<HashMap<_, _>>();
+        let mut out = Vec::with_capacity(barcodes.len());
+        let mut num_missing = 0;
+        for bc in barcodes {
+            if let Some(i) = map_row.get(bc.as_str()) {
+                out.push(*i);
+            } else {
+                if num_missing < 5 {
+                    println!("Barcode {} not in table", bc);
+                }
+                num_missing += 1;
+            }
+        }
+        if num_missing > 0 {
+            anyhow::bail!("{} of {} cells are missing in table", num_missing, barcodes.len());
+        }
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get coordinates, stored as (dim, cell) as in biscvi5 files
+    pub fn get_reduction(&self, barcodes: &Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let rows = self.rows_for_barcodes(barcodes)?;
+        let mut out = Vec::new();
+        for (col, col_name) in self.column_names.iter().enumerate() {
+            let mut values = Vec::with_capacity(rows.len());
+            for row in &rows {
+                let v = &self.rows[*row][col];
+                values.push(v.trim().parse::<f32>().context(format!("Coordinate {} in column {} is not a number", v, col_name))?);
+            }
+            out.push(values);
+        }
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get a metadata column, ordered as the given cells. The type is inferred from the values
+    pub fn get_column(&self, col: usize, barcodes: &Vec<String>) -> anyhow::Result<MetaColumn> {
+        let rows = self.rows_for_barcodes(barcodes)?;
+        let values = rows.iter().map(|row| self.rows[*row][col].trim()).collect::<Vec<_>>();
+        Ok(MetaColumn::infer(&values))
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// A metadata column, as stored in the /obs group
+pub enum MetaColumn {
+    /// Missing values are NaN
+    Numeric(Vec<f32>),
+    /// Codes and categories. Missing values have code -1
+    Categorical(Vec<i32>, Vec<String>),
+    /// None is a missing value
+    Boolean(Vec<Option<bool>>),
+    /// None is a missing value
+    Integer(Vec<Option<i64>>),
+    /// None is a missing value
+    Text(Vec<Option<String>>),
+}
+impl MetaColumn {
+
+    ////////////////////////////////////////////////////////////
+    /// Decide the type of a column. Only if all values are of one type or missing, the column
+    /// is boolean (true/false), integer or numeric, tried in that order. Otherwise it is categorical
+    pub fn infer(values: &Vec<&str>) -> MetaColumn {
+        let any_present = values.iter().any(|v| !is_missing(v));
+        if !any_present {
+            return MetaColumn::from_labels(&values.iter().map(|_| None).collect::<Vec<_>>());
+        }
+
+        if let Some(parsed) = parse_present(values, parse_bool) {
+            MetaColumn::Boolean(parsed)
+        } else if let Some(parsed) = parse_present(values, |v| v.parse::<i64>().ok()) {
+            MetaColumn::Integer(parsed)
+        } else if let Some(parsed) = parse_present(values, |v| v.parse::<f32>().ok()) {
+            MetaColumn::Numeric(parsed.into_iter().map(|x| x.unwrap_or(f32::NAN)).collect())
+        } else {
+            let labels = values.iter().map(|v| if is_missing(v) { None } else { Some(*v) }).collect::<Vec<_>>();
+            MetaColumn::from_labels(&labels)
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Make a categorical column. Categories are sorted; None is a missing value
+    pub fn from_labels(labels: &Vec<Option<&str>>) -> MetaColumn {
+        let categories = labels.iter().filter_map(|v| *v).map(|v| v.to_string()).collect::<BTreeSet<_>>();
+        let categories = categories.into_iter().collect::<Vec<_>>();
+        let map_code = categories.iter().enumerate().map(|(i, c)| (c.clone(), i as i32)).collect::<HashMap<_, _>>();
+        let codes = labels.iter().map(|v| {
+            match v {
+                Some(v) => *map_code.get(*v).unwrap(),
+                None => -1,
+            }
+        }).collect::<Vec<_>>();
+        MetaColumn::Categorical(codes, categories)
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Parse all values that are not missing. Returns None if any of them cannot be parsed
+fn parse_present<T, F>(values: &Vec<&str>, parse: F) -> Option<Vec<Option<T>>> where F: Fn(&str) -> Option<T> {
+    values.iter().map(|v| {
+        if is_missing(v) {
+            Some(None)
+        } else {
+            parse(v).map(Some)
+        }
+    }).collect()
+}
+
+
+////////////////////////////////////////////////////////////
+/// Parse a boolean, as written by R and Python
+fn parse_bool(v: &str) -> Option<bool> {
+    match v {
+        "true" | "TRUE" | "True" => Some(true),
+        "false" | "FALSE" | "False" => Some(false),
+        _ => None,
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Check if a value denotes a missing value
+fn is_missing(v: &str) -> bool {
+    v.is_empty() || v == "NA" || v == "NaN" || v == "nan"
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infer_numeric_with_missing() {
+        match MetaColumn::infer(&vec!["1.5", "NA", "3"]) {
+            MetaColumn::Numeric(v) => {
+                assert_eq!(v[0], 1.5);
+                assert!(v[1].is_nan());
+                assert_eq!(v[2], 3.0);
+            },
+            _ => panic!("expected numeric column"),
+        }
+    }
+
+    #[test]
+    fn infer_integer_with_missing() {
+        match MetaColumn::infer(&vec!["12", "", "-3"]) {
+            MetaColumn::Integer(v) => assert_eq!(v, vec![Some(12), None, Some(-3)]),
+            _ => panic!("expected integer column"),
+        }
+    }
+
+    #[test]
+    fn infer_boolean_with_missing() {
+        match MetaColumn::infer(&vec!["TRUE", "NA", "false"]) {
+            MetaColumn::Boolean(v) => assert_eq!(v, vec![Some(true), None, Some(false)]),
+            _ => panic!("expected boolean column"),
+        }
+    }
+
+    #[test]
+    fn infer_categorical_with_missing() {
+        match MetaColumn::infer(&vec!["b", "", "a", "b"]) {
+            MetaColumn::Categorical(codes, cats) => {
+                assert_eq!(cats, vec!["a".to_string(), "b".to_string()]);
+                assert_eq!(codes, vec![1, -1, 0, 1]);
+            },
+            _ => panic!("expected categorical column"),
+        }
+    }
+
+    #[test]
+    fn all_missing_is_categorical() {
+        match MetaColumn::infer(&vec!["NA", ""]) {
+            MetaColumn::Categorical(codes, cats) => {
+                assert!(cats.is_empty());
+                assert_eq!(codes, vec![-1, -1]);
+            },
+            _ => panic!("expected categorical column"),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use hdf5::types::VarLenUnicode;
+use ndarray::Array2;
+
+use crate::mtx::FeatureMajorMatrix;
+use crate::table::MetaColumn;
+
+/// Largest chunk size for 1D datasets
+const MAX_CHUNK: usize = 64*1024;
+
+/// Compression level of datasets
+const DEFLATE_LEVEL: u8 = 4;
+
+
+////////////////////////////////////////////////////////////
+/// Writer of biscvi5 files. The layout matches what the server reads:
+///
+/// /counts/<name>/{data, indices, indptr, feature_names}   feature-major CSR
+/// /reductions/<name>                                      (dim, cell)
+/// /obs/<name>                                             numeric, boolean or text column
+/// /obs/<name>/{codes, categories}                         categorical column
+/// /obs/<name>/{values, mask}                              column with missing values, or integer column
+/// /barcodes                                               cell names
+pub struct Biscvi5Writer {
+    file: hdf5::File,
+    num_cells: Option<usize>,
+}
+impl Biscvi5Writer {
+
+    ////////////////////////////////////////////////////////////
+    /// Create a new file, overwriting any existing one
+    pub fn create(p: &Path) -> anyhow::Result<Biscvi5Writer> {
+        let file = hdf5::File::create(p)?;
+        file.create_group("counts")?;
+        file.create_group("reductions")?;
+        file.create_group("obs")?;
+        Ok(Biscvi5Writer {
+            file: file,
+            num_cells: None,
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// All objects must agree on the number of cells
+    fn check_num_cells(&mut self, what: &str, num_cells: usize) -> anyhow::Result<()> {
+        if let Some(n) = self.num_cells {
+            if n != num_cells {
+                anyhow::bail!("{} has {} cells, but previously written data has {}", what, num_cells, n);
+            }
+        } else {
+            self.num_cells = Some(num_cells);
+        }
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Write the names of the cells
+    pub fn write_barcodes(&mut self, barcodes: &Vec<String>) -> anyhow::Result<()> {
+        self.check_num_cells("Barcode list", barcodes.len())?;
+        write_stringvec(&self.file, "barcodes", barcodes)?;
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Write a count matrix
+    pub fn write_counts(&mut self, name: &str, mat: &FeatureMajorMatrix, feature_names: &Vec<String>) -> anyhow::Result<()> {
+        println!("Writing count matrix {}", name);
+        if feature_names.len() != mat.num_features {
+            anyhow::bail!("Matrix {} has {} features but {} feature names were given", name, mat.num_features, feature_names.len());
+        }
+        self.check_num_cells(&format!("Matrix {}", name), mat.num_cells)?;
+
+        let group = self.file.group("counts")?.create_group(name)?;
+        write_vec(&group, "data", &mat.data)?;
+        write_vec(&group, "indices", &mat.indices)?;
+        write_vec(&group, "indptr", &mat.indptr)?;
+        write_stringvec(&group, "feature_names", feature_names)?;
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Write a reduction, given as one vector per dimension
+    pub fn write_reduction(&mut self, name: &str, coords: &Vec<Vec<f32>>) -> anyhow::Result<()> {
+        println!("Writing reduction {}", name);
+        let num_dim = coords.len();
+        if num_dim < 2 {
+            anyhow::bail!("Reduction {} needs at least 2 dimensions", name);
+        }
+        let num_cells = coords[0].len();
+        self.check_num_cells(&format!("Reduction {}", name), num_cells)?;
+
+        let flat = coords.iter().flat_map(|c| c.iter().cloned()).collect::<Vec<_>>();
+        let arr = Array2::from_shape_vec((num_dim, num_cells), flat)?;
+        self.file.group("reductions")?
+            .new_dataset_builder()
+            .with_data(&arr)
+            .create(name)?;
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Write a metadata column
+    pub fn write_meta(&mut self, name: &str, col: &MetaColumn) -> anyhow::Result<()> {
+        let group_obs = self.file.group("obs")?;
+        match col {
+            MetaColumn::Numeric(values) => {
+                println!("Writing numeric metadata column {}", name);
+                self.check_num_cells(&format!("Metadata column {}", name), values.len())?;
+                write_vec(&group_obs, name, values)?;
+            },
+            MetaColumn::Categorical(codes, categories) => {
+                println!("Writing categorical metadata column {} with {} categories", name, categories.len());
+                self.check_num_cells(&format!("Metadata column {}", name), codes.len())?;
+                let group = group_obs.create_group(name)?;
+                write_vec(&group, "codes", codes)?;
+                write_stringvec(&group, "categories", categories)?;
+            },
+            MetaColumn::Boolean(values) => {
+                println!("Writing boolean metadata column {}", name);
+                self.check_num_cells(&format!("Metadata column {}", name), values.len())?;
+                let present = values.iter().map(|x| x.unwrap_or(false)).collect::<Vec<_>>();
+                write_nullable(&group_obs, name, values, |group, name| write_vec(group, name, &present))?;
+            },
+            MetaColumn::Integer(values) => {
+                println!("Writing integer metadata column {}", name);
+                self.check_num_cells(&format!("Metadata column {}", name), values.len())?;
+                let present = values.iter().map(|x| x.unwrap_or(0)).collect::<Vec<i64>>();
+                write_nullable(&group_obs, name, values, |group, name| write_vec(group, name, &present))?;
+            },
+            MetaColumn::Text(values) => {
+                println!("Writing text metadata column {}", name);
+                self.check_num_cells(&format!("Metadata column {}", name), values.len())?;
+                let present = values.iter().map(|x| x.clone().unwrap_or_default()).collect::<Vec<_>>();
+                write_nullable(&group_obs, name, values, |group, name| write_stringvec(group, name, &present))?;
+            },
+        }
+        Ok(())
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write a column that may have missing values. Without any, it is written as a plain dataset.
+/// Otherwise a group holds the values and a mask, true where missing
+fn write_nullable<T, F>(group_obs: &hdf5::Group, name: &str, values: &Vec<Option<T>>, write_values: F) -> anyhow::Result<()>
+where F: Fn(&hdf5::Group, &str) -> anyhow::Result<()> {
+    if values.iter().all(|x| x.is_some()) {
+        write_values(group_obs, name)
+    } else {
+        let group = group_obs.create_group(name)?;
+        write_values(&group, "values")?;
+        write_vec(&group, "mask", &values.iter().map(|x| x.is_none()).collect::<Vec<bool>>())
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write a compressed 1D dataset
+fn write_vec<T: hdf5::H5Type>(group: &hdf5::Group, name: &str, data: &Vec<T>) -> anyhow::Result<()> {
+    let chunk = data.len().min(MAX_CHUNK).max(1);
+    group.new_dataset_builder()
+        .chunk(chunk)
+        .deflate(DEFLATE_LEVEL)
+        .with_data(data.as_slice())
+        .create(name)?;
+    Ok(())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write a 1D dataset of UTF-8 strings
+fn write_stringvec(group: &hdf5::Group, name: &str, data: &Vec<String>) -> anyhow::Result<()> {
+    let data = data.iter().map(|s| s.parse::<VarLenUnicode>()).collect::<Result<Vec<_>, _>>()?;
+    group.new_dataset_builder()
+        .with_data(data.as_slice())
+        .create(name)?;
+    Ok(())
+}
@@ -0,0 +1,441 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use my_web_app::countfile_struct::CountFileMetaColumnDesc;
+use my_web_app::AnnotationEdit;
+use my_web_app::CountFileMetaColumnData;
+use my_web_app::DatasetDescResponse;
+use my_web_app::MetadataColumnResponse;
+use my_web_app::ReductionResponse;
+
+use crate::countfile::CountFileMat;
+use crate::datasource::DataSource;
+
+
+////////////////////////////////////////////////////////////
+/// Where user annotations are stored, unless set in the config
+pub fn default_annotations_db() -> PathBuf {
+    PathBuf::from("annotations.sqlite")
+}
+
+
+////////////////////////////////////////////////////////////
+/// SQLite database of user-made categorical columns, for all datasets.
+/// Cells are stored by barcode, so labels survive if a dataset is rewritten
+pub struct AnnotationStore {
+    conn: Mutex<Connection>,
+}
+impl AnnotationStore {
+
+    ////////////////////////////////////////////////////////////
+    /// Open the database, creating it if needed
+    pub fn open(p: &Path) -> anyhow::Result<AnnotationStore> {
+        println!("Opening annotation database {}", p.display());
+        let conn = Connection::open(p).context(format!("Could not open annotation database {}", p.display()))?;
+        conn.execute_batch("
+            CREATE TABLE IF NOT EXISTS annotation_column (
+                dataset TEXT NOT NULL,
+                column_name TEXT NOT NULL,
+                PRIMARY KEY (dataset, column_name)
+            );
+            CREATE TABLE IF NOT EXISTS annotation_label (
+                dataset TEXT NOT NULL,
+                column_name TEXT NOT NULL,
+                barcode TEXT NOT NULL,
+                label TEXT NOT NULL,
+                PRIMARY KEY (dataset, column_name, barcode)
+            );
+        ")?;
+        Ok(AnnotationStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Names of all columns of a dataset, sorted
+    pub fn list_columns(&self, dataset_name: &str) -> anyhow::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT column_name FROM annotation_column WHERE dataset=?1 ORDER BY column_name")?;
+        let rows = stmt.query_map(params![dataset_name], |row| row.get(0))?;
+        let out = rows.collect::<Result<Vec<String>, _>>()?;
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check if a column exists
+    pub fn has_column(&self, dataset_name: &str, column_name: &str) -> anyhow::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn.query_row(
+            "SELECT 1 FROM annotation_column WHERE dataset=?1 AND column_name=?2",
+            params![dataset_name, column_name],
+            |_row| Ok(())
+        ).optional()?;
+        Ok(found.is_some())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get all labelled cells of a column, as (barcode, label)
+    pub fn get_labels(&self, dataset_name: &str, column_name: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT barcode, label FROM annotation_label WHERE dataset=?1 AND column_name=?2")?;
+        let rows = stmt.query_map(params![dataset_name, column_name], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let out = rows.collect::<Result<Vec<(String, String)>, _>>()?;
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the different labels of a column, sorted, and the number of labelled cells.
+    /// Only cells for which is_current is true are counted, so labels of cells no longer in the dataset are left out
+    pub fn get_label_summary<F>(&self, dataset_name: &str, column_name: &str, is_current: F) -> anyhow::Result<(Vec<String>, usize)> where F: Fn(&str) -> bool {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT barcode, label FROM annotation_label WHERE dataset=?1 AND column_name=?2")?;
+        let mut rows = stmt.query(params![dataset_name, column_name])?;
+        let mut labels = BTreeSet::new();
+        let mut num_labelled = 0;
+        while let Some(row) = rows.next()? {
+            let barcode: String = row.get(0)?;
+            if is_current(&barcode) {
+                labels.insert(row.get::<_, String>(1)?);
+                num_labelled += 1;
+            }
+        }
+        Ok((labels.into_iter().collect(), num_labelled))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the label of one cell, if it has any
+    pub fn get_label(&self, dataset_name: &str, column_name: &str, barcode: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let label = conn.query_row(
+            "SELECT label FROM annotation_label WHERE dataset=?1 AND column_name=?2 AND barcode=?3",
+            params![dataset_name, column_name, barcode],
+            |row| row.get(0)
+        ).optional()?;
+        Ok(label)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Give cells a label, replacing any previous one. No label removes it. The column is created if needed
+    pub fn set_labels(&self, dataset_name: &str, column_name: &str, barcodes: &Vec<String>, label: Option<&str>) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO annotation_column (dataset, column_name) VALUES (?1, ?2)",
+            params![dataset_name, column_name]
+        )?;
+        {
+            if let Some(label) = label {
+                let mut stmt = tx.prepare("INSERT OR REPLACE INTO annotation_label (dataset, column_name, barcode, label) VALUES (?1, ?2, ?3, ?4)")?;
+                for bc in barcodes {
+                    stmt.execute(params![dataset_name, column_name, bc, label])?;
+                }
+            } else {
+                let mut stmt = tx.prepare("DELETE FROM annotation_label WHERE dataset=?1 AND column_name=?2 AND barcode=?3")?;
+                for bc in barcodes {
+                    stmt.execute(params![dataset_name, column_name, bc])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Rename a column
+    pub fn rename_column(&self, dataset_name: &str, column_name: &str, new_name: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE annotation_column SET column_name=?3 WHERE dataset=?1 AND column_name=?2",
+            params![dataset_name, column_name, new_name]
+        )?;
+        tx.execute(
+            "UPDATE annotation_label SET column_name=?3 WHERE dataset=?1 AND column_name=?2",
+            params![dataset_name, column_name, new_name]
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Delete a column and all its labels
+    pub fn delete_column(&self, dataset_name: &str, column_name: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM annotation_column WHERE dataset=?1 AND column_name=?2",
+            params![dataset_name, column_name]
+        )?;
+        tx.execute(
+            "DELETE FROM annotation_label WHERE dataset=?1 AND column_name=?2",
+            params![dataset_name, column_name]
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// User-made categorical columns of one dataset
+pub struct UserAnnotations {
+    store: Arc<AnnotationStore>,
+    dataset_name: String,
+    barcodes: Vec<String>,
+    map_barcode_cell: HashMap<String, usize>,
+    /// Columns of the dataset file. User columns may not shadow these
+    file_columns: HashSet<String>,
+}
+impl UserAnnotations {
+
+    ////////////////////////////////////////////////////////////
+    /// Constructor
+    pub fn new(store: Arc<AnnotationStore>, dataset_name: &String, ds: &dyn DataSource) -> anyhow::Result<UserAnnotations> {
+        let barcodes = ds.get_barcodes()?;
+        let map_barcode_cell = barcodes.iter().enumerate().map(|(i, bc)| (bc.clone(), i)).collect();
+        let file_columns = ds.get_desc()?.meta.keys().cloned().collect();
+        Ok(UserAnnotations {
+            store,
+            dataset_name: dataset_name.clone(),
+            barcodes,
+            map_barcode_cell,
+            file_columns,
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Names of all user columns
+    pub fn list_columns(&self) -> anyhow::Result<Vec<String>> {
+        self.store.list_columns(&self.dataset_name)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the categories of a user column as get_column gives them, or None if there is no such column.
+    /// Labels are counted as they are read, rather than kept for each cell
+    pub fn get_categories(&self, column_name: &String) -> anyhow::Result<Option<Vec<String>>> {
+        if !self.store.has_column(&self.dataset_name, column_name)? {
+            return Ok(None);
+        }
+        let (mut categories, num_labelled) = self.store.get_label_summary(&self.dataset_name, column_name, |bc| self.map_barcode_cell.contains_key(bc))?;
+        if num_labelled < self.barcodes.len() {
+            categories.push("NA".to_string());
+        }
+        Ok(Some(categories))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the values of a user column, or None if there is no such column. Labels of cells no longer
+    /// in the dataset are ignored. Cells without a label are missing, and get an "NA" category as for other columns
+    pub fn get_column(&self, column_name: &String) -> anyhow::Result<Option<CountFileMetaColumnData>> {
+        if !self.store.has_column(&self.dataset_name, column_name)? {
+            return Ok(None);
+        }
+        let labels = self.store.get_labels(&self.dataset_name, column_name)?;
+        let labels = labels.into_iter().filter_map(|(bc, label)| self.map_barcode_cell.get(&bc).map(|cell| (*cell, label))).collect::<Vec<_>>();
+
+        let mut categories = labels.iter().map(|(_cell, label)| label.clone()).collect::<BTreeSet<_>>().into_iter().collect::<Vec<_>>();
+        let map_code = categories.iter().enumerate().map(|(i, c)| (c.clone(), i as u32)).collect::<HashMap<_, _>>();
+
+        let missing = categories.len() as u32;
+        let mut codes = vec![missing; self.barcodes.len()];
+        for (cell, label) in &labels {
+            codes[*cell] = *map_code.get(label).unwrap();
+        }
+        if codes.contains(&missing) {
+            categories.push("NA".to_string());
+        }
+        Ok(Some(CountFileMetaColumnData::Categorical(codes, categories)))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the label of one cell in a user column, or None if there is no such column.
+    /// Cells without a label are missing
+    pub fn get_cell_label(&self, column_name: &String, cell: usize) -> anyhow::Result<Option<Option<String>>> {
+        if !self.store.has_column(&self.dataset_name, column_name)? {
+            return Ok(None);
+        }
+        let barcode = self.barcodes.get(cell).context(format!("Cell {} out of range; there are {} cells", cell, self.barcodes.len()))?;
+        Ok(Some(self.store.get_label(&self.dataset_name, column_name, barcode)?))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that a new name can be used for a column
+    fn check_new_name(&self, column_name: &String) -> anyhow::Result<()> {
+        if column_name.trim().is_empty() {
+            anyhow::bail!("Column name cannot be empty");
+        }
+        if self.file_columns.contains(column_name) {
+            anyhow::bail!("Column {} already exists in the dataset", column_name);
+        }
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that a user column exists
+    fn check_exists(&self, column_name: &String) -> anyhow::Result<()> {
+        if !self.store.has_column(&self.dataset_name, column_name)? {
+            anyhow::bail!("There is no user column {}", column_name);
+        }
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Apply a change to a user column
+    pub fn edit(&self, edit: &AnnotationEdit) -> anyhow::Result<()> {
+        match edit {
+            AnnotationEdit::SetLabel(column_name, label, cells) => {
+                self.check_new_name(column_name)?;
+                if let Some(label) = label {
+                    if label.trim().is_empty() {
+                        anyhow::bail!("Label cannot be empty");
+                    }
+                }
+                let barcodes = cells.iter().map(|cell| {
+                    self.barcodes.get(*cell as usize).cloned().context(format!("Cell {} out of range; there are {} cells", cell, self.barcodes.len()))
+                }).collect::<anyhow::Result<Vec<_>>>()?;
+                self.store.set_labels(&self.dataset_name, column_name, &barcodes, label.as_deref())
+            },
+            AnnotationEdit::RenameColumn(column_name, new_name) => {
+                self.check_exists(column_name)?;
+                self.check_new_name(new_name)?;
+                if self.store.has_column(&self.dataset_name, new_name)? {
+                    anyhow::bail!("Column {} already exists", new_name);
+                }
+                self.store.rename_column(&self.dataset_name, column_name, new_name)
+            },
+            AnnotationEdit::DeleteColumn(column_name) => {
+                self.check_exists(column_name)?;
+                self.store.delete_column(&self.dataset_name, column_name)
+            },
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Data source with user columns added to the metadata of another one.
+/// Everything else is passed on as is
+pub struct AnnotatedDataSource {
+    inner: Box<dyn DataSource>,
+    annotations: Arc<UserAnnotations>,
+}
+impl AnnotatedDataSource {
+
+    ////////////////////////////////////////////////////////////
+    /// Constructor
+    pub fn new(inner: Box<dyn DataSource>, annotations: Arc<UserAnnotations>) -> AnnotatedDataSource {
+        AnnotatedDataSource {
+            inner,
+            annotations,
+        }
+    }
+}
+impl DataSource for AnnotatedDataSource {
+
+    ////////////////////////////////////////////////////////////
+    /// Get a description of the dataset, including user columns
+    fn get_desc(&self) -> anyhow::Result<DatasetDescResponse> {
+        let mut desc = self.inner.get_desc()?;
+        for column_name in self.annotations.list_columns()? {
+            if let Some(categories) = self.annotations.get_categories(&column_name)? {
+                desc.meta.insert(column_name.clone(), CountFileMetaColumnDesc::Categorical(categories));
+                desc.user_meta.push(column_name);
+            }
+        }
+        Ok(desc)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get all values for a metadata column, which may be a user column
+    fn get_metacolumn(&self, column_name: &String) -> anyhow::Result<MetadataColumnResponse> {
+        if let Some(data) = self.annotations.get_column(column_name)? {
+            Ok(MetadataColumnResponse {
+                data
+            })
+        } else {
+            self.inner.get_metacolumn(column_name)
+        }
+    }
+
+    fn get_matrix(&self, count_name: &String) -> anyhow::Result<&CountFileMat> {
+        self.inner.get_matrix(count_name)
+    }
+
+    fn get_reduction(&self, reduction_name: &String) -> anyhow::Result<ReductionResponse> {
+        self.inner.get_reduction(reduction_name)
+    }
+
+    fn get_reduction_dims(&self, reduction_name: &String) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.inner.get_reduction_dims(reduction_name)
+    }
+
+    fn get_feature_index(&self, count_name: &String, feature_name: &String) -> anyhow::Result<usize> {
+        self.inner.get_feature_index(count_name, feature_name)
+    }
+
+    fn get_counts_for_cell(&self, count_name: &String, row: u32) -> anyhow::Result<MetadataColumnResponse> {
+        self.inner.get_counts_for_cell(count_name, row)
+    }
+
+    fn get_cell_totals(&self, count_name: &String) -> anyhow::Result<&Vec<f64>> {
+        self.inner.get_cell_totals(count_name)
+    }
+
+    fn get_counts_batch(&self, count_name: &String, rows: &Vec<u32>) -> anyhow::Result<Vec<MetadataColumnResponse>> {
+        self.inner.get_counts_batch(count_name, rows)
+    }
+
+    fn get_cell_counts(&self, count_name: &String, cell: u32) -> anyhow::Result<(Vec<u32>, Vec<f32>)> {
+        self.inner.get_cell_counts(count_name, cell)
+    }
+
+    fn get_num_cells(&self) -> anyhow::Result<usize> {
+        self.inner.get_num_cells()
+    }
+
+    fn get_barcodes(&self) -> anyhow::Result<Vec<String>> {
+        self.inner.get_barcodes()
+    }
+
+    fn get_barcode(&self, cell: usize) -> anyhow::Result<String> {
+        self.inner.get_barcode(cell)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the value of one cell in a metadata column, which may be a user column
+    fn get_meta_label(&self, column_name: &String, cell: usize) -> anyhow::Result<Option<String>> {
+        if let Some(label) = self.annotations.get_cell_label(column_name, cell)? {
+            Ok(label)
+        } else {
+            self.inner.get_meta_label(column_name, cell)
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_skips_cells_not_in_dataset() {
+        let store = AnnotationStore::open(Path::new(":memory:")).unwrap();
+        let barcodes = vec!["a".to_string(), "b".to_string(), "old".to_string()];
+        store.set_labels("ds", "col", &barcodes[0..1].to_vec(), Some("x")).unwrap();
+        store.set_labels("ds", "col", &barcodes[2..3].to_vec(), Some("y")).unwrap();
+
+        let current = ["a", "b"];
+        let (labels, num_labelled) = store.get_label_summary("ds", "col", |bc| current.contains(&bc)).unwrap();
+        assert_eq!(labels, vec!["x".to_string()]);
+        assert_eq!(num_labelled, 1);
+
+        let (labels, num_labelled) = store.get_label_summary("ds", "col", |_bc| true).unwrap();
+        assert_eq!(labels, vec!["x".to_string(), "y".to_string()]);
+        assert_eq!(num_labelled, 2);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Mutex;
+
+use actix_web::web::Bytes;
+use lru::LruCache;
+use serde::Deserialize;
+use serde::Serialize;
+
+use my_web_app::CountNormalization;
+
+
+////////////////////////////////////////////////////////////
+/// Limits of the response cache, as given in the config file
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseCacheConfig {
+    /// Maximum total size of cached responses, in bytes. 0 disables the cache
+    #[serde(default = "ResponseCacheConfig::default_max_bytes")]
+    pub max_bytes: usize,
+
+    /// Maximum number of cached responses
+    #[serde(default = "ResponseCacheConfig::default_max_entries")]
+    pub max_entries: usize,
+}
+impl ResponseCacheConfig {
+    fn default_max_bytes() -> usize {
+        512*1024*1024
+    }
+
+    fn default_max_entries() -> usize {
+        100000
+    }
+}
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        ResponseCacheConfig {
+            max_bytes: ResponseCacheConfig::default_max_bytes(),
+            max_entries: ResponseCacheConfig::default_max_entries(),
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// What a cached response is for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ResponseCacheKey {
+    FeatureCounts(String, String, String, CountNormalization),  // dataset, count table, feature, normalization
+    MetaColumn(String, String),             // dataset, column
+    Barcodes(String),                       // dataset
+}
+
+
+////////////////////////////////////////////////////////////
+/// Cache state; kept behind a single lock, which is only held briefly
+struct ResponseCacheInner {
+    entries: LruCache<ResponseCacheKey, Bytes>,
+    total_bytes: usize,
+    num_hits: u64,
+    num_misses: u64,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Size-bounded LRU cache of ready-to-send (CBOR encoded) responses
+pub struct ResponseCache {
+    config: ResponseCacheConfig,
+    inner: Mutex<ResponseCacheInner>,
+}
+impl ResponseCache {
+
+    ////////////////////////////////////////////////////////////
+    /// Constructor
+    pub fn new(config: ResponseCacheConfig) -> ResponseCache {
+        ResponseCache {
+            config: config,
+            inner: Mutex::new(ResponseCacheInner {
+                entries: LruCache::unbounded(),
+                total_bytes: 0,
+                num_hits: 0,
+                num_misses: 0,
+            })
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Look up a response. Bytes are reference counted, so this does not copy the data
+    pub fn get(&self, key: &ResponseCacheKey) -> Option<Bytes> {
+        let mut inner = self.inner.lock().unwrap();
+        let out = inner.entries.get(key).cloned();
+        if out.is_some() {
+            inner.num_hits += 1;
+        } else {
+            inner.num_misses += 1;
+        }
+        out
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Forget a response, when the data behind it has changed
+    pub fn remove(&self, key: &ResponseCacheKey) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = inner.entries.pop(key) {
+            inner.total_bytes -= old.len();
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Store a response, evicting the least recently used ones until within limits
+    pub fn insert(&self, key: ResponseCacheKey, value: Bytes) {
+        //Responses larger than the cache itself are not worth keeping
+        if value.len() > self.config.max_bytes || self.config.max_entries == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.total_bytes += value.len();
+        if let Some(old) = inner.entries.put(key, value) {
+            inner.total_bytes -= old.len();
+        }
+        let mut num_evicted = 0;
+        while inner.total_bytes > self.config.max_bytes || inner.entries.len() > self.config.max_entries {
+            if let Some((_k, v)) = inner.entries.pop_lru() {
+                inner.total_bytes -= v.len();
+                num_evicted += 1;
+            } else {
+                break;
+            }
+        }
+        if num_evicted > 0 {
+            println!("Response cache evicted {} entries; hits: {} misses: {} entries: {} size: {} bytes",
+                num_evicted,
+                inner.num_hits,
+                inner.num_misses,
+                inner.entries.len(),
+                inner.total_bytes
+            );
+        }
+    }
+}
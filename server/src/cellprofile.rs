@@ -0,0 +1,198 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::Context;
+
+use my_web_app::CellProfileMatrix;
+use my_web_app::CellProfileResponse;
+
+use crate::datasource::DataSource;
+use crate::h5ad::transpose_csr;
+use crate::index::ListFiles;
+
+/// Most features listed per count matrix. Requests can ask for fewer
+pub const CELL_PROFILE_MAX_TOP_N: usize = 1000;
+
+/// Most memory taken by the cell-major copies of the count matrices of one dataset
+pub const CELL_MAJOR_CACHE_MAX_BYTES: usize = 2 * 1024 * 1024 * 1024;
+
+
+////////////////////////////////////////////////////////////
+/// Count matrix in compressed row format where rows are cells. Count matrices
+/// are stored feature-major, so this is the transpose, built when first needed
+pub struct CellMajorMatrix {
+    pub indptr: Vec<u32>,
+    pub indices: Vec<u32>,   // Feature index
+    pub data: Vec<f32>,
+}
+impl CellMajorMatrix {
+
+    ////////////////////////////////////////////////////////////
+    /// Transpose a feature-major matrix
+    pub fn from_feature_major(indptr: &Vec<u32>, indices: &Vec<u32>, data: &Vec<f32>, num_cells: usize) -> anyhow::Result<CellMajorMatrix> {
+        let (indptr, indices, data) = transpose_csr(indptr, indices, data, num_cells)?;
+        Ok(CellMajorMatrix {
+            indptr,
+            indices,
+            data,
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Memory taken by the copy
+    pub fn num_bytes(&self) -> usize {
+        std::mem::size_of::<u32>() * (self.indptr.len() + self.indices.len()) + std::mem::size_of::<f32>() * self.data.len()
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the non-zero counts of one cell, as feature indices and values
+    pub fn get_cell(&self, cell: u32) -> anyhow::Result<(Vec<u32>, Vec<f32>)> {
+        let from = *self.indptr.get(cell as usize).context("Cell out of range")? as usize;
+        let to = *self.indptr.get(1 + cell as usize).context("Cell out of range")? as usize;
+        Ok((self.indices[from..to].to_vec(), self.data[from..to].to_vec()))
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Cell-major copy of one matrix, if built. Has its own lock so that building
+/// one copy does not hold up requests for the others
+struct CellMajorSlot {
+    matrix: Mutex<Option<Arc<CellMajorMatrix>>>,
+    /// Value of the use counter when last asked for
+    last_used: AtomicU64,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Cell-major copies of count matrices, built on demand. A copy takes as much
+/// memory as the matrix itself, so it is only made once a cell has been asked for.
+/// The least recently used copies are dropped once they take more than CELL_MAJOR_CACHE_MAX_BYTES
+pub struct CellMajorCache {
+    slots: Mutex<HashMap<String, Arc<CellMajorSlot>>>,
+    use_counter: AtomicU64,
+}
+impl CellMajorCache {
+
+    ////////////////////////////////////////////////////////////
+    /// Constructor
+    pub fn new() -> CellMajorCache {
+        CellMajorCache {
+            slots: Mutex::new(HashMap::new()),
+            use_counter: AtomicU64::new(0),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the cell-major copy of a matrix, building it if needed. Only the lock of this matrix is held
+    /// while building, so concurrent requests for it only transpose it once, while other matrices can still be used
+    pub fn get_or_build<F>(&self, count_name: &String, build: F) -> anyhow::Result<Arc<CellMajorMatrix>> where F: FnOnce() -> anyhow::Result<CellMajorMatrix> {
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            Arc::clone(slots.entry(count_name.clone()).or_insert_with(|| Arc::new(CellMajorSlot {
+                matrix: Mutex::new(None),
+                last_used: AtomicU64::new(0),
+            })))
+        };
+        slot.last_used.store(self.use_counter.fetch_add(1, AtomicOrdering::Relaxed), AtomicOrdering::Relaxed);
+
+        let mat = {
+            let mut matrix = slot.matrix.lock().unwrap();
+            if let Some(mat) = matrix.as_ref() {
+                return Ok(Arc::clone(mat));
+            }
+            println!("Building cell-major copy of count matrix {}", count_name);
+            let mat = Arc::new(build()?);
+            *matrix = Some(Arc::clone(&mat));
+            mat
+        };
+        self.evict(count_name);
+        Ok(mat)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Drop the least recently used copies until they fit in CELL_MAJOR_CACHE_MAX_BYTES. The given matrix is
+    /// always kept, however large. Copies still used by a request are freed once it is done
+    fn evict(&self, keep: &String) {
+        let slots = self.slots.lock().unwrap();
+
+        //Copies being built are locked, and left alone
+        let mut list_built = slots.iter().filter_map(|(count_name, slot)| {
+            let matrix = slot.matrix.try_lock().ok()?;
+            let num_bytes = matrix.as_ref()?.num_bytes();
+            Some((slot.last_used.load(AtomicOrdering::Relaxed), count_name.clone(), num_bytes))
+        }).collect::<Vec<_>>();
+        list_built.sort();
+
+        let mut total_bytes = list_built.iter().map(|(_, _, num_bytes)| *num_bytes).sum::<usize>();
+        for (_, count_name, num_bytes) in list_built {
+            if total_bytes <= CELL_MAJOR_CACHE_MAX_BYTES {
+                break;
+            }
+            if &count_name != keep {
+                if let Ok(mut matrix) = slots[&count_name].matrix.try_lock() {
+                    println!("Dropping cell-major copy of count matrix {}", count_name);
+                    *matrix = None;
+                    total_bytes -= num_bytes;
+                }
+            }
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Gather everything about one cell: its name, all metadata values,
+/// the features with the highest counts in each count matrix, and its files of reads
+pub fn compute_cell_profile(ds: &dyn DataSource, shards: &ListFiles, cell: usize, top_n: usize) -> anyhow::Result<CellProfileResponse> {
+    let desc = ds.get_desc()?;
+
+    let num_cells = ds.get_num_cells()?;
+    if cell >= num_cells {
+        anyhow::bail!("Cell {} out of range; there are {} cells", cell, num_cells);
+    }
+    let barcode = ds.get_barcode(cell)?;
+
+    //Metadata values as text. Only the values of this cell are read
+    let mut meta = BTreeMap::new();
+    for column_name in desc.meta.keys() {
+        meta.insert(column_name.clone(), ds.get_meta_label(column_name, cell)?);
+    }
+
+    //Top features of each matrix
+    let mut matrices = BTreeMap::new();
+    for count_name in desc.matrices.keys() {
+        let mat = ds.get_matrix(count_name)?;
+        let (indices, data) = ds.get_cell_counts(count_name, cell as u32)?;
+
+        let total = data.iter().map(|x| *x as f64).sum::<f64>() as f32;
+        let mut list_features = indices.iter().zip(data.iter()).collect::<Vec<_>>();
+        list_features.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap_or(Ordering::Equal));
+        let top_features = list_features.iter().take(top_n).map(|(i, v)| {
+            let feature_name = mat.list_feature_names.get(**i as usize).cloned().unwrap_or_else(|| format!("#{}", i));
+            (feature_name, **v)
+        }).collect::<Vec<_>>();
+
+        matrices.insert(count_name.clone(), CellProfileMatrix {
+            total: total,
+            num_detected: indices.len(),
+            top_features: top_features,
+        });
+    }
+
+    //Reads of the cell, if the dataset has them
+    let read_files = shards.list_cell_files(&barcode)?;
+
+    Ok(CellProfileResponse {
+        cell: cell,
+        barcode: barcode,
+        meta: meta,
+        matrices: matrices,
+        read_files: read_files,
+    })
+}
@@ -1,8 +1,10 @@
 use std::{collections::HashMap, path::PathBuf};
 use hdf5::File;
+use hdf5::types::TypeDescriptor;
 //use ndarray::{arr2, s};
+use ndarray::s;
 
-use my_web_app::countfile_struct::CountFileMat;
+use my_web_app::countfile_struct::CountFileMatDesc;
 use my_web_app::countfile_struct::CountFileMetaColumnDesc;
 use my_web_app::countfile_struct::CountFileRed;
 use my_web_app::CountFileMetaColumnData;
@@ -14,6 +16,20 @@ use ndarray::Axis;
 
 use anyhow::Context;
 
+use crate::cellprofile::CellMajorCache;
+use crate::cellprofile::CellMajorMatrix;
+use crate::datasource::DataSource;
+use crate::normalize::add_cell_totals;
+use crate::normalize::CELL_TOTALS_CHUNK;
+use crate::datasource::make_numbered_barcode;
+use crate::datasource::make_numbered_barcodes;
+
+/// When reading many features, ranges of indptr less than this far apart are read as one block
+const BATCH_MAX_GAP: usize = 4096;
+
+/// Most feature names returned in one page. Requests can ask for fewer
+pub const FEATURE_NAMES_MAX_PAGE: usize = 10000;
+
 
 ////////////////////////////////////////////////////////////
 /// Reader of an HDF5 file, with a format similar to anndata
@@ -22,12 +38,144 @@ pub struct CountFile {
     pub matrices: HashMap<String, CountFileMat>,
     pub reductions: HashMap<String, CountFileRed>,    
     pub meta: HashMap<String, CountFileMetaColumnDesc>,
+    pub layout: CountFileLayout,
+    pub cell_major: CellMajorCache,
+    /// Total counts of each cell, per matrix. Used as size factors when normalizing
+    pub cell_totals: HashMap<String, Vec<f64>>,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Pointers into a sparse matrix stored on disk. Only kept on the server;
+/// clients get a CountFileMatDesc, and list feature names on demand
+pub struct CountFileMat {
+    pub list_feature_names: Vec<String>,
+    pub list_indptr: Vec<u32>,
+    pub map_feature_names_pos: HashMap<String, usize>,
+    /// Number of stored values. Dense matrices have no pointers, so this is counted while indexing
+    pub num_nonzero: u64,
 }
-impl CountFile {
+impl CountFileMat {
+
+    ////////////////////////////////////////////////////////////
+    /// Constructor. Builds the map from feature name to position, and takes the number of values from the pointers
+    pub fn new(list_feature_names: Vec<String>, list_indptr: Vec<u32>) -> CountFileMat {
+        let mut map_feature_names_pos = HashMap::new();
+        for (i,v) in list_feature_names.iter().enumerate() {
+            map_feature_names_pos.insert(v.clone(), i);
+        }
+        let num_nonzero = list_indptr.last().map(|x| *x as u64).unwrap_or(0);
+        CountFileMat {
+            list_feature_names,
+            list_indptr,
+            map_feature_names_pos,
+            num_nonzero,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get feature names from offset, at most limit of them. Offsets past the end give an empty page
+    pub fn get_feature_names_page(&self, offset: usize, limit: usize) -> Vec<String> {
+        let from = offset.min(self.list_feature_names.len());
+        let to = offset.saturating_add(limit).min(self.list_feature_names.len());
+        self.list_feature_names[from..to].to_vec()
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Summary to send to clients
+    pub fn get_desc(&self) -> CountFileMatDesc {
+        CountFileMatDesc {
+            num_features: self.list_feature_names.len(),
+            num_nonzero: self.num_nonzero,
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Where each object is stored in the HDF5 file. This differs between biscvi5 and h5ad files
+pub struct CountFileLayout {
+    pub matrices: HashMap<String, MatrixLocation>,
+    pub reductions: HashMap<String, ReductionLocation>,
+    pub meta: HashMap<String, MetaColumnLocation>,
+    /// Dataset of cell names, if any
+    pub barcodes: Option<String>,
+}
+impl CountFileLayout {
+
+    ////////////////////////////////////////////////////////////
+    /// Constructor
+    pub fn new() -> CountFileLayout {
+        CountFileLayout {
+            matrices: HashMap::new(),
+            reductions: HashMap::new(),
+            meta: HashMap::new(),
+            barcodes: None,
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Location of a count matrix. Rows are features, as stored in biscvi5 files
+pub enum MatrixLocation {
+    /// Group with feature-major indices and data. Pointers are in CountFileMat
+    SparseOnDisk(String),
+    /// Cell-major matrix that has been transposed while indexing: indices, data
+    SparseInMemory(Vec<u32>, Vec<f32>),
+    /// Dense 2D dataset, cell x feature
+    DenseOnDisk(String),
+}
+
+
+////////////////////////////////////////////////////////////
+/// Location of a reduction
+pub struct ReductionLocation {
+    pub path: String,
+    /// If true, stored as (cell, dim). Otherwise (dim, cell) as in biscvi5 files
+    pub cell_major: bool,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Location of a metadata column
+pub enum MetaColumnLocation {
+    /// Dataset of numbers
+    Numeric(String),
+    /// Group with "codes" dataset. Negative codes are missing values
+    Categorical(String),
+    /// Strings converted to categories while indexing
+    CategoricalInMemory(Vec<u32>),
+    /// Dataset of booleans
+    Boolean(String),
+    /// Dataset of integers
+    Integer(String),
+    /// Dataset of strings
+    Text(String),
+    /// Group with "values" and "mask" datasets, as anndata nullable arrays. The mask is true where missing.
+    /// The type of the values is given by the column description
+    Nullable(String),
+}
+impl MetaColumnLocation {
+
+    ////////////////////////////////////////////////////////////
+    /// Location of a column stored as a plain dataset
+    pub fn for_dataset(desc: &CountFileMetaColumnDesc, path: String) -> MetaColumnLocation {
+        match desc {
+            CountFileMetaColumnDesc::Boolean() => MetaColumnLocation::Boolean(path),
+            CountFileMetaColumnDesc::Integer() => MetaColumnLocation::Integer(path),
+            CountFileMetaColumnDesc::Text() => MetaColumnLocation::Text(path),
+            _ => MetaColumnLocation::Numeric(path),
+        }
+    }
+}
+
+
+impl DataSource for CountFile {
 
     ////////////////////////////////////////////////////////////
     /// Retrieve feature index by name
-    pub fn get_feature_index(&self, count_name: &String, feature_name: &String) -> anyhow::Result<usize> {
+    fn get_feature_index(&self, count_name: &String, feature_name: &String) -> anyhow::Result<usize> {
         let mat = self.matrices.get(count_name);
         if let Some(mat) = mat {
             if let Some(id) = mat.map_feature_names_pos.get(feature_name) {
@@ -42,28 +190,53 @@ impl CountFile {
 
     ////////////////////////////////////////////////////////////
     /// Retrieve all feature counts for a given cell
-    pub fn get_counts_for_cell(&self, count_name: &String, row: u32) -> anyhow::Result<MetadataColumnResponse> {
+    fn get_counts_for_cell(&self, count_name: &String, row: u32) -> anyhow::Result<MetadataColumnResponse> {
         
-        let group_counts = self.file.group("/counts")?; 
-        let group_cnt = group_counts.group(&count_name)?;
-
         let cnt = self.matrices.get(count_name.into()).context("err0")?;//.ok_or("Could not get matrix")?; // .expect("could not get matrix");  // D
-
-        let row_start = *cnt.list_indptr.get(row as usize).context("err1")? as usize;
-        let row_end = *cnt.list_indptr.get(1 + row as usize).context("err2")? as usize;
-
-        let df_data = group_cnt.dataset("data")?;
-        let df_indices = group_cnt.dataset("indices")?;
-        
-        //Get column indices
-        let ret_indices = df_indices.read_slice_1d::<u32, _>(
-            row_start..row_end
-        )?.iter().map(|x| *x).collect::<Vec<_>>();        
-
-        //Get values at given columns
-        let ret_data = df_data.read_slice_1d::<f32, _>(
-            row_start..row_end
-        )?.iter().map(|x| *x).collect::<Vec<_>>();
+        let loc = self.layout.matrices.get(count_name).context("Missing location of count matrix")?;
+
+        let (ret_indices, ret_data) = match loc {
+            MatrixLocation::SparseOnDisk(path) => {
+                let group_cnt = self.file.group(&path)?;
+
+                let row_start = *cnt.list_indptr.get(row as usize).context("err1")? as usize;
+                let row_end = *cnt.list_indptr.get(1 + row as usize).context("err2")? as usize;
+
+                let df_data = group_cnt.dataset("data")?;
+                let df_indices = group_cnt.dataset("indices")?;
+                
+                //Get column indices
+                let ret_indices = df_indices.read_slice_1d::<u32, _>(
+                    row_start..row_end
+                )?.iter().map(|x| *x).collect::<Vec<_>>();        
+
+                //Get values at given columns
+                let ret_data = df_data.read_slice_1d::<f32, _>(
+                    row_start..row_end
+                )?.iter().map(|x| *x).collect::<Vec<_>>();
+
+                (ret_indices, ret_data)
+            },
+            MatrixLocation::SparseInMemory(indices, data) => {
+                let row_start = *cnt.list_indptr.get(row as usize).context("err1")? as usize;
+                let row_end = *cnt.list_indptr.get(1 + row as usize).context("err2")? as usize;
+                (indices[row_start..row_end].to_vec(), data[row_start..row_end].to_vec())
+            },
+            MatrixLocation::DenseOnDisk(path) => {
+                //Pick out the column, and only keep non-zero values
+                let ds = self.file.dataset(&path)?;
+                let column = ds.read_slice_1d::<f32, _>(s![.., row as usize])?;
+                let mut ret_indices = Vec::new();
+                let mut ret_data = Vec::new();
+                for (i, v) in column.iter().enumerate() {
+                    if *v != 0.0 {
+                        ret_indices.push(i as u32);
+                        ret_data.push(*v);
+                    }
+                }
+                (ret_indices, ret_data)
+            },
+        };
 
         let v = CountFileMetaColumnData::SparseNumeric(
             ret_indices,
@@ -75,31 +248,146 @@ impl CountFile {
         })
     }
 
+    ////////////////////////////////////////////////////////////
+    /// Get the total counts of each cell, computed while indexing
+    fn get_cell_totals(&self, count_name: &String) -> anyhow::Result<&Vec<f64>> {
+        self.cell_totals.get(count_name).context("Could not find count matrix")
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve the counts of one cell. Dense matrices are cell-major and read directly;
+    /// sparse matrices are transposed in memory the first time
+    fn get_cell_counts(&self, count_name: &String, cell: u32) -> anyhow::Result<(Vec<u32>, Vec<f32>)> {
+        let cnt = self.matrices.get(count_name).context("Could not find count matrix")?;
+        let loc = self.layout.matrices.get(count_name).context("Missing location of count matrix")?;
+
+        if let MatrixLocation::DenseOnDisk(path) = loc {
+            let ds = self.file.dataset(&path)?;
+            let row = ds.read_slice_1d::<f32, _>(s![cell as usize, ..])?;
+            let mut ret_indices = Vec::new();
+            let mut ret_data = Vec::new();
+            for (i, v) in row.iter().enumerate() {
+                if *v != 0.0 {
+                    ret_indices.push(i as u32);
+                    ret_data.push(*v);
+                }
+            }
+            return Ok((ret_indices, ret_data));
+        }
+
+        let mat = self.cell_major.get_or_build(count_name, || {
+            let num_cells = self.get_num_cells()?;
+            match loc {
+                MatrixLocation::SparseOnDisk(path) => {
+                    let group_cnt = self.file.group(&path)?;
+                    let indices = read_hdf5_u32vec(&group_cnt.dataset("indices")?)?;
+                    let data = read_hdf5_f32vec(&group_cnt.dataset("data")?)?;
+                    CellMajorMatrix::from_feature_major(&cnt.list_indptr, &indices, &data, num_cells)
+                },
+                MatrixLocation::SparseInMemory(indices, data) => {
+                    CellMajorMatrix::from_feature_major(&cnt.list_indptr, indices, data, num_cells)
+                },
+                MatrixLocation::DenseOnDisk(_) => {
+                    anyhow::bail!("Dense matrices are already cell-major")
+                },
+            }
+        })?;
+        mat.get_cell(cell)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve counts for many features at once. For sparse matrices on disk, the
+    /// indptr ranges are sorted and merged, such that the file is read in a single pass
+    fn get_counts_batch(&self, count_name: &String, rows: &Vec<u32>) -> anyhow::Result<Vec<MetadataColumnResponse>> {
+
+        let cnt = self.matrices.get(count_name).context("Could not find count matrix")?;
+        let loc = self.layout.matrices.get(count_name).context("Missing location of count matrix")?;
+
+        let path = if let MatrixLocation::SparseOnDisk(path) = loc {
+            path
+        } else {
+            //Already in memory, or dense; nothing to gain
+            return rows.iter().map(|row| self.get_counts_for_cell(count_name, *row)).collect();
+        };
+
+        //Figure out which part of the file each feature is in
+        let mut ranges = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            let row_start = *cnt.list_indptr.get(*row as usize).context("err1")? as usize;
+            let row_end = *cnt.list_indptr.get(1 + *row as usize).context("err2")? as usize;
+            ranges.push((row_start, row_end, i));
+        }
+        ranges.sort();
+
+        let group_cnt = self.file.group(&path)?;
+        let df_data = group_cnt.dataset("data")?;
+        let df_indices = group_cnt.dataset("indices")?;
+
+        //Merge ranges that are close, then read each merged block once
+        let mut out: Vec<Option<MetadataColumnResponse>> = vec![None; rows.len()];
+        let mut block_i = 0;
+        while block_i < ranges.len() {
+            let block_start = ranges[block_i].0;
+            let mut block_end = ranges[block_i].1;
+            let mut block_last = block_i;
+            while block_last + 1 < ranges.len() && ranges[block_last + 1].0 <= block_end + BATCH_MAX_GAP {
+                block_last += 1;
+                block_end = block_end.max(ranges[block_last].1);
+            }
+
+            let block_indices = df_indices.read_slice_1d::<u32, _>(block_start..block_end)?;
+            let block_data = df_data.read_slice_1d::<f32, _>(block_start..block_end)?;
+
+            for (row_start, row_end, i) in &ranges[block_i..=block_last] {
+                let from = row_start - block_start;
+                let to = row_end - block_start;
+                out[*i] = Some(MetadataColumnResponse {
+                    data: CountFileMetaColumnData::SparseNumeric(
+                        block_indices.slice(s![from..to]).to_vec(),
+                        block_data.slice(s![from..to]).to_vec(),
+                    )
+                });
+            }
+            block_i = block_last + 1;
+        }
+
+        Ok(out.into_iter().map(|x| x.expect("All features read")).collect())
+    }
+
 
 
     ////////////////////////////////////////////////////////////
     /// Read the reduction coordinates from the file
-    pub fn get_reduction(&self, reduction_name: &String) -> anyhow::Result<ReductionResponse> {
-        let group_counts = self.file.group("/reductions")?; 
-        //let df_thisred = group_counts.dataset(&reduction_name)?;
-        if let Ok(df_thisred) = group_counts.dataset(&reduction_name) {
-            //Check that it is there. Need no further info right now
-            let _red = self.reductions.get(reduction_name.into()).context("0")?;
+    fn get_reduction(&self, reduction_name: &String) -> anyhow::Result<ReductionResponse> {
+        if let Some(loc) = self.layout.reductions.get(reduction_name) {
+            let df_thisred = self.file.dataset(&loc.path)?;
+
+            //Check that it is there
+            let red = self.reductions.get(reduction_name.into()).context("0")?;
 
             let my_array = df_thisred.read_2d::<f32>()?;
 
             //println!("got {:?}",my_array);
 
-            let subarray_x = my_array.select(Axis(0), &[0]);
-            let subarray_y = my_array.select(Axis(0), &[1]);
+            let axis_dim = if loc.cell_major { Axis(1) } else { Axis(0) };
+            let subarray_x = my_array.select(axis_dim, &[0]);
+            let subarray_y = my_array.select(axis_dim, &[1]);
 
             let x=subarray_x.iter().map(|x| *x).collect::<Vec<_>>();
             let y=subarray_y.iter().map(|x| *x).collect::<Vec<_>>();
 
+            //Third axis, only for 3D reductions. Larger ones, such as PCA, are shown by their first two axes
+            let z = if red.num_dim == 3 {
+                let subarray_z = my_array.select(axis_dim, &[2]);
+                Some(subarray_z.iter().map(|x| *x).collect::<Vec<_>>())
+            } else {
+                None
+            };
+
             //println!("got {:?}",x);
 
             let out = ReductionResponse {
-                x,y
+                x,y,z
             };
             Ok(out)
         } else {
@@ -107,21 +395,28 @@ impl CountFile {
         }
     }
 
+    ////////////////////////////////////////////////////////////
+    /// Read all dimensions of a reduction from the file
+    fn get_reduction_dims(&self, reduction_name: &String) -> anyhow::Result<Vec<Vec<f32>>> {
+        let loc = self.layout.reductions.get(reduction_name).context(format!("Failed to find reduction {}", reduction_name))?;
+        let my_array = self.file.dataset(&loc.path)?.read_2d::<f32>()?;
+        let axis_dim = if loc.cell_major { Axis(1) } else { Axis(0) };
+        Ok(my_array.axis_iter(axis_dim).map(|dim| dim.to_vec()).collect())
+    }
+
 
 
     ////////////////////////////////////////////////////////////
     /// Get all values for a metadata column
-    pub fn get_metacolumn(&self, column_name: &String) -> anyhow::Result<MetadataColumnResponse> {
-
-        let group_meta = self.file.group("/obs")?; 
+    fn get_metacolumn(&self, column_name: &String) -> anyhow::Result<MetadataColumnResponse> {
 
         //Check that it is there. Need no further info right now
-        if let Some(red) = self.meta.get(column_name.into()) {
+        if let (Some(red), Some(loc)) = (self.meta.get(column_name.into()), self.layout.meta.get(column_name)) {
 
-            match red {
-                CountFileMetaColumnDesc::Numeric() => {
+            match (red, loc) {
+                (CountFileMetaColumnDesc::Numeric(), MetaColumnLocation::Numeric(path)) => {
 
-                    let df_thiscol = group_meta.dataset(&column_name)?;
+                    let df_thiscol = self.file.dataset(&path)?;
                     let data = read_hdf5_f32vec(&df_thiscol)?;                
 
                     let out = MetadataColumnResponse {
@@ -129,17 +424,41 @@ impl CountFile {
                     };
                     Ok(out)                
                 },
-                CountFileMetaColumnDesc::Categorical(cats) => {
+                (CountFileMetaColumnDesc::Categorical(cats), MetaColumnLocation::Categorical(path)) => {
 
-                    let group_thiscol = group_meta.group(&column_name)?;
+                    let group_thiscol = self.file.group(&path)?;
 
                     let df_thiscol = group_thiscol.dataset("codes")?;
-                    let data = read_hdf5_u32vec(&df_thiscol)?;                
+                    let data = read_hdf5_codes(&df_thiscol, cats.len())?;                
 
                     let out = MetadataColumnResponse {
                         data: CountFileMetaColumnData::Categorical(data, cats.clone())
                     };
                     Ok(out)                
+                },
+                (CountFileMetaColumnDesc::Categorical(cats), MetaColumnLocation::CategoricalInMemory(data)) => {
+                    let out = MetadataColumnResponse {
+                        data: CountFileMetaColumnData::Categorical(data.clone(), cats.clone())
+                    };
+                    Ok(out)                
+                },
+                (_, MetaColumnLocation::Boolean(path)) | (_, MetaColumnLocation::Integer(path)) | (_, MetaColumnLocation::Text(path)) => {
+                    let df_thiscol = self.file.dataset(&path)?;
+                    let out = MetadataColumnResponse {
+                        data: read_hdf5_metavec(&df_thiscol, red, None)?
+                    };
+                    Ok(out)
+                },
+                (_, MetaColumnLocation::Nullable(path)) => {
+                    let group_thiscol = self.file.group(&path)?;
+                    let mask = read_hdf5_boolvec(&group_thiscol.dataset("mask")?)?;
+                    let out = MetadataColumnResponse {
+                        data: read_hdf5_metavec(&group_thiscol.dataset("values")?, red, Some(&mask))?
+                    };
+                    Ok(out)
+                },
+                _ => {
+                    anyhow::bail!(format!("Inconsistent type of metadata column {}", column_name))
                 }
             }            
 
@@ -151,14 +470,73 @@ impl CountFile {
 
     ////////////////////////////////////////////////////////////
     /// Get a description of the dataset
-    pub fn get_desc(&self) -> anyhow::Result<DatasetDescResponse> {
+    fn get_desc(&self) -> anyhow::Result<DatasetDescResponse> {
         Ok(DatasetDescResponse {
-            matrices: self.matrices.clone(),
+            matrices: self.matrices.iter().map(|(k, mat)| (k.clone(), mat.get_desc())).collect(),
             reductions: self.reductions.clone(),
             meta: self.meta.clone(),
+            user_meta: Vec::new(),
         })
     }    
 
+    ////////////////////////////////////////////////////////////
+    /// Get the feature names and pointers of a count matrix
+    fn get_matrix(&self, count_name: &String) -> anyhow::Result<&CountFileMat> {
+        self.matrices.get(count_name).context(format!("Could not find count matrix {}", count_name))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the names of all cells. Files without stored names get numbered cells
+    fn get_barcodes(&self) -> anyhow::Result<Vec<String>> {
+        if let Some(path) = &self.layout.barcodes {
+            read_hdf5_stringvec(&self.file.dataset(path)?)
+        } else {
+            Ok(make_numbered_barcodes(self.get_num_cells()?))
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the name of one cell, without reading the others
+    fn get_barcode(&self, cell: usize) -> anyhow::Result<String> {
+        if let Some(path) = &self.layout.barcodes {
+            read_hdf5_string_at(&self.file.dataset(path)?, cell)
+        } else {
+            Ok(make_numbered_barcode(cell))
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the value of one cell in a metadata column as text, without reading the whole column
+    fn get_meta_label(&self, column_name: &String, cell: usize) -> anyhow::Result<Option<String>> {
+        let desc = self.meta.get(column_name).context(format!("Failed to find metadata column {}", column_name))?;
+        let loc = self.layout.meta.get(column_name).context(format!("Failed to find metadata column {}", column_name))?;
+        match (desc, loc) {
+            (CountFileMetaColumnDesc::Categorical(cats), MetaColumnLocation::Categorical(path)) => {
+                let codes = self.file.group(&path)?.dataset("codes")?.read_slice_1d::<i32, _>(cell..(cell+1))?;
+                let code = *codes.get(0).context("Cell out of range")?;
+                Ok(if code < 0 { None } else { cats.get(code as usize).cloned() })
+            },
+            (CountFileMetaColumnDesc::Categorical(cats), MetaColumnLocation::CategoricalInMemory(codes)) => {
+                let code = *codes.get(cell).context("Cell out of range")?;
+                Ok(cats.get(code as usize).cloned())
+            },
+            (_, MetaColumnLocation::Numeric(path)) | (_, MetaColumnLocation::Boolean(path)) | (_, MetaColumnLocation::Integer(path)) | (_, MetaColumnLocation::Text(path)) => {
+                Ok(read_hdf5_metavalue(&self.file.dataset(&path)?, desc, cell)?.get_label(0))
+            },
+            (_, MetaColumnLocation::Nullable(path)) => {
+                let group_thiscol = self.file.group(&path)?;
+                if read_hdf5_bool_at(&group_thiscol.dataset("mask")?, cell)? {
+                    Ok(None)
+                } else {
+                    Ok(read_hdf5_metavalue(&group_thiscol.dataset("values")?, desc, cell)?.get_label(0))
+                }
+            },
+            _ => {
+                anyhow::bail!(format!("Inconsistent type of metadata column {}", column_name))
+            }
+        }
+    }
+
 
 }
 
@@ -176,8 +554,11 @@ pub fn index_countfile(p: &PathBuf) -> anyhow::Result<CountFile> {
     let file = hdf5::File::open(p)?; 
 
     /////// Gather all count matrices
+    let mut layout = CountFileLayout::new();
+
     let group_counts = file.group("/counts")?; 
     let mut map_matrices: HashMap<String, CountFileMat> = HashMap::new();
+    let mut map_cell_totals: HashMap<String, Vec<f64>> = HashMap::new();
     let count_names = group_counts.member_names()?;
     for count_name in count_names {
         let cnt = group_counts.group(&count_name)?;
@@ -193,13 +574,14 @@ pub fn index_countfile(p: &PathBuf) -> anyhow::Result<CountFile> {
         let list_indptr = read_hdf5_u32vec(&cnt.dataset("indptr")?)?;
         let list_feature_names = read_hdf5_stringvec(&cnt.dataset("feature_names")?)?;
 
-        let mut c = CountFileMat {
-            list_feature_names: list_feature_names,
-            list_indptr: list_indptr,
-            map_feature_names_pos: HashMap::new(),
-        };
-        c.build_map();
-        map_matrices.insert(count_name.clone(), c);
+        map_matrices.insert(count_name.clone(), CountFileMat::new(list_feature_names, list_indptr));
+
+        //This is the only read of all indices and data at startup; the check before indexing
+        //only looks at the structure of the file
+        let loc = MatrixLocation::SparseOnDisk(format!("/counts/{}", count_name));
+        let (cell_totals, _num_nonzero) = compute_cell_totals(&file, &loc)?;
+        map_cell_totals.insert(count_name.clone(), cell_totals);
+        layout.matrices.insert(count_name.clone(), loc);
     }
 
     /////// Gather all reductions
@@ -214,14 +596,19 @@ pub fn index_countfile(p: &PathBuf) -> anyhow::Result<CountFile> {
         let shape = ds_thisred.shape();
         println!("indexing reduction {} with dim {:?}",red_name, shape);
 
-        let num_sample = *shape.get(0).expect("Failed to get num samples for red");
-        let num_dim = *shape.get(1).expect("Failed to get num dimensions for red");
+        // Stored as (dim, cell), as written by biscvi-convert and checked by check_reduction
+        let num_dim = *shape.get(0).context(format!("Failed to get num dimensions for reduction {}", red_name))?;
+        let num_sample = *shape.get(1).context(format!("Failed to get num samples for reduction {}", red_name))?;
 
         let c = CountFileRed {
             num_sample: num_sample,
             num_dim: num_dim,
         };
         map_reductions.insert(red_name.clone(), c);
+        layout.reductions.insert(red_name.clone(), ReductionLocation {
+            path: format!("/reductions/{}", red_name),
+            cell_major: false,
+        });
 
     }
 
@@ -234,18 +621,46 @@ pub fn index_countfile(p: &PathBuf) -> anyhow::Result<CountFile> {
     for meta_name in meta_names {
         let ds_thismeta = group_meta.dataset(&meta_name);
 
-        let desc = if let Ok(_ds_thismeta) = ds_thismeta {
-            CountFileMetaColumnDesc::Numeric()
+        let path = format!("/obs/{}", meta_name);
+        let (desc, loc) = if let Ok(ds_thismeta) = ds_thismeta {
+            let desc = match get_hdf5_meta_type(&ds_thismeta) {
+                Ok(Some(desc)) => desc,
+                _ => {
+                    println!("Skipping metadata column {} of unsupported data type", meta_name);
+                    continue;
+                }
+            };
+            let loc = MetaColumnLocation::for_dataset(&desc, path);
+            (desc, loc)
         } else {
             //println!("{}",meta_name);
             let group_thismeta = group_meta.group(&meta_name)?;
-            let ds_categories = group_thismeta.dataset("categories")?;
-            let categories = read_hdf5_stringvec(&ds_categories)?;  //  FixedAscii(19)
-            CountFileMetaColumnDesc::Categorical(categories)
+            if group_thismeta.link_exists("values") && group_thismeta.link_exists("mask") {
+                //Values with missing data
+                let ds_values = group_thismeta.dataset("values")?;
+                let desc = match get_hdf5_meta_type(&ds_values) {
+                    Ok(Some(desc)) => desc,
+                    _ => {
+                        println!("Skipping metadata column {} of unsupported data type", meta_name);
+                        continue;
+                    }
+                };
+                (desc, MetaColumnLocation::Nullable(path))
+            } else {
+                let ds_categories = group_thismeta.dataset("categories")?;
+                let categories = read_hdf5_stringvec(&ds_categories)?;  //  FixedAscii(19)
+                (CountFileMetaColumnDesc::Categorical(categories), MetaColumnLocation::Categorical(path))
+            }
         };
 
         //println!("Meta column {} --- {:?}", meta_name, desc);
         map_meta.insert(meta_name.clone(), desc);
+        layout.meta.insert(meta_name.clone(), loc);
+    }
+
+    /////// Cell names are optional
+    if file.link_exists("barcodes") {
+        layout.barcodes = Some("/barcodes".to_string());
     }
 
     println!("======== parsing count file DONE ========");
@@ -255,19 +670,117 @@ pub fn index_countfile(p: &PathBuf) -> anyhow::Result<CountFile> {
         matrices: map_matrices,
         reductions: map_reductions,
         meta: map_meta,
+        layout: layout,
+        cell_major: CellMajorCache::new(),
+        cell_totals: map_cell_totals,
     })
 }
 
 
 
 ////////////////////////////////////////////////////////////
-/// Read a HDF5 string vector
+/// Sum the counts of each cell in a matrix. Matrices on disk are read in chunks.
+/// Done once per matrix while indexing, and the only full pass over the counts before serving.
+/// Also returns the number of non-zero values, which dense matrices have no pointers to tell
+pub fn compute_cell_totals(file: &File, loc: &MatrixLocation) -> anyhow::Result<(Vec<f64>, u64)> {
+    let mut totals = Vec::new();
+    let mut num_nonzero = 0;
+    match loc {
+        MatrixLocation::SparseOnDisk(path) => {
+            let group_cnt = file.group(&path)?;
+            let df_data = group_cnt.dataset("data")?;
+            let df_indices = group_cnt.dataset("indices")?;
+            let num_values = df_data.size();
+            let mut from = 0;
+            while from < num_values {
+                let to = (from + CELL_TOTALS_CHUNK).min(num_values);
+                let indices = df_indices.read_slice_1d::<u32, _>(from..to)?;
+                let data = df_data.read_slice_1d::<f32, _>(from..to)?;
+                add_cell_totals(&mut totals, indices.as_slice().context("Non-contiguous indices")?, data.as_slice().context("Non-contiguous data")?);
+                from = to;
+            }
+            num_nonzero = num_values as u64;
+        },
+        MatrixLocation::SparseInMemory(indices, data) => {
+            add_cell_totals(&mut totals, indices, data);
+            num_nonzero = data.len() as u64;
+        },
+        MatrixLocation::DenseOnDisk(path) => {
+            //Cell x feature; sum whole rows at a time
+            let ds = file.dataset(&path)?;
+            let shape = ds.shape();
+            let num_cells = *shape.get(0).context("Dense matrix lacks dimensions")?;
+            let num_features = *shape.get(1).context("Dense matrix lacks dimensions")?;
+            let rows_per_chunk = (CELL_TOTALS_CHUNK / num_features.max(1)).max(1);
+            let mut from = 0;
+            while from < num_cells {
+                let to = (from + rows_per_chunk).min(num_cells);
+                let block = ds.read_slice_2d::<f32, _>(s![from..to, ..])?;
+                for row in block.axis_iter(Axis(0)) {
+                    totals.push(row.iter().map(|x| *x as f64).sum());
+                    num_nonzero += row.iter().filter(|x| **x != 0.0).count() as u64;
+                }
+                from = to;
+            }
+        },
+    }
+    Ok((totals, num_nonzero))
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read a HDF5 string vector. Both ASCII and UTF-8 strings are supported
 pub fn read_hdf5_stringvec(ds: &hdf5::Dataset) -> anyhow::Result<Vec<String>>{
-    let v = ds.
-        read_1d::<hdf5::types::VarLenAscii>()?;
-    let v = v.
-        iter().collect::<Vec<_>>();
-    let out = v.iter().map(|s| s.to_string().clone()).collect::<Vec<_>>();
+    if let TypeDescriptor::VarLenUnicode = ds.dtype()?.to_descriptor()? {
+        let v = ds.
+            read_1d::<hdf5::types::VarLenUnicode>()?;
+        let out = v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Ok(out)
+    } else {
+        let v = ds.
+            read_1d::<hdf5::types::VarLenAscii>()?;
+        let v = v.
+            iter().collect::<Vec<_>>();
+        let out = v.iter().map(|s| s.to_string().clone()).collect::<Vec<_>>();
+        Ok(out)
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read one string of a HDF5 string vector
+pub fn read_hdf5_string_at(ds: &hdf5::Dataset, i: usize) -> anyhow::Result<String>{
+    let out = if let TypeDescriptor::VarLenUnicode = ds.dtype()?.to_descriptor()? {
+        ds.read_slice_1d::<hdf5::types::VarLenUnicode, _>(i..(i+1))?.iter().next().map(|s| s.to_string())
+    } else {
+        ds.read_slice_1d::<hdf5::types::VarLenAscii, _>(i..(i+1))?.iter().next().map(|s| s.to_string())
+    };
+    out.context(format!("Index {} out of range", i))
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read one value of a HDF5 vector of booleans. Integers are also accepted, with non-zero being true
+pub fn read_hdf5_bool_at(ds: &hdf5::Dataset, i: usize) -> anyhow::Result<bool>{
+    let out = if let TypeDescriptor::Boolean = ds.dtype()?.to_descriptor()? {
+        ds.read_slice_1d::<bool, _>(i..(i+1))?.iter().next().cloned()
+    } else {
+        ds.read_slice_1d::<i64, _>(i..(i+1))?.iter().next().map(|x| *x != 0)
+    };
+    out.context(format!("Index {} out of range", i))
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read a HDF5 vector of category codes. Negative codes (missing values) are given code num_categories,
+/// which is past the last category and thus seen as missing
+pub fn read_hdf5_codes(ds: &hdf5::Dataset, num_categories: usize) -> anyhow::Result<Vec<u32>>{
+    let v = ds.read_1d::<i32>()?;
+    let out = v.iter().map(|x| if *x < 0 { num_categories as u32 } else { *x as u32 }).collect::<Vec<_>>();
     Ok(out)
 }
 
@@ -289,4 +802,96 @@ pub fn read_hdf5_f32vec(ds: &hdf5::Dataset) -> anyhow::Result<Vec<f32>>{
     let v = ds.read_1d::<f32>()?;
     let out = v.iter().map(|x| *x).collect::<Vec<_>>();
     Ok(out)
-}
\ No newline at end of file
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read a HDF5 vector of booleans. Integers are also accepted, with non-zero being true
+pub fn read_hdf5_boolvec(ds: &hdf5::Dataset) -> anyhow::Result<Vec<bool>>{
+    if let TypeDescriptor::Boolean = ds.dtype()?.to_descriptor()? {
+        let v = ds.read_1d::<bool>()?;
+        Ok(v.iter().map(|x| *x).collect::<Vec<_>>())
+    } else {
+        let v = ds.read_1d::<i64>()?;
+        Ok(v.iter().map(|x| *x != 0).collect::<Vec<_>>())
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read a HDF5 i64 vector
+pub fn read_hdf5_i64vec(ds: &hdf5::Dataset) -> anyhow::Result<Vec<i64>>{
+    let v = ds.read_1d::<i64>()?;
+    let out = v.iter().map(|x| *x).collect::<Vec<_>>();
+    Ok(out)
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Figure out the type of a metadata column stored as a dataset. Returns None if not supported
+pub fn get_hdf5_meta_type(ds: &hdf5::Dataset) -> anyhow::Result<Option<CountFileMetaColumnDesc>> {
+    Ok(match ds.dtype()?.to_descriptor()? {
+        TypeDescriptor::Float(_) => Some(CountFileMetaColumnDesc::Numeric()),
+        TypeDescriptor::Integer(_) | TypeDescriptor::Unsigned(_) => Some(CountFileMetaColumnDesc::Integer()),
+        TypeDescriptor::Boolean => Some(CountFileMetaColumnDesc::Boolean()),
+        TypeDescriptor::VarLenAscii | TypeDescriptor::VarLenUnicode => Some(CountFileMetaColumnDesc::Text()),
+        _ => None
+    })
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read a metadata column stored as a dataset, of the given type. The mask, if given, is true where values are missing
+pub fn read_hdf5_metavec(ds: &hdf5::Dataset, desc: &CountFileMetaColumnDesc, mask: Option<&Vec<bool>>) -> anyhow::Result<CountFileMetaColumnData>{
+    let is_missing = |i: usize| mask.map(|m| m.get(i).cloned().unwrap_or(false)).unwrap_or(false);
+    Ok(match desc {
+        CountFileMetaColumnDesc::Numeric() => {
+            let v = read_hdf5_f32vec(ds)?;
+            CountFileMetaColumnData::Numeric(v.into_iter().enumerate().map(|(i, x)| if is_missing(i) { f32::NAN } else { x }).collect())
+        },
+        CountFileMetaColumnDesc::Boolean() => {
+            let v = read_hdf5_boolvec(ds)?;
+            CountFileMetaColumnData::Boolean(v.into_iter().enumerate().map(|(i, x)| if is_missing(i) { None } else { Some(x) }).collect())
+        },
+        CountFileMetaColumnDesc::Integer() => {
+            let v = read_hdf5_i64vec(ds)?;
+            CountFileMetaColumnData::Integer(v.into_iter().enumerate().map(|(i, x)| if is_missing(i) { None } else { Some(x) }).collect())
+        },
+        CountFileMetaColumnDesc::Text() => {
+            let v = read_hdf5_stringvec(ds)?;
+            CountFileMetaColumnData::Text(v.into_iter().enumerate().map(|(i, x)| if is_missing(i) { None } else { Some(x) }).collect())
+        },
+        CountFileMetaColumnDesc::Categorical(_) => {
+            anyhow::bail!("Categorical columns are not stored as a single dataset")
+        },
+    })
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read the value of one cell of a metadata column stored as a dataset, as a column holding only that cell
+pub fn read_hdf5_metavalue(ds: &hdf5::Dataset, desc: &CountFileMetaColumnDesc, cell: usize) -> anyhow::Result<CountFileMetaColumnData>{
+    let range = cell..(cell+1);
+    Ok(match desc {
+        CountFileMetaColumnDesc::Numeric() => {
+            CountFileMetaColumnData::Numeric(ds.read_slice_1d::<f32, _>(range)?.to_vec())
+        },
+        CountFileMetaColumnDesc::Boolean() => {
+            CountFileMetaColumnData::Boolean(vec![Some(read_hdf5_bool_at(ds, cell)?)])
+        },
+        CountFileMetaColumnDesc::Integer() => {
+            CountFileMetaColumnData::Integer(ds.read_slice_1d::<i64, _>(range)?.iter().map(|x| Some(*x)).collect())
+        },
+        CountFileMetaColumnDesc::Text() => {
+            CountFileMetaColumnData::Text(vec![Some(read_hdf5_string_at(ds, cell)?)])
+        },
+        CountFileMetaColumnDesc::Categorical(_) => {
+            anyhow::bail!("Categorical columns are not stored as a single dataset")
+        },
+    })
+}
@@ -0,0 +1,149 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use my_web_app::DatasetDescResponse;
+use my_web_app::MetadataColumnResponse;
+use my_web_app::ReductionResponse;
+
+use crate::countfile::index_countfile;
+use crate::countfile::CountFileMat;
+use crate::h5ad::index_h5ad;
+use crate::validate::{check_biscvi5, ValidationLevel};
+use crate::zarr::index_zarr;
+
+
+////////////////////////////////////////////////////////////
+/// A source of count data, reductions and metadata. Implemented by each
+/// storage backend, so that the REST entry points need not know the file format.
+/// Calls may happen from several threads at once; they are made from the blocking
+/// thread pool since file I/O would otherwise stall the async executor
+pub trait DataSource: Send + Sync {
+
+    ////////////////////////////////////////////////////////////
+    /// Get a description of the dataset
+    fn get_desc(&self) -> anyhow::Result<DatasetDescResponse>;
+
+    ////////////////////////////////////////////////////////////
+    /// Get the feature names and pointers of a count matrix. These are not part of the description,
+    /// as they are too large to send to clients
+    fn get_matrix(&self, count_name: &String) -> anyhow::Result<&CountFileMat>;
+
+    ////////////////////////////////////////////////////////////
+    /// Read the reduction coordinates
+    fn get_reduction(&self, reduction_name: &String) -> anyhow::Result<ReductionResponse>;
+
+    ////////////////////////////////////////////////////////////
+    /// Read all dimensions of a reduction, one vector per dimension. get_reduction only gives those that are shown
+    fn get_reduction_dims(&self, reduction_name: &String) -> anyhow::Result<Vec<Vec<f32>>>;
+
+    ////////////////////////////////////////////////////////////
+    /// Get all values for a metadata column
+    fn get_metacolumn(&self, column_name: &String) -> anyhow::Result<MetadataColumnResponse>;
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve feature index by name
+    fn get_feature_index(&self, count_name: &String, feature_name: &String) -> anyhow::Result<usize>;
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve all counts for a given feature, over all cells
+    fn get_counts_for_cell(&self, count_name: &String, row: u32) -> anyhow::Result<MetadataColumnResponse>;
+
+    ////////////////////////////////////////////////////////////
+    /// Get the total counts of each cell in a matrix, computed while indexing. Cells
+    /// past the end of the list have no counts
+    fn get_cell_totals(&self, count_name: &String) -> anyhow::Result<&Vec<f64>>;
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve counts for many features at once, in the order given.
+    /// Backends should override this if they can read several features faster than one at a time
+    fn get_counts_batch(&self, count_name: &String, rows: &Vec<u32>) -> anyhow::Result<Vec<MetadataColumnResponse>> {
+        rows.iter().map(|row| self.get_counts_for_cell(count_name, *row)).collect()
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve the non-zero counts of one cell, as feature indices and values.
+    /// Count matrices are feature-major, so backends may need a transposed copy for this
+    fn get_cell_counts(&self, count_name: &String, cell: u32) -> anyhow::Result<(Vec<u32>, Vec<f32>)>;
+
+    ////////////////////////////////////////////////////////////
+    /// Get the number of cells. By default, taken from any reduction or metadata column
+    fn get_num_cells(&self) -> anyhow::Result<usize> {
+        let desc = self.get_desc()?;
+        if let Some(red) = desc.reductions.values().next() {
+            return Ok(red.num_sample);
+        }
+        if let Some(column_name) = desc.meta.keys().next() {
+            if let Some(num_cells) = self.get_metacolumn(column_name)?.data.len() {
+                return Ok(num_cells);
+            } else {
+                anyhow::bail!("Unexpected sparse metadata column");
+            }
+        }
+        anyhow::bail!("Cannot tell the number of cells without a reduction or metadata column")
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the names of all cells, in the order of the count matrices
+    fn get_barcodes(&self) -> anyhow::Result<Vec<String>> {
+        Ok(make_numbered_barcodes(self.get_num_cells()?))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the name of one cell.
+    /// Backends should override this if they can read one name without the whole list
+    fn get_barcode(&self, cell: usize) -> anyhow::Result<String> {
+        let barcodes = self.get_barcodes()?;
+        barcodes.get(cell).cloned().context(format!("Cell {} out of range; there are {} cells", cell, barcodes.len()))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the value of one cell in a metadata column as text, or None if missing.
+    /// Backends should override this if they can read one value without the whole column
+    fn get_meta_label(&self, column_name: &String, cell: usize) -> anyhow::Result<Option<String>> {
+        Ok(self.get_metacolumn(column_name)?.data.get_label(cell))
+    }
+
+}
+
+
+////////////////////////////////////////////////////////////
+/// Names for cells when the file does not store any
+pub fn make_numbered_barcodes(num_cells: usize) -> Vec<String> {
+    (0..num_cells).map(make_numbered_barcode).collect()
+}
+
+
+////////////////////////////////////////////////////////////
+/// Name of one cell when the file does not store any
+pub fn make_numbered_barcode(cell: usize) -> String {
+    format!("cell{}", cell)
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Check if a file holds count data that can be served. Zarr stores are directories
+pub fn is_datasource_file(p: &PathBuf) -> bool {
+    let spath = p.to_string_lossy();
+    spath.ends_with(".biscvi5") || spath.ends_with(".h5ad") || (spath.ends_with(".zarr") && p.is_dir())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Open a data source, picking the backend from the file type.
+/// Our own format is fully checked first, to give a useful error rather than failing halfway through indexing
+/// or serving broken indices. Indexing reads every count anyway, so this costs about as much again
+pub fn open_datasource(p: &PathBuf) -> anyhow::Result<Box<dyn DataSource>> {
+    let spath = p.to_string_lossy();
+    if spath.ends_with(".biscvi5") {
+        check_biscvi5(p, ValidationLevel::Full)?;
+        Ok(Box::new(index_countfile(p)?))
+    } else if spath.ends_with(".h5ad") {
+        Ok(Box::new(index_h5ad(p)?))
+    } else if spath.ends_with(".zarr") && p.is_dir() {
+        Ok(Box::new(index_zarr(p)?))
+    } else {
+        anyhow::bail!("Could not tell file format of count data {:?}", p);
+    }
+}
@@ -0,0 +1,432 @@
+use std::cmp::Ordering;
+
+use anyhow::Context;
+
+use my_web_app::CountFileMetaColumnData;
+use my_web_app::DiffExpFeature;
+use my_web_app::DiffExpResponse;
+use my_web_app::MarkersResponse;
+
+use crate::datasource::DataSource;
+
+/// Number of features read from the data source at a time
+const DIFFEXP_BATCH_SIZE: usize = 1000;
+
+/// Number of marker features kept per category. Requests can ask for fewer
+pub const MARKERS_MAX_PER_CATEGORY: usize = 100;
+
+/// Added to means before taking the fold change, to avoid division by zero
+const DIFFEXP_EPSILON: f64 = 1e-9;
+
+
+////////////////////////////////////////////////////////////
+/// Which group each cell belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CellGroup {
+    None,
+    A,
+    B,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Sums over the non-zero counts of one feature, for one group
+struct GroupStats {
+    num_cells: usize,
+    num_nonzero: usize,
+    sum: f64,
+}
+impl GroupStats {
+
+    fn new(num_cells: usize) -> GroupStats {
+        GroupStats {
+            num_cells,
+            num_nonzero: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / (self.num_cells as f64)
+    }
+
+    fn frac(&self) -> f64 {
+        (self.num_nonzero as f64) / (self.num_cells as f64)
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Compute differential expression between two groups of cells, for all features in a count matrix.
+/// If group_b is None, the rest of the cells are used
+pub fn compute_diffexp(
+    source: &dyn DataSource,
+    counts_name: &String,
+    group_a: &Vec<u32>,
+    group_b: &Option<Vec<u32>>
+) -> anyhow::Result<DiffExpResponse> {
+
+    //Assign each cell to a group
+    let num_cells = source.get_num_cells()?;
+    let mut cell_group = vec![CellGroup::None; num_cells];
+    for cell in group_a {
+        *cell_group.get_mut(*cell as usize).context("Cell index out of range")? = CellGroup::A;
+    }
+    if let Some(group_b) = group_b {
+        for cell in group_b {
+            let g = cell_group.get_mut(*cell as usize).context("Cell index out of range")?;
+            if *g == CellGroup::A {
+                anyhow::bail!("Cell {} is in both groups", cell);
+            }
+            *g = CellGroup::B;
+        }
+    } else {
+        for g in cell_group.iter_mut() {
+            if *g == CellGroup::None {
+                *g = CellGroup::B;
+            }
+        }
+    }
+    let num_a = cell_group.iter().filter(|g| **g == CellGroup::A).count();
+    let num_b = cell_group.iter().filter(|g| **g == CellGroup::B).count();
+    if num_a == 0 || num_b == 0 {
+        anyhow::bail!("Both groups must contain cells");
+    }
+
+    let mat = source.get_matrix(counts_name)?;
+
+    //Test each feature
+    let mut features = Vec::with_capacity(mat.list_feature_names.len());
+    visit_features(source, counts_name, mat.list_feature_names.len(), |row, indices, data| {
+        let (stats_a, stats_b, pvalue) = wilcoxon_sparse(&cell_group, num_a, num_b, indices, data)?;
+        features.push(make_feature_result(&mat.list_feature_names[row], &stats_a, &stats_b, pvalue));
+        Ok(())
+    })?;
+
+    //Adjust p-values and present the most significant first
+    let list_p = features.iter().map(|f| f.pvalue).collect::<Vec<_>>();
+    for (f, q) in features.iter_mut().zip(benjamini_hochberg(&list_p)) {
+        f.qvalue = q;
+    }
+    features.sort_by(|a, b| a.pvalue.partial_cmp(&b.pvalue).unwrap_or(Ordering::Equal));
+
+    Ok(DiffExpResponse {
+        features
+    })
+}
+
+
+////////////////////////////////////////////////////////////
+/// Find marker features of each category in a metadata column, by testing each category against the rest.
+/// Only features more abundant in the category are kept, at most top_n per category, most significant first
+pub fn compute_markers(
+    source: &dyn DataSource,
+    counts_name: &String,
+    column_name: &String,
+    top_n: usize,
+) -> anyhow::Result<MarkersResponse> {
+
+    let (codes, categories) = match source.get_metacolumn(column_name)?.data {
+        CountFileMetaColumnData::Categorical(codes, categories) => (codes, categories),
+        CountFileMetaColumnData::Boolean(values) => {
+            //Treated as categories false, true and, if needed, missing
+            let codes = values.iter().map(|v| match v { Some(false) => 0, Some(true) => 1, None => 2 }).collect::<Vec<u32>>();
+            let mut categories = vec!["false".to_string(), "true".to_string()];
+            if codes.iter().any(|c| *c == 2) {
+                categories.push("NA".to_string());
+            }
+            (codes, categories)
+        },
+        _ => anyhow::bail!("Metadata column {} is neither categorical nor boolean", column_name),
+    };
+
+    //One grouping of cells per category
+    let mut list_cell_group = Vec::new();
+    for cat_i in 0..categories.len() {
+        let cell_group = codes.iter().map(|c| if *c as usize == cat_i { CellGroup::A } else { CellGroup::B }).collect::<Vec<_>>();
+        let num_a = cell_group.iter().filter(|g| **g == CellGroup::A).count();
+        let num_b = cell_group.len() - num_a;
+        list_cell_group.push((cell_group, num_a, num_b));
+    }
+
+    let mat = source.get_matrix(counts_name)?;
+
+    //Test each feature against each category. Names are looked up last, to save memory
+    let mut list_tests: Vec<Vec<(usize, GroupStats, GroupStats, f64)>> = (0..categories.len()).map(|_| Vec::new()).collect();
+    visit_features(source, counts_name, mat.list_feature_names.len(), |row, indices, data| {
+        for (cat_i, (cell_group, num_a, num_b)) in list_cell_group.iter().enumerate() {
+            if *num_a == 0 || *num_b == 0 {
+                continue;
+            }
+            let (stats_a, stats_b, pvalue) = wilcoxon_sparse(cell_group, *num_a, *num_b, indices, data)?;
+            list_tests[cat_i].push((row, stats_a, stats_b, pvalue));
+        }
+        Ok(())
+    })?;
+
+    let mut markers = Vec::new();
+    for tests in list_tests {
+        let list_p = tests.iter().map(|t| t.3).collect::<Vec<_>>();
+        let mut cat_features = tests.iter()
+            .zip(benjamini_hochberg(&list_p))
+            .map(|((row, stats_a, stats_b, pvalue), q)| {
+                let mut f = make_feature_result(&mat.list_feature_names[*row], stats_a, stats_b, *pvalue);
+                f.qvalue = q;
+                f
+            })
+            .filter(|f| f.log2_fold_change > 0.0)
+            .collect::<Vec<_>>();
+        cat_features.sort_by(|a, b| a.pvalue.partial_cmp(&b.pvalue).unwrap_or(Ordering::Equal)
+            .then(b.log2_fold_change.partial_cmp(&a.log2_fold_change).unwrap_or(Ordering::Equal)));
+        cat_features.truncate(top_n);
+        markers.push(cat_features);
+    }
+
+    Ok(MarkersResponse {
+        categories,
+        markers,
+    })
+}
+
+
+////////////////////////////////////////////////////////////
+/// Go through all features of a count matrix, reading them in batches to limit memory usage
+pub fn visit_features<F>(source: &dyn DataSource, counts_name: &String, num_features: usize, mut f: F) -> anyhow::Result<()>
+where F: FnMut(usize, &Vec<u32>, &Vec<f32>) -> anyhow::Result<()> {
+    let mut feature_start = 0;
+    while feature_start < num_features {
+        let feature_end = (feature_start + DIFFEXP_BATCH_SIZE).min(num_features);
+        let rows = (feature_start as u32..feature_end as u32).collect::<Vec<_>>();
+        let list_counts = source.get_counts_batch(counts_name, &rows)?;
+
+        for (row, counts) in rows.iter().zip(list_counts.iter()) {
+            match &counts.data {
+                CountFileMetaColumnData::SparseNumeric(indices, data) => f(*row as usize, indices, data)?,
+                _ => anyhow::bail!("Expected sparse counts"),
+            };
+        }
+        feature_start = feature_end;
+    }
+    Ok(())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Summarize the test of one feature. The q-value is filled in later
+fn make_feature_result(feature_name: &String, stats_a: &GroupStats, stats_b: &GroupStats, pvalue: f64) -> DiffExpFeature {
+    let log2_fold_change = ((stats_a.mean() + DIFFEXP_EPSILON) / (stats_b.mean() + DIFFEXP_EPSILON)).log2();
+    DiffExpFeature {
+        feature_name: feature_name.clone(),
+        log2_fold_change: log2_fold_change as f32,
+        frac_a: stats_a.frac() as f32,
+        frac_b: stats_b.frac() as f32,
+        pvalue,
+        qvalue: 1.0,
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Wilcoxon rank-sum test of a sparse vector, using the normal approximation with tie correction.
+/// All zeros form one block of ties, so the cost only depends on the number of non-zero values
+fn wilcoxon_sparse(
+    cell_group: &Vec<CellGroup>,
+    num_a: usize,
+    num_b: usize,
+    indices: &Vec<u32>,
+    data: &Vec<f32>
+) -> anyhow::Result<(GroupStats, GroupStats, f64)> {
+
+    let mut stats_a = GroupStats::new(num_a);
+    let mut stats_b = GroupStats::new(num_b);
+
+    //Gather non-zero values of cells in either group
+    let mut values = Vec::with_capacity(indices.len());
+    for (cell, v) in indices.iter().zip(data.iter()) {
+        if *v == 0.0 {
+            continue;
+        }
+        let g = *cell_group.get(*cell as usize).context("Cell index out of range")?;
+        match g {
+            CellGroup::A => { stats_a.num_nonzero += 1; stats_a.sum += *v as f64; },
+            CellGroup::B => { stats_b.num_nonzero += 1; stats_b.sum += *v as f64; },
+            CellGroup::None => continue,
+        }
+        values.push((*v, g));
+    }
+    values.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    let zeros_a = num_a - stats_a.num_nonzero;
+    let zeros_b = num_b - stats_b.num_nonzero;
+
+    //Sum ranks of group A, averaging ranks over ties. Zeros go in between negative and positive values
+    let mut rank_sum_a = 0.0;
+    let mut tie_sum = 0.0;
+    let mut num_ranked = 0.0;
+    let mut add_tie_block = |count_a: usize, count_total: usize| {
+        let t = count_total as f64;
+        let avg_rank = num_ranked + (t + 1.0)/2.0;
+        rank_sum_a += (count_a as f64) * avg_rank;
+        tie_sum += t*t*t - t;
+        num_ranked += t;
+    };
+
+    let mut zeros_added = false;
+    let mut i = 0;
+    while i < values.len() {
+        if !zeros_added && values[i].0 > 0.0 {
+            add_tie_block(zeros_a, zeros_a + zeros_b);
+            zeros_added = true;
+        }
+        let mut j = i;
+        let mut count_a = 0;
+        while j < values.len() && values[j].0 == values[i].0 {
+            if values[j].1 == CellGroup::A {
+                count_a += 1;
+            }
+            j += 1;
+        }
+        add_tie_block(count_a, j - i);
+        i = j;
+    }
+    if !zeros_added {
+        add_tie_block(zeros_a, zeros_a + zeros_b);
+    }
+
+    //Normal approximation of U statistic
+    let n_a = num_a as f64;
+    let n_b = num_b as f64;
+    let n = n_a + n_b;
+    let u = rank_sum_a - n_a*(n_a + 1.0)/2.0;
+    let mean_u = n_a*n_b/2.0;
+    let var_u = n_a*n_b/12.0 * ((n + 1.0) - tie_sum/(n*(n - 1.0)));
+
+    let pvalue = if var_u > 0.0 {
+        let z = (u - mean_u) / var_u.sqrt();
+        erfc(z.abs() / std::f64::consts::SQRT_2).min(1.0)
+    } else {
+        //All values are tied
+        1.0
+    };
+
+    Ok((stats_a, stats_b, pvalue))
+}
+
+
+////////////////////////////////////////////////////////////
+/// Benjamini-Hochberg adjustment of p-values. Output is in the same order as input
+pub fn benjamini_hochberg(list_p: &Vec<f64>) -> Vec<f64> {
+    let m = list_p.len();
+    let mut order = (0..m).collect::<Vec<_>>();
+    order.sort_by(|a, b| list_p[*a].partial_cmp(&list_p[*b]).unwrap_or(Ordering::Equal));
+
+    //Go from the largest p-value down, keeping q-values monotonic
+    let mut list_q = vec![1.0; m];
+    let mut q_min: f64 = 1.0;
+    for (rank, i) in order.iter().enumerate().rev() {
+        let q = list_p[*i] * (m as f64) / ((rank + 1) as f64);
+        q_min = q_min.min(q);
+        list_q[*i] = q_min;
+    }
+    list_q
+}
+
+
+////////////////////////////////////////////////////////////
+/// Complementary error function, with fractional error below 1.2e-7 (Numerical Recipes)
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0/(1.0 + 0.5*z);
+    let r = t*(-z*z - 1.26551223 + t*(1.00002368 + t*(0.37409196 + t*(0.09678418 +
+        t*(-0.18628806 + t*(0.27886807 + t*(-1.13520398 + t*(1.48851587 +
+        t*(-0.82215223 + t*0.17087277))))))))).exp();
+    if x >= 0.0 { r } else { 2.0 - r }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ////////////////////////////////////////////////////////////
+    /// Run the sparse test on dense values, where the first num_a cells are group A and the rest group B
+    fn wilcoxon_dense(values: &Vec<f32>, num_a: usize) -> f64 {
+        let num_b = values.len() - num_a;
+        let cell_group = (0..values.len()).map(|i| if i < num_a { CellGroup::A } else { CellGroup::B }).collect::<Vec<_>>();
+        let indices = (0..values.len() as u32).filter(|i| values[*i as usize] != 0.0).collect::<Vec<_>>();
+        let data = indices.iter().map(|i| values[*i as usize]).collect::<Vec<_>>();
+        let (_, _, pvalue) = wilcoxon_sparse(&cell_group, num_a, num_b, &indices, &data).unwrap();
+        pvalue
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Textbook rank-sum test with average ranks for ties, as a reference
+    fn wilcoxon_reference(values: &Vec<f32>, num_a: usize) -> f64 {
+        let mut sorted = values.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let rank_of = |v: f32| {
+            let first = sorted.iter().position(|x| *x == v).unwrap() as f64;
+            let count = sorted.iter().filter(|x| **x == v).count() as f64;
+            first + (count + 1.0)/2.0
+        };
+        let rank_sum_a: f64 = values[0..num_a].iter().map(|v| rank_of(*v)).sum();
+
+        let mut tie_sum = 0.0;
+        let mut i = 0;
+        while i < sorted.len() {
+            let t = sorted.iter().filter(|x| **x == sorted[i]).count();
+            tie_sum += (t*t*t - t) as f64;
+            i += t;
+        }
+
+        let n_a = num_a as f64;
+        let n_b = (values.len() - num_a) as f64;
+        let n = n_a + n_b;
+        let u = rank_sum_a - n_a*(n_a + 1.0)/2.0;
+        let var_u = n_a*n_b/12.0 * ((n + 1.0) - tie_sum/(n*(n - 1.0)));
+        erfc(((u - n_a*n_b/2.0) / var_u.sqrt()).abs() / std::f64::consts::SQRT_2)
+    }
+
+    #[test]
+    fn erfc_matches_known_values() {
+        for (x, expected) in [(0.0, 1.0), (0.5, 0.4795001222), (1.0, 0.1572992071), (2.0, 0.0046777350), (-1.0, 1.8427007929)] {
+            assert!((erfc(x) - expected).abs() < 1.2e-7 * expected.max(1.0), "erfc({}) = {}", x, erfc(x));
+        }
+    }
+
+    #[test]
+    fn wilcoxon_without_ties() {
+        //U = 0 for 3 vs 3 cells; same as scipy mannwhitneyu without continuity correction
+        let p = wilcoxon_dense(&vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3);
+        assert!((p - 0.049535).abs() < 1e-5, "p = {}", p);
+    }
+
+    #[test]
+    fn wilcoxon_ties_match_reference() {
+        for (values, num_a) in [
+            (vec![0.0, 0.0, 1.0, 0.0, 2.0, 3.0], 3),
+            (vec![0.0, 2.0, 2.0, 1.0, 0.0, 2.0, 0.0, 5.0], 4),
+            (vec![-1.0, 0.0, 3.0, 0.0, -1.0, 3.0, 3.0], 2),
+        ] {
+            let p = wilcoxon_dense(&values, num_a);
+            let expected = wilcoxon_reference(&values, num_a);
+            assert!((p - expected).abs() < 1e-12, "{:?}: {} vs {}", values, p, expected);
+        }
+    }
+
+    #[test]
+    fn wilcoxon_all_tied() {
+        assert_eq!(wilcoxon_dense(&vec![0.0; 5], 2), 1.0);
+    }
+
+    #[test]
+    fn benjamini_hochberg_in_input_order() {
+        let q = benjamini_hochberg(&vec![0.01, 0.04, 0.03, 0.5]);
+        let expected = vec![0.04, 0.04*4.0/3.0, 0.04*4.0/3.0, 0.5];
+        for (a, b) in q.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-12, "{:?}", q);
+        }
+        assert!(benjamini_hochberg(&vec![]).is_empty());
+    }
+}
@@ -25,6 +25,9 @@ pub enum MyError {
     #[api_error(code = 500, msg = "An internal server error occurred. {err}")]
     SerdeCborError {err: serde_cbor::Error},
 
+    #[api_error(code = 500, msg = "An internal server error occurred. {err}")]
+    BlockingError {err: actix_web::error::BlockingError},
+
     // This variant's single field of type Option<Value> will be used for `ApiError.details`
     //#[api_error(status = "BadRequest", msg = "Invalid input provided.")]
     //WithDetails(Option<Value>),
@@ -69,3 +72,9 @@ impl From<serde_cbor::Error> for MyError {
         MyError::SerdeCborError {err: error}
     }
 }
+
+impl From<actix_web::error::BlockingError> for MyError {
+    fn from(error: actix_web::error::BlockingError) -> Self {
+        MyError::BlockingError {err: error}
+    }
+}
@@ -0,0 +1,186 @@
+use actix_web::web::Bytes;
+use anyhow::Context;
+
+use my_web_app::CountFileMetaColumnData;
+use my_web_app::ExportCellsRequest;
+
+use crate::datasource::DataSource;
+use crate::normalize::normalize_response;
+
+/// Number of cells written per piece of the response
+pub const EXPORT_ROWS_PER_CHUNK: usize = 10000;
+
+/// Number of features read at a time when building the table
+pub const EXPORT_FEATURES_PER_BATCH: usize = 64;
+
+/// Largest export request accepted. Requests list cells, at most about 11 bytes each as JSON,
+/// so this allows selecting several million cells
+pub const EXPORT_MAX_FORM_BYTES: usize = 64*1024*1024;
+
+
+////////////////////////////////////////////////////////////
+/// One or more columns of the table
+enum ExportColumn {
+    /// Metadata column over all cells, written as labels. Missing values are left empty
+    Labels(CountFileMetaColumnData),
+    /// Number for each selected cell, in the order of the selection
+    Numbers(Vec<f32>),
+}
+
+
+////////////////////////////////////////////////////////////
+/// Everything needed to write the table. Values are read up front, but only those of the selected
+/// cells are kept. Features are read a batch at a time, so only a few are ever held for all cells.
+/// The text is then made a piece at a time, so it never has to be in memory all at once
+pub struct ExportTable {
+    header: Vec<String>,
+    /// Names of the selected cells, in the order of the selection
+    barcodes: Vec<String>,
+    columns: Vec<ExportColumn>,
+    cells: Vec<usize>,
+    delimiter: u8,
+}
+impl ExportTable {
+
+    ////////////////////////////////////////////////////////////
+    /// Read all values asked for
+    pub fn build(ds: &dyn DataSource, req: &ExportCellsRequest) -> anyhow::Result<ExportTable> {
+        let num_cells = ds.get_num_cells()?;
+
+        //Which cells to write
+        let cells = if let Some(cells) = &req.cells {
+            let cells = cells.iter().map(|x| *x as usize).collect::<Vec<_>>();
+            if let Some(bad_cell) = cells.iter().find(|x| **x >= num_cells) {
+                anyhow::bail!("Cell {} out of range; there are {} cells", bad_cell, num_cells);
+            }
+            cells
+        } else {
+            (0..num_cells).collect()
+        };
+        let barcodes = {
+            let all_barcodes = ds.get_barcodes()?;
+            cells.iter().map(|cell| all_barcodes.get(*cell).cloned().unwrap_or_default()).collect()
+        };
+
+        let mut header = vec!["barcode".to_string()];
+        let mut columns = Vec::new();
+
+        //Metadata columns
+        for column_name in &req.meta_columns {
+            let col = ds.get_metacolumn(column_name)?;
+            header.push(column_name.clone());
+            columns.push(ExportColumn::Labels(col.data));
+        }
+
+        //Coordinates of reductions, one column per dimension
+        for reduction_name in &req.reductions {
+            let red = ds.get_reduction(reduction_name)?;
+            header.push(format!("{}_1", reduction_name));
+            columns.push(ExportColumn::Numbers(pick_cells(&red.x, &cells)));
+            header.push(format!("{}_2", reduction_name));
+            columns.push(ExportColumn::Numbers(pick_cells(&red.y, &cells)));
+            if let Some(z) = red.z {
+                header.push(format!("{}_3", reduction_name));
+                columns.push(ExportColumn::Numbers(pick_cells(&z, &cells)));
+            }
+        }
+
+        //Feature values, read in batches per count matrix
+        let mut feature_values = vec![Vec::new(); req.features.len()];
+        let mut list_counts_names: Vec<&String> = Vec::new();
+        for (counts_name, _feature_name) in &req.features {
+            if !list_counts_names.contains(&counts_name) {
+                list_counts_names.push(counts_name);
+            }
+        }
+        for counts_name in list_counts_names {
+            let list_pos = (0..req.features.len()).filter(|i| &req.features[*i].0 == counts_name).collect::<Vec<_>>();
+            for batch in list_pos.chunks(EXPORT_FEATURES_PER_BATCH) {
+                let rows = batch.iter().map(|i| {
+                    let feature_name = &req.features[*i].1;
+                    ds.get_feature_index(counts_name, feature_name)
+                        .map(|row| row as u32)
+                        .map_err(|e| e.context(format!("Unknown feature {}", feature_name)))
+                }).collect::<anyhow::Result<Vec<_>>>()?;
+                let list_mat = ds.get_counts_batch(counts_name, &rows)?;
+                for (i, mat) in batch.iter().zip(list_mat.into_iter()) {
+                    let mat = normalize_response(ds, counts_name, mat, req.normalization)?;
+                    feature_values[*i] = pick_feature_cells(mat.data, &cells, num_cells, &req.features[*i].1)?;
+                }
+            }
+        }
+        for ((counts_name, feature_name), values) in req.features.iter().zip(feature_values.into_iter()) {
+            header.push(format!("{}:{}", counts_name, feature_name));
+            columns.push(ExportColumn::Numbers(values));
+        }
+
+        Ok(ExportTable {
+            header,
+            barcodes,
+            columns,
+            cells,
+            delimiter: req.format.delimiter(),
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Number of pieces the table is written in
+    pub fn num_chunks(&self) -> usize {
+        self.cells.len().div_ceil(EXPORT_ROWS_PER_CHUNK).max(1)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Write one piece of the table. The first piece starts with the header
+    pub fn write_chunk(&self, chunk: usize) -> anyhow::Result<Bytes> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .from_writer(Vec::new());
+        if chunk == 0 {
+            writer.write_record(&self.header)?;
+        }
+
+        let from = (chunk*EXPORT_ROWS_PER_CHUNK).min(self.cells.len());
+        let to = ((chunk+1)*EXPORT_ROWS_PER_CHUNK).min(self.cells.len());
+        let mut record = Vec::with_capacity(self.header.len());
+        for pos in from..to {
+            let cell = self.cells[pos];
+            record.clear();
+            record.push(self.barcodes[pos].clone());
+            for col in &self.columns {
+                record.push(match col {
+                    ExportColumn::Labels(data) => data.get_label(cell).unwrap_or_default(),
+                    ExportColumn::Numbers(values) => values.get(pos).filter(|x| !x.is_nan()).map(|x| x.to_string()).unwrap_or_default(),
+                });
+            }
+            writer.write_record(&record)?;
+        }
+
+        let data = writer.into_inner().map_err(|e| anyhow::anyhow!("{}", e))?;
+        Ok(Bytes::from(data))
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Values of the selected cells, in the order of the selection. Cells past the end have no value
+fn pick_cells(values: &Vec<f32>, cells: &Vec<usize>) -> Vec<f32> {
+    cells.iter().map(|cell| values.get(*cell).cloned().unwrap_or(f32::NAN)).collect()
+}
+
+
+////////////////////////////////////////////////////////////
+/// Counts of one feature for the selected cells, in the order of the selection
+fn pick_feature_cells(data: CountFileMetaColumnData, cells: &Vec<usize>, num_cells: usize, feature_name: &String) -> anyhow::Result<Vec<f32>> {
+    match data {
+        CountFileMetaColumnData::SparseNumeric(indices, data) => {
+            let mut values = vec![0.0; num_cells];
+            for (i, v) in indices.iter().zip(data.iter()) {
+                *values.get_mut(*i as usize).context("Count matrix has more cells than the dataset")? = *v;
+            }
+            Ok(pick_cells(&values, cells))
+        },
+        CountFileMetaColumnData::Numeric(values) => Ok(pick_cells(&values, cells)),
+        _ => anyhow::bail!("Unexpected type of counts for {}", feature_name),
+    }
+}
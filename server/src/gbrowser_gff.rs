@@ -2,6 +2,7 @@ use bstr::BString;
 use flate2::Compression;
 use flate2::read::GzDecoder;
 use flate2::read::GzEncoder;
+use my_web_app::gbrowser_struct::GBrowserChunk;
 use my_web_app::gbrowser_struct::GBrowserGFF;
 use my_web_app::gbrowser_struct::GBrowserGFFchunkID;
 use my_web_app::gbrowser_struct::GBrowserGFFchunkpos;
@@ -310,6 +311,27 @@ impl FeatureCollection {
     }
 
 
+    ////////////////////////////////////////////////////////////
+    /// Read all records, from every chunk and the remainder. Used to build search indices
+    pub fn read_all_records(index: &GBrowserGFFindex, path: &PathBuf) -> anyhow::Result<Vec<GBrowserRecordBuf>> {
+        let path_chunks = FeatureCollection::get_path_chunks(path);
+        let mut f = File::open(path_chunks)?;
+
+        let mut list_records = index.remainder.clone();
+        for coord in index.chunk_coordinates.values() {
+            let len = coord.end - coord.start;
+            f.seek(SeekFrom::Start(coord.start))?;
+            let mut buf = vec![0; len as usize];
+            f.read_exact(&mut buf)?;
+
+            let gz = GzDecoder::new(Cursor::new(buf));
+            let chunk: GBrowserChunk = serde_cbor::from_reader(gz)?;
+            list_records.extend(chunk.records);
+        }
+        anyhow::Ok(list_records)
+    }
+
+
     ////////////////////////////////////////////////////////////
     /// Read GFF index file
     pub fn read_gff_index(path: &PathBuf) -> anyhow::Result<GBrowserGFFindex> {
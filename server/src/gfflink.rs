@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use my_web_app::gbrowser_struct::GBrowserAttributeValue;
+use my_web_app::gbrowser_struct::GBrowserLocus;
+use my_web_app::gbrowser_struct::GBrowserRecordBuf;
+
+use crate::countfile::CountFileMat;
+use crate::datasource::DataSource;
+
+
+////////////////////////////////////////////////////////////
+/// GFF attributes that hold feature names of count matrices, tried in this order, unless set in the config
+pub fn default_gff_link_attributes() -> Vec<String> {
+    vec!["ID".into(), "locus_tag".into(), "gene_id".into()]
+}
+
+
+////////////////////////////////////////////////////////////
+/// Links between GFF records and features of count matrices, through a list of attributes.
+/// A feature may be named by several records, such as a gene and its exons; its locus then spans them all
+pub struct GffFeatureLinks {
+    /// Attributes to try, in order of preference
+    pub attributes: Vec<String>,
+    /// Locus of each (count matrix, feature)
+    loci: HashMap<(String, String), GBrowserLocus>,
+    /// Names of count matrices, sorted, so that links are the same between runs
+    counts_names: Vec<String>,
+}
+impl GffFeatureLinks {
+
+    ////////////////////////////////////////////////////////////
+    /// Build links from all GFF records
+    pub fn build(ds: &dyn DataSource, gff_records: &Vec<GBrowserRecordBuf>, attributes: &Vec<String>) -> anyhow::Result<GffFeatureLinks> {
+        let mut counts_names = ds.get_desc()?.matrices.keys().cloned().collect::<Vec<_>>();
+        counts_names.sort();
+
+        let mut links = GffFeatureLinks {
+            attributes: attributes.clone(),
+            loci: HashMap::new(),
+            counts_names,
+        };
+
+        for rec in gff_records {
+            let list_attr = rec.attributes.iter().map(|(k, GBrowserAttributeValue::String(v))| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+            if let Some(key) = links.resolve(ds, &list_attr)? {
+                links.loci.entry(key)
+                    .and_modify(|locus| {
+                        if locus.chr == rec.reference_sequence_name {
+                            locus.start = locus.start.min(rec.start);
+                            locus.end = locus.end.max(rec.end);
+                        }
+                    })
+                    .or_insert_with(|| GBrowserLocus {
+                        chr: rec.reference_sequence_name.clone(),
+                        start: rec.start,
+                        end: rec.end,
+                    });
+            }
+        }
+        println!("GFF records link to {} features", links.loci.len());
+        Ok(links)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Find the feature that a record refers to, given its attributes. Returns (count matrix, feature name)
+    pub fn resolve(&self, ds: &dyn DataSource, list_attr: &Vec<(String, String)>) -> anyhow::Result<Option<(String, String)>> {
+        let mut matrices: Vec<(&String, &CountFileMat)> = Vec::new();
+        for counts_name in &self.counts_names {
+            matrices.push((counts_name, ds.get_matrix(counts_name)?));
+        }
+
+        for attr_name in &self.attributes {
+            for (k, value) in list_attr {
+                if k != attr_name {
+                    continue;
+                }
+                for (counts_name, mat) in &matrices {
+                    if mat.map_feature_names_pos.contains_key(value) {
+                        return Ok(Some(((*counts_name).clone(), value.clone())));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the locus of a feature, if any GFF record links to it
+    pub fn get_locus(&self, counts_name: &String, feature_name: &String) -> Option<GBrowserLocus> {
+        self.loci.get(&(counts_name.clone(), feature_name.clone())).cloned()
+    }
+}
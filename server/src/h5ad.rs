@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use hdf5::Group;
+use hdf5::types::VarLenUnicode;
+
+use my_web_app::countfile_struct::CountFileMetaColumnDesc;
+use my_web_app::countfile_struct::CountFileRed;
+
+use crate::cellprofile::CellMajorCache;
+use crate::countfile::compute_cell_totals;
+use crate::countfile::get_hdf5_meta_type;
+use crate::countfile::read_hdf5_f32vec;
+use crate::countfile::read_hdf5_stringvec;
+use crate::countfile::read_hdf5_u32vec;
+use crate::countfile::CountFile;
+use crate::countfile::CountFileMat;
+use crate::countfile::CountFileLayout;
+use crate::countfile::MatrixLocation;
+use crate::countfile::MetaColumnLocation;
+use crate::countfile::ReductionLocation;
+
+/// String columns with more distinct values than this are shown as free text rather than categories
+const MAX_CATEGORIES_FROM_STRINGS: usize = 1000;
+
+
+// AnnData (.h5ad) files are mapped onto the same structures as biscvi5 files:
+//
+// X, layers/*   -- count matrices "X" and one per layer. CSC is used as-is, CSR is transposed
+//                  in memory as biscvi5 matrices are feature-major
+// var/_index    -- feature names
+// obsm/X_*      -- reductions, stored as (cell, dim)
+// obs/*         -- metadata columns
+//
+// See https://anndata.readthedocs.io/en/latest/fileformat-prose.html
+
+
+////////////////////////////////////////////////////////////
+/// Read an h5ad file and figure out the contents for later rapid response
+pub fn index_h5ad(p: &PathBuf) -> anyhow::Result<CountFile> {
+
+    println!("======== parsing h5ad file ========");
+
+    let file = hdf5::File::open(p)?;
+    let mut layout = CountFileLayout::new();
+
+    /////// Feature names are shared by all matrices
+    let group_var = file.group("/var")?;
+    let list_feature_names = read_h5ad_index(&group_var)?;
+
+    /////// Gather all count matrices
+    let mut map_matrices: HashMap<String, CountFileMat> = HashMap::new();
+    let mut map_cell_totals: HashMap<String, Vec<f64>> = HashMap::new();
+    let mut list_matrix_paths = Vec::new();
+    if file.link_exists("X") {
+        list_matrix_paths.push(("X".to_string(), "/X".to_string()));
+    }
+    if file.link_exists("layers") {
+        for layer_name in file.group("/layers")?.member_names()? {
+            list_matrix_paths.push((layer_name.clone(), format!("/layers/{}", layer_name)));
+        }
+    }
+    for (count_name, path) in list_matrix_paths {
+        println!("Indexing count matrix: {}", count_name);
+
+        let (list_indptr, loc) = index_h5ad_matrix(&file, &path, list_feature_names.len())?;
+
+        //Dense matrices have no pointers, so the number of values is taken from the totals pass
+        let (cell_totals, num_nonzero) = compute_cell_totals(&file, &loc)?;
+        let mut mat = CountFileMat::new(list_feature_names.clone(), list_indptr);
+        mat.num_nonzero = num_nonzero;
+        map_matrices.insert(count_name.clone(), mat);
+        map_cell_totals.insert(count_name.clone(), cell_totals);
+        layout.matrices.insert(count_name, loc);
+    }
+
+    /////// Gather all reductions
+    let mut map_reductions: HashMap<String, CountFileRed> = HashMap::new();
+    if file.link_exists("obsm") {
+        let group_reds = file.group("/obsm")?;
+        for red_name in group_reds.member_names()? {
+            //Only dense embeddings can be shown
+            if let Ok(ds_thisred) = group_reds.dataset(&red_name) {
+                let shape = ds_thisred.shape();
+                println!("indexing reduction {} with dim {:?}",red_name, shape);
+                if shape.len() != 2 || shape[1] < 2 {
+                    println!("Skipping reduction {}; need at least 2 dimensions", red_name);
+                    continue;
+                }
+
+                // Stored as (cell, dim). Present X_umap as "umap" etc
+                let show_name = red_name.strip_prefix("X_").unwrap_or(&red_name).to_string();
+                map_reductions.insert(show_name.clone(), CountFileRed {
+                    num_sample: shape[0],
+                    num_dim: shape[1],
+                });
+                layout.reductions.insert(show_name, ReductionLocation {
+                    path: format!("/obsm/{}", red_name),
+                    cell_major: true,
+                });
+            }
+        }
+    }
+
+    /////// Gather all metadata
+    let group_meta = file.group("/obs")?;
+    let index_name = get_h5ad_index_name(&group_meta);
+    if group_meta.link_exists(&index_name) {
+        layout.barcodes = Some(format!("/obs/{}", index_name));
+    }
+    let mut map_meta: HashMap<String, CountFileMetaColumnDesc> = HashMap::new();
+    let meta_names = group_meta.member_names()?;
+    println!("Indexing Metadata columns {:?}", meta_names);
+    for meta_name in meta_names {
+        //The index holds cell names; older files also keep categories in a separate group
+        if meta_name == index_name || meta_name == "__categories" {
+            continue;
+        }
+        let path = format!("/obs/{}", meta_name);
+
+        if let Ok(ds_thismeta) = group_meta.dataset(&meta_name) {
+            if is_fixed_string_dataset(&ds_thismeta)? {
+                println!("Skipping metadata column {} of unsupported data type", meta_name);
+            } else if get_encoding_type(&ds_thismeta) == "string-array" || is_string_dataset(&ds_thismeta)? {
+                //Strings are turned into categories, unless they look like free text
+                let list_values = read_hdf5_stringvec(&ds_thismeta)?;
+                let (codes, categories) = make_categories(&list_values);
+                if categories.len() > MAX_CATEGORIES_FROM_STRINGS {
+                    map_meta.insert(meta_name.clone(), CountFileMetaColumnDesc::Text());
+                    layout.meta.insert(meta_name.clone(), MetaColumnLocation::Text(path));
+                } else {
+                    map_meta.insert(meta_name.clone(), CountFileMetaColumnDesc::Categorical(categories));
+                    layout.meta.insert(meta_name.clone(), MetaColumnLocation::CategoricalInMemory(codes));
+                }
+            } else if group_meta.link_exists("__categories") && group_meta.group("__categories")?.link_exists(&meta_name) {
+                //anndata < 0.8: codes in obs, categories elsewhere
+                let ds_categories = group_meta.group("__categories")?.dataset(&meta_name)?;
+                if is_fixed_string_dataset(&ds_categories)? {
+                    println!("Skipping metadata column {} of unsupported data type", meta_name);
+                    continue;
+                }
+                let categories = read_hdf5_stringvec(&ds_categories)?;
+                let codes = read_h5ad_codes(&ds_thismeta, categories.len())?;
+                map_meta.insert(meta_name.clone(), CountFileMetaColumnDesc::Categorical(with_missing_category(categories, &codes)));
+                layout.meta.insert(meta_name.clone(), MetaColumnLocation::CategoricalInMemory(codes));
+            } else if let Some(desc) = get_hdf5_meta_type(&ds_thismeta)? {
+                layout.meta.insert(meta_name.clone(), MetaColumnLocation::for_dataset(&desc, path));
+                map_meta.insert(meta_name.clone(), desc);
+            } else {
+                println!("Skipping metadata column {} of unsupported data type", meta_name);
+            }
+        } else {
+            let group_thismeta = group_meta.group(&meta_name)?;
+            let encoding_type = get_encoding_type(&group_thismeta);
+            if encoding_type == "nullable-integer" || encoding_type == "nullable-boolean" || encoding_type == "nullable-string-array" {
+                //Values and a mask, true where missing
+                if let Some(desc) = get_hdf5_meta_type(&group_thismeta.dataset("values")?)? {
+                    map_meta.insert(meta_name.clone(), desc);
+                    layout.meta.insert(meta_name.clone(), MetaColumnLocation::Nullable(path));
+                } else {
+                    println!("Skipping metadata column {} of unsupported data type", meta_name);
+                }
+                continue;
+            }
+            if encoding_type != "categorical" {
+                println!("Skipping metadata column {} of unsupported type {}", meta_name, get_encoding_type(&group_thismeta));
+                continue;
+            }
+
+            //anndata >= 0.8: group with categories and codes. Codes are -1 if missing
+            let ds_categories = group_thismeta.dataset("categories")?;
+            if is_fixed_string_dataset(&ds_categories)? {
+                println!("Skipping metadata column {} of unsupported data type", meta_name);
+                continue;
+            }
+            let categories = read_hdf5_stringvec(&ds_categories)?;
+            let codes = read_h5ad_codes(&group_thismeta.dataset("codes")?, categories.len())?;
+            let has_missing = codes.iter().any(|c| *c as usize >= categories.len());
+            if has_missing {
+                map_meta.insert(meta_name.clone(), CountFileMetaColumnDesc::Categorical(with_missing_category(categories, &codes)));
+                layout.meta.insert(meta_name.clone(), MetaColumnLocation::CategoricalInMemory(codes));
+            } else {
+                map_meta.insert(meta_name.clone(), CountFileMetaColumnDesc::Categorical(categories));
+                layout.meta.insert(meta_name.clone(), MetaColumnLocation::Categorical(path));
+            }
+        }
+    }
+
+    println!("======== parsing h5ad file DONE ========");
+
+    Ok(CountFile {
+        file: file,
+        matrices: map_matrices,
+        reductions: map_reductions,
+        meta: map_meta,
+        layout: layout,
+        cell_major: CellMajorCache::new(),
+        cell_totals: map_cell_totals,
+    })
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Figure out how a matrix is stored. Returns feature-major pointers and where to get the rest
+fn index_h5ad_matrix(file: &hdf5::File, path: &String, num_features: usize) -> anyhow::Result<(Vec<u32>, MatrixLocation)> {
+
+    //Dense matrix, cell x feature
+    if file.dataset(path).is_ok() {
+        return Ok((Vec::new(), MatrixLocation::DenseOnDisk(path.clone())));
+    }
+
+    let group_mat = file.group(path)?;
+    let encoding_type = get_encoding_type(&group_mat);
+    let list_indptr = read_hdf5_u32vec(&group_mat.dataset("indptr")?)?;
+
+    match encoding_type.as_str() {
+        "csc_matrix" => {
+            //Already feature-major; indices are cell numbers
+            Ok((list_indptr, MatrixLocation::SparseOnDisk(path.clone())))
+        },
+        "csr_matrix" => {
+            //Cell-major. Transpose in memory so that one feature can be looked up quickly
+            println!("Transposing cell-major matrix {}", path);
+            let indices = read_hdf5_u32vec(&group_mat.dataset("indices")?)?;
+            let data = read_hdf5_f32vec(&group_mat.dataset("data")?)?;
+            let (t_indptr, t_indices, t_data) = transpose_csr(&list_indptr, &indices, &data, num_features)
+                .context(format!("Could not transpose matrix {}", path))?;
+            Ok((t_indptr, MatrixLocation::SparseInMemory(t_indices, t_data)))
+        },
+        _ => {
+            anyhow::bail!("Unsupported encoding {} of matrix {}", encoding_type, path)
+        }
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Transpose a CSR matrix. Returns pointers, indices and data.
+/// Pointers and indices are checked, so a broken file gives an error rather than a panic
+pub fn transpose_csr(indptr: &Vec<u32>, indices: &Vec<u32>, data: &Vec<f32>, num_cols: usize) -> anyhow::Result<(Vec<u32>, Vec<u32>, Vec<f32>)> {
+    if indptr.is_empty() {
+        anyhow::bail!("Matrix has an empty indptr");
+    }
+    if indices.len() != data.len() {
+        anyhow::bail!("Matrix has {} indices but {} values", indices.len(), data.len());
+    }
+    if indptr[0] != 0 || indptr[indptr.len()-1] as usize != indices.len() {
+        anyhow::bail!("Matrix indptr does not span the {} stored values", indices.len());
+    }
+    if indptr.windows(2).any(|w| w[0] > w[1]) {
+        anyhow::bail!("Matrix indptr is not increasing");
+    }
+
+    //Count entries in each column
+    let mut t_indptr: Vec<u32> = vec![0; num_cols + 1];
+    for col in indices {
+        if *col as usize >= num_cols {
+            anyhow::bail!("Matrix index {} out of range; there are {} columns", col, num_cols);
+        }
+        t_indptr[*col as usize + 1] += 1;
+    }
+    for i in 0..num_cols {
+        t_indptr[i+1] += t_indptr[i];
+    }
+
+    //Place each entry. Rows are visited in order, so indices end up sorted within each column
+    let mut next_pos = t_indptr.clone();
+    let mut t_indices: Vec<u32> = vec![0; indices.len()];
+    let mut t_data: Vec<f32> = vec![0.0; data.len()];
+    for row in 0..(indptr.len()-1) {
+        for j in (indptr[row] as usize)..(indptr[row+1] as usize) {
+            let col = indices[j] as usize;
+            let pos = next_pos[col] as usize;
+            t_indices[pos] = row as u32;
+            t_data[pos] = data[j];
+            next_pos[col] += 1;
+        }
+    }
+
+    Ok((t_indptr, t_indices, t_data))
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Get the anndata encoding type of a group or dataset, or empty string if not set
+fn get_encoding_type(loc: &hdf5::Location) -> String {
+    if let Ok(attr) = loc.attr("encoding-type") {
+        if let Ok(v) = attr.read_scalar::<VarLenUnicode>() {
+            return v.to_string();
+        }
+    }
+    String::new()
+}
+
+
+////////////////////////////////////////////////////////////
+/// Get the name of the dataset holding the index of obs or var
+fn get_h5ad_index_name(group: &Group) -> String {
+    if let Ok(attr) = group.attr("_index") {
+        if let Ok(v) = attr.read_scalar::<VarLenUnicode>() {
+            return v.to_string();
+        }
+    }
+    "_index".to_string()
+}
+
+
+////////////////////////////////////////////////////////////
+/// Read the index of obs or var
+fn read_h5ad_index(group: &Group) -> anyhow::Result<Vec<String>> {
+    let index_name = get_h5ad_index_name(group);
+    read_hdf5_stringvec(&group.dataset(&index_name)?)
+}
+
+
+////////////////////////////////////////////////////////////
+/// Check if a dataset holds variable-length strings, the kind read_hdf5_stringvec can read
+fn is_string_dataset(ds: &hdf5::Dataset) -> anyhow::Result<bool> {
+    use hdf5::types::TypeDescriptor;
+    Ok(match ds.dtype()?.to_descriptor()? {
+        TypeDescriptor::VarLenAscii | TypeDescriptor::VarLenUnicode => true,
+        _ => false
+    })
+}
+
+
+////////////////////////////////////////////////////////////
+/// Check if a dataset holds fixed-length strings, as older files may have. These cannot be read
+fn is_fixed_string_dataset(ds: &hdf5::Dataset) -> anyhow::Result<bool> {
+    use hdf5::types::TypeDescriptor;
+    Ok(match ds.dtype()?.to_descriptor()? {
+        TypeDescriptor::FixedAscii(_) | TypeDescriptor::FixedUnicode(_) => true,
+        _ => false
+    })
+}
+
+
+////////////////////////////////////////////////////////////
+/// Read anndata category codes. Missing values (-1) are given code num_categories
+fn read_h5ad_codes(ds: &hdf5::Dataset, num_categories: usize) -> anyhow::Result<Vec<u32>> {
+    let v = ds.read_1d::<i32>()?;
+    Ok(v.iter().map(|x| if *x < 0 { num_categories as u32 } else { *x as u32 }).collect())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Add an "NA" category if any code refers to a missing value
+fn with_missing_category(mut categories: Vec<String>, codes: &Vec<u32>) -> Vec<String> {
+    let num_categories = categories.len();
+    if codes.iter().any(|c| *c as usize >= num_categories) {
+        categories.push("NA".to_string());
+    }
+    categories
+}
+
+
+////////////////////////////////////////////////////////////
+/// Turn a list of strings into codes and sorted categories
+fn make_categories(list_values: &Vec<String>) -> (Vec<u32>, Vec<String>) {
+    let mut map_cat: BTreeMap<&String, u32> = BTreeMap::new();
+    for v in list_values {
+        map_cat.insert(v, 0);
+    }
+    let mut categories = Vec::new();
+    for (i, (k, v)) in map_cat.iter_mut().enumerate() {
+        *v = i as u32;
+        categories.push((*k).clone());
+    }
+    let codes = list_values.iter().map(|v| *map_cat.get(v).unwrap()).collect();
+    (codes, categories)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transpose_small_matrix() {
+        //Rows: [a _ b], [_ c _]
+        let (indptr, indices, data) = transpose_csr(&vec![0, 2, 3], &vec![0, 2, 1], &vec![1.0, 2.0, 3.0], 3).unwrap();
+        assert_eq!(indptr, vec![0, 1, 2, 3]);
+        assert_eq!(indices, vec![0, 1, 0]);
+        assert_eq!(data, vec![1.0, 3.0, 2.0]);
+    }
+
+    #[test]
+    fn transpose_rejects_broken_matrices() {
+        assert!(transpose_csr(&vec![], &vec![], &vec![], 3).is_err());
+        assert!(transpose_csr(&vec![0, 1], &vec![3], &vec![1.0], 3).is_err());
+        assert!(transpose_csr(&vec![0, 2, 1], &vec![0, 1], &vec![1.0, 2.0], 3).is_err());
+        assert!(transpose_csr(&vec![0, 1, 3], &vec![0, 1], &vec![1.0, 2.0], 3).is_err());
+        assert!(transpose_csr(&vec![0, 2], &vec![0, 1], &vec![1.0], 3).is_err());
+    }
+}
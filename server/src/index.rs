@@ -1,55 +1,287 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-use crate::ConfigFile;
-use crate::countfile::{index_countfile, CountFile};
+use anyhow::Context;
+use flate2::read::DeflateDecoder;
+use zip::{CompressionMethod, ZipArchive};
+
+use my_web_app::CellReadFile;
+use my_web_app::MarkersResponse;
+
+use crate::ConfigDataset;
+use crate::annotations::{AnnotatedDataSource, AnnotationStore, UserAnnotations};
+use crate::datasource::{is_datasource_file, open_datasource, DataSource};
 use crate::gbrowser_gff::{FeatureCollection, GBrowserGFFindex, GFFparseSettings};
+use crate::search::FeatureSearchIndex;
+use crate::gfflink::GffFeatureLinks;
+
+
+/// Bytes of reads sent at a time when downloading
+pub const CELL_READS_CHUNK_SIZE: usize = 64*1024;
+
+/// Extensions of files with reads or contigs, possibly gzipped. Other files of a cell are not served
+const READ_FILE_EXTENSIONS: [&str; 5] = ["fq", "fastq", "fa", "fasta", "fna"];
 
 
 ////////////////////////////////////////////////////////////
-/// 
-pub struct ListFiles { //TODO
+/// Check if a file of a cell holds reads or contigs
+pub fn is_read_file(file_name: &str) -> bool {
+    let file_name = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    if let Some((_, ext)) = file_name.rsplit_once('.') {
+        READ_FILE_EXTENSIONS.contains(&ext.to_lowercase().as_str())
+    } else {
+        false
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Name of the set a Bascet zip shard belongs to. Shards are named such as filtered.1.zip, filtered.2.zip,
+/// which are all in the set "filtered". A zip file without a shard number is a set of its own
+pub fn get_shard_set_name(p: &Path) -> Option<String> {
+    let file_name = p.file_name()?.to_str()?;
+    let stem = file_name.strip_suffix(".zip")?;
+    match stem.rsplit_once('.') {
+        Some((set_name, num)) if !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()) => Some(set_name.to_string()),
+        _ => Some(stem.to_string()),
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// All sets of Bascet zip shards of a dataset, by name
+pub struct ListFiles {
     pub files: BTreeMap<String, ShardSet>,
+}
+impl ListFiles {
+
+    ////////////////////////////////////////////////////////////
+    /// Index zip shards, grouping them into sets by name
+    pub fn index(list_zipfiles: &Vec<PathBuf>) -> anyhow::Result<ListFiles> {
+        let mut map_set_paths: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for p in list_zipfiles {
+            if let Some(set_name) = get_shard_set_name(p) {
+                map_set_paths.entry(set_name).or_default().push(p.clone());
+            }
+        }
+
+        let mut files = BTreeMap::new();
+        for (set_name, paths) in map_set_paths {
+            let shard_set = ShardSet::index(&paths)?;
+            println!("Shard set {}: {} shards, {} cells", set_name, shard_set.shards.len(), shard_set.map_cell_shard.len());
+            files.insert(set_name, shard_set);
+        }
+        Ok(ListFiles {
+            files: files,
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Files of reads of a cell, in all sets of shards
+    pub fn list_cell_files(&self, cell: &str) -> anyhow::Result<Vec<CellReadFile>> {
+        let mut list_files = Vec::new();
+        for (set_name, shard_set) in &self.files {
+            if let Some(shard) = shard_set.get_shard(cell) {
+                for (file_name, size) in shard.list_cell_files(cell)? {
+                    list_files.push(CellReadFile {
+                        shard_set: set_name.clone(),
+                        file_name: file_name,
+                        size: size,
+                    });
+                }
+            }
+        }
+        Ok(list_files)
+    }
 
+    ////////////////////////////////////////////////////////////
+    /// Open a file of reads of a cell
+    pub fn open_cell_file(&self, set_name: &str, cell: &str, file_name: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        let shard_set = self.files.get(set_name).context(format!("Could not find shard set {}", set_name))?;
+        let shard = shard_set.get_shard(cell).context(format!("Cell {} is not in shard set {}", cell, set_name))?;
+        shard.open_cell_file(cell, file_name)
+    }
 }
 
+
 ////////////////////////////////////////////////////////////
-/// 
-pub struct ShardSet {  //TODO
+/// Bascet zip shards written by one step, such as filtered.1.zip, filtered.2.zip... Each cell is in one shard
+pub struct ShardSet {
+    pub shards: Vec<BascetZipFile>,
+
+    /// Shard of each cell, by barcode
+    pub map_cell_shard: HashMap<String, usize>,
+}
+impl ShardSet {
+
+    ////////////////////////////////////////////////////////////
+    /// Index all shards of a set
+    pub fn index(paths: &Vec<PathBuf>) -> anyhow::Result<ShardSet> {
+        let mut shards = Vec::new();
+        let mut map_cell_shard = HashMap::new();
+        for p in paths {
+            let shard = BascetZipFile::open(p)?;
+            for cell in shard.cell_files.keys() {
+                if map_cell_shard.contains_key(cell) {
+                    println!("Cell {} is in more than one shard; ignoring it in {}", cell, p.display());
+                } else {
+                    map_cell_shard.insert(cell.clone(), shards.len());
+                }
+            }
+            shards.push(shard);
+        }
+        Ok(ShardSet {
+            shards: shards,
+            map_cell_shard: map_cell_shard,
+        })
+    }
 
-    pub lst: Vec<String>,
-    pub list_cells: Vec<String>, //which file is which? sqlite database?
-    
+    ////////////////////////////////////////////////////////////
+    /// Get the shard holding a cell
+    pub fn get_shard(&self, cell: &str) -> Option<&BascetZipFile> {
+        self.map_cell_shard.get(cell).map(|i| &self.shards[*i])
+    }
 }
 
+
 ////////////////////////////////////////////////////////////
-/// 
-pub struct BascetZipFile { //TODO
+/// One Bascet zip shard. Each cell is a directory of files, such as CELL/r1.fq and CELL/r2.fq.
+/// Only the central directory is read when indexing, as a shard can hold the reads of many cells
+pub struct BascetZipFile {
+    pub path: PathBuf,
+    archive: Mutex<ZipArchive<BufReader<File>>>,
 
+    /// Files of reads of each cell, as (name within the cell, index of zip entry).
+    /// Files in subdirectories of a cell keep their path, such as sub/r1.fq
+    pub cell_files: HashMap<String, Vec<(String, usize)>>,
+}
+impl BascetZipFile {
+
+    ////////////////////////////////////////////////////////////
+    /// Open a shard and index its cells
+    pub fn open(p: &Path) -> anyhow::Result<BascetZipFile> {
+        let f = File::open(p).context(format!("Could not open shard {}", p.display()))?;
+        let archive = ZipArchive::new(BufReader::new(f)).context(format!("Could not read zip file {}", p.display()))?;
+
+        let mut cell_files: HashMap<String, Vec<(String, usize)>> = HashMap::new();
+        for i in 0..archive.len() {
+            let entry_name = archive.name_for_index(i).context(format!("Missing zip entry {} in {}", i, p.display()))?;
+            if let Some((cell, file_name)) = entry_name.split_once('/') {
+                if is_read_file(file_name) {
+                    cell_files.entry(cell.to_string()).or_default().push((file_name.to_string(), i));
+                }
+            }
+        }
+        for list_files in cell_files.values_mut() {
+            list_files.sort();
+        }
 
+        Ok(BascetZipFile {
+            path: p.to_path_buf(),
+            archive: Mutex::new(archive),
+            cell_files: cell_files,
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Files of reads of a cell, as (name, uncompressed size)
+    pub fn list_cell_files(&self, cell: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut list_files = Vec::new();
+        if let Some(files) = self.cell_files.get(cell) {
+            let mut archive = self.archive.lock().unwrap();
+            for (file_name, index) in files {
+                let entry = archive.by_index_raw(*index)?;
+                list_files.push((file_name.clone(), entry.size()));
+            }
+        }
+        Ok(list_files)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Open a file of reads of a cell. Only the location of the entry is looked up in the archive;
+    /// the content is then read using a separate file handle, so other requests need not wait
+    pub fn open_cell_file(&self, cell: &str, file_name: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        let index = self.cell_files.get(cell)
+            .and_then(|files| files.iter().find(|(name, _)| name == file_name))
+            .map(|(_, index)| *index)
+            .context(format!("Cell {} has no file {}", cell, file_name))?;
+
+        let (data_start, compressed_size, compression) = {
+            let mut archive = self.archive.lock().unwrap();
+            let entry = archive.by_index_raw(index)?;
+            (entry.data_start(), entry.compressed_size(), entry.compression())
+        };
+
+        let mut f = File::open(&self.path).context(format!("Could not open shard {}", self.path.display()))?;
+        f.seek(SeekFrom::Start(data_start))?;
+        let raw = BufReader::new(f).take(compressed_size);
+        match compression {
+            CompressionMethod::Stored => Ok(Box::new(raw)),
+            CompressionMethod::Deflated => Ok(Box::new(DeflateDecoder::new(raw))),
+            other => anyhow::bail!("Compression {:?} of {}/{} in {} is not supported", other, cell, file_name, self.path.display()),
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////
 /// 
 pub struct BascetDir { //TODO
-    pub counts: CountFile,
+    pub counts: Box<dyn DataSource>,
     pub gff_data: Option<(GBrowserGFFindex,PathBuf)>,
+
+    /// Marker features already computed, by (count matrix, metadata column)
+    pub markers: Mutex<HashMap<(String, String), Arc<MarkersResponse>>>,
+
+    /// Feature names and GFF attributes, for searching
+    pub search_index: FeatureSearchIndex,
+
+    /// Links between GFF records and count matrix features
+    pub gff_links: GffFeatureLinks,
+
+    /// Categorical columns made by users. These are also seen through counts
+    pub annotations: Arc<UserAnnotations>,
+
+    /// Reads of each cell, in Bascet zip shards
+    pub shards: ListFiles,
 }
 
 
 ////////////////////////////////////////////////////////////
 /// Go through dir, index all files
-pub fn index_bascet_dir(bascet_dir: &Path, config: &ConfigFile) -> anyhow::Result<BascetDir> {
+pub fn index_bascet_dir(bascet_dir: &Path, config: &ConfigDataset, annotation_store: &Arc<AnnotationStore>) -> anyhow::Result<BascetDir> {
 
     let path_cf = bascet_dir.join("counts.biscvi5");
 
-    //Prepare count files
-    let cf = index_countfile(&path_cf)?;
-
+    let mut list_countfiles = Vec::new();
+    let mut list_zipfiles = Vec::new();
     let paths = std::fs::read_dir(bascet_dir).unwrap();
     for path in paths {
-        println!("Name: {}", path.unwrap().path().display())
+        let path = path.unwrap().path();
+        println!("Name: {}", path.display());
+        if is_datasource_file(&path) {
+            list_countfiles.push(path);
+        } else if path.is_file() && get_shard_set_name(&path).is_some() {
+            list_zipfiles.push(path);
+        }
     }
+    list_countfiles.sort();
+    list_zipfiles.sort();
+
+    //Prepare count files. Our own format is preferred over others
+    let cf = if path_cf.exists() {
+        open_datasource(&path_cf)?
+    } else if let Some(path_other) = list_countfiles.first() {
+        open_datasource(path_other)?
+    } else {
+        anyhow::bail!("No count file in {}", bascet_dir.display());
+    };
+
+    //Add user columns to the metadata
+    let annotations = Arc::new(UserAnnotations::new(Arc::clone(annotation_store), &config.name, cf.as_ref())?);
+    let cf: Box<dyn DataSource> = Box::new(AnnotatedDataSource::new(cf, Arc::clone(&annotations)));
 
     //Optional: Parse GFF file
     let gff_data = if let Some(gff_path) = &config.gff {
@@ -75,8 +307,25 @@ pub fn index_bascet_dir(bascet_dir: &Path, config: &ConfigFile) -> anyhow::Resul
         None
     };
 
+    //Index names for searching, and for linking the GFF to count matrices
+    let gff_records = if let Some((index, gff_path)) = &gff_data {
+        FeatureCollection::read_all_records(index, gff_path)?
+    } else {
+        Vec::new()
+    };
+    let search_index = FeatureSearchIndex::build(cf.as_ref(), &gff_records)?;
+    let gff_links = GffFeatureLinks::build(cf.as_ref(), &gff_records, &config.gff_link_attributes)?;
+
+    //Index reads of each cell
+    let shards = ListFiles::index(&list_zipfiles)?;
+
     Ok(BascetDir {
         counts: cf,
         gff_data,
+        markers: Mutex::new(HashMap::new()),
+        search_index,
+        gff_links,
+        annotations,
+        shards,
     })
 }
\ No newline at end of file
@@ -1,31 +1,88 @@
 pub mod index;
+pub mod cache;
+pub mod cellprofile;
 pub mod countfile;
+pub mod datasource;
+pub mod diffexp;
+pub mod normalize;
+pub mod h5ad;
+pub mod zarr;
+pub mod validate;
 pub mod err;
 pub mod gbrowser_gff;
 pub mod gbrowser_noodles;
-
+pub mod search;
+pub mod gfflink;
+pub mod export;
+pub mod subset;
+pub mod annotations;
+pub mod sessions;
+
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-use std::io::BufReader;
+use std::sync::Arc;
+use std::io::{BufReader, Read};
 
 use actix_files::Files;
-use actix_web::http::header::ContentType;
-use actix_web::web::Json;
-use actix_web::{web, web::Data, App, HttpResponse, HttpServer, post};
-use my_web_app::gbrowser_struct::{GBrowserGFFchunkRequest, GBrowserGFFchunkResponse, GBrowserGFFdescription, GBrowserGFFdescriptionRequest};
-use my_web_app::{FeatureCountsRequest, DatasetDescRequest, MetadataColumnRequest, ReductionRequest};
+use actix_web::http::header::{ContentDisposition, ContentType};
+use actix_web::web::{Bytes, Json};
+use actix_web::{web, web::Data, App, HttpResponse, HttpServer, get, post};
+use my_web_app::gbrowser_struct::{GBrowserGFFchunkRequest, GBrowserGFFchunkResponse, GBrowserGFFdescription, GBrowserGFFdescriptionRequest, GBrowserFeatureLinkRequest, GBrowserFeatureLinkResponse, GBrowserLocusRequest, GBrowserLocusResponse};
+use my_web_app::{DiffExpRequest, MarkersRequest, MarkersResponse, FeatureCountsBatchRequest, FeatureCountsBatchResponse, FeatureCountsRequest, DatasetDescRequest, DatasetListRequest, DatasetListResponse, MetadataColumnRequest, ReductionRequest, BarcodesRequest, BarcodesResponse, CellProfileRequest, FeatureSearchRequest, FeatureSearchResponse, FeatureNamesRequest, FeatureNamesResponse, FeatureLookupRequest, FeatureLookupResponse, ExportCellsRequest, ExportFormat, SubsetExportRequest, SubsetJobRequest, SubsetJobResponse, SubsetJobState, AnnotationEdit, AnnotationEditRequest, SessionListRequest, SessionListResponse, SessionSaveRequest, SessionRequest, SessionLoadResponse};
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::cache::{ResponseCache, ResponseCacheConfig, ResponseCacheKey};
+use crate::cellprofile::{compute_cell_profile, CELL_PROFILE_MAX_TOP_N};
+use crate::datasource::DataSource;
+use crate::diffexp::{compute_diffexp, compute_markers, MARKERS_MAX_PER_CATEGORY};
 use crate::err::MyError;
+use crate::export::{ExportTable, EXPORT_MAX_FORM_BYTES};
+use crate::normalize::normalize_response;
 use crate::gbrowser_gff::FeatureCollection;
-use crate::index::{index_bascet_dir, BascetDir};
+use crate::index::{index_bascet_dir, BascetDir, CELL_READS_CHUNK_SIZE};
+use crate::search::SEARCH_MAX_HITS;
+use crate::gfflink::default_gff_link_attributes;
+use crate::countfile::FEATURE_NAMES_MAX_PAGE;
+use crate::validate::{check_biscvi5, ValidationLevel};
+use crate::subset::{default_export_dir, SubsetJobs, SUBSET_STATUS_WAIT_MS};
+use crate::annotations::{default_annotations_db, AnnotationStore};
+use crate::sessions::{default_sessions_db, SessionStore};
+
+/// Largest JSON request accepted. Selections list cells, so subset, annotation, session
+/// and differential expression requests of large datasets are much larger than the default limit
+const REQUEST_MAX_JSON_BYTES: usize = 64*1024*1024;
 
 ////////////////////////////////////////////////////////////
-/// Backend state
+/// Backend state. The datasets are not modified after indexing, so they are shared
+/// between all workers without a lock. Each dataset is reference counted such that
+/// a request can keep hold of it while its work runs on the blocking thread pool,
+/// rather than on the async executor.
+/// What does change while serving, such as user annotations, sessions, subset jobs
+/// and the response cache, is kept behind locks of its own, held only briefly.
+///
+/// Reads of biscvi5 and h5ad files are not parallel. The hdf5 crate holds one global lock
+/// around every call into libhdf5, so two feature requests on such files, even of different
+/// datasets, still wait for each other. Requests on Zarr stores, requests answered from the
+/// response cache, and work on values already read do not
 pub struct ServerData {
-    bdir: BascetDir
+    bdirs: BTreeMap<String, Arc<BascetDir>>,
+    response_cache: ResponseCache,
+    subset_jobs: Arc<SubsetJobs>,
+    sessions: SessionStore,
+}
+impl ServerData {
+
+    ////////////////////////////////////////////////////////////
+    /// Get a dataset by name
+    pub fn get_dataset(&self, dataset_name: &String) -> anyhow::Result<Arc<BascetDir>> {
+        if let Some(bdir) = self.bdirs.get(dataset_name) {
+            anyhow::Ok(Arc::clone(bdir))
+        } else {
+            anyhow::bail!("Could not find dataset {}", dataset_name)
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////
@@ -33,27 +90,415 @@ pub struct ServerData {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ConfigFile {
     bind: String,
+
+    // A single dataset can be given directly; it will be called "default"
+    datadir: Option<String>,
+    gff: Option<PathBuf>,
+    #[serde(default = "default_gff_link_attributes")]
+    gff_link_attributes: Vec<String>,
+
+    #[serde(default)]
+    datasets: Vec<ConfigDataset>,
+
+    #[serde(default)]
+    cache: ResponseCacheConfig,
+
+    /// Where subsets of datasets are written for download
+    #[serde(default = "default_export_dir")]
+    export_dir: PathBuf,
+
+    /// SQLite database holding columns made by users
+    #[serde(default = "default_annotations_db")]
+    annotations_db: PathBuf,
+
+    /// SQLite database holding workspaces saved by users
+    #[serde(default = "default_sessions_db")]
+    sessions_db: PathBuf,
+}
+impl ConfigFile {
+
+    ////////////////////////////////////////////////////////////
+    /// Get all datasets to serve, including the unnamed one if given
+    pub fn get_datasets(&self) -> Vec<ConfigDataset> {
+        let mut list_datasets = Vec::new();
+        if let Some(datadir) = &self.datadir {
+            list_datasets.push(ConfigDataset {
+                name: "default".into(),
+                datadir: datadir.clone(),
+                gff: self.gff.clone(),
+                gff_link_attributes: self.gff_link_attributes.clone(),
+            });
+        }
+        list_datasets.extend(self.datasets.iter().cloned());
+        list_datasets
+    }
+}
+
+////////////////////////////////////////////////////////////
+/// Config for one dataset
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigDataset {
+    name: String,
     datadir: String,
     gff: Option<PathBuf>,
+    /// GFF attributes that hold feature names of count matrices, in order of preference
+    #[serde(default = "default_gff_link_attributes")]
+    gff_link_attributes: Vec<String>,
 }
 
 
 ////////////////////////////////////////////////////////////
 /// REST entry point: Get feature counts for a given cell
 #[post("/get_featurecounts")]
-async fn get_featurecounts(server_data: Data<Mutex<ServerData>>, req_body: web::Json<FeatureCountsRequest>) -> Result<HttpResponse, MyError> { 
+async fn get_featurecounts(server_data: Data<ServerData>, req_body: web::Json<FeatureCountsRequest>) -> Result<HttpResponse, MyError> { 
 
     println!("get_featurecounts {:?}",req_body);
     let Json(req) = req_body;
 
-    let server_data =server_data.lock().unwrap();
+    let cache_key = ResponseCacheKey::FeatureCounts(req.dataset_name.clone(), req.counts_name.clone(), req.feature_name.clone(), req.normalization);
+    let ser_out = if let Some(ser_out) = server_data.response_cache.get(&cache_key) {
+        ser_out
+    } else {
+        let bdir = server_data.get_dataset(&req.dataset_name)?;
+        let mat = web::block(move || {
+            let feature_index = bdir.counts.get_feature_index(&req.counts_name, &req.feature_name)?;
+            let mat = bdir.counts.get_counts_for_cell(&req.counts_name, feature_index as u32)?;
+            normalize_response(bdir.counts.as_ref(), &req.counts_name, mat, req.normalization)
+        }).await??;
+        let ser_out = Bytes::from(serde_cbor::to_vec(&mat)?);
+        server_data.response_cache.insert(cache_key, ser_out.clone());
+        ser_out
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
 
-    let feature_index = server_data.bdir.counts.get_feature_index(&req.counts_name, &req.feature_name)?;
+////////////////////////////////////////////////////////////
+/// REST entry point: Get feature counts for many features at once
+#[post("/get_featurecounts_batch")]
+async fn get_featurecounts_batch(server_data: Data<ServerData>, req_body: web::Json<FeatureCountsBatchRequest>) -> Result<HttpResponse, MyError> { 
 
-    let mat = server_data.bdir.counts.get_counts_for_cell(&req.counts_name.into(), feature_index as u32)?;
-    let ser_out = serde_cbor::to_vec(&mat)?;
+    println!("get_featurecounts_batch {:?}",req_body);
+    let Json(req) = req_body;
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+
+    let list_mat = web::block(move || {
+        let mut rows = Vec::new();
+        for feature_name in &req.feature_names {
+            let feature_index = bdir.counts.get_feature_index(&req.counts_name, feature_name)
+                .map_err(|e| e.context(format!("Unknown feature {}", feature_name)))?;
+            rows.push(feature_index as u32);
+        }
+        let list_mat = bdir.counts.get_counts_batch(&req.counts_name, &rows)?;
+        list_mat.into_iter().map(|mat| normalize_response(bdir.counts.as_ref(), &req.counts_name, mat, req.normalization)).collect::<anyhow::Result<Vec<_>>>()
+    }).await??;
+    let resp = FeatureCountsBatchResponse {
+        data: list_mat.into_iter().map(|x| x.data).collect()
+    };
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Differential expression between two groups of cells
+#[post("/get_diffexp")]
+async fn get_diffexp(server_data: Data<ServerData>, req_body: web::Json<DiffExpRequest>) -> Result<HttpResponse, MyError> { 
+
+    let Json(req) = req_body;
+    println!("get_diffexp {} {} cells vs {:?} cells", req.counts_name, req.group_a.len(), req.group_b.as_ref().map(|x| x.len()));
 
-    println!("get_featurecounts response {:?}",mat);
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let resp = web::block(move || {
+        compute_diffexp(bdir.counts.as_ref(), &req.counts_name, &req.group_a, &req.group_b)
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Marker features for each category of a metadata column.
+/// These are computed once per count matrix and column, then cached
+#[post("/get_markers")]
+async fn get_markers(server_data: Data<ServerData>, req_body: web::Json<MarkersRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("get_markers {:?}",req_body);
+    let Json(req) = req_body;
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+
+    let key = (req.counts_name.clone(), req.column_name.clone());
+    let cached = bdir.markers.lock().unwrap().get(&key).cloned();
+    let markers = if let Some(markers) = cached {
+        markers
+    } else {
+        //Two requests for the same markers may both compute them; the last one to finish is kept
+        let bdir_block = Arc::clone(&bdir);
+        let counts_name = req.counts_name.clone();
+        let column_name = req.column_name.clone();
+        let markers = Arc::new(web::block(move || {
+            compute_markers(bdir_block.counts.as_ref(), &counts_name, &column_name, MARKERS_MAX_PER_CATEGORY)
+        }).await??);
+        bdir.markers.lock().unwrap().insert(key, Arc::clone(&markers));
+        markers
+    };
+
+    //Only send as many as asked for
+    let resp = MarkersResponse {
+        categories: markers.categories.clone(),
+        markers: markers.markers.iter().map(|m| m.iter().take(req.top_n).cloned().collect()).collect(),
+    };
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Search features by name, in all count matrices and the GFF
+#[post("/search_features")]
+async fn search_features(server_data: Data<ServerData>, req_body: web::Json<FeatureSearchRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("search_features {:?}",req_body);
+    let Json(req) = req_body;
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let limit = req.limit.min(SEARCH_MAX_HITS);
+    let resp = web::block(move || {
+        FeatureSearchResponse {
+            hits: bdir.search_index.search(&req.query, limit),
+            query: req.query,
+        }
+    }).await?;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: A page of feature names of one count matrix
+#[post("/get_feature_names")]
+async fn get_feature_names(server_data: Data<ServerData>, req_body: web::Json<FeatureNamesRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("get_feature_names {:?}",req_body);
+    let Json(req) = req_body;
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let limit = req.limit.min(FEATURE_NAMES_MAX_PAGE);
+    let resp = web::block(move || {
+        let mat = bdir.counts.get_matrix(&req.counts_name)?;
+        anyhow::Ok(FeatureNamesResponse {
+            feature_names: mat.get_feature_names_page(req.offset, limit),
+            num_features: mat.list_feature_names.len(),
+            offset: req.offset,
+            counts_name: req.counts_name,
+        })
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Check which names are features of one count matrix
+#[post("/lookup_features")]
+async fn lookup_features(server_data: Data<ServerData>, req_body: web::Json<FeatureLookupRequest>) -> Result<HttpResponse, MyError> { 
+
+    let Json(req) = req_body;
+    println!("lookup_features {} names in {}", req.feature_names.len(), req.counts_name);
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let resp = web::block(move || {
+        let mat = bdir.counts.get_matrix(&req.counts_name)?;
+        let (known, unknown): (Vec<String>, Vec<String>) = req.feature_names.into_iter().partition(|f| mat.map_feature_names_pos.contains_key(f));
+        anyhow::Ok(FeatureLookupResponse {
+            counts_name: req.counts_name,
+            known,
+            unknown,
+        })
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// Form posted by the browser to export cells. The request is JSON in a single field
+#[derive(Debug, Deserialize)]
+pub struct ExportCellsForm {
+    query: String,
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Export values of cells as CSV or TSV. Only values of the selected cells are kept,
+/// and the table is sent as it is written, so the text of large exports is not held in memory
+#[post("/export_cells")]
+async fn export_cells(server_data: Data<ServerData>, form: web::Form<ExportCellsForm>) -> Result<HttpResponse, MyError> { 
+
+    let req: ExportCellsRequest = serde_json::from_str(&form.query).map_err(anyhow::Error::from)?;
+    println!("export_cells {:?} cells, {} columns, {} reductions, {} features", req.cells.as_ref().map(|x| x.len()), req.meta_columns.len(), req.reductions.len(), req.features.len());
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let file_name = format!("{}_cells.{}", req.dataset_name, req.format.name().to_lowercase());
+    let content_type = match req.format {
+        ExportFormat::CSV => "text/csv",
+        ExportFormat::TSV => "text/tab-separated-values",
+    };
+    let table = web::block(move || ExportTable::build(bdir.counts.as_ref(), &req)).await??;
+
+    let body = async_stream::stream! {
+        for chunk in 0..table.num_chunks() {
+            yield table.write_chunk(chunk).map_err(actix_web::error::ErrorInternalServerError);
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition::attachment(file_name))
+        .streaming(body))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Start writing a subset of cells as a new dataset
+#[post("/subset_start")]
+async fn subset_start(server_data: Data<ServerData>, req_body: web::Json<SubsetExportRequest>) -> Result<HttpResponse, MyError> { 
+
+    let Json(req) = req_body;
+    println!("subset_start {} cells as {:?}", req.cells.len(), req.format);
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let job_id = server_data.subset_jobs.start(bdir, req)?;
+    let resp = SubsetJobResponse {
+        job_id,
+        state: server_data.subset_jobs.get_state(job_id)?,
+    };
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: State of a subset job. While the job is running, this waits
+/// until the state changes, so the client can ask again as soon as it gets an answer
+#[post("/subset_status")]
+async fn subset_status(server_data: Data<ServerData>, req_body: web::Json<SubsetJobRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("subset_status {:?}",req_body);
+    let Json(req) = req_body;
+
+    let first_state = server_data.subset_jobs.get_state(req.job_id)?;
+    let mut state = first_state.clone();
+    let mut waited_ms = 0;
+    while matches!(state, SubsetJobState::Running(_)) && state == first_state && waited_ms < SUBSET_STATUS_WAIT_MS {
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(250)).await;
+        waited_ms += 250;
+        state = server_data.subset_jobs.get_state(req.job_id)?;
+    }
+    let resp = SubsetJobResponse {
+        job_id: req.job_id,
+        state,
+    };
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Download a file written by a subset job
+#[get("/subset_download/{job_id}/{file_name}")]
+async fn subset_download(server_data: Data<ServerData>, path: web::Path<(u64, String)>) -> Result<actix_files::NamedFile, MyError> { 
+
+    let (job_id, file_name) = path.into_inner();
+    println!("subset_download {} {}", job_id, file_name);
+
+    let p = server_data.subset_jobs.get_file(job_id, &file_name)?;
+    let file = actix_files::NamedFile::open(p)?
+        .set_content_disposition(ContentDisposition::attachment(file_name));
+    Ok(file)
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Download a file of reads or contigs of a cell, from a Bascet zip shard.
+/// The file may be in a subdirectory of the cell, so its name can contain /.
+/// The file is decompressed as it is sent, a chunk at a time on the blocking thread pool
+#[get("/cell_reads/{dataset_name}/{shard_set}/{barcode}/{file_name:.*}")]
+async fn cell_reads(server_data: Data<ServerData>, path: web::Path<(String, String, String, String)>) -> Result<HttpResponse, MyError> { 
+
+    let (dataset_name, shard_set, barcode, file_name) = path.into_inner();
+    println!("cell_reads {} {} {} {}", dataset_name, shard_set, barcode, file_name);
+
+    let bdir = server_data.get_dataset(&dataset_name)?;
+    let download_name = format!("{}_{}", barcode, file_name.replace('/', "_"));
+    let content_type = if file_name.ends_with(".gz") {
+        "application/gzip"
+    } else {
+        "text/plain"
+    };
+    let reader = web::block(move || bdir.shards.open_cell_file(&shard_set, &barcode, &file_name)).await??;
+
+    let body = async_stream::stream! {
+        let mut reader = Some(reader);
+        while let Some(mut r) = reader.take() {
+            let chunk = web::block(move || {
+                let mut buf = Vec::with_capacity(CELL_READS_CHUNK_SIZE);
+                (&mut r).take(CELL_READS_CHUNK_SIZE as u64).read_to_end(&mut buf)?;
+                Ok::<_, std::io::Error>((r, buf))
+            }).await;
+            match chunk {
+                Ok(Ok((r, buf))) => {
+                    if buf.is_empty() {
+                        break;
+                    }
+                    reader = Some(r);
+                    yield Ok(Bytes::from(buf));
+                },
+                Ok(Err(e)) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                },
+                Err(e) => {
+                    yield Err(actix_web::error::ErrorInternalServerError(e));
+                },
+            }
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition::attachment(download_name))
+        .streaming(body))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Everything about one cell, for the cell inspector
+#[post("/get_cell_profile")]
+async fn get_cell_profile(server_data: Data<ServerData>, req_body: web::Json<CellProfileRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("get_cell_profile {:?}",req_body);
+    let Json(req) = req_body;
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let top_n = req.top_n.min(CELL_PROFILE_MAX_TOP_N);
+    let profile = web::block(move || compute_cell_profile(bdir.counts.as_ref(), &bdir.shards, req.cell, top_n)).await??;
+    let ser_out = serde_cbor::to_vec(&profile)?;
 
     Ok(HttpResponse::Ok()
         .content_type(ContentType::octet_stream())
@@ -63,13 +508,13 @@ async fn get_featurecounts(server_data: Data<Mutex<ServerData>>, req_body: web::
 ////////////////////////////////////////////////////////////
 /// REST entry point: Get coordinates for a reduction
 #[post("/get_reduction")]
-async fn get_reduction(server_data: Data<Mutex<ServerData>>, req_body: web::Json<ReductionRequest>) -> Result<HttpResponse, MyError> { 
+async fn get_reduction(server_data: Data<ServerData>, req_body: web::Json<ReductionRequest>) -> Result<HttpResponse, MyError> { 
 
     println!("get_reduction {:?}",req_body);
     let Json(req) = req_body;
 
-    let server_data =server_data.lock().unwrap();
-    let mat = server_data.bdir.counts.get_reduction(&req.reduction_name.into())?;
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let mat = web::block(move || bdir.counts.get_reduction(&req.reduction_name.into())).await??;
     let ser_out = serde_cbor::to_vec(&mat)?;
 
     Ok(HttpResponse::Ok()
@@ -80,14 +525,65 @@ async fn get_reduction(server_data: Data<Mutex<ServerData>>, req_body: web::Json
 ////////////////////////////////////////////////////////////
 /// REST entry point: Get a metadata column
 #[post("/get_metacolumn")]
-async fn get_metacolumn(server_data: Data<Mutex<ServerData>>, req_body: web::Json<MetadataColumnRequest>) -> Result<HttpResponse, MyError> { 
+async fn get_metacolumn(server_data: Data<ServerData>, req_body: web::Json<MetadataColumnRequest>) -> Result<HttpResponse, MyError> { 
 
     println!("get_metacolumn {:?}",req_body);
     let Json(req) = req_body;
 
-    let server_data =server_data.lock().unwrap();
-    let mat = server_data.bdir.counts.get_metacolumn(&req.column_name.into())?;
-    let ser_out = serde_cbor::to_vec(&mat)?;
+    let cache_key = ResponseCacheKey::MetaColumn(req.dataset_name.clone(), req.column_name.clone());
+    let ser_out = if let Some(ser_out) = server_data.response_cache.get(&cache_key) {
+        ser_out
+    } else {
+        let bdir = server_data.get_dataset(&req.dataset_name)?;
+        let mat = web::block(move || bdir.counts.get_metacolumn(&req.column_name.into())).await??;
+        let ser_out = Bytes::from(serde_cbor::to_vec(&mat)?);
+        server_data.response_cache.insert(cache_key, ser_out.clone());
+        ser_out
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Names of all cells
+#[post("/get_barcodes")]
+async fn get_barcodes(server_data: Data<ServerData>, req_body: web::Json<BarcodesRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("get_barcodes {:?}",req_body);
+    let Json(req) = req_body;
+
+    let cache_key = ResponseCacheKey::Barcodes(req.dataset_name.clone());
+    let ser_out = if let Some(ser_out) = server_data.response_cache.get(&cache_key) {
+        ser_out
+    } else {
+        let bdir = server_data.get_dataset(&req.dataset_name)?;
+        let barcodes = web::block(move || bdir.counts.get_barcodes()).await??;
+        let out = BarcodesResponse {
+            barcodes: barcodes
+        };
+        let ser_out = Bytes::from(serde_cbor::to_vec(&out)?);
+        server_data.response_cache.insert(cache_key, ser_out.clone());
+        ser_out
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: List all datasets
+#[post("/get_dataset_list")]
+async fn get_dataset_list(server_data: Data<ServerData>, req_body: web::Json<DatasetListRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("get_dataset_list {:?}",req_body);
+
+    let out = DatasetListResponse {
+        datasets: server_data.bdirs.keys().cloned().collect()
+    };
+    let ser_out = serde_cbor::to_vec(&out)?;
 
     Ok(HttpResponse::Ok()
         .content_type(ContentType::octet_stream())
@@ -97,12 +593,13 @@ async fn get_metacolumn(server_data: Data<Mutex<ServerData>>, req_body: web::Jso
 ////////////////////////////////////////////////////////////
 /// REST entry point
 #[post("/get_dataset_desc")]
-async fn get_dataset_desc(server_data: Data<Mutex<ServerData>>, req_body: web::Json<DatasetDescRequest>) -> Result<HttpResponse, MyError> { 
+async fn get_dataset_desc(server_data: Data<ServerData>, req_body: web::Json<DatasetDescRequest>) -> Result<HttpResponse, MyError> { 
 
     println!("get_dataset_desc {:?}",req_body);
+    let Json(req) = req_body;
 
-    let server_data =server_data.lock().unwrap();
-    let mat = server_data.bdir.counts.get_desc()?; 
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let mat = web::block(move || bdir.counts.get_desc()).await??;
     let ser_out = serde_cbor::to_vec(&mat)?;
 
     Ok(HttpResponse::Ok()
@@ -113,16 +610,178 @@ async fn get_dataset_desc(server_data: Data<Mutex<ServerData>>, req_body: web::J
 
 
 
+////////////////////////////////////////////////////////////
+/// REST entry point: Change a column made by users. Returns the new description of the dataset
+#[post("/edit_annotation")]
+async fn edit_annotation(server_data: Data<ServerData>, req_body: web::Json<AnnotationEditRequest>) -> Result<HttpResponse, MyError> { 
+
+    let Json(AnnotationEditRequest { dataset_name, edit }) = req_body;
+    match &edit {
+        AnnotationEdit::SetLabel(column_name, label, cells) => println!("edit_annotation {} set {:?} for {} cells", column_name, label, cells.len()),
+        edit => println!("edit_annotation {:?}", edit),
+    }
+
+    let bdir = server_data.get_dataset(&dataset_name)?;
+
+    //Responses about the changed columns are no longer valid
+    let mut changed_columns = vec![edit.get_column_name().clone()];
+    if let AnnotationEdit::RenameColumn(_, new_name) = &edit {
+        changed_columns.push(new_name.clone());
+    }
+    let desc = web::block(move || {
+        bdir.annotations.edit(&edit)?;
+        bdir.markers.lock().unwrap().retain(|(_counts_name, column_name), _| !changed_columns.contains(column_name));
+        for column_name in &changed_columns {
+            server_data.response_cache.remove(&ResponseCacheKey::MetaColumn(dataset_name.clone(), column_name.clone()));
+        }
+        bdir.counts.get_desc()
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&desc)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: List the sessions saved for a dataset
+#[post("/session_list")]
+async fn session_list(server_data: Data<ServerData>, req_body: web::Json<SessionListRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("session_list {:?}",req_body);
+    let Json(req) = req_body;
+
+    server_data.get_dataset(&req.dataset_name)?;
+    let resp = web::block(move || {
+        anyhow::Ok(SessionListResponse {
+            sessions: server_data.sessions.list(&req.dataset_name)?,
+        })
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Save a session under a name. Returns the new list of sessions
+#[post("/session_save")]
+async fn session_save(server_data: Data<ServerData>, req_body: web::Json<SessionSaveRequest>) -> Result<HttpResponse, MyError> { 
+
+    let Json(req) = req_body;
+    println!("session_save {} {}", req.dataset_name, req.session_name);
+
+    server_data.get_dataset(&req.dataset_name)?;
+    let resp = web::block(move || {
+        server_data.sessions.save(&req.dataset_name, &req.session_name, &req.content)?;
+        anyhow::Ok(SessionListResponse {
+            sessions: server_data.sessions.list(&req.dataset_name)?,
+        })
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Load a saved session
+#[post("/session_load")]
+async fn session_load(server_data: Data<ServerData>, req_body: web::Json<SessionRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("session_load {:?}",req_body);
+    let Json(req) = req_body;
+
+    server_data.get_dataset(&req.dataset_name)?;
+    let resp = web::block(move || {
+        anyhow::Ok(SessionLoadResponse {
+            content: server_data.sessions.load(&req.dataset_name, &req.session_name)?,
+            session_name: req.session_name,
+        })
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Delete a saved session. Returns the new list of sessions
+#[post("/session_delete")]
+async fn session_delete(server_data: Data<ServerData>, req_body: web::Json<SessionRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("session_delete {:?}",req_body);
+    let Json(req) = req_body;
+
+    server_data.get_dataset(&req.dataset_name)?;
+    let resp = web::block(move || {
+        server_data.sessions.delete(&req.dataset_name, &req.session_name)?;
+        anyhow::Ok(SessionListResponse {
+            sessions: server_data.sessions.list(&req.dataset_name)?,
+        })
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Find the count matrix feature that a GFF record refers to
+#[post("/get_gff_feature_link")]
+async fn get_gff_feature_link(server_data: Data<ServerData>, req_body: web::Json<GBrowserFeatureLinkRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("get_gff_feature_link {:?}",req_body);
+    let Json(req) = req_body;
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let resp = web::block(move || {
+        anyhow::Ok(GBrowserFeatureLinkResponse {
+            feature: bdir.gff_links.resolve(bdir.counts.as_ref(), &req.attributes)?,
+        })
+    }).await??;
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
+////////////////////////////////////////////////////////////
+/// REST entry point: Where a count matrix feature is in the genome
+#[post("/get_feature_locus")]
+async fn get_feature_locus(server_data: Data<ServerData>, req_body: web::Json<GBrowserLocusRequest>) -> Result<HttpResponse, MyError> { 
+
+    println!("get_feature_locus {:?}",req_body);
+    let Json(req) = req_body;
+
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let resp = GBrowserLocusResponse {
+        locus: bdir.gff_links.get_locus(&req.counts_name, &req.feature_name),
+        feature_name: req.feature_name,
+    };
+    let ser_out = serde_cbor::to_vec(&resp)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::octet_stream())
+        .body(ser_out))
+}
+
 ////////////////////////////////////////////////////////////
 /// REST entry point
 #[post("/get_gff_desc")]
-async fn get_gff_desc(server_data: Data<Mutex<ServerData>>, req_body: web::Json<GBrowserGFFdescriptionRequest>) -> Result<HttpResponse, MyError> { 
+async fn get_gff_desc(server_data: Data<ServerData>, req_body: web::Json<GBrowserGFFdescriptionRequest>) -> Result<HttpResponse, MyError> { 
 
     println!("get_gff_desc {:?}",req_body);
+    let Json(req) = req_body;
 
-    let server_data =server_data.lock().unwrap(); 
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
 
-    let out = if let Some((gff_index, _gff_path)) = &server_data.bdir.gff_data {
+    let out = if let Some((gff_index, _gff_path)) = &bdir.gff_data {
         gff_index.get_description()
     } else {
         GBrowserGFFdescription::new()
@@ -138,13 +797,13 @@ async fn get_gff_desc(server_data: Data<Mutex<ServerData>>, req_body: web::Json<
 ////////////////////////////////////////////////////////////
 /// REST entry point
 #[post("/get_gff_chunks")]
-async fn get_gff_chunks(server_data: Data<Mutex<ServerData>>, req_body: web::Json<GBrowserGFFchunkRequest>) -> Result<HttpResponse, MyError> { 
+async fn get_gff_chunks(server_data: Data<ServerData>, req_body: web::Json<GBrowserGFFchunkRequest>) -> Result<HttpResponse, MyError> { 
 
     println!("get_gff_chunks {:?}",req_body);
     let Json(req) = req_body;
 
-    let server_data = server_data.lock().unwrap();
-    let out = if let Some((gff_index, gff_path)) = &server_data.bdir.gff_data {
+    let bdir = server_data.get_dataset(&req.dataset_name)?;
+    let out = if let Some((gff_index, gff_path)) = &bdir.gff_data {
         FeatureCollection::get_gff_response(&req, gff_index, gff_path).await?
     } else {
         //Return empty response if no data. or error?
@@ -169,29 +828,103 @@ async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "1");
     env_logger::init();
 
+    // Subcommand: only validate files, then exit
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|s| s.as_str()) == Some("validate") {
+        if args.len() < 3 {
+            println!("Usage: {} validate FILE.biscvi5 ...", args[0]);
+            std::process::exit(2);
+        }
+        let mut all_ok = true;
+        for p in &args[2..] {
+            if let Err(e) = check_biscvi5(&PathBuf::from(p), ValidationLevel::Full) {
+                println!("{}", e);
+                all_ok = false;
+            }
+        }
+        std::process::exit(if all_ok { 0 } else { 1 });
+    }
+
     // Read the config file
     let f_meta = File::open("config.json").expect("Could not open config.json");
     let config_reader = BufReader::new(f_meta);
     let config_file:ConfigFile = serde_json::from_reader(config_reader).expect("Could not open config file");
 
-    let bascet_dir = Path::new(&config_file.datadir);
-    let bdir = index_bascet_dir(&bascet_dir, &config_file).expect("Failed to index data");
+    // User annotations are kept in one database for all datasets
+    let annotation_store = match AnnotationStore::open(&config_file.annotations_db) {
+        Ok(store) => Arc::new(store),
+        Err(e) => panic!("Failed to open annotation database: {:#}", e),
+    };
+    let session_store = match SessionStore::open(&config_file.sessions_db) {
+        Ok(store) => store,
+        Err(e) => panic!("Failed to open session database: {:#}", e),
+    };
+
+    // Dataset names must be unique. The dataset given by datadir is called "default"
+    let list_datasets = config_file.get_datasets();
+    let mut seen_names = std::collections::BTreeSet::new();
+    for config_dataset in &list_datasets {
+        if !seen_names.insert(config_dataset.name.clone()) {
+            panic!("Dataset {} is given more than once in config file", config_dataset.name);
+        }
+    }
+
+    // Index all datasets
+    let mut bdirs = BTreeMap::new();
+    for config_dataset in list_datasets {
+        println!("Indexing dataset {}", config_dataset.name);
+        let bascet_dir = Path::new(&config_dataset.datadir);
+        let bdir = match index_bascet_dir(&bascet_dir, &config_dataset, &annotation_store) {
+            Ok(bdir) => bdir,
+            Err(e) => panic!("Failed to index dataset {}: {:#}", config_dataset.name, e),
+        };
+        bdirs.insert(config_dataset.name.clone(), Arc::new(bdir));
+    }
+    if bdirs.is_empty() {
+        panic!("No datasets given in config file");
+    }
     
-    let data = Data::new(Mutex::new(
+    let data = Data::new(
         ServerData {
-            bdir: bdir
+            bdirs: bdirs,
+            response_cache: ResponseCache::new(config_file.cache.clone()),
+            subset_jobs: Arc::new(SubsetJobs::new(config_file.export_dir.clone())),
+            sessions: session_store,
         }
-    ));
+    );
 
     HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(web::FormConfig::default().limit(EXPORT_MAX_FORM_BYTES))
+            .app_data(web::JsonConfig::default().limit(REQUEST_MAX_JSON_BYTES))
             .wrap(actix_web::middleware::Logger::default())  //for debugging
             .service(get_featurecounts)
+            .service(get_featurecounts_batch)
+            .service(get_diffexp)
+            .service(get_markers)
             .service(get_reduction)
             .service(get_metacolumn)
+            .service(get_barcodes)
+            .service(get_cell_profile)
+            .service(cell_reads)
+            .service(export_cells)
+            .service(subset_start)
+            .service(subset_status)
+            .service(subset_download)
+            .service(search_features)
+            .service(get_feature_names)
+            .service(lookup_features)
+            .service(get_dataset_list)
             .service(get_dataset_desc)
+            .service(edit_annotation)
+            .service(session_list)
+            .service(session_save)
+            .service(session_load)
+            .service(session_delete)
             .service(get_gff_desc)
+            .service(get_gff_feature_link)
+            .service(get_feature_locus)
             .service(get_gff_chunks)
             .service(Files::new("/", "./dist/").index_file("index.html"))
             //.service(get_)
@@ -0,0 +1,106 @@
+use my_web_app::CountFileMetaColumnData;
+use my_web_app::CountNormalization;
+use my_web_app::MetadataColumnResponse;
+
+use crate::datasource::DataSource;
+
+/// Counts of each cell are scaled to add up to this, as scanpy normalize_total(target_sum=1e4)
+pub const NORMALIZE_TARGET_SUM: f64 = 10000.0;
+
+/// Number of non-zero values read at a time when summing counts per cell
+pub const CELL_TOTALS_CHUNK: usize = 1024*1024;
+
+
+////////////////////////////////////////////////////////////
+/// Add the values of a feature-major sparse block to the total of each cell.
+/// The list grows as needed; cells without any counts may be missing at the end
+pub fn add_cell_totals(totals: &mut Vec<f64>, indices: &[u32], data: &[f32]) {
+    for (cell, v) in indices.iter().zip(data.iter()) {
+        let cell = *cell as usize;
+        if cell >= totals.len() {
+            totals.resize(cell + 1, 0.0);
+        }
+        totals[cell] += *v as f64;
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Counts per 10k for one cell. Cells without counts stay at 0
+fn scale_to_target(v: f32, total: f64) -> f64 {
+    if total > 0.0 {
+        (v as f64) / total * NORMALIZE_TARGET_SUM
+    } else {
+        0.0
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Normalize the counts of one feature over all cells, given as SparseNumeric.
+/// CP10k and log1p keep zeros, so the result stays sparse. The z-score is taken over
+/// log1p(CP10k) including zeros, which makes every cell non-zero and the result dense
+pub fn normalize_feature_counts(
+    counts: CountFileMetaColumnData,
+    cell_totals: &Vec<f64>,
+    num_cells: usize,
+    mode: CountNormalization
+) -> anyhow::Result<CountFileMetaColumnData> {
+
+    let (indices, data) = match counts {
+        CountFileMetaColumnData::SparseNumeric(indices, data) => (indices, data),
+        _ => anyhow::bail!("Only sparse counts can be normalized"),
+    };
+    let get_total = |cell: u32| *cell_totals.get(cell as usize).unwrap_or(&0.0);
+
+    match mode {
+        CountNormalization::Raw => {
+            Ok(CountFileMetaColumnData::SparseNumeric(indices, data))
+        },
+        CountNormalization::CP10k => {
+            let data = indices.iter().zip(data.iter()).map(|(cell, v)| scale_to_target(*v, get_total(*cell)) as f32).collect();
+            Ok(CountFileMetaColumnData::SparseNumeric(indices, data))
+        },
+        CountNormalization::Log1pCP10k => {
+            let data = indices.iter().zip(data.iter()).map(|(cell, v)| scale_to_target(*v, get_total(*cell)).ln_1p() as f32).collect();
+            Ok(CountFileMetaColumnData::SparseNumeric(indices, data))
+        },
+        CountNormalization::ZScore => {
+            let log_values = indices.iter().zip(data.iter()).map(|(cell, v)| scale_to_target(*v, get_total(*cell)).ln_1p()).collect::<Vec<_>>();
+
+            //Mean and variance over all cells. Zeros add nothing to the sums
+            let n = num_cells.max(1) as f64;
+            let mean = log_values.iter().sum::<f64>() / n;
+            let sum_sq = log_values.iter().map(|x| x*x).sum::<f64>();
+            let var = (sum_sq / n - mean*mean).max(0.0);
+            let sd = var.sqrt();
+
+            let zscore = |x: f64| if sd > 0.0 { ((x - mean) / sd) as f32 } else { 0.0 };
+            let mut out = vec![zscore(0.0); num_cells];
+            for (cell, x) in indices.iter().zip(log_values.iter()) {
+                if let Some(o) = out.get_mut(*cell as usize) {
+                    *o = zscore(*x);
+                }
+            }
+            Ok(CountFileMetaColumnData::Numeric(out))
+        },
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Normalize the counts of one feature, using the size factors of its count matrix
+pub fn normalize_response(ds: &dyn DataSource, counts_name: &String, mat: MetadataColumnResponse, mode: CountNormalization) -> anyhow::Result<MetadataColumnResponse> {
+    if mode == CountNormalization::Raw {
+        return Ok(mat);
+    }
+    let cell_totals = ds.get_cell_totals(counts_name)?;
+    let num_cells = if mode == CountNormalization::ZScore {
+        ds.get_num_cells()?
+    } else {
+        cell_totals.len()
+    };
+    Ok(MetadataColumnResponse {
+        data: normalize_feature_counts(mat.data, cell_totals, num_cells, mode)?
+    })
+}
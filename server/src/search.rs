@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+
+use my_web_app::gbrowser_struct::GBrowserAttributeValue;
+use my_web_app::gbrowser_struct::GBrowserRecordBuf;
+use my_web_app::FeatureSearchGffHit;
+use my_web_app::FeatureSearchHit;
+use my_web_app::FeatureSearchMatch;
+
+use crate::countfile::CountFileMat;
+use crate::datasource::DataSource;
+
+/// GFF attributes that are searched, besides feature names in count matrices
+pub const GFF_SEARCH_ATTRIBUTES: [&str; 5] = ["Name", "gene", "gene_name", "locus_tag", "product"];
+
+/// Most hits returned for one search. Requests can ask for fewer
+pub const SEARCH_MAX_HITS: usize = 500;
+
+/// Queries shorter than this are not matched fuzzily, as almost anything would match
+const FUZZY_MIN_QUERY_LEN: usize = 3;
+
+
+////////////////////////////////////////////////////////////
+/// One searchable name
+struct SearchEntry {
+    /// Lowercase name, for case-insensitive matching
+    key: String,
+    /// What to return if it matches. The match type is filled in when searching
+    hit: FeatureSearchHit,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Index of feature names in all count matrices, and of selected GFF attributes.
+/// Entries are sorted by lowercase name, so that prefixes can be found by binary search
+pub struct FeatureSearchIndex {
+    entries: Vec<SearchEntry>,
+}
+impl FeatureSearchIndex {
+
+    ////////////////////////////////////////////////////////////
+    /// Build the index from the count matrices of a dataset and all GFF records
+    pub fn build(ds: &dyn DataSource, gff_records: &Vec<GBrowserRecordBuf>) -> anyhow::Result<FeatureSearchIndex> {
+        let mut entries = Vec::new();
+
+        //Matrices are sorted by name, so that the order of entries and the matrix a GFF hit points to are the same between runs
+        let mut counts_names = ds.get_desc()?.matrices.keys().cloned().collect::<Vec<_>>();
+        counts_names.sort();
+        let mut matrices: Vec<(String, &CountFileMat)> = Vec::new();
+        for counts_name in counts_names {
+            let mat = ds.get_matrix(&counts_name)?;
+            matrices.push((counts_name, mat));
+        }
+
+        //Feature names of each matrix
+        let mut all_feature_names = HashSet::new();
+        for (counts_name, mat) in &matrices {
+            for feature_name in &mat.list_feature_names {
+                all_feature_names.insert(feature_name.as_str());
+                entries.push(SearchEntry {
+                    key: feature_name.to_lowercase(),
+                    hit: FeatureSearchHit {
+                        feature_name: feature_name.clone(),
+                        counts_name: Some(counts_name.clone()),
+                        gff: None,
+                        match_type: FeatureSearchMatch::Exact,
+                    },
+                });
+            }
+        }
+
+        //GFF attributes. If the value is also the name of a feature in a matrix, the hit points there
+        for rec in gff_records {
+            for attr_name in GFF_SEARCH_ATTRIBUTES {
+                if let Some(GBrowserAttributeValue::String(value)) = rec.attributes.get(attr_name.as_bytes()) {
+                    let value = value.to_string();
+                    let counts_name = if all_feature_names.contains(value.as_str()) {
+                        matrices.iter()
+                            .find(|(_k, mat)| mat.map_feature_names_pos.contains_key(&value))
+                            .map(|(k, _mat)| k.clone())
+                    } else {
+                        None
+                    };
+                    entries.push(SearchEntry {
+                        key: value.to_lowercase(),
+                        hit: FeatureSearchHit {
+                            feature_name: value.clone(),
+                            counts_name: counts_name,
+                            gff: Some(FeatureSearchGffHit {
+                                attribute: attr_name.to_string(),
+                                seqid: rec.reference_sequence_name.to_string(),
+                                start: rec.start,
+                                end: rec.end,
+                            }),
+                            match_type: FeatureSearchMatch::Exact,
+                        },
+                    });
+                }
+            }
+        }
+
+        //The sort is stable, so entries with the same name stay in the order added
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        println!("Feature search index has {} entries", entries.len());
+        Ok(FeatureSearchIndex {
+            entries
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Find names matching the query, best first: exact, prefix, substring, then fuzzy.
+    /// Later kinds of matching are only tried if there is room for more hits
+    pub fn search(&self, query: &str, limit: usize) -> Vec<FeatureSearchHit> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() || limit == 0 {
+            return Vec::new();
+        }
+
+        let mut hits: Vec<(FeatureSearchMatch, &SearchEntry)> = Vec::new();
+
+        //Exact and prefix matches are consecutive in the sorted list
+        let from = self.entries.partition_point(|e| e.key.as_str() < query.as_str());
+        for e in self.entries[from..].iter().take_while(|e| e.key.starts_with(&query)) {
+            let match_type = if e.key == query { FeatureSearchMatch::Exact } else { FeatureSearchMatch::Prefix };
+            hits.push((match_type, e));
+        }
+
+        //Substrings anywhere else
+        if hits.len() < limit {
+            for e in &self.entries {
+                if !e.key.starts_with(&query) && e.key.contains(&query) {
+                    hits.push((FeatureSearchMatch::Substring, e));
+                }
+            }
+        }
+
+        //Names within a few edits
+        //Lengths are in characters, as the edit distance is
+        let query_len = query.chars().count();
+        if hits.len() < limit && query_len >= FUZZY_MIN_QUERY_LEN {
+            let max_dist = if query_len <= 4 { 1 } else { 2 };
+            for e in &self.entries {
+                if e.key.contains(&query) || e.key.chars().count().abs_diff(query_len) > max_dist {
+                    continue;
+                }
+                let dist = edit_distance(&e.key, &query);
+                if dist <= max_dist {
+                    hits.push((FeatureSearchMatch::Fuzzy(dist as u32), e));
+                }
+            }
+        }
+
+        //Rank by kind of match, then prefer short names
+        hits.sort_by(|(ma, ea), (mb, eb)| {
+            ma.cmp(mb)
+                .then(ea.key.chars().count().cmp(&eb.key.chars().count()))
+                .then(ea.key.cmp(&eb.key))
+        });
+        hits.into_iter().take(limit).map(|(match_type, e)| {
+            let mut hit = e.hit.clone();
+            hit.match_type = match_type;
+            hit
+        }).collect()
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Levenshtein distance between two strings, by character
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ////////////////////////////////////////////////////////////
+    /// Index of feature names in one matrix, without any GFF
+    fn make_index(names: &[&str]) -> FeatureSearchIndex {
+        let mut entries = names.iter().map(|name| SearchEntry {
+            key: name.to_lowercase(),
+            hit: FeatureSearchHit {
+                feature_name: name.to_string(),
+                counts_name: Some("RNA".to_string()),
+                gff: None,
+                match_type: FeatureSearchMatch::Exact,
+            },
+        }).collect::<Vec<_>>();
+        //The sort is stable, so entries with the same name stay in the order added
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        FeatureSearchIndex {
+            entries
+        }
+    }
+
+    fn names(hits: &Vec<FeatureSearchHit>) -> Vec<&str> {
+        hits.iter().map(|h| h.feature_name.as_str()).collect()
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("flaw", "lawn"), 2);
+        assert_eq!(edit_distance("gata1", "gata1"), 0);
+        //Multi-byte characters are one edit each
+        assert_eq!(edit_distance("αβγ", "abγ"), 2);
+        assert_eq!(edit_distance("µm", "um"), 1);
+    }
+
+    #[test]
+    fn search_ranks_exact_prefix_substring_fuzzy() {
+        let index = make_index(&["Gata1", "Gata10", "Gata1b", "xGata1", "Gatb1", "Actb"]);
+        let hits = index.search("gata1", 10);
+        assert_eq!(names(&hits), vec!["Gata1", "Gata10", "Gata1b", "xGata1", "Gatb1"]);
+        assert_eq!(hits[0].match_type, FeatureSearchMatch::Exact);
+        assert_eq!(hits[1].match_type, FeatureSearchMatch::Prefix);
+        assert_eq!(hits[3].match_type, FeatureSearchMatch::Substring);
+        assert_eq!(hits[4].match_type, FeatureSearchMatch::Fuzzy(1));
+    }
+
+    #[test]
+    fn search_prefers_short_names_and_respects_limit() {
+        let index = make_index(&["Cd4long", "Cd44", "Cd4"]);
+        assert_eq!(names(&index.search("CD4", 10)), vec!["Cd4", "Cd44", "Cd4long"]);
+        assert_eq!(names(&index.search("cd4", 2)), vec!["Cd4", "Cd44"]);
+        assert!(index.search("  ", 10).is_empty());
+        assert!(index.search("cd4", 0).is_empty());
+    }
+
+    #[test]
+    fn search_fuzzy_uses_character_lengths() {
+        //4 characters but 8 bytes; only one edit is allowed
+        let index = make_index(&["αβγ", "αβxy"]);
+        let hits = index.search("αβγδ", 10);
+        assert_eq!(names(&hits), vec!["αβγ"]);
+        assert_eq!(hits[0].match_type, FeatureSearchMatch::Fuzzy(1));
+
+        //2 characters is too short to be matched fuzzily, however many bytes
+        let index = make_index(&["αγ"]);
+        assert!(index.search("αβ", 10).is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use my_web_app::SessionContent;
+use my_web_app::SessionInfo;
+
+
+/// Longest name of a session, in characters
+pub const SESSION_NAME_MAX_LEN: usize = 200;
+
+
+////////////////////////////////////////////////////////////
+/// Where saved sessions are stored, unless set in the config
+pub fn default_sessions_db() -> PathBuf {
+    PathBuf::from("sessions.sqlite")
+}
+
+
+////////////////////////////////////////////////////////////
+/// SQLite database of workspaces saved under a name, for all datasets.
+/// The content is stored as JSON, as given by the app
+pub struct SessionStore {
+    conn: Mutex<Connection>,
+}
+impl SessionStore {
+
+    ////////////////////////////////////////////////////////////
+    /// Open the database, creating it if needed
+    pub fn open(p: &Path) -> anyhow::Result<SessionStore> {
+        println!("Opening session database {}", p.display());
+        let conn = Connection::open(p).context(format!("Could not open session database {}", p.display()))?;
+        conn.execute_batch("
+            CREATE TABLE IF NOT EXISTS session (
+                dataset TEXT NOT NULL,
+                session_name TEXT NOT NULL,
+                saved_at INTEGER NOT NULL,
+                content TEXT NOT NULL,
+                PRIMARY KEY (dataset, session_name)
+            );
+        ")?;
+        Ok(SessionStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// All sessions of a dataset, sorted by name
+    pub fn list(&self, dataset_name: &str) -> anyhow::Result<Vec<SessionInfo>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT session_name, saved_at FROM session WHERE dataset=?1 ORDER BY session_name")?;
+        let rows = stmt.query_map(params![dataset_name], |row| Ok(SessionInfo {
+            session_name: row.get(0)?,
+            saved_at: row.get::<_, i64>(1)? as u64,
+        }))?;
+        let out = rows.collect::<Result<Vec<SessionInfo>, _>>()?;
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Save a session, replacing any with the same name
+    pub fn save(&self, dataset_name: &str, session_name: &str, content: &SessionContent) -> anyhow::Result<()> {
+        let session_name = clean_session_name(session_name)?;
+        let content = serde_json::to_string(content)?;
+        let saved_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO session (dataset, session_name, saved_at, content) VALUES (?1, ?2, ?3, ?4)",
+            params![dataset_name, session_name, saved_at, content]
+        )?;
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Load a session
+    pub fn load(&self, dataset_name: &str, session_name: &str) -> anyhow::Result<SessionContent> {
+        let session_name = clean_session_name(session_name)?;
+        let conn = self.conn.lock().unwrap();
+        let content: Option<String> = conn.query_row(
+            "SELECT content FROM session WHERE dataset=?1 AND session_name=?2",
+            params![dataset_name, session_name],
+            |row| row.get(0)
+        ).optional()?;
+        let content = content.context(format!("There is no session {}", session_name))?;
+        let content = serde_json::from_str(&content).context(format!("Session {} is corrupt", session_name))?;
+        Ok(content)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Delete a session
+    pub fn delete(&self, dataset_name: &str, session_name: &str) -> anyhow::Result<()> {
+        let session_name = clean_session_name(session_name)?;
+        let conn = self.conn.lock().unwrap();
+        let num_deleted = conn.execute(
+            "DELETE FROM session WHERE dataset=?1 AND session_name=?2",
+            params![dataset_name, session_name]
+        )?;
+        if num_deleted == 0 {
+            anyhow::bail!("There is no session {}", session_name);
+        }
+        Ok(())
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Trim a session name and check that it can be used. Saving, loading and deleting
+/// all go through this, so that they agree on which session a name refers to
+fn clean_session_name(session_name: &str) -> anyhow::Result<&str> {
+    let session_name = session_name.trim();
+    if session_name.is_empty() {
+        anyhow::bail!("Session name cannot be empty");
+    }
+    if session_name.chars().count() > SESSION_NAME_MAX_LEN {
+        anyhow::bail!("Session name is longer than {} characters", SESSION_NAME_MAX_LEN);
+    }
+    Ok(session_name)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_trimmed_the_same_way() {
+        let store = SessionStore::open(Path::new(":memory:")).unwrap();
+        let content = SessionContent {
+            view: "#page=umap".to_string(),
+            ..Default::default()
+        };
+        store.save("ds", "  first ", &content).unwrap();
+        assert_eq!(store.list("ds").unwrap().iter().map(|x| x.session_name.clone()).collect::<Vec<_>>(), vec!["first".to_string()]);
+        assert_eq!(store.load("ds", "first").unwrap(), content);
+        assert_eq!(store.load("ds", " first  ").unwrap(), content);
+        store.delete("ds", "first ").unwrap();
+        assert!(store.list("ds").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_names_are_rejected() {
+        let store = SessionStore::open(Path::new(":memory:")).unwrap();
+        let content = SessionContent::default();
+        assert!(store.save("ds", "   ", &content).is_err());
+        assert!(store.load("ds", "").is_err());
+        assert!(store.delete("ds", " ").is_err());
+        assert!(store.save("ds", &"x".repeat(SESSION_NAME_MAX_LEN + 1), &content).is_err());
+        assert!(store.save("ds", &"x".repeat(SESSION_NAME_MAX_LEN), &content).is_ok());
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use biscvi_convert::mtx::{create_maybe_gz, write_mtx, write_tsv_column};
+use biscvi_convert::{Biscvi5Writer, FeatureMajorMatrix, MetaColumn};
+
+use my_web_app::CountFileMetaColumnData;
+use my_web_app::SubsetExportRequest;
+use my_web_app::SubsetFormat;
+use my_web_app::SubsetJobState;
+
+use crate::datasource::DataSource;
+use crate::diffexp::visit_features;
+use crate::index::BascetDir;
+use crate::validate::{check_biscvi5, ValidationLevel};
+
+/// Longest time to wait for a change of a running job before answering a status request, in milliseconds
+pub const SUBSET_STATUS_WAIT_MS: u64 = 20000;
+
+/// Most subset jobs running at the same time. Each holds a copy of its subset in memory
+pub const SUBSET_MAX_RUNNING: usize = 2;
+
+/// Time that the files of a subset job are kept after it ended, in seconds
+pub const SUBSET_KEEP_SECS: u64 = 24*60*60;
+
+/// Name of the file written for the biscvi5 format
+const SUBSET_BISCVI5_NAME: &str = "counts.biscvi5";
+
+
+////////////////////////////////////////////////////////////
+/// Where export jobs are written, unless set in the config
+pub fn default_export_dir() -> PathBuf {
+    PathBuf::from("exports")
+}
+
+
+////////////////////////////////////////////////////////////
+/// A subset job and when it ended
+struct SubsetJob {
+    state: SubsetJobState,
+    ended: Option<Instant>,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Subset jobs started since the server was started. Each job writes into its own directory.
+/// IDs count up from the time the server started, in milliseconds, so that a restarted server
+/// does not reuse the directory of an earlier job
+pub struct SubsetJobs {
+    dir: PathBuf,
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, SubsetJob>>,
+}
+impl SubsetJobs {
+
+    ////////////////////////////////////////////////////////////
+    /// Constructor
+    pub fn new(dir: PathBuf) -> SubsetJobs {
+        let first_id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(1);
+        SubsetJobs {
+            dir,
+            next_id: AtomicU64::new(first_id),
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Directory holding the files of a job
+    pub fn get_job_dir(&self, job_id: u64) -> PathBuf {
+        self.dir.join(format!("subset_{}", job_id))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the state of a job
+    pub fn get_state(&self, job_id: u64) -> anyhow::Result<SubsetJobState> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&job_id).map(|job| job.state.clone()).context(format!("No subset job {}", job_id))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Set the state of a job. The time is noted once it has ended
+    fn set_state(&self, job_id: u64, state: SubsetJobState) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let ended = match state {
+            SubsetJobState::Running(_) => None,
+            _ => Some(Instant::now()),
+        };
+        jobs.insert(job_id, SubsetJob {
+            state,
+            ended,
+        });
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the path of a file written by a job. Only files of finished jobs are given out
+    pub fn get_file(&self, job_id: u64, file_name: &String) -> anyhow::Result<PathBuf> {
+        match self.get_state(job_id)? {
+            SubsetJobState::Done(files) if files.contains(file_name) => Ok(self.get_job_dir(job_id).join(file_name)),
+            _ => anyhow::bail!("File {} is not available for subset job {}", file_name, job_id),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Start writing a subset in a separate thread. Returns the ID of the job.
+    /// Fails if SUBSET_MAX_RUNNING jobs are already running
+    pub fn start(self: &Arc<Self>, bdir: Arc<BascetDir>, req: SubsetExportRequest) -> anyhow::Result<u64> {
+        self.remove_old_jobs();
+
+        let job_id = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let num_running = jobs.values().filter(|job| job.ended.is_none()).count();
+            if num_running >= SUBSET_MAX_RUNNING {
+                anyhow::bail!("There are already {} subset jobs running; try again once one is done", num_running);
+            }
+            let job_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            jobs.insert(job_id, SubsetJob {
+                state: SubsetJobState::Running("Starting".into()),
+                ended: None,
+            });
+            job_id
+        };
+
+        let jobs = Arc::clone(self);
+        std::thread::spawn(move || {
+            let out_dir = jobs.get_job_dir(job_id);
+            let progress = |msg: String| {
+                println!("Subset job {}: {}", job_id, msg);
+                jobs.set_state(job_id, SubsetJobState::Running(msg));
+            };
+            let state = match write_subset(bdir.counts.as_ref(), &req.cells, req.format, &out_dir, &progress) {
+                Ok(files) => SubsetJobState::Done(files),
+                Err(e) => {
+                    println!("Subset job {} failed: {:#}", job_id, e);
+                    SubsetJobState::Failed(format!("{:#}", e))
+                },
+            };
+            jobs.set_state(job_id, state);
+        });
+        Ok(job_id)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Forget jobs that ended more than SUBSET_KEEP_SECS ago, and delete their files. Directories
+    /// left by earlier runs of the server are deleted once they are as old
+    fn remove_old_jobs(&self) {
+        let max_age = Duration::from_secs(SUBSET_KEEP_SECS);
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_job_id, job| job.ended.map(|t| t.elapsed() < max_age).unwrap_or(true));
+
+        let list_entries = if let Ok(entries) = std::fs::read_dir(&self.dir) { entries } else { return };
+        for entry in list_entries.flatten() {
+            let job_id = entry.file_name().to_string_lossy().strip_prefix("subset_").and_then(|x| x.parse::<u64>().ok());
+            if let Some(job_id) = job_id {
+                if jobs.contains_key(&job_id) {
+                    continue;
+                }
+                let age = entry.metadata().and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+                if age.map(|age| age >= max_age).unwrap_or(false) {
+                    println!("Removing old subset job directory {}", entry.path().display());
+                    if let Err(e) = std::fs::remove_dir_all(entry.path()) {
+                        println!("Could not remove {}: {}", entry.path().display(), e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// A dataset restricted to some cells, in the layout of a biscvi5 file
+struct Subset {
+    barcodes: Vec<String>,
+    matrices: Vec<(String, FeatureMajorMatrix, Vec<String>)>,
+    reductions: Vec<(String, Vec<Vec<f32>>)>,
+    obs: Vec<(String, CountFileMetaColumnData)>,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write the given cells of a dataset as a new dataset. Returns the names of the files written
+pub fn write_subset(ds: &dyn DataSource, cells: &Vec<u32>, format: SubsetFormat, out_dir: &Path, progress: &dyn Fn(String)) -> anyhow::Result<Vec<String>> {
+    let subset = read_subset(ds, cells, progress)?;
+
+    //Start from an empty directory, in case an earlier run of the server left files
+    if out_dir.exists() {
+        std::fs::remove_dir_all(out_dir)?;
+    }
+    std::fs::create_dir_all(out_dir).context(format!("Could not create {}", out_dir.display()))?;
+
+    match format {
+        SubsetFormat::Biscvi5 => write_subset_biscvi5(&subset, out_dir, progress),
+        SubsetFormat::MTX => write_subset_mtx(&subset, out_dir, progress),
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Read everything about the given cells. Cells are kept in the order of the dataset
+fn read_subset(ds: &dyn DataSource, cells: &Vec<u32>, progress: &dyn Fn(String)) -> anyhow::Result<Subset> {
+    let num_cells = ds.get_num_cells()?;
+
+    let mut cells = cells.iter().map(|x| *x as usize).collect::<Vec<_>>();
+    cells.sort();
+    cells.dedup();
+    if cells.is_empty() {
+        anyhow::bail!("No cells given");
+    }
+    if let Some(bad_cell) = cells.iter().find(|x| **x >= num_cells) {
+        anyhow::bail!("Cell {} out of range; there are {} cells", bad_cell, num_cells);
+    }
+
+    //Position of each cell in the subset
+    let mut new_index = vec![None; num_cells];
+    for (i, cell) in cells.iter().enumerate() {
+        new_index[*cell] = Some(i as u32);
+    }
+
+    let all_barcodes = ds.get_barcodes()?;
+    let barcodes = cells.iter().map(|i| all_barcodes.get(*i).cloned().unwrap_or_default()).collect::<Vec<_>>();
+
+    let desc = ds.get_desc()?;
+
+    //Count matrices. Cells of each feature are sorted, and stay sorted as the order of cells is kept
+    let mut matrix_names = desc.matrices.keys().cloned().collect::<Vec<_>>();
+    matrix_names.sort();
+    let mut matrices = Vec::new();
+    for counts_name in matrix_names {
+        progress(format!("Reading count matrix {}", counts_name));
+        let feature_names = ds.get_matrix(&counts_name)?.list_feature_names.clone();
+        let mut indptr = vec![0u32];
+        let mut indices = Vec::new();
+        let mut data = Vec::new();
+        visit_features(ds, &counts_name, feature_names.len(), |_row, row_indices, row_data| {
+            for (cell, value) in row_indices.iter().zip(row_data.iter()) {
+                if let Some(i) = new_index.get(*cell as usize).cloned().flatten() {
+                    indices.push(i);
+                    data.push(*value);
+                }
+            }
+            indptr.push(u32::try_from(indices.len()).context("Too many non-zero values for 32-bit indptr")?);
+            Ok(())
+        })?;
+        let mat = FeatureMajorMatrix {
+            num_features: feature_names.len(),
+            num_cells: cells.len(),
+            indptr,
+            indices,
+            data,
+        };
+        matrices.push((counts_name, mat, feature_names));
+    }
+
+    //Reductions, with all their dimensions
+    let mut reduction_names = desc.reductions.keys().cloned().collect::<Vec<_>>();
+    reduction_names.sort();
+    let mut reductions = Vec::new();
+    for reduction_name in reduction_names {
+        progress(format!("Reading reduction {}", reduction_name));
+        let coords = ds.get_reduction_dims(&reduction_name)?;
+        let coords = coords.iter().map(|dim| cells.iter().map(|i| dim.get(*i).cloned().unwrap_or(f32::NAN)).collect()).collect();
+        reductions.push((reduction_name, coords));
+    }
+
+    //Metadata columns
+    let mut column_names = desc.meta.keys().cloned().collect::<Vec<_>>();
+    column_names.sort();
+    let mut obs = Vec::new();
+    for column_name in column_names {
+        progress(format!("Reading metadata column {}", column_name));
+        let col = ds.get_metacolumn(&column_name)?;
+        obs.push((column_name, subset_column(col.data, &cells)));
+    }
+
+    Ok(Subset {
+        barcodes,
+        matrices,
+        reductions,
+        obs,
+    })
+}
+
+
+////////////////////////////////////////////////////////////
+/// Keep the values of some cells of a metadata column
+fn subset_column(data: CountFileMetaColumnData, cells: &Vec<usize>) -> CountFileMetaColumnData {
+    match data {
+        CountFileMetaColumnData::Numeric(v) => CountFileMetaColumnData::Numeric(cells.iter().map(|i| v.get(*i).cloned().unwrap_or(f32::NAN)).collect()),
+        CountFileMetaColumnData::SparseNumeric(_, _) => CountFileMetaColumnData::Numeric(cells.iter().map(|i| data.get_number(*i).unwrap_or(f32::NAN)).collect()),
+        CountFileMetaColumnData::Categorical(codes, cats) => {
+            let missing = cats.len() as u32;
+            CountFileMetaColumnData::Categorical(cells.iter().map(|i| codes.get(*i).cloned().unwrap_or(missing)).collect(), cats)
+        },
+        CountFileMetaColumnData::Boolean(v) => CountFileMetaColumnData::Boolean(cells.iter().map(|i| v.get(*i).cloned().flatten()).collect()),
+        CountFileMetaColumnData::Integer(v) => CountFileMetaColumnData::Integer(cells.iter().map(|i| v.get(*i).cloned().flatten()).collect()),
+        CountFileMetaColumnData::Text(v) => CountFileMetaColumnData::Text(cells.iter().map(|i| v.get(*i).cloned().flatten()).collect()),
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Convert a metadata column to how biscvi5 files store it, keeping its type
+fn to_biscvi5_column(data: &CountFileMetaColumnData) -> anyhow::Result<MetaColumn> {
+    Ok(match data {
+        CountFileMetaColumnData::Numeric(v) => MetaColumn::Numeric(v.clone()),
+        CountFileMetaColumnData::SparseNumeric(_, _) => anyhow::bail!("Sparse columns are made dense before writing"),
+        CountFileMetaColumnData::Categorical(codes, cats) => {
+            //Codes past the last category are missing values
+            let codes = codes.iter().map(|c| if (*c as usize) < cats.len() { *c as i32 } else { -1 }).collect::<Vec<_>>();
+            MetaColumn::Categorical(codes, cats.clone())
+        },
+        CountFileMetaColumnData::Boolean(v) => MetaColumn::Boolean(v.clone()),
+        CountFileMetaColumnData::Integer(v) => MetaColumn::Integer(v.clone()),
+        CountFileMetaColumnData::Text(v) => MetaColumn::Text(v.clone()),
+    })
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write the subset as a biscvi5 file, then check that the server can open it
+fn write_subset_biscvi5(subset: &Subset, out_dir: &Path, progress: &dyn Fn(String)) -> anyhow::Result<Vec<String>> {
+    let p = out_dir.join(SUBSET_BISCVI5_NAME);
+    let mut writer = Biscvi5Writer::create(&p)?;
+    writer.write_barcodes(&subset.barcodes)?;
+    for (counts_name, mat, feature_names) in &subset.matrices {
+        progress(format!("Writing count matrix {}", counts_name));
+        writer.write_counts(counts_name, mat, feature_names)?;
+    }
+    for (reduction_name, coords) in &subset.reductions {
+        writer.write_reduction(reduction_name, coords)?;
+    }
+    progress("Writing metadata".into());
+    for (column_name, data) in &subset.obs {
+        writer.write_meta(column_name, &to_biscvi5_column(data)?)?;
+    }
+    drop(writer);
+
+    progress("Validating file".into());
+    check_biscvi5(&p, ValidationLevel::Full)?;
+    Ok(vec![SUBSET_BISCVI5_NAME.to_string()])
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write the subset as Matrix Market files with TSV files for names, reductions and metadata.
+/// Each count matrix gets its own pair of matrix and feature files
+fn write_subset_mtx(subset: &Subset, out_dir: &Path, progress: &dyn Fn(String)) -> anyhow::Result<Vec<String>> {
+    let mut files = Vec::new();
+
+    let name = "barcodes.tsv.gz".to_string();
+    write_tsv_column(&out_dir.join(&name), &subset.barcodes)?;
+    files.push(name);
+
+    for (counts_name, mat, feature_names) in &subset.matrices {
+        progress(format!("Writing count matrix {}", counts_name));
+        let name = format!("{}_matrix.mtx.gz", safe_file_name(counts_name));
+        write_mtx(&out_dir.join(&name), mat)?;
+        files.push(name);
+
+        let name = format!("{}_features.tsv.gz", safe_file_name(counts_name));
+        write_tsv_column(&out_dir.join(&name), feature_names)?;
+        files.push(name);
+    }
+
+    for (reduction_name, coords) in &subset.reductions {
+        let name = format!("{}_reduction.tsv.gz", safe_file_name(reduction_name));
+        let mut header = vec!["barcode".to_string()];
+        header.extend((1..=coords.len()).map(|d| format!("{}_{}", reduction_name, d)));
+        write_tsv_table(&out_dir.join(&name), &header, &subset.barcodes, |cell| {
+            coords.iter().map(|dim| dim[cell].to_string()).collect()
+        })?;
+        files.push(name);
+    }
+
+    if !subset.obs.is_empty() {
+        progress("Writing metadata".into());
+        let name = "obs.tsv.gz".to_string();
+        let mut header = vec!["barcode".to_string()];
+        header.extend(subset.obs.iter().map(|(column_name, _)| column_name.clone()));
+        write_tsv_table(&out_dir.join(&name), &header, &subset.barcodes, |cell| {
+            subset.obs.iter().map(|(_, data)| data.get_label(cell).unwrap_or_default()).collect()
+        })?;
+        files.push(name);
+    }
+
+    Ok(files)
+}
+
+
+////////////////////////////////////////////////////////////
+/// Write a TSV file with a header, one row per cell starting with the barcode.
+/// The table can be read back by the converter
+fn write_tsv_table<F>(p: &Path, header: &Vec<String>, barcodes: &Vec<String>, f: F) -> anyhow::Result<()>
+where F: Fn(usize) -> Vec<String> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(create_maybe_gz(p)?);
+    writer.write_record(header)?;
+    for (cell, barcode) in barcodes.iter().enumerate() {
+        let mut record = vec![barcode.clone()];
+        record.extend(f(cell));
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+
+////////////////////////////////////////////////////////////
+/// Turn a name of a matrix or reduction into something safe to use in a file name
+fn safe_file_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' }).collect()
+}
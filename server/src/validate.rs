@@ -0,0 +1,611 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use hdf5::types::TypeDescriptor;
+
+/// Number of values read at a time when scanning large datasets
+const SCAN_CHUNK: usize = 1024*1024;
+
+
+////////////////////////////////////////////////////////////
+/// A problem found in a biscvi5 file, with the HDF5 path of the object
+#[derive(Debug, Clone)]
+pub struct ValidationIssue {
+    pub path: String,
+    pub message: String,
+}
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// How thoroughly to check a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationLevel {
+    /// Layout, data types and sizes of all objects. This is cheap
+    Structure,
+    /// Also every value of indptr, indices and category codes. This reads most of the file, and is done whenever a file is served
+    Full,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Collects problems while going through a file
+struct Validator {
+    file: hdf5::File,
+    level: ValidationLevel,
+    issues: Vec<ValidationIssue>,
+    /// Number of cells, and the object it was taken from
+    num_cells: Option<(usize, String)>,
+}
+impl Validator {
+
+    ////////////////////////////////////////////////////////////
+    /// Record a problem
+    fn issue(&mut self, path: &str, message: String) {
+        self.issues.push(ValidationIssue {
+            path: path.to_string(),
+            message: message,
+        });
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that an object has as many cells as the rest of the file
+    fn check_num_cells(&mut self, path: &str, n: usize) {
+        if let Some((expected, from)) = &self.num_cells {
+            if *expected != n {
+                let msg = format!("has {} cells, but {} has {}", n, from, expected);
+                self.issue(path, msg);
+            }
+        } else {
+            self.num_cells = Some((n, path.to_string()));
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get a dataset that must exist
+    fn get_dataset(&mut self, path: &str) -> Option<hdf5::Dataset> {
+        match self.file.dataset(path) {
+            Ok(ds) => Some(ds),
+            Err(_) => {
+                if self.file.group(path).is_ok() {
+                    self.issue(path, "expected a dataset, found a group".to_string());
+                } else {
+                    self.issue(path, "missing dataset".to_string());
+                }
+                None
+            }
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get a group that must exist
+    fn get_group(&mut self, path: &str) -> Option<hdf5::Group> {
+        match self.file.group(path) {
+            Ok(g) => Some(g),
+            Err(_) => {
+                if self.file.dataset(path).is_ok() {
+                    self.issue(path, "expected a group, found a dataset".to_string());
+                } else {
+                    self.issue(path, "missing group".to_string());
+                }
+                None
+            }
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the type of a dataset
+    fn get_type(&mut self, path: &str, ds: &hdf5::Dataset) -> Option<TypeDescriptor> {
+        match ds.dtype().and_then(|t| t.to_descriptor()) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                self.issue(path, format!("unsupported data type: {}", e));
+                None
+            }
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that a dataset is 1D, and return its length
+    fn check_1d(&mut self, path: &str, ds: &hdf5::Dataset) -> Option<usize> {
+        let shape = ds.shape();
+        if shape.len() == 1 {
+            Some(shape[0])
+        } else {
+            self.issue(path, format!("expected a 1D dataset, got shape {:?}", shape));
+            None
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that a dataset holds integers that fit in 32 bits, as read by the server
+    fn check_int32(&mut self, path: &str, ds: &hdf5::Dataset) -> bool {
+        match self.get_type(path, ds) {
+            Some(TypeDescriptor::Integer(size)) | Some(TypeDescriptor::Unsigned(size)) => {
+                let num_bytes = size as usize;
+                if num_bytes > 4 {
+                    self.issue(path, format!("stored as {}-bit integers; must be 32-bit or smaller", num_bytes*8));
+                    false
+                } else {
+                    true
+                }
+            },
+            Some(t) => {
+                self.issue(path, format!("expected integers, got {:?}", t));
+                false
+            },
+            None => false,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that a dataset holds numbers
+    fn check_numeric(&mut self, path: &str, ds: &hdf5::Dataset) -> bool {
+        match self.get_type(path, ds) {
+            Some(TypeDescriptor::Integer(_)) | Some(TypeDescriptor::Unsigned(_)) | Some(TypeDescriptor::Float(_)) => true,
+            Some(t) => {
+                self.issue(path, format!("expected numbers, got {:?}", t));
+                false
+            },
+            None => false,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that a dataset holds a supported type of metadata: numbers, booleans or strings.
+    /// The server skips other columns, so they are only reported by a full check
+    fn check_meta_values(&mut self, path: &str, ds: &hdf5::Dataset) -> bool {
+        match self.get_type(path, ds) {
+            Some(TypeDescriptor::Integer(_)) | Some(TypeDescriptor::Unsigned(_)) | Some(TypeDescriptor::Float(_)) |
+            Some(TypeDescriptor::Boolean) | Some(TypeDescriptor::VarLenAscii) | Some(TypeDescriptor::VarLenUnicode) => true,
+            Some(t) => {
+                if self.level == ValidationLevel::Full {
+                    self.issue(path, format!("expected numbers, booleans or variable-length strings, got {:?}", t));
+                }
+                false
+            },
+            None => false,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check that a dataset holds variable-length strings, which is what the server can read
+    fn check_strings(&mut self, path: &str, ds: &hdf5::Dataset) -> bool {
+        match self.get_type(path, ds) {
+            Some(TypeDescriptor::VarLenUnicode) | Some(TypeDescriptor::VarLenAscii) => true,
+            Some(t) => {
+                self.issue(path, format!("expected variable-length strings, got {:?}", t));
+                false
+            },
+            None => false,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read part of a 1D integer dataset
+    fn read_ints(&mut self, path: &str, ds: &hdf5::Dataset, from: usize, to: usize) -> Option<Vec<i64>> {
+        match ds.read_slice_1d::<i64, _>(from..to) {
+            Ok(v) => Some(v.to_vec()),
+            Err(e) => {
+                self.issue(path, format!("could not read values: {}", e));
+                None
+            }
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Find the number of cells before checking anything else, such that
+    /// mismatches are reported on the odd object out
+    fn find_num_cells(&mut self) {
+        if let Ok(ds) = self.file.dataset("/barcodes") {
+            if ds.shape().len() == 1 {
+                self.num_cells = Some((ds.shape()[0], "/barcodes".to_string()));
+                return;
+            }
+        }
+
+        //Most files have a UMAP or similar; take the majority if they disagree
+        if let Ok(group_reds) = self.file.group("/reductions") {
+            let mut counts: Vec<(usize, String)> = Vec::new();
+            for name in group_reds.member_names().unwrap_or_default() {
+                if let Ok(ds) = group_reds.dataset(&name) {
+                    let shape = ds.shape();
+                    if shape.len() == 2 {
+                        counts.push((shape[1], format!("/reductions/{}", name)));
+                    }
+                }
+            }
+            let mut best: Option<(usize, String, usize)> = None;
+            for (n, path) in &counts {
+                let num_agree = counts.iter().filter(|(m, _)| m == n).count();
+                if best.as_ref().map(|(_, _, b)| num_agree > *b).unwrap_or(true) {
+                    best = Some((*n, path.clone(), num_agree));
+                }
+            }
+            if let Some((n, path, _)) = best {
+                self.num_cells = Some((n, path));
+            }
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Check one count matrix, stored feature-major
+    fn check_counts(&mut self, name: &str) {
+        let path = format!("/counts/{}", name);
+        if self.get_group(&path).is_none() {
+            return;
+        }
+
+        let path_data = format!("{}/data", path);
+        let path_indices = format!("{}/indices", path);
+        let path_indptr = format!("{}/indptr", path);
+        let path_names = format!("{}/feature_names", path);
+
+        //Feature names
+        let mut num_features = None;
+        if let Some(ds) = self.get_dataset(&path_names) {
+            if self.check_strings(&path_names, &ds) {
+                num_features = self.check_1d(&path_names, &ds);
+            }
+        }
+
+        //Values
+        let mut num_data = None;
+        if let Some(ds) = self.get_dataset(&path_data) {
+            if self.check_numeric(&path_data, &ds) {
+                num_data = self.check_1d(&path_data, &ds);
+            }
+        }
+
+        //Cell of each value
+        let mut ds_indices = None;
+        if let Some(ds) = self.get_dataset(&path_indices) {
+            if self.check_int32(&path_indices, &ds) {
+                if let Some(n) = self.check_1d(&path_indices, &ds) {
+                    if let Some(num_data) = num_data {
+                        if n != num_data {
+                            self.issue(&path_indices, format!("has {} values, but data has {}", n, num_data));
+                        }
+                    }
+                    ds_indices = Some((ds, n));
+                }
+            }
+        }
+
+        //Pointers into indices and data
+        if let Some(ds) = self.get_dataset(&path_indptr) {
+            if self.check_int32(&path_indptr, &ds) {
+                if let Some(n) = self.check_1d(&path_indptr, &ds) {
+                    self.check_indptr(&path_indptr, &ds, n, num_features, num_data);
+                }
+            }
+        }
+
+        //Indices must refer to cells. This requires reading all of them
+        if self.level != ValidationLevel::Full {
+            return;
+        }
+        if let Some((ds, n)) = ds_indices {
+            let num_cells = self.num_cells.as_ref().map(|(n, _)| *n);
+            let mut max_index: i64 = -1;
+            let mut from = 0;
+            while from < n {
+                let to = (from + SCAN_CHUNK).min(n);
+                if let Some(v) = self.read_ints(&path_indices, &ds, from, to) {
+                    if let Some(bad) = v.iter().find(|x| **x < 0) {
+                        self.issue(&path_indices, format!("negative cell index {}", bad));
+                        return;
+                    }
+                    max_index = max_index.max(v.iter().cloned().max().unwrap_or(-1));
+                } else {
+                    return;
+                }
+                from = to;
+            }
+            if let Some(num_cells) = num_cells {
+                if max_index >= num_cells as i64 {
+                    self.issue(&path_indices, format!("refers to cell {}, but there are only {} cells. Is the matrix stored cell-major rather than feature-major?", max_index, num_cells));
+                }
+            }
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Check that indptr starts at 0, never decreases, and ends at the number of values
+    fn check_indptr(&mut self, path: &str, ds: &hdf5::Dataset, n: usize, num_features: Option<usize>, num_data: Option<usize>) {
+        if let Some(num_features) = num_features {
+            if n != num_features + 1 {
+                self.issue(path, format!("has length {}, but must be number of features + 1 = {}", n, num_features + 1));
+            }
+        }
+        if n == 0 {
+            self.issue(path, "is empty; must at least hold a single 0".to_string());
+            return;
+        }
+
+        //Without a full check, only look at the ends
+        if self.level != ValidationLevel::Full {
+            let first = if let Some(v) = self.read_ints(path, ds, 0, 1) { v[0] } else { return };
+            let last = if let Some(v) = self.read_ints(path, ds, n - 1, n) { v[0] } else { return };
+            if first != 0 {
+                self.issue(path, format!("must start at 0, starts at {}", first));
+            }
+            if let Some(num_data) = num_data {
+                if last != num_data as i64 {
+                    self.issue(path, format!("ends at {}, but data has {} values", last, num_data));
+                }
+            }
+            return;
+        }
+
+        let mut last: Option<i64> = None;
+        let mut from = 0;
+        while from < n {
+            let to = (from + SCAN_CHUNK).min(n);
+            let v = if let Some(v) = self.read_ints(path, ds, from, to) { v } else { return };
+            for (i, x) in v.iter().enumerate() {
+                if let Some(last) = last {
+                    if *x < last {
+                        self.issue(path, format!("decreases at position {}: {} after {}", from + i, x, last));
+                        return;
+                    }
+                } else if *x != 0 {
+                    self.issue(path, format!("must start at 0, starts at {}", x));
+                }
+                last = Some(*x);
+            }
+            from = to;
+        }
+
+        if let (Some(last), Some(num_data)) = (last, num_data) {
+            if last != num_data as i64 {
+                self.issue(path, format!("ends at {}, but data has {} values", last, num_data));
+            }
+        }
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Check one reduction, stored as (dim, cell)
+    fn check_reduction(&mut self, name: &str) {
+        let path = format!("/reductions/{}", name);
+        let ds = if let Some(ds) = self.get_dataset(&path) { ds } else { return };
+        if !self.check_numeric(&path, &ds) {
+            return;
+        }
+        let shape = ds.shape();
+        if shape.len() != 2 {
+            self.issue(&path, format!("expected a 2D dataset (dim, cell), got shape {:?}", shape));
+            return;
+        }
+
+        let (num_dim, num_sample) = (shape[0], shape[1]);
+        let num_cells = self.num_cells.as_ref().map(|(n, _)| *n);
+        if num_cells == Some(num_dim) && num_cells != Some(num_sample) {
+            self.issue(&path, format!("has shape {:?}, which looks transposed; expected (dim, cell)", shape));
+            return;
+        }
+        if num_dim < 2 {
+            self.issue(&path, format!("has {} dimensions; at least 2 are needed", num_dim));
+        }
+        self.check_num_cells(&path, num_sample);
+    }
+
+
+    ////////////////////////////////////////////////////////////
+    /// Check one metadata column; either a numeric dataset or a categorical group
+    fn check_meta(&mut self, name: &str) {
+        let path = format!("/obs/{}", name);
+
+        //Numeric, boolean, integer or text column
+        if let Ok(ds) = self.file.dataset(&path) {
+            if self.check_meta_values(&path, &ds) {
+                if let Some(n) = self.check_1d(&path, &ds) {
+                    self.check_num_cells(&path, n);
+                }
+            }
+            return;
+        }
+
+        let group = if let Some(group) = self.get_group(&path) { group } else { return };
+
+        //Column with missing values
+        if group.link_exists("values") || group.link_exists("mask") {
+            let path_values = format!("{}/values", path);
+            let path_mask = format!("{}/mask", path);
+            let mut num_values = None;
+            if let Some(ds) = self.get_dataset(&path_values) {
+                if self.check_meta_values(&path_values, &ds) {
+                    num_values = self.check_1d(&path_values, &ds);
+                }
+            }
+            if let Some(ds) = self.get_dataset(&path_mask) {
+                if let Some(n) = self.check_1d(&path_mask, &ds) {
+                    if let Some(num_values) = num_values {
+                        if n != num_values {
+                            self.issue(&path_mask, format!("has {} values, but values has {}", n, num_values));
+                        }
+                    }
+                }
+            }
+            if let Some(n) = num_values {
+                self.check_num_cells(&path_values, n);
+            }
+            return;
+        }
+
+        //Categorical column
+        let path_categories = format!("{}/categories", path);
+        let path_codes = format!("{}/codes", path);
+
+        let mut num_categories = None;
+        if let Some(ds) = self.get_dataset(&path_categories) {
+            if self.check_strings(&path_categories, &ds) {
+                num_categories = self.check_1d(&path_categories, &ds);
+            }
+        }
+
+        if let Some(ds) = self.get_dataset(&path_codes) {
+            if self.check_int32(&path_codes, &ds) {
+                if let Some(n) = self.check_1d(&path_codes, &ds) {
+                    self.check_num_cells(&path_codes, n);
+                    if let (Some(num_categories), ValidationLevel::Full) = (num_categories, self.level) {
+                        //Negative codes are missing values
+                        if let Some(v) = self.read_ints(&path_codes, &ds, 0, n) {
+                            if let Some(bad) = v.iter().find(|x| **x >= num_categories as i64) {
+                                self.issue(&path_codes, format!("code {} is out of range; there are {} categories", bad, num_categories));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Check a biscvi5 file against the layout the server expects. Returns the problems found;
+/// an error is only returned if the file cannot be opened at all
+pub fn validate_biscvi5(p: &PathBuf, level: ValidationLevel) -> anyhow::Result<Vec<ValidationIssue>> {
+    let file = hdf5::File::open(p)?;
+    let mut v = Validator {
+        file: file,
+        level: level,
+        issues: Vec::new(),
+        num_cells: None,
+    };
+    v.find_num_cells();
+
+    if let Some(group) = v.get_group("/counts") {
+        for name in group.member_names()? {
+            v.check_counts(&name);
+        }
+    }
+    if let Some(group) = v.get_group("/reductions") {
+        for name in group.member_names()? {
+            v.check_reduction(&name);
+        }
+    }
+    if let Some(group) = v.get_group("/obs") {
+        for name in group.member_names()? {
+            v.check_meta(&name);
+        }
+    }
+    Ok(v.issues)
+}
+
+
+////////////////////////////////////////////////////////////
+/// Validate a biscvi5 file, printing all problems. Fails if there are any
+pub fn check_biscvi5(p: &PathBuf, level: ValidationLevel) -> anyhow::Result<()> {
+    println!("Validating {} ({:?})", p.display(), level);
+    let issues = validate_biscvi5(p, level)?;
+    if issues.is_empty() {
+        println!("No problems found in {}", p.display());
+        Ok(())
+    } else {
+        for issue in &issues {
+            println!("  {}", issue);
+        }
+        anyhow::bail!("{} problem(s) found in {}", issues.len(), p.display())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use biscvi_convert::{Biscvi5Writer, FeatureMajorMatrix, MetaColumn};
+
+    ////////////////////////////////////////////////////////////
+    /// Write a small valid file with 3 cells
+    fn write_test_file(name: &str, mat: FeatureMajorMatrix) -> PathBuf {
+        let p = std::env::temp_dir().join(format!("biscvi_validate_test_{}_{}.biscvi5", std::process::id(), name));
+        let mut writer = Biscvi5Writer::create(&p).unwrap();
+        writer.write_barcodes(&vec!["A".to_string(), "B".to_string(), "C".to_string()]).unwrap();
+        writer.write_counts("rna", &mat, &vec!["g1".to_string(), "g2".to_string()]).unwrap();
+        writer.write_reduction("umap", &vec![vec![0.0, 1.0, 2.0], vec![3.0, 4.0, 5.0]]).unwrap();
+        writer.write_meta("cluster", &MetaColumn::Categorical(vec![0, -1, 1], vec!["x".to_string(), "y".to_string()])).unwrap();
+        writer.write_meta("score", &MetaColumn::Numeric(vec![0.5, f32::NAN, 1.0])).unwrap();
+        p
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// A 2 feature x 3 cell matrix
+    fn test_matrix() -> FeatureMajorMatrix {
+        FeatureMajorMatrix::from_triplets(2, 3, vec![(0, 0, 1.0), (1, 2, 4.0)]).unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_file() {
+        let p = write_test_file("valid", test_matrix());
+        assert!(validate_biscvi5(&p, ValidationLevel::Full).unwrap().is_empty());
+        assert!(validate_biscvi5(&p, ValidationLevel::Structure).unwrap().is_empty());
+        std::fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn rejects_cell_major_matrix_in_full_check() {
+        //Indices refer to a cell past the last one, as if the matrix was stored cell-major
+        let mut mat = test_matrix();
+        mat.indices[1] = 7;
+        let p = write_test_file("cellmajor", mat);
+        let issues = validate_biscvi5(&p, ValidationLevel::Full).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path, "/counts/rna/indices");
+        assert!(validate_biscvi5(&p, ValidationLevel::Structure).unwrap().is_empty());
+        std::fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_indptr_in_structure_check() {
+        let mut mat = test_matrix();
+        mat.indptr[2] = 5;
+        let p = write_test_file("indptr", mat);
+        let issues = validate_biscvi5(&p, ValidationLevel::Structure).unwrap();
+        assert!(issues.iter().any(|i| i.path == "/counts/rna/indptr"), "{:?}", issues);
+        std::fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn rejects_transposed_reduction_and_missing_group() {
+        let p = write_test_file("transposed", test_matrix());
+        {
+            let file = hdf5::File::open_rw(&p).unwrap();
+            file.group("reductions").unwrap()
+                .new_dataset_builder()
+                .with_data(&ndarray::Array2::<f32>::zeros((3, 2)))
+                .create("pca").unwrap();
+            file.unlink("obs").unwrap();
+        }
+        let issues = validate_biscvi5(&p, ValidationLevel::Structure).unwrap();
+        assert!(issues.iter().any(|i| i.path == "/reductions/pca" && i.message.contains("transposed")), "{:?}", issues);
+        assert!(issues.iter().any(|i| i.path == "/obs" && i.message == "missing group"), "{:?}", issues);
+        std::fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn rejects_out_of_range_codes() {
+        let p = write_test_file("codes", test_matrix());
+        {
+            let file = hdf5::File::open_rw(&p).unwrap();
+            let group = file.group("obs").unwrap().create_group("batch").unwrap();
+            group.new_dataset_builder().with_data(&[0i32, 3, 1][..]).create("codes").unwrap();
+            let cats = ["a", "b"].iter().map(|s| s.parse::<hdf5::types::VarLenUnicode>().unwrap()).collect::<Vec<_>>();
+            group.new_dataset_builder().with_data(cats.as_slice()).create("categories").unwrap();
+        }
+        let issues = validate_biscvi5(&p, ValidationLevel::Full).unwrap();
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert_eq!(issues[0].path, "/obs/batch/codes");
+        std::fs::remove_file(&p).unwrap();
+    }
+}
@@ -0,0 +1,1004 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use flate2::read::GzDecoder;
+use flate2::read::ZlibDecoder;
+use serde_json::Value;
+
+use my_web_app::countfile_struct::CountFileMetaColumnDesc;
+use my_web_app::countfile_struct::CountFileRed;
+use my_web_app::CountFileMetaColumnData;
+use my_web_app::DatasetDescResponse;
+use my_web_app::MetadataColumnResponse;
+use my_web_app::ReductionResponse;
+
+use crate::cellprofile::CellMajorCache;
+use crate::cellprofile::CellMajorMatrix;
+use crate::countfile::CountFileMat;
+use crate::datasource::DataSource;
+use crate::normalize::add_cell_totals;
+use crate::normalize::CELL_TOTALS_CHUNK;
+use crate::datasource::make_numbered_barcode;
+use crate::datasource::make_numbered_barcodes;
+
+
+// A minimal reader of Zarr v2 and v3 directory stores. Only what is needed for count files
+// is supported: C-order arrays of numbers or strings, compressed using zlib, gzip or zstd.
+// Arrays with any other filter or codec are refused rather than decoded wrongly. This includes
+// blosc, the default compressor of zarr-python, so such stores have to be rewritten with one of
+// the supported compressors before they can be served.
+// Chunks are only read when needed, so looking up a single feature is cheap
+//
+// https://zarr-specs.readthedocs.io/en/latest/v2/v2.0.html
+// https://zarr-specs.readthedocs.io/en/latest/v3/core/v3.0.html
+
+
+////////////////////////////////////////////////////////////
+/// Type of data stored in an array
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZarrDataType {
+    Int(usize),
+    UInt(usize),
+    Float(usize),
+    Bool,
+    FixedUnicode(usize),  // Number of characters, UTF-32
+    FixedBytes(usize),
+    VarLenString,
+}
+impl ZarrDataType {
+
+    ////////////////////////////////////////////////////////////
+    /// Size of one element in bytes, if fixed
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            ZarrDataType::Int(n) | ZarrDataType::UInt(n) | ZarrDataType::Float(n) => Some(*n),
+            ZarrDataType::Bool => Some(1),
+            ZarrDataType::FixedUnicode(n) => Some(*n*4),
+            ZarrDataType::FixedBytes(n) => Some(*n),
+            ZarrDataType::VarLenString => None,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Parse a Zarr v2 dtype, such as "<f4" or "|O"
+    pub fn parse_v2(s: &str) -> anyhow::Result<(ZarrDataType, bool)> {
+        let big_endian = s.starts_with('>');
+        let kind = s.get(1..2).context("Empty dtype")?;
+        let size = s.get(2..).unwrap_or("");
+        let dtype = match kind {
+            "O" => ZarrDataType::VarLenString,
+            "b" => ZarrDataType::Bool,
+            "i" => ZarrDataType::Int(size.parse()?),
+            "u" => ZarrDataType::UInt(size.parse()?),
+            "f" => ZarrDataType::Float(size.parse()?),
+            "U" => ZarrDataType::FixedUnicode(size.parse()?),
+            "S" => ZarrDataType::FixedBytes(size.parse()?),
+            _ => anyhow::bail!("Unsupported zarr dtype {}", s)
+        };
+        Ok((dtype, big_endian))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Parse a Zarr v3 data type, such as "float32"
+    pub fn parse_v3(s: &str) -> anyhow::Result<ZarrDataType> {
+        Ok(match s {
+            "bool" => ZarrDataType::Bool,
+            "int8" => ZarrDataType::Int(1),
+            "int16" => ZarrDataType::Int(2),
+            "int32" => ZarrDataType::Int(4),
+            "int64" => ZarrDataType::Int(8),
+            "uint8" => ZarrDataType::UInt(1),
+            "uint16" => ZarrDataType::UInt(2),
+            "uint32" => ZarrDataType::UInt(4),
+            "uint64" => ZarrDataType::UInt(8),
+            "float32" => ZarrDataType::Float(4),
+            "float64" => ZarrDataType::Float(8),
+            "string" => ZarrDataType::VarLenString,
+            _ => anyhow::bail!("Unsupported zarr data type {}", s)
+        })
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Compression of chunks
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZarrCompression {
+    None,
+    Zlib,
+    Gzip,
+    Zstd,
+}
+impl ZarrCompression {
+
+    ////////////////////////////////////////////////////////////
+    /// Parse name of the compressor of an array. Blosc is not supported
+    pub fn parse(name: &str, path: &Path) -> anyhow::Result<ZarrCompression> {
+        Ok(match name {
+            "zlib" => ZarrCompression::Zlib,
+            "gzip" => ZarrCompression::Gzip,
+            "zstd" => ZarrCompression::Zstd,
+            _ => anyhow::bail!("Unsupported zarr compressor {} in array {}; rewrite the store using zlib, gzip or zstd", name, path.display())
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Uncompress a chunk
+    pub fn decompress(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self {
+            ZarrCompression::None => return Ok(data),
+            ZarrCompression::Zlib => { ZlibDecoder::new(data.as_slice()).read_to_end(&mut out)?; },
+            ZarrCompression::Gzip => { GzDecoder::new(data.as_slice()).read_to_end(&mut out)?; },
+            ZarrCompression::Zstd => { out = zstd::decode_all(data.as_slice())?; },
+        }
+        Ok(out)
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// An array in a Zarr store. Metadata is read when opened, chunks on demand
+#[derive(Debug, Clone)]
+pub struct ZarrArray {
+    pub path: PathBuf,
+    pub shape: Vec<usize>,
+    pub chunks: Vec<usize>,
+    pub dtype: ZarrDataType,
+    pub big_endian: bool,
+    pub compression: ZarrCompression,
+    pub fill_value: f64,        // Value of numbers in chunks that are not stored
+    pub key_prefix: String,     // "c/" for v3 default keys
+    pub key_separator: String,
+}
+impl ZarrArray {
+
+    ////////////////////////////////////////////////////////////
+    /// Open an array, v2 or v3
+    pub fn open(path: &Path) -> anyhow::Result<ZarrArray> {
+        let path_v2 = path.join(".zarray");
+        let path_v3 = path.join("zarr.json");
+        if path_v2.exists() {
+            Self::open_v2(path, &read_json(&path_v2)?)
+        } else if path_v3.exists() {
+            Self::open_v3(path, &read_json(&path_v3)?)
+        } else {
+            anyhow::bail!("Not a zarr array: {}", path.display())
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Open a Zarr v2 array
+    fn open_v2(path: &Path, meta: &Value) -> anyhow::Result<ZarrArray> {
+        let (dtype, big_endian) = ZarrDataType::parse_v2(meta["dtype"].as_str().context("Missing dtype")?)?;
+        if meta["order"].as_str() == Some("F") {
+            anyhow::bail!("Fortran order not supported for zarr array {}", path.display());
+        }
+        let compression = match meta["compressor"]["id"].as_str() {
+            Some(id) => ZarrCompression::parse(id, path)?,
+            None => ZarrCompression::None,
+        };
+
+        //Filters change the bytes before compression. Only the one giving variable-length strings is understood
+        if let Some(filters) = meta["filters"].as_array() {
+            for filter in filters {
+                let id = filter["id"].as_str().unwrap_or("");
+                if !(id == "vlen-utf8" && dtype == ZarrDataType::VarLenString) {
+                    anyhow::bail!("Unsupported zarr filter {} in array {}", filter, path.display());
+                }
+            }
+        }
+
+        Ok(ZarrArray {
+            path: path.to_path_buf(),
+            shape: parse_usize_list(&meta["shape"])?,
+            chunks: parse_usize_list(&meta["chunks"])?,
+            fill_value: parse_fill_value(&meta["fill_value"], &dtype)?,
+            dtype,
+            big_endian,
+            compression,
+            key_prefix: String::new(),
+            key_separator: meta["dimension_separator"].as_str().unwrap_or(".").to_string(),
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Open a Zarr v3 array
+    fn open_v3(path: &Path, meta: &Value) -> anyhow::Result<ZarrArray> {
+        if meta["node_type"].as_str() != Some("array") {
+            anyhow::bail!("Not a zarr array: {}", path.display());
+        }
+        let dtype = ZarrDataType::parse_v3(meta["data_type"].as_str().context("Missing data_type")?)?;
+
+        //Figure out how the bytes are encoded
+        let mut big_endian = false;
+        let mut compression = ZarrCompression::None;
+        for codec in meta["codecs"].as_array().context("Missing codecs")? {
+            let name = codec["name"].as_str().context("Missing codec name")?;
+            match name {
+                "bytes" => { big_endian = codec["configuration"]["endian"].as_str() == Some("big"); },
+                "vlen-utf8" => {},
+                "transpose" => anyhow::bail!("Transposed zarr arrays are not supported: {}", path.display()),
+                _ => { compression = ZarrCompression::parse(name, path)?; }
+            }
+        }
+
+        //Figure out chunk file names
+        let key_encoding = meta["chunk_key_encoding"]["name"].as_str().unwrap_or("default");
+        let default_separator = if key_encoding == "v2" { "." } else { "/" };
+        let key_separator = meta["chunk_key_encoding"]["configuration"]["separator"].as_str().unwrap_or(default_separator).to_string();
+        let key_prefix = if key_encoding == "v2" { String::new() } else { format!("c{}", key_separator) };
+
+        Ok(ZarrArray {
+            path: path.to_path_buf(),
+            shape: parse_usize_list(&meta["shape"])?,
+            chunks: parse_usize_list(&meta["chunk_grid"]["configuration"]["chunk_shape"])?,
+            fill_value: parse_fill_value(&meta["fill_value"], &dtype)?,
+            dtype,
+            big_endian,
+            compression,
+            key_prefix,
+            key_separator,
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Number of elements in total
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read one chunk, uncompressed. Missing chunks are None, meaning they are all fill value
+    fn read_chunk(&self, chunk_pos: &Vec<usize>) -> anyhow::Result<Option<Vec<u8>>> {
+        let key = chunk_pos.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(&self.key_separator);
+        let path_chunk = self.path.join(format!("{}{}", self.key_prefix, key));
+        if !path_chunk.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(&path_chunk)?;
+        Ok(Some(self.compression.decompress(data)?))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read a range of a 1D numeric array, as f64. Only the chunks covering the range are read
+    pub fn read_range_f64(&self, range: Range<usize>) -> anyhow::Result<Vec<f64>> {
+        if self.shape.len() != 1 {
+            anyhow::bail!("Expected 1D array: {}", self.path.display());
+        }
+        let mut out = Vec::with_capacity(range.len());
+        if range.is_empty() {
+            return Ok(out);
+        }
+        let chunk_size = self.chunks[0];
+        let first_chunk = range.start / chunk_size;
+        let last_chunk = (range.end - 1) / chunk_size;
+        for chunk_i in first_chunk..=last_chunk {
+            let chunk_start = chunk_i * chunk_size;
+            let from = range.start.max(chunk_start) - chunk_start;
+            let to = range.end.min(chunk_start + chunk_size) - chunk_start;
+            if let Some(bytes) = self.read_chunk(&vec![chunk_i])? {
+                out.extend(self.decode_numeric(&bytes, from..to)?);
+            } else {
+                out.extend(std::iter::repeat(self.fill_value).take(to - from));
+            }
+        }
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read all of a numeric array, in C order, as f64
+    pub fn read_all_f64(&self) -> anyhow::Result<Vec<f64>> {
+        if self.shape.len() == 1 {
+            return self.read_range_f64(0..self.shape[0]);
+        }
+        if self.shape.len() != 2 {
+            anyhow::bail!("Only 1D and 2D arrays are supported: {}", self.path.display());
+        }
+
+        //Copy each chunk into place. Chunks at the edges are still stored at full size
+        let (num_row, num_col) = (self.shape[0], self.shape[1]);
+        let (chunk_row, chunk_col) = (self.chunks[0], self.chunks[1]);
+        let mut out = vec![self.fill_value; num_row*num_col];
+        for ci in 0..((num_row + chunk_row - 1)/chunk_row) {
+            for cj in 0..((num_col + chunk_col - 1)/chunk_col) {
+                if let Some(bytes) = self.read_chunk(&vec![ci, cj])? {
+                    let chunk = self.decode_numeric(&bytes, 0..(chunk_row*chunk_col))?;
+                    for i in 0..chunk_row {
+                        for j in 0..chunk_col {
+                            let (row, col) = (ci*chunk_row + i, cj*chunk_col + j);
+                            if row < num_row && col < num_col {
+                                out[row*num_col + col] = chunk[i*chunk_col + j];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Decode a range of numbers from an uncompressed chunk
+    fn decode_numeric(&self, bytes: &Vec<u8>, range: Range<usize>) -> anyhow::Result<Vec<f64>> {
+        let size = self.dtype.size().context("Not a numeric array")?;
+        let mut out = Vec::with_capacity(range.len());
+        for i in range {
+            let mut b = bytes.get(i*size..(i+1)*size).context("Truncated zarr chunk")?.to_vec();
+            if self.big_endian {
+                b.reverse();
+            }
+            let v = match (&self.dtype, size) {
+                (ZarrDataType::Bool, _) => b[0] as f64,
+                (ZarrDataType::Int(_), 1) => b[0] as i8 as f64,
+                (ZarrDataType::Int(_), 2) => i16::from_le_bytes(b.try_into().unwrap()) as f64,
+                (ZarrDataType::Int(_), 4) => i32::from_le_bytes(b.try_into().unwrap()) as f64,
+                (ZarrDataType::Int(_), 8) => i64::from_le_bytes(b.try_into().unwrap()) as f64,
+                (ZarrDataType::UInt(_), 1) => b[0] as f64,
+                (ZarrDataType::UInt(_), 2) => u16::from_le_bytes(b.try_into().unwrap()) as f64,
+                (ZarrDataType::UInt(_), 4) => u32::from_le_bytes(b.try_into().unwrap()) as f64,
+                (ZarrDataType::UInt(_), 8) => u64::from_le_bytes(b.try_into().unwrap()) as f64,
+                (ZarrDataType::Float(_), 4) => f32::from_le_bytes(b.try_into().unwrap()) as f64,
+                (ZarrDataType::Float(_), 8) => f64::from_le_bytes(b.try_into().unwrap()),
+                _ => anyhow::bail!("Not a numeric array: {}", self.path.display())
+            };
+            out.push(v);
+        }
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read all of a 1D string array
+    pub fn read_all_strings(&self) -> anyhow::Result<Vec<String>> {
+        if self.shape.len() != 1 {
+            anyhow::bail!("Expected 1D array: {}", self.path.display());
+        }
+        self.read_range_strings(0..self.shape[0])
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read a range of a 1D string array. Only the chunks covering the range are read
+    pub fn read_range_strings(&self, range: Range<usize>) -> anyhow::Result<Vec<String>> {
+        if self.shape.len() != 1 {
+            anyhow::bail!("Expected 1D array: {}", self.path.display());
+        }
+        let mut out = Vec::with_capacity(range.len());
+        if range.is_empty() {
+            return Ok(out);
+        }
+        let chunk_size = self.chunks[0];
+        let first_chunk = range.start / chunk_size;
+        let last_chunk = (range.end - 1) / chunk_size;
+        for chunk_i in first_chunk..=last_chunk {
+            let chunk_start = chunk_i * chunk_size;
+            let from = range.start.max(chunk_start) - chunk_start;
+            let to = range.end.min(chunk_start + chunk_size) - chunk_start;
+            let bytes = self.read_chunk(&vec![chunk_i])?.context("Missing chunk in string array")?;
+            let list_str = self.decode_strings(&bytes)?;
+            out.extend(list_str.get(from..to).context("Truncated zarr chunk")?.iter().cloned());
+        }
+        Ok(out)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Decode strings from an uncompressed chunk
+    fn decode_strings(&self, bytes: &Vec<u8>) -> anyhow::Result<Vec<String>> {
+        let mut out = Vec::new();
+        match self.dtype {
+            ZarrDataType::VarLenString => {
+                //Layout: number of items, then length and bytes of each item
+                let read_u32 = |pos: usize| -> anyhow::Result<usize> {
+                    let b = bytes.get(pos..pos+4).context("Truncated zarr chunk")?;
+                    Ok(u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                };
+                let num_items = read_u32(0)?;
+                let mut pos = 4;
+                for _ in 0..num_items {
+                    let len = read_u32(pos)?;
+                    pos += 4;
+                    let b = bytes.get(pos..pos+len).context("Truncated zarr chunk")?;
+                    out.push(String::from_utf8_lossy(b).to_string());
+                    pos += len;
+                }
+            },
+            ZarrDataType::FixedUnicode(n) => {
+                for item in bytes.chunks(n*4) {
+                    let s: String = item.chunks(4)
+                        .map(|c| {
+                            let c: [u8; 4] = c.try_into().unwrap();
+                            if self.big_endian { u32::from_be_bytes(c) } else { u32::from_le_bytes(c) }
+                        })
+                        .take_while(|c| *c != 0)
+                        .filter_map(char::from_u32)
+                        .collect();
+                    out.push(s);
+                }
+            },
+            ZarrDataType::FixedBytes(n) => {
+                for item in bytes.chunks(n) {
+                    let end = item.iter().position(|c| *c == 0).unwrap_or(item.len());
+                    out.push(String::from_utf8_lossy(&item[0..end]).to_string());
+                }
+            },
+            _ => anyhow::bail!("Not a string array: {}", self.path.display())
+        }
+        Ok(out)
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Check if a directory is a Zarr array
+fn is_zarr_array(path: &Path) -> bool {
+    if path.join(".zarray").exists() {
+        true
+    } else if let Ok(meta) = read_json(&path.join("zarr.json")) {
+        meta["node_type"].as_str() == Some("array")
+    } else {
+        false
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// List members of a Zarr group, which are subdirectories
+fn list_zarr_members(path: &Path) -> anyhow::Result<Vec<String>> {
+    let mut out = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            out.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+
+////////////////////////////////////////////////////////////
+/// Read a JSON file
+fn read_json(path: &Path) -> anyhow::Result<Value> {
+    let f = std::fs::File::open(path)?;
+    Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+}
+
+
+////////////////////////////////////////////////////////////
+/// Parse the fill value of an array, for numbers. It can be given as a number, as a bool,
+/// as one of "NaN", "Infinity" and "-Infinity", or in v3 as the bits of a float such as "0x7fc00000".
+/// Strings have no fill value that is used here, so it is taken as 0
+fn parse_fill_value(v: &Value, dtype: &ZarrDataType) -> anyhow::Result<f64> {
+    Ok(match v {
+        Value::Null => 0.0,
+        Value::Bool(b) => if *b { 1.0 } else { 0.0 },
+        Value::Number(n) => n.as_f64().context("Fill value is not a number")?,
+        Value::String(s) => match (s.as_str(), dtype) {
+            ("NaN", _) => f64::NAN,
+            ("Infinity", _) => f64::INFINITY,
+            ("-Infinity", _) => f64::NEG_INFINITY,
+            (s, ZarrDataType::Float(4)) if s.starts_with("0x") => f32::from_bits(u32::from_str_radix(&s[2..], 16)?) as f64,
+            (s, ZarrDataType::Float(8)) if s.starts_with("0x") => f64::from_bits(u64::from_str_radix(&s[2..], 16)?),
+            (_, ZarrDataType::VarLenString | ZarrDataType::FixedUnicode(_) | ZarrDataType::FixedBytes(_)) => 0.0,
+            (s, _) => anyhow::bail!("Unsupported zarr fill value {}", s),
+        },
+        _ => match dtype {
+            ZarrDataType::VarLenString | ZarrDataType::FixedUnicode(_) | ZarrDataType::FixedBytes(_) => 0.0,
+            _ => anyhow::bail!("Unsupported zarr fill value {}", v),
+        },
+    })
+}
+
+
+////////////////////////////////////////////////////////////
+/// Parse a JSON list of sizes
+fn parse_usize_list(v: &Value) -> anyhow::Result<Vec<usize>> {
+    v.as_array().context("Expected a list")?
+        .iter()
+        .map(|x| x.as_u64().map(|x| x as usize).context("Expected a number"))
+        .collect()
+}
+
+
+
+
+
+
+////////////////////////////////////////////////////////////
+/// A count matrix in a Zarr store
+pub struct ZarrCountMat {
+    pub indices: ZarrArray,
+    pub data: ZarrArray,
+}
+
+
+////////////////////////////////////////////////////////////
+/// Reader of a Zarr directory store, laid out as a biscvi5 file
+pub struct ZarrCountFile {
+    pub root: PathBuf,
+    pub matrices: HashMap<String, CountFileMat>,
+    pub matrix_arrays: HashMap<String, ZarrCountMat>,
+    pub reductions: HashMap<String, CountFileRed>,
+    pub meta: HashMap<String, CountFileMetaColumnDesc>,
+    pub cell_major: CellMajorCache,
+    /// Total counts of each cell, per matrix. Used as size factors when normalizing
+    pub cell_totals: HashMap<String, Vec<f64>>,
+}
+
+impl ZarrCountFile {
+
+    ////////////////////////////////////////////////////////////
+    /// Read a range of cells of a metadata column, or all of them if no range is given
+    fn read_meta_range(&self, column_name: &String, range: Option<Range<usize>>) -> anyhow::Result<CountFileMetaColumnData> {
+        let desc = self.meta.get(column_name).context(format!("Failed to find metadata column {}", column_name))?;
+        let path_col = self.root.join("obs").join(column_name);
+        let range_of = |arr: &ZarrArray| range.clone().unwrap_or(0..arr.len());
+
+        //Columns with missing values are stored as values and a mask, true where missing
+        let (path_values, mask) = if !is_zarr_array(&path_col) && is_zarr_array(&path_col.join("mask")) {
+            let arr = ZarrArray::open(&path_col.join("mask"))?;
+            let mask = arr.read_range_f64(range_of(&arr))?.iter().map(|x| *x != 0.0).collect::<Vec<_>>();
+            (path_col.join("values"), Some(mask))
+        } else {
+            (path_col.clone(), None)
+        };
+        let is_missing = |i: usize| mask.as_ref().map(|m| m.get(i).cloned().unwrap_or(false)).unwrap_or(false);
+
+        Ok(match desc {
+            CountFileMetaColumnDesc::Numeric() => {
+                let arr = ZarrArray::open(&path_values)?;
+                CountFileMetaColumnData::Numeric(arr.read_range_f64(range_of(&arr))?.iter().enumerate().map(|(i, x)| if is_missing(i) { f32::NAN } else { *x as f32 }).collect())
+            },
+            CountFileMetaColumnDesc::Boolean() => {
+                let arr = ZarrArray::open(&path_values)?;
+                CountFileMetaColumnData::Boolean(arr.read_range_f64(range_of(&arr))?.iter().enumerate().map(|(i, x)| if is_missing(i) { None } else { Some(*x != 0.0) }).collect())
+            },
+            CountFileMetaColumnDesc::Integer() => {
+                let arr = ZarrArray::open(&path_values)?;
+                CountFileMetaColumnData::Integer(arr.read_range_f64(range_of(&arr))?.iter().enumerate().map(|(i, x)| if is_missing(i) { None } else { Some(*x as i64) }).collect())
+            },
+            CountFileMetaColumnDesc::Text() => {
+                let arr = ZarrArray::open(&path_values)?;
+                CountFileMetaColumnData::Text(arr.read_range_strings(range_of(&arr))?.into_iter().enumerate().map(|(i, x)| if is_missing(i) { None } else { Some(x) }).collect())
+            },
+            CountFileMetaColumnDesc::Categorical(cats) => {
+                let arr = ZarrArray::open(&path_col.join("codes"))?;
+                //Negative codes are missing values, given a code past the last category
+                let missing_code = cats.len() as u32;
+                let codes = arr.read_range_f64(range_of(&arr))?.iter().map(|x| if *x < 0.0 { missing_code } else { *x as u32 }).collect();
+                CountFileMetaColumnData::Categorical(codes, cats.clone())
+            },
+        })
+    }
+}
+
+impl DataSource for ZarrCountFile {
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve feature index by name
+    fn get_feature_index(&self, count_name: &String, feature_name: &String) -> anyhow::Result<usize> {
+        let mat = self.matrices.get(count_name).context("Could not find count matrix")?;
+        let id = mat.map_feature_names_pos.get(feature_name).context("Could not find feature ID")?;
+        Ok(*id)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve all feature counts for a given cell
+    fn get_counts_for_cell(&self, count_name: &String, row: u32) -> anyhow::Result<MetadataColumnResponse> {
+        let cnt = self.matrices.get(count_name).context("Could not find count matrix")?;
+        let arrays = self.matrix_arrays.get(count_name).context("Could not find count matrix")?;
+
+        let row_start = *cnt.list_indptr.get(row as usize).context("err1")? as usize;
+        let row_end = *cnt.list_indptr.get(1 + row as usize).context("err2")? as usize;
+
+        let ret_indices = arrays.indices.read_range_f64(row_start..row_end)?.iter().map(|x| *x as u32).collect();
+        let ret_data = arrays.data.read_range_f64(row_start..row_end)?.iter().map(|x| *x as f32).collect();
+
+        Ok(MetadataColumnResponse {
+            data: CountFileMetaColumnData::SparseNumeric(ret_indices, ret_data)
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the total counts of each cell, computed while indexing
+    fn get_cell_totals(&self, count_name: &String) -> anyhow::Result<&Vec<f64>> {
+        self.cell_totals.get(count_name).context("Could not find count matrix")
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Retrieve the counts of one cell. The matrix is transposed in memory the first time
+    fn get_cell_counts(&self, count_name: &String, cell: u32) -> anyhow::Result<(Vec<u32>, Vec<f32>)> {
+        let cnt = self.matrices.get(count_name).context("Could not find count matrix")?;
+        let arrays = self.matrix_arrays.get(count_name).context("Could not find count matrix")?;
+
+        let mat = self.cell_major.get_or_build(count_name, || {
+            let num_cells = self.get_num_cells()?;
+            let indices = arrays.indices.read_all_f64()?.iter().map(|x| *x as u32).collect();
+            let data = arrays.data.read_all_f64()?.iter().map(|x| *x as f32).collect();
+            CellMajorMatrix::from_feature_major(&cnt.list_indptr, &indices, &data, num_cells)
+        })?;
+        mat.get_cell(cell)
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read the reduction coordinates from the store
+    fn get_reduction(&self, reduction_name: &String) -> anyhow::Result<ReductionResponse> {
+        let red = self.reductions.get(reduction_name).context(format!("Failed to find reduction {}", reduction_name))?;
+        let arr = ZarrArray::open(&self.root.join("reductions").join(reduction_name))?;
+        let v = arr.read_all_f64()?;
+
+        // Stored as (dim, cell)
+        let x = v[0..red.num_sample].iter().map(|x| *x as f32).collect();
+        let y = v[red.num_sample..(2*red.num_sample)].iter().map(|x| *x as f32).collect();
+        let z = if red.num_dim == 3 {
+            Some(v[(2*red.num_sample)..(3*red.num_sample)].iter().map(|x| *x as f32).collect())
+        } else {
+            None
+        };
+        Ok(ReductionResponse {
+            x,y,z
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Read all dimensions of a reduction
+    fn get_reduction_dims(&self, reduction_name: &String) -> anyhow::Result<Vec<Vec<f32>>> {
+        let red = self.reductions.get(reduction_name).context(format!("Failed to find reduction {}", reduction_name))?;
+        let arr = ZarrArray::open(&self.root.join("reductions").join(reduction_name))?;
+        let v = arr.read_all_f64()?;
+
+        // Stored as (dim, cell)
+        Ok(v.chunks(red.num_sample.max(1)).map(|dim| dim.iter().map(|x| *x as f32).collect()).collect())
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get all values for a metadata column
+    fn get_metacolumn(&self, column_name: &String) -> anyhow::Result<MetadataColumnResponse> {
+        Ok(MetadataColumnResponse {
+            data: self.read_meta_range(column_name, None)?
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the value of one cell in a metadata column as text, reading only the chunks holding it
+    fn get_meta_label(&self, column_name: &String, cell: usize) -> anyhow::Result<Option<String>> {
+        Ok(self.read_meta_range(column_name, Some(cell..(cell+1)))?.get_label(0))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get a description of the dataset
+    fn get_desc(&self) -> anyhow::Result<DatasetDescResponse> {
+        Ok(DatasetDescResponse {
+            matrices: self.matrices.iter().map(|(k, mat)| (k.clone(), mat.get_desc())).collect(),
+            reductions: self.reductions.clone(),
+            meta: self.meta.clone(),
+            user_meta: Vec::new(),
+        })
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the feature names and pointers of a count matrix
+    fn get_matrix(&self, count_name: &String) -> anyhow::Result<&CountFileMat> {
+        self.matrices.get(count_name).context(format!("Could not find count matrix {}", count_name))
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the names of all cells. Stores without stored names get numbered cells
+    fn get_barcodes(&self) -> anyhow::Result<Vec<String>> {
+        let path_barcodes = self.root.join("barcodes");
+        if is_zarr_array(&path_barcodes) {
+            ZarrArray::open(&path_barcodes)?.read_all_strings()
+        } else {
+            Ok(make_numbered_barcodes(self.get_num_cells()?))
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the name of one cell, reading only the chunk holding it
+    fn get_barcode(&self, cell: usize) -> anyhow::Result<String> {
+        let path_barcodes = self.root.join("barcodes");
+        if is_zarr_array(&path_barcodes) {
+            let barcodes = ZarrArray::open(&path_barcodes)?.read_range_strings(cell..(cell+1))?;
+            barcodes.into_iter().next().context(format!("Cell {} out of range", cell))
+        } else {
+            Ok(make_numbered_barcode(cell))
+        }
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Read a Zarr store and figure out the contents for later rapid response.
+/// Same layout as index_countfile
+pub fn index_zarr(p: &PathBuf) -> anyhow::Result<ZarrCountFile> {
+
+    println!("======== parsing zarr store ========");
+
+    /////// Gather all count matrices
+    let mut map_matrices: HashMap<String, CountFileMat> = HashMap::new();
+    let mut map_matrix_arrays: HashMap<String, ZarrCountMat> = HashMap::new();
+    let mut map_cell_totals: HashMap<String, Vec<f64>> = HashMap::new();
+    let path_counts = p.join("counts");
+    for count_name in list_zarr_members(&path_counts)? {
+        println!("Indexing count matrix: {}", count_name);
+        let path_cnt = path_counts.join(&count_name);
+
+        let list_indptr = ZarrArray::open(&path_cnt.join("indptr"))?.read_all_f64()?.iter().map(|x| *x as u32).collect();
+        let list_feature_names = ZarrArray::open(&path_cnt.join("feature_names"))?.read_all_strings()?;
+
+        map_matrices.insert(count_name.clone(), CountFileMat::new(list_feature_names, list_indptr));
+        let arrays = ZarrCountMat {
+            indices: ZarrArray::open(&path_cnt.join("indices"))?,
+            data: ZarrArray::open(&path_cnt.join("data"))?,
+        };
+        map_cell_totals.insert(count_name.clone(), compute_zarr_cell_totals(&arrays)?);
+        map_matrix_arrays.insert(count_name.clone(), arrays);
+    }
+
+    /////// Gather all reductions
+    let mut map_reductions: HashMap<String, CountFileRed> = HashMap::new();
+    let path_reds = p.join("reductions");
+    for red_name in list_zarr_members(&path_reds)? {
+        let arr = ZarrArray::open(&path_reds.join(&red_name))?;
+        println!("indexing reduction {} with dim {:?}",red_name, arr.shape);
+        if arr.shape.len() != 2 {
+            anyhow::bail!("Reduction {} is not a 2D array", red_name);
+        }
+
+        // Stored as (dim, cell)
+        map_reductions.insert(red_name.clone(), CountFileRed {
+            num_sample: arr.shape[1],
+            num_dim: arr.shape[0],
+        });
+    }
+
+    /////// Gather all metadata
+    let mut map_meta: HashMap<String, CountFileMetaColumnDesc> = HashMap::new();
+    let path_meta = p.join("obs");
+    let meta_names = list_zarr_members(&path_meta)?;
+    println!("Indexing Metadata columns {:?}", meta_names);
+    for meta_name in meta_names {
+        let path_col = path_meta.join(&meta_name);
+        let desc = if is_zarr_array(&path_col) {
+            get_zarr_meta_type(&ZarrArray::open(&path_col)?)
+        } else if is_zarr_array(&path_col.join("mask")) {
+            get_zarr_meta_type(&ZarrArray::open(&path_col.join("values"))?)
+        } else {
+            let categories = ZarrArray::open(&path_col.join("categories"))?.read_all_strings()?;
+            CountFileMetaColumnDesc::Categorical(categories)
+        };
+        map_meta.insert(meta_name.clone(), desc);
+    }
+
+    println!("======== parsing zarr store DONE ========");
+
+    Ok(ZarrCountFile {
+        root: p.clone(),
+        matrices: map_matrices,
+        matrix_arrays: map_matrix_arrays,
+        reductions: map_reductions,
+        meta: map_meta,
+        cell_major: CellMajorCache::new(),
+        cell_totals: map_cell_totals,
+    })
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Sum the counts of each cell in a matrix, reading it in chunks
+fn compute_zarr_cell_totals(arrays: &ZarrCountMat) -> anyhow::Result<Vec<f64>> {
+    let mut totals = Vec::new();
+    let num_values = arrays.data.len();
+    let mut from = 0;
+    while from < num_values {
+        let to = (from + CELL_TOTALS_CHUNK).min(num_values);
+        let indices = arrays.indices.read_range_f64(from..to)?.iter().map(|x| *x as u32).collect::<Vec<_>>();
+        let data = arrays.data.read_range_f64(from..to)?.iter().map(|x| *x as f32).collect::<Vec<_>>();
+        add_cell_totals(&mut totals, &indices, &data);
+        from = to;
+    }
+    Ok(totals)
+}
+
+
+
+////////////////////////////////////////////////////////////
+/// Figure out the type of a metadata column stored as an array
+fn get_zarr_meta_type(arr: &ZarrArray) -> CountFileMetaColumnDesc {
+    match arr.dtype {
+        ZarrDataType::Bool => CountFileMetaColumnDesc::Boolean(),
+        ZarrDataType::Int(_) | ZarrDataType::UInt(_) => CountFileMetaColumnDesc::Integer(),
+        ZarrDataType::Float(_) => CountFileMetaColumnDesc::Numeric(),
+        ZarrDataType::FixedUnicode(_) | ZarrDataType::FixedBytes(_) | ZarrDataType::VarLenString => CountFileMetaColumnDesc::Text(),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ////////////////////////////////////////////////////////////
+    /// Make an empty directory for an array
+    fn make_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("biscvi_zarr_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Little-endian bytes of f32 values
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn parse_v2_dtypes() {
+        assert_eq!(ZarrDataType::parse_v2("<f4").unwrap(), (ZarrDataType::Float(4), false));
+        assert_eq!(ZarrDataType::parse_v2(">i8").unwrap(), (ZarrDataType::Int(8), true));
+        assert_eq!(ZarrDataType::parse_v2("|b1").unwrap(), (ZarrDataType::Bool, false));
+        assert_eq!(ZarrDataType::parse_v2("<U10").unwrap(), (ZarrDataType::FixedUnicode(10), false));
+        assert_eq!(ZarrDataType::parse_v2("|O").unwrap(), (ZarrDataType::VarLenString, false));
+        assert!(ZarrDataType::parse_v2("<c8").is_err());
+    }
+
+    #[test]
+    fn blosc_is_refused_naming_the_array() {
+        let dir = make_test_dir("blosc");
+        std::fs::write(dir.join(".zarray"), r#"{"shape": [5], "chunks": [2], "dtype": "<f4", "order": "C", "compressor": {"id": "blosc", "cname": "lz4", "clevel": 5, "shuffle": 1}, "filters": null, "fill_value": 0.0, "zarr_format": 2}"#).unwrap();
+
+        let msg = ZarrArray::open(&dir).unwrap_err().to_string();
+        assert!(msg.contains("blosc"));
+        assert!(msg.contains(&dir.display().to_string()));
+
+        let dir = make_test_dir("blosc3");
+        std::fs::write(dir.join("zarr.json"), r#"{"zarr_format": 3, "node_type": "array", "shape": [5], "data_type": "float32", "chunk_grid": {"name": "regular", "configuration": {"chunk_shape": [2]}}, "chunk_key_encoding": {"name": "default"}, "fill_value": 0.0, "codecs": [{"name": "bytes", "configuration": {"endian": "little"}}, {"name": "blosc", "configuration": {"cname": "lz4"}}]}"#).unwrap();
+
+        let msg = ZarrArray::open(&dir).unwrap_err().to_string();
+        assert!(msg.contains("blosc"));
+        assert!(msg.contains(&dir.display().to_string()));
+    }
+
+    #[test]
+    fn missing_chunks_are_fill_value() {
+        let dir = make_test_dir("fill");
+        std::fs::write(dir.join(".zarray"), r#"{"shape": [5], "chunks": [2], "dtype": "<f4", "order": "C", "compressor": null, "filters": null, "fill_value": 7.0, "zarr_format": 2}"#).unwrap();
+        std::fs::write(dir.join("0"), f32_bytes(&[1.0, 2.0])).unwrap();
+        std::fs::write(dir.join("2"), f32_bytes(&[5.0, 0.0])).unwrap();
+
+        let arr = ZarrArray::open(&dir).unwrap();
+        assert_eq!(arr.read_range_f64(0..5).unwrap(), vec![1.0, 2.0, 7.0, 7.0, 5.0]);
+        assert_eq!(arr.read_range_f64(1..3).unwrap(), vec![2.0, 7.0]);
+    }
+
+    #[test]
+    fn missing_2d_chunks_are_nan_fill_value() {
+        let dir = make_test_dir("fill2d");
+        std::fs::write(dir.join(".zarray"), r#"{"shape": [2, 3], "chunks": [2, 2], "dtype": "<f4", "order": "C", "compressor": null, "filters": null, "fill_value": "NaN", "zarr_format": 2}"#).unwrap();
+        std::fs::write(dir.join("0.0"), f32_bytes(&[1.0, 2.0, 4.0, 5.0])).unwrap();
+
+        let arr = ZarrArray::open(&dir).unwrap();
+        let v = arr.read_all_f64().unwrap();
+        assert_eq!(v[0..2], [1.0, 2.0]);
+        assert_eq!(v[3..5], [4.0, 5.0]);
+        assert!(v[2].is_nan() && v[5].is_nan());
+    }
+
+    #[test]
+    fn unsupported_filters_are_refused() {
+        let dir = make_test_dir("filter");
+        std::fs::write(dir.join(".zarray"), r#"{"shape": [4], "chunks": [4], "dtype": "<i4", "order": "C", "compressor": null, "filters": [{"id": "delta", "dtype": "<i4"}], "fill_value": 0, "zarr_format": 2}"#).unwrap();
+        assert!(ZarrArray::open(&dir).is_err());
+    }
+
+    #[test]
+    fn varlen_strings_v2() {
+        let dir = make_test_dir("vlen");
+        std::fs::write(dir.join(".zarray"), r#"{"shape": [2], "chunks": [2], "dtype": "|O", "order": "C", "compressor": null, "filters": [{"id": "vlen-utf8"}], "fill_value": 0, "zarr_format": 2}"#).unwrap();
+        let mut chunk = Vec::new();
+        chunk.extend(2u32.to_le_bytes());
+        for s in ["ab", "\u{e9}t\u{e9}"] {
+            chunk.extend((s.len() as u32).to_le_bytes());
+            chunk.extend(s.as_bytes());
+        }
+        std::fs::write(dir.join("0"), chunk).unwrap();
+
+        let arr = ZarrArray::open(&dir).unwrap();
+        assert_eq!(arr.read_all_strings().unwrap(), vec!["ab".to_string(), "\u{e9}t\u{e9}".to_string()]);
+    }
+
+    #[test]
+    fn int_array_v3() {
+        let dir = make_test_dir("v3");
+        std::fs::write(dir.join("zarr.json"), r#"{
+            "zarr_format": 3, "node_type": "array", "shape": [3], "data_type": "int32",
+            "chunk_grid": {"name": "regular", "configuration": {"chunk_shape": [2]}},
+            "chunk_key_encoding": {"name": "default", "configuration": {"separator": "/"}},
+            "codecs": [{"name": "bytes", "configuration": {"endian": "little"}}],
+            "fill_value": -1
+        }"#).unwrap();
+        std::fs::create_dir_all(dir.join("c")).unwrap();
+        let chunk = [3i32, -4].iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        std::fs::write(dir.join("c").join("0"), chunk).unwrap();
+
+        let arr = ZarrArray::open(&dir).unwrap();
+        assert_eq!(arr.read_range_f64(0..3).unwrap(), vec![3.0, -4.0, -1.0]);
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Write a 1D v2 array of little-endian 4-byte values, in chunks of 16
+    fn write_test_array(dir: &Path, dtype: &str, values: Vec<[u8; 4]>) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(".zarray"), format!(r#"{{"shape": [{}], "chunks": [16], "dtype": "{}", "order": "C", "compressor": null, "filters": null, "fill_value": 0, "zarr_format": 2}}"#, values.len(), dtype)).unwrap();
+        for (i, chunk) in values.chunks(16).enumerate() {
+            std::fs::write(dir.join(i.to_string()), chunk.concat()).unwrap();
+        }
+    }
+
+    #[test]
+    fn concurrent_feature_reads() {
+        //Feature f is found in 3 cells, with value f+1
+        let num_features = 50;
+        let num_cells = 20;
+        let dir = make_test_dir("concurrent");
+        let dir_cnt = dir.join("counts").join("rna");
+        let indptr = (0..=num_features).map(|f| ((3*f) as i32).to_le_bytes()).collect();
+        let indices = (0..num_features).flat_map(|f| (0..3).map(move |j| (((f+j) % num_cells) as i32).to_le_bytes())).collect();
+        let data = (0..num_features).flat_map(|f| (0..3).map(move |_j| ((f+1) as f32).to_le_bytes())).collect();
+        write_test_array(&dir_cnt.join("indptr"), "<i4", indptr);
+        write_test_array(&dir_cnt.join("indices"), "<i4", indices);
+        write_test_array(&dir_cnt.join("data"), "<f4", data);
+
+        let dir_names = dir_cnt.join("feature_names");
+        std::fs::create_dir_all(&dir_names).unwrap();
+        std::fs::write(dir_names.join(".zarray"), format!(r#"{{"shape": [{}], "chunks": [{}], "dtype": "|O", "order": "C", "compressor": null, "filters": [{{"id": "vlen-utf8"}}], "fill_value": 0, "zarr_format": 2}}"#, num_features, num_features)).unwrap();
+        let mut chunk = Vec::new();
+        chunk.extend((num_features as u32).to_le_bytes());
+        for f in 0..num_features {
+            let name = format!("gene{}", f);
+            chunk.extend((name.len() as u32).to_le_bytes());
+            chunk.extend(name.as_bytes());
+        }
+        std::fs::write(dir_names.join("0"), chunk).unwrap();
+        std::fs::create_dir_all(dir.join("reductions")).unwrap();
+        std::fs::create_dir_all(dir.join("obs")).unwrap();
+
+        //Several threads read the same store at once, as requests on the blocking thread pool do
+        let ds = std::sync::Arc::new(index_zarr(&dir).unwrap());
+        let count_name = "rna".to_string();
+        let threads = (0..8).map(|t| {
+            let ds = std::sync::Arc::clone(&ds);
+            let count_name = count_name.clone();
+            std::thread::spawn(move || {
+                for k in 0..200 {
+                    let f = (t*7 + k) % num_features;
+                    let row = ds.get_feature_index(&count_name, &format!("gene{}", f)).unwrap();
+                    match ds.get_counts_for_cell(&count_name, row as u32).unwrap().data {
+                        CountFileMetaColumnData::SparseNumeric(indices, data) => {
+                            assert_eq!(indices, (0..3).map(|j| ((f+j) % num_cells) as u32).collect::<Vec<_>>());
+                            assert_eq!(data, vec![(f+1) as f32; 3]);
+                        },
+                        _ => panic!("expected sparse counts"),
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn fill_value_as_float_bits() {
+        let v = parse_fill_value(&Value::String("0x3f800000".into()), &ZarrDataType::Float(4)).unwrap();
+        assert_eq!(v, 1.0);
+        assert!(parse_fill_value(&Value::String("0x3f800000".into()), &ZarrDataType::Int(4)).is_err());
+    }
+}
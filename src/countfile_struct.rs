@@ -1,25 +1,13 @@
-use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 
 
 ////////////////////////////////////////////////////////////
-/// Pointers into a sparse matrix stored on disk. 
+/// Summary of a count matrix. Feature names are too many to send up front, and are
+/// instead listed on demand
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct CountFileMat {
-    pub list_feature_names: Vec<String>,
-    pub list_indptr: Vec<u32>,
-
-    #[serde(skip)]
-    pub map_feature_names_pos: HashMap<String, usize>,      
-}
-impl CountFileMat {
-
-    pub fn build_map(&mut self){
-        for (i,v) in self.list_feature_names.iter().enumerate() {
-            self.map_feature_names_pos.insert(v.clone(), i);
-        }
-    }
-
+pub struct CountFileMatDesc {
+    pub num_features: usize,
+    pub num_nonzero: u64,
 }
 
 ////////////////////////////////////////////////////////////
@@ -36,6 +24,23 @@ pub struct CountFileRed {
 pub enum CountFileMetaColumnDesc {
     Numeric(),
     Categorical(Vec<String>),
+    Boolean(),
+    Integer(),
+    Text(),
+}
+impl CountFileMetaColumnDesc {
+
+    ////////////////////////////////////////////////////////////
+    /// Name of the type, for showing to the user
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CountFileMetaColumnDesc::Numeric() => "numeric",
+            CountFileMetaColumnDesc::Categorical(_) => "categorical",
+            CountFileMetaColumnDesc::Boolean() => "boolean",
+            CountFileMetaColumnDesc::Integer() => "integer",
+            CountFileMetaColumnDesc::Text() => "text",
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////
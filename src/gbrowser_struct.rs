@@ -188,6 +188,50 @@ impl GBrowserGFFdescription {
 
 
 
+////////////////////////////////////////////////////////////
+/// Request for the count matrix feature that a GFF record refers to. All attributes
+/// of the record are given; the server decides which ones link to feature names
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GBrowserFeatureLinkRequest {
+    pub dataset_name: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+////////////////////////////////////////////////////////////
+/// The linked feature as (count matrix, feature name), if any
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GBrowserFeatureLinkResponse {
+    pub feature: Option<(String, String)>,
+}
+
+////////////////////////////////////////////////////////////
+/// Request for where a count matrix feature is in the genome
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GBrowserLocusRequest {
+    pub dataset_name: String,
+    pub counts_name: String,
+    pub feature_name: String,
+}
+
+////////////////////////////////////////////////////////////
+/// Position of a feature. Coordinates are 1-based, as in GFF
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GBrowserLocus {
+    pub chr: BString,
+    pub start: u64,
+    pub end: u64,
+}
+
+////////////////////////////////////////////////////////////
+/// Locus of a feature, if the GFF has it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GBrowserLocusResponse {
+    pub feature_name: String,
+    pub locus: Option<GBrowserLocus>,
+}
+
+
+
 ////////////////////////////////////////////////////////////
 /// 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
@@ -224,6 +268,7 @@ impl GBrowserGFFchunkID {
 /// 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct GBrowserGFFchunkRequest {
+    pub dataset_name: String,
     pub to_get: Vec<GBrowserGFFchunkID>
 }
 
@@ -243,6 +288,7 @@ pub struct GBrowserGFFchunkResponse {
 /// 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GBrowserGFFdescriptionRequest {
+    pub dataset_name: String,
 }
 
 ////////////////////////////////////////////////////////////
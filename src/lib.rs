@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
@@ -5,7 +6,7 @@ use serde::{Deserialize, Serialize};
 pub mod countfile_struct;
 pub mod gbrowser_struct;
 
-use countfile_struct::CountFileMat;
+use countfile_struct::CountFileMatDesc;
 use countfile_struct::CountFileMetaColumnDesc;
 use countfile_struct::CountFileRed;
 
@@ -16,6 +17,7 @@ use countfile_struct::CountFileRed;
 /// 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ReductionRequest {
+    pub dataset_name: String,
     pub reduction_name: String,
 }
 
@@ -25,6 +27,8 @@ pub struct ReductionRequest {
 pub struct ReductionResponse {
     pub x: Vec<f32>,
     pub y: Vec<f32>,
+    #[serde(default)]
+    pub z: Option<Vec<f32>>,  // Only for reductions with exactly 3 dimensions
 }
 
 
@@ -34,8 +38,176 @@ pub struct ReductionResponse {
 /// 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct FeatureCountsRequest {
+    pub dataset_name: String,
     pub counts_name: String,
     pub feature_name: String,
+    #[serde(default)]
+    pub normalization: CountNormalization,
+}
+
+////////////////////////////////////////////////////////////
+/// Request counts for many features of one count matrix in one go
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeatureCountsBatchRequest {
+    pub dataset_name: String,
+    pub counts_name: String,
+    pub feature_names: Vec<String>,
+    #[serde(default)]
+    pub normalization: CountNormalization,
+}
+
+////////////////////////////////////////////////////////////
+/// How feature counts are transformed before being sent. Size factors are the
+/// total counts of each cell in the same matrix
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CountNormalization {
+    /// Values as stored
+    #[default]
+    Raw,
+    /// Counts per 10k
+    CP10k,
+    /// log(1 + counts per 10k)
+    Log1pCP10k,
+    /// log(1 + counts per 10k), then centered and scaled over all cells. Dense
+    ZScore,
+}
+impl CountNormalization {
+
+    ////////////////////////////////////////////////////////////
+    /// All modes, in the order shown to the user
+    pub fn all() -> Vec<CountNormalization> {
+        vec![CountNormalization::Raw, CountNormalization::CP10k, CountNormalization::Log1pCP10k, CountNormalization::ZScore]
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Name shown to the user
+    pub fn name(&self) -> &'static str {
+        match self {
+            CountNormalization::Raw => "Raw",
+            CountNormalization::CP10k => "CP10k",
+            CountNormalization::Log1pCP10k => "log1p(CP10k)",
+            CountNormalization::ZScore => "z-score",
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////
+/// Format of exported tables
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    CSV,
+    TSV,
+}
+impl ExportFormat {
+
+    ////////////////////////////////////////////////////////////
+    /// All formats, in the order shown to the user
+    pub fn all() -> Vec<ExportFormat> {
+        vec![ExportFormat::CSV, ExportFormat::TSV]
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Name shown to the user, also used as file extension after lowercasing
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::CSV => "CSV",
+            ExportFormat::TSV => "TSV",
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Character between values
+    pub fn delimiter(&self) -> u8 {
+        match self {
+            ExportFormat::CSV => b',',
+            ExportFormat::TSV => b'\t',
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////
+/// Request to export values of cells as a table, one row per cell. The browser posts this
+/// as JSON in the form field "query", so that it can save the response as it arrives
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExportCellsRequest {
+    pub dataset_name: String,
+    pub cells: Option<Vec<u32>>,    // None means all cells
+    pub meta_columns: Vec<String>,
+    pub reductions: Vec<String>,
+    pub features: Vec<(String, String)>,    // (count matrix, feature name)
+    #[serde(default)]
+    pub normalization: CountNormalization,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+////////////////////////////////////////////////////////////
+/// Format of a standalone subset of a dataset
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubsetFormat {
+    #[default]
+    Biscvi5,
+    MTX,
+}
+impl SubsetFormat {
+
+    ////////////////////////////////////////////////////////////
+    /// All formats, in the order shown to the user
+    pub fn all() -> Vec<SubsetFormat> {
+        vec![SubsetFormat::Biscvi5, SubsetFormat::MTX]
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Name shown to the user
+    pub fn name(&self) -> &'static str {
+        match self {
+            SubsetFormat::Biscvi5 => "biscvi5",
+            SubsetFormat::MTX => "MTX+TSV",
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////
+/// Request to write a new dataset holding only some cells. This is done in the background;
+/// the response tells which job to ask about
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SubsetExportRequest {
+    pub dataset_name: String,
+    pub cells: Vec<u32>,
+    pub format: SubsetFormat,
+}
+
+////////////////////////////////////////////////////////////
+/// Request for the state of a subset job. The server waits a while before answering
+/// if the job is still running, so the client can ask again directly
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SubsetJobRequest {
+    pub job_id: u64,
+}
+
+////////////////////////////////////////////////////////////
+/// State of a subset job
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum SubsetJobState {
+    Running(String),    // What is being written
+    Done(Vec<String>),  // Files that can be downloaded
+    Failed(String),
+}
+
+////////////////////////////////////////////////////////////
+/// Response about a subset job
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SubsetJobResponse {
+    pub job_id: u64,
+    pub state: SubsetJobState,
+}
+
+////////////////////////////////////////////////////////////
+/// Counts for each requested feature, in the order requested. Each entry is SparseNumeric
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeatureCountsBatchResponse {
+    pub data: Vec<CountFileMetaColumnData>,
 }
 
 ////////////////////////////////////////////////////////////
@@ -58,6 +230,7 @@ pub struct ClusterResponse {
 /// 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MetadataColumnRequest {
+    pub dataset_name: String,
     pub column_name: String,
 }
 
@@ -66,11 +239,96 @@ pub struct MetadataColumnRequest {
 /// 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum CountFileMetaColumnData {
-    Numeric(Vec<f32>),
+    Numeric(Vec<f32>),                  // NaN if missing
     SparseNumeric(Vec<u32>, Vec<f32>), // indices, data
     Categorical(Vec<u32>, Vec<String>), //u32 is a lot
+    Boolean(Vec<Option<bool>>),         // None if missing
+    Integer(Vec<Option<i64>>),          // None if missing
+    Text(Vec<Option<String>>),          // None if missing
 }
 impl CountFileMetaColumnData {
+
+    ////////////////////////////////////////////////////////////
+    /// Number of cells, if known. Sparse data does not know its length
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            CountFileMetaColumnData::Numeric(v) => Some(v.len()),
+            CountFileMetaColumnData::SparseNumeric(_, _) => None,
+            CountFileMetaColumnData::Categorical(v, _) => Some(v.len()),
+            CountFileMetaColumnData::Boolean(v) => Some(v.len()),
+            CountFileMetaColumnData::Integer(v) => Some(v.len()),
+            CountFileMetaColumnData::Text(v) => Some(v.len()),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the value of a cell as a number, or None if missing or not a number.
+    /// Booleans are 0 or 1; cells not listed in sparse data are 0
+    pub fn get_number(&self, i: usize) -> Option<f32> {
+        match self {
+            CountFileMetaColumnData::Numeric(v) => v.get(i).cloned().filter(|x| !x.is_nan()),
+            CountFileMetaColumnData::SparseNumeric(indices, data) => {
+                match indices.binary_search(&(i as u32)) {
+                    Ok(pos) => Some(data[pos]),
+                    Err(_) => Some(0.0),
+                }
+            },
+            CountFileMetaColumnData::Categorical(_, _) => None,
+            CountFileMetaColumnData::Boolean(v) => v.get(i).cloned().flatten().map(|x| if x { 1.0 } else { 0.0 }),
+            CountFileMetaColumnData::Integer(v) => v.get(i).cloned().flatten().map(|x| x as f32),
+            CountFileMetaColumnData::Text(_) => None,
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Get the value of a cell as text, or None if missing
+    pub fn get_label(&self, i: usize) -> Option<String> {
+        match self {
+            CountFileMetaColumnData::Categorical(codes, cats) => codes.get(i).and_then(|c| cats.get(*c as usize)).cloned(),
+            CountFileMetaColumnData::Boolean(v) => v.get(i).cloned().flatten().map(|x| x.to_string()),
+            CountFileMetaColumnData::Integer(v) => v.get(i).cloned().flatten().map(|x| x.to_string()),
+            CountFileMetaColumnData::Text(v) => v.get(i).cloned().flatten(),
+            _ => self.get_number(i).map(|x| x.to_string()),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Check if the value of a cell is missing
+    pub fn is_missing(&self, i: usize) -> bool {
+        match self {
+            CountFileMetaColumnData::Numeric(v) => v.get(i).map(|x| x.is_nan()).unwrap_or(true),
+            CountFileMetaColumnData::SparseNumeric(_, _) => false,
+            CountFileMetaColumnData::Categorical(codes, cats) => codes.get(i).map(|c| *c as usize >= cats.len()).unwrap_or(true),
+            CountFileMetaColumnData::Boolean(v) => v.get(i).cloned().flatten().is_none(),
+            CountFileMetaColumnData::Integer(v) => v.get(i).cloned().flatten().is_none(),
+            CountFileMetaColumnData::Text(v) => v.get(i).cloned().flatten().is_none(),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////
+    /// Find the cells matching a filter
+    pub fn select_cells(&self, num_cells: usize, filter: &MetaColumnFilter) -> Vec<usize> {
+        let num_cells = self.len().unwrap_or(num_cells);
+        (0..num_cells).filter(|i| {
+            match filter {
+                MetaColumnFilter::Range(min, max) => self.get_number(*i).map(|x| *min <= x && x <= *max).unwrap_or(false),
+                MetaColumnFilter::Label(label) => self.get_label(*i).as_ref() == Some(label),
+                MetaColumnFilter::Contains(text) => self.get_label(*i).map(|x| x.contains(text.as_str())).unwrap_or(false),
+                MetaColumnFilter::Missing => self.is_missing(*i),
+            }
+        }).collect()
+    }
+}
+
+
+////////////////////////////////////////////////////////////
+/// Condition on the values of a metadata column or feature
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaColumnFilter {
+    Range(f32, f32),    // Inclusive
+    Label(String),
+    Contains(String),
+    Missing,
 }
 
 ////////////////////////////////////////////////////////////
@@ -90,15 +348,32 @@ pub struct MetadataColumnResponse {
 /// 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DatasetDescRequest {
+    pub dataset_name: String,
+}
+
+////////////////////////////////////////////////////////////
+/// 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BarcodesRequest {
+    pub dataset_name: String,
+}
+
+////////////////////////////////////////////////////////////
+/// Names of all cells, in the order used by reductions and metadata columns
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct BarcodesResponse {
+    pub barcodes: Vec<String>,
 }
 
 ////////////////////////////////////////////////////////////
 /// 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DatasetDescResponse {
-    pub matrices: HashMap<String, CountFileMat>,
+    pub matrices: HashMap<String, CountFileMatDesc>,
     pub reductions: HashMap<String, CountFileRed>,    
     pub meta: HashMap<String, CountFileMetaColumnDesc>,
+    #[serde(default)]
+    pub user_meta: Vec<String>,    // Columns in meta that were made by users, and can be edited
 }
 impl DatasetDescResponse {
 
@@ -107,6 +382,7 @@ impl DatasetDescResponse {
             matrices: HashMap::new(),
             reductions: HashMap::new(),
             meta: HashMap::new(),
+            user_meta: Vec::new(),
         }
     }
 
@@ -115,3 +391,279 @@ impl DatasetDescResponse {
 
 }
 
+
+
+
+////////////////////////////////////////////////////////////
+/// Change to a user-made categorical column
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum AnnotationEdit {
+    SetLabel(String, Option<String>, Vec<u32>),  // column, label (None removes the label), cells. Creates the column if needed
+    RenameColumn(String, String),                // column, new name
+    DeleteColumn(String),                        // column
+}
+impl AnnotationEdit {
+
+    ////////////////////////////////////////////////////////////
+    /// Name of the column changed
+    pub fn get_column_name(&self) -> &String {
+        match self {
+            AnnotationEdit::SetLabel(column_name, _, _) => column_name,
+            AnnotationEdit::RenameColumn(column_name, _) => column_name,
+            AnnotationEdit::DeleteColumn(column_name) => column_name,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////
+/// Request to change a user-made column. The response is the new DatasetDescResponse
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnnotationEditRequest {
+    pub dataset_name: String,
+    pub edit: AnnotationEdit,
+}
+
+////////////////////////////////////////////////////////////
+/// A workspace saved under a name. The view is given as the fragment of the app URL; the selection
+/// is also kept separately, as it is left out of the URL if large
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct SessionContent {
+    pub view: String,
+    pub expanded_meta: Vec<String>,
+    pub cell_selection: Vec<u32>,
+}
+
+////////////////////////////////////////////////////////////
+/// Name of a saved session, and when it was saved in seconds since the epoch
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub session_name: String,
+    pub saved_at: u64,
+}
+
+////////////////////////////////////////////////////////////
+/// Request for the sessions saved for a dataset
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionListRequest {
+    pub dataset_name: String,
+}
+
+////////////////////////////////////////////////////////////
+/// Sessions saved for a dataset, sorted by name. Also the response to saving and deleting
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SessionListResponse {
+    pub sessions: Vec<SessionInfo>,
+}
+
+////////////////////////////////////////////////////////////
+/// Request to save a session, replacing any with the same name
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionSaveRequest {
+    pub dataset_name: String,
+    pub session_name: String,
+    pub content: SessionContent,
+}
+
+////////////////////////////////////////////////////////////
+/// Request to load or delete a session
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionRequest {
+    pub dataset_name: String,
+    pub session_name: String,
+}
+
+////////////////////////////////////////////////////////////
+/// A loaded session
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SessionLoadResponse {
+    pub session_name: String,
+    pub content: SessionContent,
+}
+
+////////////////////////////////////////////////////////////
+/// Request for a page of feature names of a count matrix, in the order of the matrix
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeatureNamesRequest {
+    pub dataset_name: String,
+    pub counts_name: String,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+////////////////////////////////////////////////////////////
+/// A page of feature names. The page may be shorter than asked for, at the end of the matrix
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct FeatureNamesResponse {
+    pub counts_name: String,
+    pub offset: usize,
+    pub num_features: usize,
+    pub feature_names: Vec<String>,
+}
+
+////////////////////////////////////////////////////////////
+/// Request to check which of the given names are features of a count matrix
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeatureLookupRequest {
+    pub dataset_name: String,
+    pub counts_name: String,
+    pub feature_names: Vec<String>,
+}
+
+////////////////////////////////////////////////////////////
+/// Names from a lookup, split by whether they are in the count matrix. Order is kept
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct FeatureLookupResponse {
+    pub counts_name: String,
+    pub known: Vec<String>,
+    pub unknown: Vec<String>,
+}
+
+////////////////////////////////////////////////////////////
+/// 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DatasetListRequest {
+}
+
+////////////////////////////////////////////////////////////
+/// List of datasets served by this instance
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DatasetListResponse {
+    pub datasets: Vec<String>,
+}
+
+
+
+
+////////////////////////////////////////////////////////////
+/// Request differential expression between two sets of cells.
+/// If group_b is not given, group_a is compared to all other cells
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiffExpRequest {
+    pub dataset_name: String,
+    pub counts_name: String,
+    pub group_a: Vec<u32>,
+    pub group_b: Option<Vec<u32>>,
+}
+
+////////////////////////////////////////////////////////////
+/// Differential expression statistics for one feature
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DiffExpFeature {
+    pub feature_name: String,
+    pub log2_fold_change: f32,
+    pub frac_a: f32,    // Fraction of cells with non-zero counts
+    pub frac_b: f32,
+    pub pvalue: f64,    // Wilcoxon rank-sum
+    pub qvalue: f64,    // Benjamini-Hochberg adjusted
+}
+
+////////////////////////////////////////////////////////////
+/// Differential expression for all features, sorted by p-value
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiffExpResponse {
+    pub features: Vec<DiffExpFeature>,
+}
+
+////////////////////////////////////////////////////////////
+/// Search feature names in all count matrices, and GFF attributes
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeatureSearchRequest {
+    pub dataset_name: String,
+    pub query: String,
+    pub limit: usize,
+}
+
+////////////////////////////////////////////////////////////
+/// How a name matched a search. Better matches sort first
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FeatureSearchMatch {
+    Exact,
+    Prefix,
+    Substring,
+    Fuzzy(u32),     // Edit distance
+}
+
+////////////////////////////////////////////////////////////
+/// GFF record whose attribute matched a search
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct FeatureSearchGffHit {
+    pub attribute: String,
+    pub seqid: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+////////////////////////////////////////////////////////////
+/// One search hit. For GFF hits, counts_name is set if there is a feature of the same name
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct FeatureSearchHit {
+    pub feature_name: String,
+    pub counts_name: Option<String>,
+    pub gff: Option<FeatureSearchGffHit>,
+    pub match_type: FeatureSearchMatch,
+}
+
+////////////////////////////////////////////////////////////
+/// Search hits, best first
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct FeatureSearchResponse {
+    pub query: String,
+    pub hits: Vec<FeatureSearchHit>,
+}
+
+////////////////////////////////////////////////////////////
+/// Request everything about one cell
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CellProfileRequest {
+    pub dataset_name: String,
+    pub cell: usize,
+    pub top_n: usize,   // Number of features to list per count matrix
+}
+
+////////////////////////////////////////////////////////////
+/// Counts of one cell in one count matrix
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CellProfileMatrix {
+    pub total: f32,
+    pub num_detected: usize,                // Features with non-zero counts
+    pub top_features: Vec<(String, f32)>,   // Highest counts first
+}
+
+////////////////////////////////////////////////////////////
+/// Everything about one cell. Missing metadata values are None
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CellProfileResponse {
+    pub cell: usize,
+    pub barcode: String,
+    pub meta: BTreeMap<String, Option<String>>,
+    pub matrices: BTreeMap<String, CellProfileMatrix>,
+    pub read_files: Vec<CellReadFile>,      // Reads of the cell in Bascet zip shards, if any
+}
+
+////////////////////////////////////////////////////////////
+/// A file of reads or contigs of one cell, in a set of Bascet zip shards
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CellReadFile {
+    pub shard_set: String,  // Name of the shards, such as "filtered" for filtered.1.zip, filtered.2.zip...
+    pub file_name: String,  // Name within the cell, such as r1.fq
+    pub size: u64,          // Uncompressed size, in bytes
+}
+
+////////////////////////////////////////////////////////////
+/// Request marker features for each category of a metadata column
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MarkersRequest {
+    pub dataset_name: String,
+    pub counts_name: String,
+    pub column_name: String,
+    pub top_n: usize,
+}
+
+////////////////////////////////////////////////////////////
+/// Marker features for each category, in the same order as the categories.
+/// Group A is the category, group B the rest of the cells
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MarkersResponse {
+    pub categories: Vec<String>,
+    pub markers: Vec<Vec<DiffExpFeature>>,
+}